@@ -1,4 +1,3 @@
-use iroh::protocol::Router;
 use std::net::Ipv4Addr;
 use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -6,6 +5,7 @@ use tokio::net::TcpListener;
 use tokio::sync::oneshot;
 use tracing::{error, info};
 
+use s2p::iroh::{generate_secret_key, load_or_generate_secret_key, spawn_router};
 use s2p::{Host, S2pProtocol, TargetAddress, TcpClient, ALPN_S2P_V1};
 
 #[tokio::main]
@@ -86,8 +86,15 @@ async fn run_s2p_server(
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     info!("Starting S2P proxy server");
 
+    // Keep the server's node id across runs when S2P_SECRET_KEY names a key file
+    let secret_key = match std::env::var_os("S2P_SECRET_KEY") {
+        Some(path) => load_or_generate_secret_key(path)?,
+        None => generate_secret_key(),
+    };
+
     // Create Iroh endpoint for server with discovery
     let endpoint = iroh::endpoint::Endpoint::builder()
+        .secret_key(secret_key)
         .discovery_n0()
         .bind()
         .await?;
@@ -97,14 +104,12 @@ async fn run_s2p_server(
     info!("Server Node ID: {}", node_id);
 
     // Send the node ID to the client
-    if let Err(_) = server_id_tx.send(node_id) {
+    if server_id_tx.send(node_id).is_err() {
         error!("Failed to send server node ID to client");
     }
 
     // Use Router to handle the S2P protocol
-    let _router = Router::builder(endpoint)
-        .accept(ALPN_S2P_V1, S2pProtocol::new())
-        .spawn();
+    let _router = spawn_router(endpoint, S2pProtocol::new());
 
     // Keep server running
     tokio::time::sleep(Duration::from_secs(30)).await;
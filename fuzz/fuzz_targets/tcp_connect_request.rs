@@ -0,0 +1,23 @@
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use s2p::TcpConnectRequestCodec;
+use tokio_util::codec::{Decoder, Encoder};
+
+// Requests arrive on a stream, so keep decoding until the input runs out. Anything
+// decoded must survive a round trip through the encoder.
+fuzz_target!(|data: &[u8]| {
+    let mut src = BytesMut::from(data);
+    while let Ok(Some(request)) = TcpConnectRequestCodec.decode(&mut src) {
+        let mut encoded = BytesMut::new();
+        TcpConnectRequestCodec
+            .encode(request.clone(), &mut encoded)
+            .unwrap();
+        assert_eq!(
+            TcpConnectRequestCodec.decode(&mut encoded).unwrap(),
+            Some(request)
+        );
+        assert!(encoded.is_empty());
+    }
+});
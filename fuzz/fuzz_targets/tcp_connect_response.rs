@@ -0,0 +1,20 @@
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use s2p::TcpConnectResponseCodec;
+use tokio_util::codec::{Decoder, Encoder};
+
+fuzz_target!(|data: &[u8]| {
+    let mut src = BytesMut::from(data);
+    while let Ok(Some(response)) = TcpConnectResponseCodec.decode(&mut src) {
+        let mut encoded = BytesMut::new();
+        TcpConnectResponseCodec
+            .encode(response.clone(), &mut encoded)
+            .unwrap();
+        assert_eq!(
+            TcpConnectResponseCodec.decode(&mut encoded).unwrap(),
+            Some(response)
+        );
+    }
+});
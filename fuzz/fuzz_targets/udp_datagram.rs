@@ -0,0 +1,22 @@
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use s2p::UdpDatagramCodec;
+use tokio_util::codec::{Decoder, Encoder};
+
+// The input is a single datagram.
+fuzz_target!(|data: &[u8]| {
+    let mut src = BytesMut::from(data);
+    if let Ok(Some(datagram)) = UdpDatagramCodec.decode(&mut src) {
+        assert!(src.is_empty());
+        let mut encoded = BytesMut::new();
+        UdpDatagramCodec
+            .encode(datagram.clone(), &mut encoded)
+            .unwrap();
+        assert_eq!(
+            UdpDatagramCodec.decode(&mut encoded).unwrap(),
+            Some(datagram)
+        );
+    }
+});
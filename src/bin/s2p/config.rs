@@ -0,0 +1,26 @@
+use s2p::iroh::{ConfigError, S2pConfig};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+// Settings of `s2p serve`, read from a TOML file. The `[protocol]` table holds an
+// `S2pConfig`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServeConfig {
+    // Where the node's secret key is kept; generated on first start.
+    pub secret_key: Option<PathBuf>,
+    #[serde(default)]
+    pub protocol: S2pConfig,
+}
+
+impl ServeConfig {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let contents = std::fs::read_to_string(path).map_err(|source| ConfigError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let config: Self = toml::from_str(&contents)?;
+        config.protocol.validate()?;
+        Ok(config)
+    }
+}
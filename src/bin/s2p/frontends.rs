@@ -0,0 +1,202 @@
+use s2p::iroh::TargetConnector;
+use s2p::{ConnectStatusCode, Host, TargetAddress, TcpClientError};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::{copy_bidirectional, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{error, info};
+
+const MAX_HTTP_REQUEST_HEADER: usize = 8192;
+
+#[derive(Debug, Clone)]
+pub enum Frontend {
+    Socks5(SocketAddr),
+    Http(SocketAddr),
+    Forward {
+        listen: SocketAddr,
+        target: TargetAddress,
+    },
+}
+
+impl Frontend {
+    fn listen_addr(&self) -> SocketAddr {
+        match self {
+            Frontend::Socks5(addr) | Frontend::Http(addr) => *addr,
+            Frontend::Forward { listen, .. } => *listen,
+        }
+    }
+
+    pub async fn run(self, connector: Arc<dyn TargetConnector>) -> io::Result<()> {
+        let listener = TcpListener::bind(self.listen_addr()).await?;
+        info!("{:?} listening on {}", self, listener.local_addr()?);
+
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let frontend = self.clone();
+            let connector = connector.clone();
+            tokio::spawn(async move {
+                let result = match frontend {
+                    Frontend::Socks5(_) => serve_socks5(stream, connector).await,
+                    Frontend::Http(_) => serve_http(stream, connector).await,
+                    Frontend::Forward { target, .. } => {
+                        forward(stream, target, connector.as_ref()).await
+                    }
+                };
+                if let Err(e) = result {
+                    error!("Session from {} failed: {}", peer, e);
+                }
+            });
+        }
+    }
+}
+
+async fn forward(
+    mut stream: TcpStream,
+    target: TargetAddress,
+    connector: &dyn TargetConnector,
+) -> io::Result<()> {
+    let mut target_stream = connector.connect(target).await.map_err(into_io_error)?;
+    copy_bidirectional(&mut stream, &mut target_stream).await?;
+    Ok(())
+}
+
+// SOCKS5 without authentication, CONNECT only.
+async fn serve_socks5(
+    mut stream: TcpStream,
+    connector: Arc<dyn TargetConnector>,
+) -> io::Result<()> {
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).await?;
+    if header[0] != 0x05 {
+        return Err(invalid_data("unsupported SOCKS version"));
+    }
+    let mut methods = vec![0u8; header[1] as usize];
+    stream.read_exact(&mut methods).await?;
+    if !methods.contains(&0x00) {
+        stream.write_all(&[0x05, 0xFF]).await?;
+        return Err(invalid_data("client offered no supported auth method"));
+    }
+    stream.write_all(&[0x05, 0x00]).await?;
+
+    let mut request = [0u8; 4];
+    stream.read_exact(&mut request).await?;
+    let host = match request[3] {
+        0x01 => {
+            let mut octets = [0u8; 4];
+            stream.read_exact(&mut octets).await?;
+            Host::IPv4(Ipv4Addr::from(octets))
+        }
+        0x03 => {
+            let len = stream.read_u8().await? as usize;
+            let mut domain = vec![0u8; len];
+            stream.read_exact(&mut domain).await?;
+            Host::Domain(
+                String::from_utf8(domain).map_err(|_| invalid_data("invalid domain name"))?,
+            )
+        }
+        0x04 => {
+            let mut octets = [0u8; 16];
+            stream.read_exact(&mut octets).await?;
+            Host::IPv6(Ipv6Addr::from(octets))
+        }
+        _ => {
+            socks5_reply(
+                &mut stream,
+                ConnectStatusCode::AddressTypeNotSupported as u8,
+            )
+            .await?;
+            return Err(invalid_data("unsupported address type"));
+        }
+    };
+    let port = stream.read_u16().await?;
+    if request[1] != 0x01 {
+        socks5_reply(&mut stream, 0x07).await?;
+        return Err(invalid_data("only CONNECT is supported"));
+    }
+
+    let target = TargetAddress { host, port };
+    match connector.connect(target).await {
+        Ok(mut target_stream) => {
+            socks5_reply(&mut stream, ConnectStatusCode::Success as u8).await?;
+            copy_bidirectional(&mut stream, &mut target_stream).await?;
+            Ok(())
+        }
+        Err(e) => {
+            // s2p status codes share their values with SOCKS5 reply codes.
+            let reply = match &e {
+                TcpClientError::ProtocolError(status) => *status as u8,
+                _ => ConnectStatusCode::GeneralFailure as u8,
+            };
+            socks5_reply(&mut stream, reply).await?;
+            Err(into_io_error(e))
+        }
+    }
+}
+
+async fn socks5_reply(stream: &mut TcpStream, reply: u8) -> io::Result<()> {
+    stream
+        .write_all(&[0x05, reply, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+        .await
+}
+
+// HTTP proxy accepting CONNECT requests.
+async fn serve_http(mut stream: TcpStream, connector: Arc<dyn TargetConnector>) -> io::Result<()> {
+    let mut request = Vec::new();
+    while !request.ends_with(b"\r\n\r\n") {
+        if request.len() >= MAX_HTTP_REQUEST_HEADER {
+            return Err(invalid_data("request header too long"));
+        }
+        request.push(stream.read_u8().await?);
+    }
+
+    let request_line = String::from_utf8_lossy(&request);
+    let mut parts = request_line.split_whitespace();
+    let (method, authority) = (parts.next(), parts.next());
+    let target = match (method, authority) {
+        (Some("CONNECT"), Some(authority)) => authority.parse::<TargetAddress>().ok(),
+        _ => None,
+    };
+    let Some(target) = target else {
+        stream
+            .write_all(b"HTTP/1.1 405 Method Not Allowed\r\nAllow: CONNECT\r\n\r\n")
+            .await?;
+        return Err(invalid_data("only CONNECT is supported"));
+    };
+
+    match connector.connect(target).await {
+        Ok(mut target_stream) => {
+            stream
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await?;
+            copy_bidirectional(&mut stream, &mut target_stream).await?;
+            Ok(())
+        }
+        Err(e) => {
+            let status = match &e {
+                TcpClientError::ProtocolError(ConnectStatusCode::ConnectionNotAllowed) => {
+                    "403 Forbidden"
+                }
+                TcpClientError::ProtocolError(ConnectStatusCode::TTLExpired) => {
+                    "504 Gateway Timeout"
+                }
+                _ => "502 Bad Gateway",
+            };
+            stream
+                .write_all(format!("HTTP/1.1 {}\r\n\r\n", status).as_bytes())
+                .await?;
+            Err(into_io_error(e))
+        }
+    }
+}
+
+fn into_io_error(error: TcpClientError) -> io::Error {
+    match error {
+        TcpClientError::IoError(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
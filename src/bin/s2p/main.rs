@@ -0,0 +1,319 @@
+mod config;
+mod frontends;
+
+use config::ServeConfig;
+use frontends::Frontend;
+use iroh::endpoint::Endpoint;
+use iroh::{NodeAddr, SecretKey};
+use s2p::iroh::{
+    generate_secret_key, load_or_generate_secret_key, node_ticket, parse_node_addr, spawn_router,
+    TargetConnector, TcpClientPool, TcpClientPoolOptions,
+};
+use s2p::{S2pProtocol, TargetAddress, ALPN_S2P_V1};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
+use tokio::task::JoinSet;
+use tracing::{error, info};
+
+type CliResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+const USAGE: &str = "\
+Usage:
+  s2p serve [--config <file>] [--secret-key <file> | --ephemeral]
+  s2p connect <node id | ticket> [--secret-key <file>]
+              [--socks5 <addr>] [--http <addr>] [--forward <listen addr>=<host:port>]...
+
+`serve` accepts s2p connections and proxies them to their targets. `connect` runs local
+SOCKS5, HTTP CONNECT and port forwarding frontends that reach targets through the given
+node. Sending SIGHUP to `serve` reloads its config file.
+
+`serve` keeps its secret key, and so its node id, in the file given by --secret-key or
+the config file, or otherwise next to the config file (`<config>.key`) or in
+`$XDG_DATA_HOME/s2p/secret.key`. With --ephemeral it uses a new key on every start.";
+
+enum Command {
+    Help,
+    Serve {
+        config: Option<PathBuf>,
+        secret_key: Option<PathBuf>,
+        ephemeral: bool,
+    },
+    Connect {
+        remote: NodeAddr,
+        secret_key: Option<PathBuf>,
+        frontends: Vec<Frontend>,
+    },
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
+        )
+        .init();
+
+    let command = match parse_args(std::env::args().skip(1).collect()) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    let result = match command {
+        Command::Help => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        Command::Serve {
+            config,
+            secret_key,
+            ephemeral,
+        } => serve(config, secret_key, ephemeral).await,
+        Command::Connect {
+            remote,
+            secret_key,
+            frontends,
+        } => connect(remote, secret_key, frontends).await,
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn parse_args(args: Vec<String>) -> Result<Command, String> {
+    let mut args = args.into_iter();
+    let subcommand = args.next().ok_or("missing subcommand")?;
+
+    let mut positional = Vec::new();
+    let mut config = None;
+    let mut secret_key = None;
+    let mut ephemeral = false;
+    let mut frontends = Vec::new();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("missing value for {}", arg));
+        match arg.as_str() {
+            "--config" => config = Some(PathBuf::from(value()?)),
+            "--secret-key" => secret_key = Some(PathBuf::from(value()?)),
+            "--ephemeral" => ephemeral = true,
+            "--socks5" => frontends.push(Frontend::Socks5(parse_socket_addr(&value()?)?)),
+            "--http" => frontends.push(Frontend::Http(parse_socket_addr(&value()?)?)),
+            "--forward" => {
+                let value = value()?;
+                let (listen, target) = value.split_once('=').ok_or(format!(
+                    "expected <listen addr>=<host:port>, got {:?}",
+                    value
+                ))?;
+                frontends.push(Frontend::Forward {
+                    listen: parse_socket_addr(listen)?,
+                    target: target.parse::<TargetAddress>()?,
+                });
+            }
+            "-h" | "--help" => return Ok(Command::Help),
+            flag if flag.starts_with('-') => return Err(format!("unknown option {}", flag)),
+            _ => positional.push(arg),
+        }
+    }
+
+    match subcommand.as_str() {
+        "serve" if positional.is_empty() => {
+            if ephemeral && secret_key.is_some() {
+                return Err("--ephemeral cannot be combined with --secret-key".into());
+            }
+            Ok(Command::Serve {
+                config,
+                secret_key,
+                ephemeral,
+            })
+        }
+        "connect" if ephemeral => Err("--ephemeral only applies to serve".into()),
+        "connect" => {
+            let [remote] = <[String; 1]>::try_from(positional)
+                .map_err(|_| "connect takes exactly one node id or ticket")?;
+            if frontends.is_empty() {
+                return Err("connect needs at least one --socks5, --http or --forward".into());
+            }
+            Ok(Command::Connect {
+                remote: parse_remote(&remote)?,
+                secret_key,
+                frontends,
+            })
+        }
+        "serve" => Err("serve takes no positional arguments".into()),
+        "-h" | "--help" | "help" => Ok(Command::Help),
+        other => Err(format!("unknown subcommand {:?}", other)),
+    }
+}
+
+fn parse_socket_addr(value: &str) -> Result<std::net::SocketAddr, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid socket address {:?}", value))
+}
+
+fn parse_remote(value: &str) -> Result<NodeAddr, String> {
+    parse_node_addr(value).map_err(|e| e.to_string())
+}
+
+// Without a path the node gets an ephemeral key.
+fn load_secret_key(path: Option<&Path>) -> CliResult<SecretKey> {
+    Ok(match path {
+        Some(path) => load_or_generate_secret_key(path)?,
+        None => generate_secret_key(),
+    })
+}
+
+// Where `serve` keeps its secret key when no path is configured: next to the config file,
+// or in the XDG data directory without one.
+fn default_secret_key_path(config_path: Option<&Path>) -> CliResult<PathBuf> {
+    if let Some(config_path) = config_path {
+        return Ok(config_path.with_extension("key"));
+    }
+    let data_dir = std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share")))
+        .ok_or("no data directory for the secret key, pass --secret-key or --ephemeral")?;
+    let dir = data_dir.join("s2p");
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir.join("secret.key"))
+}
+
+async fn bind_endpoint(secret_key: SecretKey, alpns: Vec<Vec<u8>>) -> CliResult<Endpoint> {
+    Ok(Endpoint::builder()
+        .secret_key(secret_key)
+        .alpns(alpns)
+        .discovery_n0()
+        .bind()
+        .await?)
+}
+
+async fn serve(
+    config_path: Option<PathBuf>,
+    secret_key: Option<PathBuf>,
+    ephemeral: bool,
+) -> CliResult<()> {
+    let config = match &config_path {
+        Some(path) => ServeConfig::load(path)?,
+        None => ServeConfig::default(),
+    };
+    let secret_key = if ephemeral {
+        generate_secret_key()
+    } else {
+        let path = match secret_key.or_else(|| config.secret_key.clone()) {
+            Some(path) => path,
+            None => default_secret_key_path(config_path.as_deref())?,
+        };
+        load_or_generate_secret_key(path)?
+    };
+    let endpoint = bind_endpoint(secret_key, vec![ALPN_S2P_V1.as_bytes().to_vec()]).await?;
+
+    let protocol = S2pProtocol::builder_from_config(&config.protocol)
+        .await?
+        .endpoint(endpoint.clone())
+        .build()?;
+
+    let router = spawn_router(endpoint.clone(), protocol.clone());
+    if let Some(path) = config_path {
+        reload_on_hangup(path, protocol);
+    }
+
+    let ticket = node_ticket(&endpoint).await;
+    info!("Serving s2p as node {}", endpoint.node_id());
+    println!("node id: {}", endpoint.node_id());
+    println!("ticket:  {}", ticket);
+
+    shutdown_signal().await;
+    info!("Shutting down");
+    router.shutdown().await?;
+    Ok(())
+}
+
+// Re-reads the config file on SIGHUP and applies what can change on a running node.
+#[cfg(unix)]
+fn reload_on_hangup(path: PathBuf, protocol: S2pProtocol) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            error!("Failed to install SIGHUP handler: {}", e);
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            match ServeConfig::load(&path)
+                .and_then(|config| protocol.apply_config(&config.protocol))
+            {
+                Ok(()) => info!("Reloaded config {}", path.display()),
+                Err(e) => error!(
+                    "Rejected config {}, keeping previous settings: {}",
+                    path.display(),
+                    e
+                ),
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+fn reload_on_hangup(_path: PathBuf, _protocol: S2pProtocol) {}
+
+async fn connect(
+    remote: NodeAddr,
+    secret_key: Option<PathBuf>,
+    frontends: Vec<Frontend>,
+) -> CliResult<()> {
+    let secret_key = load_secret_key(secret_key.as_deref())?;
+    let endpoint = bind_endpoint(secret_key, Vec::new()).await?;
+    println!("node id: {}", endpoint.node_id());
+
+    let remote_id = remote.node_id;
+    endpoint.add_node_addr(remote)?;
+    let pool: Arc<dyn TargetConnector> = Arc::new(TcpClientPool::new(
+        endpoint.clone(),
+        [remote_id],
+        TcpClientPoolOptions::default(),
+    ));
+
+    let mut tasks = JoinSet::new();
+    for frontend in frontends {
+        tasks.spawn(frontend.run(pool.clone()));
+    }
+
+    tokio::select! {
+        _ = shutdown_signal() => info!("Shutting down"),
+        Some(result) = tasks.join_next() => {
+            result??;
+        }
+    }
+    tasks.shutdown().await;
+    endpoint.close().await;
+    Ok(())
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                return;
+            }
+            Err(e) => error!("Failed to install SIGTERM handler: {}", e),
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
@@ -1,3 +1,6 @@
+use crate::codec::encoder::{
+    ATYP_EXTENDED, EXTENDED_KIND_NODE, EXTENDED_KIND_SERVICE, EXTENDED_KIND_UNIX_SOCKET,
+};
 use crate::codec::types::CodecError::InvalidStatusCode;
 use crate::codec::types::{
     CodecError, TcpConnectRequestCodec, TcpConnectResponseCodec, UdpDatagramCodec,
@@ -7,6 +10,7 @@ use crate::message_types::{
 };
 use crate::TcpConnectRequest;
 use bytes::{Buf, BytesMut};
+use iroh::NodeId;
 use std::net::{Ipv4Addr, Ipv6Addr};
 use tokio_util::codec::Decoder;
 
@@ -21,10 +25,10 @@ impl Decoder for TcpConnectRequestCodec {
 
         let atyp = Self::parse_address_type(src[0])?;
 
-        let required_len = Self::calculate_required_length(src, atyp)?;
-        if src.len() < required_len {
-            return Ok(None);
-        }
+        let required_len = match Self::required_length(src, atyp) {
+            Some(len) if src.len() >= len => len,
+            _ => return Ok(None),
+        };
 
         let mut data = src.split_to(required_len);
         data.advance(1);
@@ -53,12 +57,13 @@ impl Decoder for UdpDatagramCodec {
         let flow_id = src[0];
         let atyp = src[1] & 0b11;
 
-        let required_len = Self::calculate_required_length(src, atyp)?;
-        if src.len() < required_len {
-            return Ok(None);
+        match Self::header_length(src, atyp)? {
+            Some(len) if src.len() >= len => {}
+            _ => return Ok(None),
         }
 
-        let mut data = src.split_to(required_len);
+        // The payload runs to the end of the datagram.
+        let mut data = src.split_to(src.len());
         data.advance(2); // Skip flow_id and atyp
 
         let address = Self::parse_address(&mut data, atyp)?;
@@ -77,34 +82,18 @@ impl Decoder for UdpDatagramCodec {
 }
 
 impl UdpDatagramCodec {
-    fn calculate_required_length(src: &BytesMut, atyp: u8) -> Result<usize, CodecError> {
+    // Length of flow_id, atyp, address and port, or None while the bytes that determine
+    // it have not arrived.
+    fn header_length(src: &BytesMut, atyp: u8) -> Result<Option<usize>, CodecError> {
         match atyp {
-            0 => {
-                if src.len() < 8 {
-                    Ok(usize::MAX) // Not enough data
-                } else {
-                    Ok(8 + (src.len() - 8)) // flow_id + atyp + IPv4 + port + data
-                }
-            }
-            1 => {
-                if src.len() < 20 {
-                    Ok(usize::MAX) // Not enough data
-                } else {
-                    Ok(20 + (src.len() - 20)) // flow_id + atyp + IPv6 + port + data
-                }
-            }
-            2 => {
-                if src.len() < 3 {
-                    return Ok(usize::MAX); // Not enough data for domain length
-                }
-                let domain_len = src[2] as usize;
-                let header_and_addr_len = 5 + domain_len; // flow_id + atyp + len + domain + port
-                if src.len() < header_and_addr_len {
-                    Ok(usize::MAX)
-                } else {
-                    Ok(header_and_addr_len + (src.len() - header_and_addr_len))
-                }
-            }
+            // flow_id + atyp + IPv4 + port
+            0 => Ok(Some(8)),
+            // flow_id + atyp + IPv6 + port
+            1 => Ok(Some(20)),
+            // flow_id + atyp + length + domain + port
+            2 => Ok(src.get(2).map(|&len| 5 + len as usize)),
+            // flow_id + atyp + kind + length + payload + port
+            ATYP_EXTENDED => Ok(src.get(3).map(|&len| 6 + len as usize)),
             _ => Err(CodecError::InvalidAddressType(atyp)),
         }
     }
@@ -129,6 +118,7 @@ impl UdpDatagramCodec {
                     .map_err(|_| CodecError::InvalidDomainEncoding)?;
                 Ok(Host::Domain(domain))
             }
+            ATYP_EXTENDED => parse_extended_address(data),
             _ => Err(CodecError::InvalidAddressType(atyp)),
         }
     }
@@ -136,24 +126,21 @@ impl UdpDatagramCodec {
 
 impl TcpConnectRequestCodec {
     fn parse_address_type(header: u8) -> Result<u8, CodecError> {
-        let atyp = header & 0b11;
-        match atyp {
-            0..=2 => Ok(atyp),
-            _ => Err(CodecError::InvalidAddressType(atyp)),
-        }
+        Ok(header & 0b11)
     }
 
-    fn calculate_required_length(src: &BytesMut, atyp: u8) -> Result<usize, CodecError> {
+    // Length of the whole request, or None while the bytes that determine it have not
+    // arrived.
+    fn required_length(src: &BytesMut, atyp: u8) -> Option<usize> {
         match atyp {
-            0 => Ok(7),  // header + IPv4 + port
-            1 => Ok(19), // header + IPv6 + port
-            2 => {
-                if src.len() < 2 {
-                    return Ok(usize::MAX); // Force "not enough data"
-                }
-                let domain_len = src[1] as usize;
-                Ok(4 + domain_len) // header + length + domain + port
-            }
+            // header + IPv4 + port
+            0 => Some(7),
+            // header + IPv6 + port
+            1 => Some(19),
+            // header + length + domain + port
+            2 => src.get(1).map(|&len| 4 + len as usize),
+            // header + kind + length + payload + port
+            ATYP_EXTENDED => src.get(2).map(|&len| 5 + len as usize),
             _ => unreachable!(),
         }
     }
@@ -178,17 +165,44 @@ impl TcpConnectRequestCodec {
                     .map_err(|_| CodecError::InvalidDomainEncoding)?;
                 Ok(Host::Domain(domain))
             }
+            ATYP_EXTENDED => parse_extended_address(data),
             _ => unreachable!(), // Already validated in parse_address_type
         }
     }
 }
 
+fn parse_extended_address(data: &mut BytesMut) -> Result<Host, CodecError> {
+    let kind = data.get_u8();
+    let len = data.get_u8() as usize;
+    let payload = data.split_to(len);
+    match kind {
+        EXTENDED_KIND_NODE => {
+            let bytes: &[u8; 32] = payload
+                .as_ref()
+                .try_into()
+                .map_err(|_| CodecError::InvalidNodeId)?;
+            let node_id = NodeId::from_bytes(bytes).map_err(|_| CodecError::InvalidNodeId)?;
+            Ok(Host::Node(node_id))
+        }
+        EXTENDED_KIND_SERVICE => {
+            let name = String::from_utf8(payload.to_vec())
+                .map_err(|_| CodecError::InvalidDomainEncoding)?;
+            Ok(Host::Service(name))
+        }
+        EXTENDED_KIND_UNIX_SOCKET => {
+            let path = String::from_utf8(payload.to_vec()).map_err(|_| CodecError::InvalidPath)?;
+            Ok(Host::UnixSocket(path.into()))
+        }
+        _ => Err(CodecError::InvalidExtendedAddressKind(kind)),
+    }
+}
+
 impl Decoder for TcpConnectResponseCodec {
     type Item = TcpConnectResponse;
     type Error = CodecError;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if src.len() < 1 {
+        if src.is_empty() {
             return Ok(None);
         }
 
@@ -61,6 +61,12 @@ impl Encoder<TcpConnectResponse> for TcpConnectResponseCodec {
     }
 }
 
+// Address type 3 is an extended address: a kind byte, a length byte and the payload.
+pub(crate) const ATYP_EXTENDED: u8 = 3;
+pub(crate) const EXTENDED_KIND_NODE: u8 = 0;
+pub(crate) const EXTENDED_KIND_SERVICE: u8 = 1;
+pub(crate) const EXTENDED_KIND_UNIX_SOCKET: u8 = 2;
+
 struct SerializedAddress {
     atyp: u8,
     domain_length: Option<u8>,
@@ -88,6 +94,28 @@ impl SerializedAddress {
                 }
                 (2u8, Some(domain.len() as u8), domain.as_bytes().to_vec())
             }
+            Host::Node(node_id) => {
+                let mut address = vec![EXTENDED_KIND_NODE, 32];
+                address.extend_from_slice(node_id.as_bytes());
+                (ATYP_EXTENDED, None, address)
+            }
+            Host::Service(name) => {
+                if name.len() > 255 {
+                    return Err(CodecError::DomainTooLong(name.len()));
+                }
+                let mut address = vec![EXTENDED_KIND_SERVICE, name.len() as u8];
+                address.extend_from_slice(name.as_bytes());
+                (ATYP_EXTENDED, None, address)
+            }
+            Host::UnixSocket(path) => {
+                let path = path.to_str().ok_or(CodecError::InvalidPath)?;
+                if path.len() > 255 {
+                    return Err(CodecError::InvalidPath);
+                }
+                let mut address = vec![EXTENDED_KIND_UNIX_SOCKET, path.len() as u8];
+                address.extend_from_slice(path.as_bytes());
+                (ATYP_EXTENDED, None, address)
+            }
         };
 
         Ok(SerializedAddress::new(atyp, domain_length, address))
@@ -21,6 +21,15 @@ pub enum CodecError {
     #[error("Invalid address type: {0}")]
     InvalidAddressType(u8),
 
+    #[error("Invalid node id")]
+    InvalidNodeId,
+
+    #[error("Invalid unix socket path")]
+    InvalidPath,
+
+    #[error("Invalid extended address kind: {0}")]
+    InvalidExtendedAddressKind(u8),
+
     #[error("Invalid status code: {0}")]
     InvalidStatusCode(u8),
 }
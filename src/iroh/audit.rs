@@ -0,0 +1,207 @@
+use crate::message_types::{ConnectStatusCode, TargetAddress};
+use iroh::NodeId;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tracing::{error, info};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditProtocol {
+    Tcp,
+    Udp { flow_id: u8 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CloseReason {
+    Completed,
+    ConnectFailed,
+    TargetNotAllowed,
+    QuotaExhausted,
+    IdleTimeout,
+    TargetChanged,
+    IoError(String),
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloseReason::Completed => write!(f, "completed"),
+            CloseReason::ConnectFailed => write!(f, "connect_failed"),
+            CloseReason::TargetNotAllowed => write!(f, "target_not_allowed"),
+            CloseReason::QuotaExhausted => write!(f, "quota_exhausted"),
+            CloseReason::IdleTimeout => write!(f, "idle_timeout"),
+            CloseReason::TargetChanged => write!(f, "target_changed"),
+            CloseReason::IoError(error) => write!(f, "io_error: {}", error),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AuditRecord {
+    pub timestamp: SystemTime,
+    pub remote_node_id: NodeId,
+    pub profile_label: Option<String>,
+    pub protocol: AuditProtocol,
+    pub target: TargetAddress,
+    pub resolved_address: Option<SocketAddr>,
+    pub status: ConnectStatusCode,
+    pub duration: Duration,
+    pub bytes_to_target: u64,
+    pub bytes_from_target: u64,
+    pub close_reason: CloseReason,
+}
+
+impl AuditRecord {
+    pub fn to_json(&self) -> serde_json::Value {
+        let (protocol, flow_id) = match self.protocol {
+            AuditProtocol::Tcp => ("tcp", None),
+            AuditProtocol::Udp { flow_id } => ("udp", Some(flow_id)),
+        };
+
+        serde_json::json!({
+            "timestamp_ms": unix_millis(self.timestamp),
+            "remote_node_id": self.remote_node_id.to_string(),
+            "profile": self.profile_label,
+            "protocol": protocol,
+            "flow_id": flow_id,
+            "target": self.target.to_string(),
+            "resolved_address": self.resolved_address.map(|addr| addr.to_string()),
+            "status": format!("{:?}", self.status),
+            "duration_ms": self.duration.as_millis() as u64,
+            "bytes_to_target": self.bytes_to_target,
+            "bytes_from_target": self.bytes_from_target,
+            "close_reason": self.close_reason.to_string(),
+        })
+    }
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+pub trait AuditSink: Send + Sync + std::fmt::Debug {
+    fn record(&self, record: AuditRecord) -> Pin<Box<dyn Future<Output = ()> + Send + '_>>;
+}
+
+#[derive(Debug, Clone)]
+pub struct NoopAuditSink;
+
+impl NoopAuditSink {
+    pub fn new() -> Self {
+        Self
+    }
+
+    pub fn arc() -> Arc<dyn AuditSink> {
+        Arc::new(Self::new())
+    }
+}
+
+impl AuditSink for NoopAuditSink {
+    fn record(&self, _record: AuditRecord) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(async move {})
+    }
+}
+
+impl Default for NoopAuditSink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Emits every record as an `info` event on the `s2p::audit` target.
+#[derive(Debug, Clone)]
+pub struct TracingAuditSink;
+
+impl TracingAuditSink {
+    pub fn new() -> Self {
+        Self
+    }
+
+    pub fn arc() -> Arc<dyn AuditSink> {
+        Arc::new(Self::new())
+    }
+}
+
+impl AuditSink for TracingAuditSink {
+    fn record(&self, record: AuditRecord) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(async move {
+            let flow_id = match record.protocol {
+                AuditProtocol::Tcp => None,
+                AuditProtocol::Udp { flow_id } => Some(flow_id),
+            };
+            info!(
+                target: "s2p::audit",
+                timestamp_ms = unix_millis(record.timestamp),
+                remote_node_id = %record.remote_node_id,
+                profile = ?record.profile_label,
+                protocol = if flow_id.is_some() { "udp" } else { "tcp" },
+                flow_id = ?flow_id,
+                target_address = %record.target,
+                resolved_address = ?record.resolved_address,
+                status = ?record.status,
+                duration_ms = record.duration.as_millis() as u64,
+                bytes_to_target = record.bytes_to_target,
+                bytes_from_target = record.bytes_from_target,
+                close_reason = %record.close_reason,
+                "proxied session closed"
+            );
+        })
+    }
+}
+
+impl Default for TracingAuditSink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Appends one JSON object per line to a file.
+#[derive(Debug)]
+pub struct JsonLinesAuditSink {
+    file: Mutex<File>,
+}
+
+impl JsonLinesAuditSink {
+    pub async fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub async fn arc(path: impl AsRef<Path>) -> io::Result<Arc<dyn AuditSink>> {
+        Ok(Arc::new(Self::open(path).await?))
+    }
+}
+
+impl AuditSink for JsonLinesAuditSink {
+    fn record(&self, record: AuditRecord) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(async move {
+            let mut line = record.to_json().to_string();
+            line.push('\n');
+
+            let mut file = self.file.lock().await;
+            if let Err(e) = file.write_all(line.as_bytes()).await {
+                error!("Failed to write audit record: {}", e);
+                return;
+            }
+            if let Err(e) = file.flush().await {
+                error!("Failed to flush audit log: {}", e);
+            }
+        })
+    }
+}
@@ -0,0 +1,209 @@
+use iroh::NodeId;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::warn;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Violation {
+    MalformedHandshake,
+    HandshakeTimeout,
+    MalformedDatagram,
+    PolicyDenied,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::MalformedHandshake => write!(f, "malformed_handshake"),
+            Violation::HandshakeTimeout => write!(f, "handshake_timeout"),
+            Violation::MalformedDatagram => write!(f, "malformed_datagram"),
+            Violation::PolicyDenied => write!(f, "policy_denied"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BanPolicy {
+    pub max_violations: usize,
+    pub window: Duration,
+    pub ban_duration: Duration,
+}
+
+impl Default for BanPolicy {
+    fn default() -> Self {
+        Self {
+            max_violations: 10,
+            window: Duration::from_secs(60),
+            ban_duration: Duration::from_secs(15 * 60),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct NodeRecord {
+    violations: VecDeque<Instant>,
+    banned_until: Option<Instant>,
+}
+
+impl NodeRecord {
+    // Drops violations that have left the window and a ban that has run out. Returns
+    // whether anything is left to keep the record for.
+    fn expire(&mut self, now: Instant, window: Duration) -> bool {
+        while self
+            .violations
+            .front()
+            .is_some_and(|at| now.duration_since(*at) > window)
+        {
+            self.violations.pop_front();
+        }
+        if self.banned_until.is_some_and(|until| until <= now) {
+            self.banned_until = None;
+        }
+        !self.violations.is_empty() || self.banned_until.is_some()
+    }
+}
+
+#[derive(Debug)]
+struct Nodes {
+    records: HashMap<NodeId, NodeRecord>,
+    // Records of nodes that stop misbehaving are dropped at most once per window.
+    pruned_at: Instant,
+}
+
+// Counts protocol violations per node in a sliding window and bans a node for
+// `ban_duration` once it reaches `max_violations` within `window`.
+#[derive(Debug)]
+pub struct BanList {
+    policy: BanPolicy,
+    nodes: Mutex<Nodes>,
+    bans: broadcast::Sender<NodeId>,
+}
+
+impl BanList {
+    pub fn new(policy: BanPolicy) -> Self {
+        let (bans, _) = broadcast::channel(64);
+        Self {
+            policy,
+            nodes: Mutex::new(Nodes {
+                records: HashMap::new(),
+                pruned_at: Instant::now(),
+            }),
+            bans,
+        }
+    }
+
+    pub fn arc(policy: BanPolicy) -> Arc<Self> {
+        Arc::new(Self::new(policy))
+    }
+
+    pub fn policy(&self) -> &BanPolicy {
+        &self.policy
+    }
+
+    // Returns true when this violation caused the node to be banned.
+    pub fn record_violation(&self, node_id: &NodeId, violation: Violation) -> bool {
+        let now = Instant::now();
+        let mut nodes = self.nodes.lock().unwrap();
+        if now.duration_since(nodes.pruned_at) >= self.policy.window {
+            nodes
+                .records
+                .retain(|_, record| record.expire(now, self.policy.window));
+            nodes.pruned_at = now;
+        }
+
+        let record = nodes.records.entry(*node_id).or_default();
+        record.expire(now, self.policy.window);
+        if record.banned_until.is_some() {
+            return false;
+        }
+        record.violations.push_back(now);
+
+        if record.violations.len() < self.policy.max_violations {
+            return false;
+        }
+
+        record.violations.clear();
+        record.banned_until = Some(now + self.policy.ban_duration);
+        drop(nodes);
+
+        warn!(
+            "Banning node {} for {:?} after repeated violations (last: {})",
+            node_id, self.policy.ban_duration, violation
+        );
+        let _ = self.bans.send(*node_id);
+        true
+    }
+
+    pub fn ban(&self, node_id: &NodeId, duration: Duration) {
+        self.nodes
+            .lock()
+            .unwrap()
+            .records
+            .entry(*node_id)
+            .or_default()
+            .banned_until = Some(Instant::now() + duration);
+        let _ = self.bans.send(*node_id);
+    }
+
+    pub fn unban(&self, node_id: &NodeId) {
+        self.nodes.lock().unwrap().records.remove(node_id);
+    }
+
+    pub fn is_banned(&self, node_id: &NodeId) -> bool {
+        let now = Instant::now();
+        let mut nodes = self.nodes.lock().unwrap();
+        let Some(record) = nodes.records.get_mut(node_id) else {
+            return false;
+        };
+        if !record.expire(now, self.policy.window) {
+            nodes.records.remove(node_id);
+            return false;
+        }
+        record.banned_until.is_some()
+    }
+
+    pub fn banned_nodes(&self) -> Vec<NodeId> {
+        let now = Instant::now();
+        let mut nodes = self.nodes.lock().unwrap();
+        nodes
+            .records
+            .retain(|_, record| record.expire(now, self.policy.window));
+        nodes.pruned_at = now;
+        nodes
+            .records
+            .iter()
+            .filter(|(_, record)| record.banned_until.is_some())
+            .map(|(node_id, _)| *node_id)
+            .collect()
+    }
+
+    // Resolves once the node gets banned.
+    pub async fn wait_for_ban(&self, node_id: &NodeId) {
+        let mut bans = self.bans.subscribe();
+        if self.is_banned(node_id) {
+            return;
+        }
+        loop {
+            match bans.recv().await {
+                Ok(banned) if banned == *node_id => return,
+                Ok(_) => {}
+                Err(RecvError::Lagged(_)) => {
+                    if self.is_banned(node_id) {
+                        return;
+                    }
+                }
+                Err(RecvError::Closed) => std::future::pending::<()>().await,
+            }
+        }
+    }
+}
+
+impl Default for BanList {
+    fn default() -> Self {
+        Self::new(BanPolicy::default())
+    }
+}
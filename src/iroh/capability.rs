@@ -0,0 +1,344 @@
+use super::node_authenticator::NodeProfile;
+use crate::target_pattern::TargetPattern;
+use bytes::{Buf, BufMut, BytesMut};
+use iroh::endpoint::{Connection, RecvStream};
+use iroh::{NodeId, PublicKey, SecretKey};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::timeout;
+
+const TOKEN_VERSION: u8 = 1;
+const SIGNATURE_CONTEXT: &[u8] = b"s2p-capability-v1";
+const MAX_TOKEN_LEN: usize = 16 * 1024;
+
+pub const BANDWIDTH_CLASS_ATTRIBUTE: &str = "bandwidth_class";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CapabilitySubject {
+    Node(NodeId),
+    AnyNode,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capability {
+    pub subject: CapabilitySubject,
+    pub allowed_targets: Vec<TargetPattern>,
+    pub expires_at: SystemTime,
+    pub bandwidth_class: Option<String>,
+}
+
+impl Capability {
+    pub fn to_profile(&self) -> NodeProfile {
+        let mut profile = NodeProfile {
+            label: Some("capability".to_string()),
+            allowed_targets: Some(self.allowed_targets.clone()),
+            ..NodeProfile::default()
+        };
+        if let Some(bandwidth_class) = &self.bandwidth_class {
+            profile = profile.with_attribute(BANDWIDTH_CLASS_ATTRIBUTE, bandwidth_class.clone());
+        }
+        profile
+    }
+
+    fn encode(&self, dst: &mut BytesMut) -> Result<(), CapabilityError> {
+        dst.put_u8(TOKEN_VERSION);
+        match self.subject {
+            CapabilitySubject::AnyNode => dst.put_u8(0),
+            CapabilitySubject::Node(node_id) => {
+                dst.put_u8(1);
+                dst.put_slice(node_id.as_bytes());
+            }
+        }
+
+        let expires_at = self
+            .expires_at
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| CapabilityError::Malformed("expiry before unix epoch"))?;
+        dst.put_u64(expires_at.as_secs());
+
+        let bandwidth_class = self.bandwidth_class.as_deref().unwrap_or_default();
+        put_string(dst, bandwidth_class)?;
+
+        let target_count = u16::try_from(self.allowed_targets.len())
+            .map_err(|_| CapabilityError::Malformed("too many target patterns"))?;
+        dst.put_u16(target_count);
+        for target in &self.allowed_targets {
+            put_string(dst, &target.to_string())?;
+        }
+        Ok(())
+    }
+
+    fn decode(src: &mut &[u8]) -> Result<Self, CapabilityError> {
+        if get_u8(src)? != TOKEN_VERSION {
+            return Err(CapabilityError::Malformed("unsupported token version"));
+        }
+
+        let subject = match get_u8(src)? {
+            0 => CapabilitySubject::AnyNode,
+            1 => CapabilitySubject::Node(get_public_key(src)?),
+            _ => return Err(CapabilityError::Malformed("invalid subject")),
+        };
+
+        if src.remaining() < 8 {
+            return Err(CapabilityError::Malformed("truncated expiry"));
+        }
+        let expires_at = UNIX_EPOCH
+            .checked_add(Duration::from_secs(src.get_u64()))
+            .ok_or(CapabilityError::Malformed("expiry out of range"))?;
+
+        let bandwidth_class = get_string(src)?;
+        let bandwidth_class = (!bandwidth_class.is_empty()).then_some(bandwidth_class);
+
+        if src.remaining() < 2 {
+            return Err(CapabilityError::Malformed("truncated target list"));
+        }
+        let target_count = src.get_u16();
+        let allowed_targets = (0..target_count)
+            .map(|_| {
+                TargetPattern::from_str(&get_string(src)?)
+                    .map_err(|_| CapabilityError::Malformed("invalid target pattern"))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            subject,
+            allowed_targets,
+            expires_at,
+            bandwidth_class,
+        })
+    }
+}
+
+fn put_string(dst: &mut BytesMut, value: &str) -> Result<(), CapabilityError> {
+    let len = u16::try_from(value.len())
+        .map_err(|_| CapabilityError::Malformed("string field too long"))?;
+    dst.put_u16(len);
+    dst.put_slice(value.as_bytes());
+    Ok(())
+}
+
+fn get_u8(src: &mut &[u8]) -> Result<u8, CapabilityError> {
+    if !src.has_remaining() {
+        return Err(CapabilityError::Malformed("truncated token"));
+    }
+    Ok(src.get_u8())
+}
+
+fn get_string(src: &mut &[u8]) -> Result<String, CapabilityError> {
+    if src.remaining() < 2 {
+        return Err(CapabilityError::Malformed("truncated string length"));
+    }
+    let len = src.get_u16() as usize;
+    if src.remaining() < len {
+        return Err(CapabilityError::Malformed("truncated string"));
+    }
+    let value = String::from_utf8(src[..len].to_vec())
+        .map_err(|_| CapabilityError::Malformed("invalid string encoding"))?;
+    src.advance(len);
+    Ok(value)
+}
+
+fn get_public_key(src: &mut &[u8]) -> Result<PublicKey, CapabilityError> {
+    if src.remaining() < 32 {
+        return Err(CapabilityError::Malformed("truncated public key"));
+    }
+    let mut key = [0u8; 32];
+    src.copy_to_slice(&mut key);
+    PublicKey::from_bytes(&key).map_err(|_| CapabilityError::Malformed("invalid public key"))
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CapabilityError {
+    #[error("Malformed capability token: {0}")]
+    Malformed(&'static str),
+
+    #[error("Capability token issuer {0} is not trusted")]
+    UntrustedIssuer(PublicKey),
+
+    #[error("Invalid capability token signature")]
+    InvalidSignature,
+
+    #[error("Capability token has expired")]
+    Expired,
+
+    #[error("Capability token was issued for a different node")]
+    SubjectMismatch,
+
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+// A capability signed by an operator key. The binary form is the encoded capability,
+// followed by the issuer public key and the ed25519 signature over both; the text form
+// is its lowercase hex encoding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapabilityToken {
+    pub capability: Capability,
+    pub issuer: PublicKey,
+    signature: [u8; 64],
+}
+
+impl CapabilityToken {
+    pub fn issue(issuer: &SecretKey, capability: Capability) -> Result<Self, CapabilityError> {
+        let mut token = Self {
+            capability,
+            issuer: issuer.public(),
+            signature: [0u8; 64],
+        };
+        let message = token.signed_message()?;
+        token.signature = issuer.sign(&message).to_bytes();
+        Ok(token)
+    }
+
+    fn signed_message(&self) -> Result<BytesMut, CapabilityError> {
+        let mut message = BytesMut::new();
+        message.put_slice(SIGNATURE_CONTEXT);
+        self.capability.encode(&mut message)?;
+        message.put_slice(self.issuer.as_bytes());
+        Ok(message)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, CapabilityError> {
+        let mut bytes = BytesMut::new();
+        self.capability.encode(&mut bytes)?;
+        bytes.put_slice(self.issuer.as_bytes());
+        bytes.put_slice(&self.signature);
+        Ok(bytes.to_vec())
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CapabilityError> {
+        let mut src = bytes;
+        let capability = Capability::decode(&mut src)?;
+        let issuer = get_public_key(&mut src)?;
+        if src.remaining() != 64 {
+            return Err(CapabilityError::Malformed("invalid signature length"));
+        }
+        let mut signature = [0u8; 64];
+        src.copy_to_slice(&mut signature);
+
+        Ok(Self {
+            capability,
+            issuer,
+            signature,
+        })
+    }
+
+    fn verify_signature(&self) -> Result<(), CapabilityError> {
+        let message = self.signed_message()?;
+        let signature = iroh_base::Signature::from_bytes(&self.signature);
+        self.issuer
+            .verify(&message, &signature)
+            .map_err(|_| CapabilityError::InvalidSignature)
+    }
+}
+
+impl fmt::Display for CapabilityToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes = self.to_bytes().map_err(|_| fmt::Error)?;
+        for byte in bytes {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for CapabilityToken {
+    type Err = CapabilityError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.len() % 2 != 0 || !s.is_ascii() {
+            return Err(CapabilityError::Malformed("invalid hex encoding"));
+        }
+        let bytes = (0..s.len())
+            .step_by(2)
+            .map(|idx| u8::from_str_radix(&s[idx..idx + 2], 16))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| CapabilityError::Malformed("invalid hex encoding"))?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+// Verifies capability tokens offline against a set of trusted issuer keys.
+#[derive(Debug, Clone)]
+pub struct CapabilityVerifier {
+    trusted_issuers: Vec<PublicKey>,
+    presentation_timeout: Duration,
+}
+
+impl CapabilityVerifier {
+    pub fn new(trusted_issuers: Vec<PublicKey>) -> Self {
+        Self {
+            trusted_issuers,
+            presentation_timeout: Duration::from_secs(5),
+        }
+    }
+
+    pub fn arc(trusted_issuers: Vec<PublicKey>) -> Arc<Self> {
+        Arc::new(Self::new(trusted_issuers))
+    }
+
+    pub fn with_presentation_timeout(mut self, presentation_timeout: Duration) -> Self {
+        self.presentation_timeout = presentation_timeout;
+        self
+    }
+
+    pub fn verify(
+        &self,
+        token: &CapabilityToken,
+        node_id: &NodeId,
+        now: SystemTime,
+    ) -> Result<Capability, CapabilityError> {
+        if !self.trusted_issuers.contains(&token.issuer) {
+            return Err(CapabilityError::UntrustedIssuer(token.issuer));
+        }
+        token.verify_signature()?;
+
+        let capability = &token.capability;
+        if capability.expires_at <= now {
+            return Err(CapabilityError::Expired);
+        }
+        match capability.subject {
+            CapabilitySubject::AnyNode => {}
+            CapabilitySubject::Node(subject) if subject == *node_id => {}
+            CapabilitySubject::Node(_) => return Err(CapabilityError::SubjectMismatch),
+        }
+
+        Ok(capability.clone())
+    }
+
+    // Waits for the client to present a token on the first unidirectional stream of the
+    // connection and verifies it.
+    pub async fn accept_presented(
+        &self,
+        connection: &Connection,
+        node_id: &NodeId,
+    ) -> Result<Capability, CapabilityError> {
+        let token = timeout(self.presentation_timeout, async {
+            let mut recv = connection
+                .accept_uni()
+                .await
+                .map_err(std::io::Error::other)?;
+            read_token(&mut recv).await
+        })
+        .await
+        .map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "capability token presentation timed out",
+            )
+        })??;
+
+        self.verify(&token, node_id, SystemTime::now())
+    }
+}
+
+async fn read_token(recv: &mut RecvStream) -> Result<CapabilityToken, CapabilityError> {
+    let bytes = recv
+        .read_to_end(MAX_TOKEN_LEN)
+        .await
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    CapabilityToken::from_bytes(&bytes)
+}
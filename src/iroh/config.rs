@@ -0,0 +1,541 @@
+use super::dns_resolver::{DefaultDnsResolver, DnsResolver, StaticDnsResolver};
+use super::file_authenticator::{AllowlistError, FileNodeAuthenticator};
+use super::node_authenticator::{
+    AllowAllNodeAuthenticator, DynamicNodeAuthenticator, NodeAuthenticator,
+};
+use super::quota::{FileAccountingStore, InMemoryAccountingStore, QuotaLimits, QuotaManager};
+use super::service_registry::{ServiceRegistry, ServiceTarget};
+use super::socket_factory::{DefaultSocketFactory, TransportFactory};
+use super::tuned_socket_factory::{SocketOptions, TunedSocketFactory};
+use super::types::{
+    BuildError, ProxyTimeouts, ReloadableSettings, S2pProtocol, S2pProtocolBuilder, TargetPolicy,
+    UdpSettings,
+};
+use super::unix_socket_policy::UnixSocketPolicy;
+use crate::message_types::TargetAddress;
+use crate::target_pattern::TargetPattern;
+use iroh::NodeId;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("Failed to read config {path:?}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+
+    #[error("Invalid TOML config: {0}")]
+    Toml(#[from] toml::de::Error),
+
+    #[error("Invalid JSON config: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("Invalid value for `{key}`: {message}")]
+    InvalidValue { key: String, message: String },
+
+    #[error("Failed to load allowlist of `authenticator.path`: {0}")]
+    Allowlist(#[from] AllowlistError),
+
+    #[error(transparent)]
+    Build(#[from] BuildError),
+}
+
+// Declarative settings of an `S2pProtocol`, read from TOML or JSON. Every section and key
+// is optional; omitted values keep the defaults of `S2pProtocol::builder`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct S2pConfig {
+    pub timeouts: TimeoutsConfig,
+    pub udp: UdpConfig,
+    pub authenticator: AuthenticatorConfig,
+    pub dns: DnsConfig,
+    pub socket: SocketConfig,
+    pub policy: PolicyConfig,
+    pub limits: LimitsConfig,
+    pub services: Vec<ServiceConfig>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutsConfig {
+    pub tcp_connection_secs: Option<u64>,
+    pub dns_resolution_secs: Option<u64>,
+    pub handshake_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UdpConfig {
+    pub flow_idle_timeout_secs: Option<u64>,
+    pub dns_resolution_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum AuthenticatorConfig {
+    #[default]
+    AllowAll,
+    // Allowlist file read by `FileNodeAuthenticator`, polled for changes every
+    // `watch_interval_secs` (5 by default, 0 disables reloading).
+    Allowlist {
+        path: PathBuf,
+        watch_interval_secs: Option<u64>,
+    },
+    // Fixed list of node ids.
+    Nodes {
+        nodes: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DnsResolverKind {
+    // The system resolver, with `hosts` taking precedence.
+    #[default]
+    System,
+    // Only names listed in `hosts` resolve.
+    Static,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DnsConfig {
+    pub resolver: DnsResolverKind,
+    pub hosts: HashMap<String, Vec<IpAddr>>,
+}
+
+// See `SocketOptions`; durations are given in seconds.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SocketConfig {
+    pub source_ipv4: Option<Ipv4Addr>,
+    pub source_ipv6: Option<Ipv6Addr>,
+    pub bind_device: Option<String>,
+    pub mark: Option<u32>,
+    pub nodelay: Option<bool>,
+    pub keepalive_time_secs: Option<u64>,
+    pub keepalive_interval_secs: Option<u64>,
+    pub keepalive_retries: Option<u32>,
+    pub send_buffer_size: Option<usize>,
+    pub recv_buffer_size: Option<usize>,
+    pub ttl: Option<u32>,
+    pub dscp: Option<u8>,
+    pub freebind: Option<bool>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicyConfig {
+    // Target patterns as parsed by `TargetPattern`.
+    pub allow_targets: Option<Vec<String>>,
+    pub deny_targets: Vec<String>,
+    pub allow_relay: bool,
+    // Path prefixes of Unix sockets clients may connect to.
+    pub unix_sockets: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    // Default data transfer quotas per node. Usage is persisted to `accounting_file` when
+    // set and kept in memory otherwise, every `persist_interval_secs` and whenever a
+    // session closes.
+    pub daily_bytes: Option<u64>,
+    pub monthly_bytes: Option<u64>,
+    pub total_bytes: Option<u64>,
+    pub accounting_file: Option<PathBuf>,
+    pub persist_interval_secs: Option<u64>,
+    // Concurrent UDP flows per connection.
+    pub max_udp_flows: Option<usize>,
+}
+
+// A named service, mapping `name` to `host:port` or `unix:<path>`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceConfig {
+    pub name: String,
+    pub target: String,
+}
+
+impl S2pConfig {
+    pub fn from_toml(contents: &str) -> Result<Self, ConfigError> {
+        let config: Self = toml::from_str(contents)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_json(contents: &str) -> Result<Self, ConfigError> {
+        let config: Self = serde_json::from_str(contents)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Files with a `.json` extension are read as JSON, anything else as TOML.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path).map_err(|source| ConfigError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        if path.extension().is_some_and(|ext| ext == "json") {
+            Self::from_json(&contents)
+        } else {
+            Self::from_toml(&contents)
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.reloadable_settings()?;
+        self.services()?;
+        self.socket_options()?;
+        self.node_ids()?;
+        if let Err(BuildError::InvalidValue { field, reason }) = self.quota_limits().validate() {
+            return Err(invalid(format!("limits.{}", field), reason));
+        }
+        self.persist_interval()?;
+        if let AuthenticatorConfig::Allowlist { path, .. } = &self.authenticator {
+            if path.as_os_str().is_empty() {
+                return Err(invalid("authenticator.path", "must not be empty"));
+            }
+        }
+        if self.dns.resolver == DnsResolverKind::Static && self.dns.hosts.is_empty() {
+            return Err(invalid(
+                "dns.hosts",
+                "a static resolver needs at least one host",
+            ));
+        }
+        for (name, addrs) in &self.dns.hosts {
+            if addrs.is_empty() {
+                return Err(invalid(format!("dns.hosts.{}", name), "no addresses"));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn proxy_timeouts(&self) -> Result<ProxyTimeouts, ConfigError> {
+        let defaults = ProxyTimeouts::default();
+        Ok(ProxyTimeouts {
+            tcp_connection_timeout: secs(
+                "timeouts.tcp_connection_secs",
+                self.timeouts.tcp_connection_secs,
+                defaults.tcp_connection_timeout,
+            )?,
+            dns_resolution_timeout: secs(
+                "timeouts.dns_resolution_secs",
+                self.timeouts.dns_resolution_secs,
+                defaults.dns_resolution_timeout,
+            )?,
+            tcp_proxy_handshake_timeout: secs(
+                "timeouts.handshake_secs",
+                self.timeouts.handshake_secs,
+                defaults.tcp_proxy_handshake_timeout,
+            )?,
+        })
+    }
+
+    pub fn udp_settings(&self) -> Result<UdpSettings, ConfigError> {
+        let defaults = UdpSettings::default();
+        let settings = UdpSettings {
+            flow_idle_timeout: secs(
+                "udp.flow_idle_timeout_secs",
+                self.udp.flow_idle_timeout_secs,
+                defaults.flow_idle_timeout,
+            )?,
+            dns_resolution_timeout: secs(
+                "udp.dns_resolution_secs",
+                self.udp.dns_resolution_secs,
+                defaults.dns_resolution_timeout,
+            )?,
+            max_flows: self.limits.max_udp_flows,
+        };
+        match settings.validate() {
+            Err(BuildError::InvalidValue { field, reason }) if field == "udp.max_flows" => {
+                Err(invalid("limits.max_udp_flows", reason))
+            }
+            result => result.map(|()| settings).map_err(Into::into),
+        }
+    }
+
+    pub fn target_policy(&self) -> Result<TargetPolicy, ConfigError> {
+        let allow = match &self.policy.allow_targets {
+            Some(patterns) => Some(parse_patterns("policy.allow_targets", patterns)?),
+            None => None,
+        };
+        Ok(TargetPolicy {
+            allow,
+            deny: parse_patterns("policy.deny_targets", &self.policy.deny_targets)?,
+        })
+    }
+
+    pub fn unix_socket_policy(&self) -> Result<UnixSocketPolicy, ConfigError> {
+        let mut policy = UnixSocketPolicy::new();
+        for (idx, prefix) in self.policy.unix_sockets.iter().enumerate() {
+            if !prefix.is_absolute() {
+                return Err(invalid(
+                    format!("policy.unix_sockets[{}]", idx),
+                    format!("{:?} is not an absolute path", prefix),
+                ));
+            }
+            policy = policy.allow(prefix.clone());
+        }
+        Ok(policy)
+    }
+
+    pub fn services(&self) -> Result<Vec<(String, ServiceTarget)>, ConfigError> {
+        let mut services: Vec<(String, ServiceTarget)> = Vec::new();
+        for (idx, service) in self.services.iter().enumerate() {
+            if service.name.is_empty() {
+                return Err(invalid(
+                    format!("services[{}].name", idx),
+                    "must not be empty",
+                ));
+            }
+            if services.iter().any(|(name, _)| *name == service.name) {
+                return Err(invalid(
+                    format!("services[{}].name", idx),
+                    format!("duplicate service {:?}", service.name),
+                ));
+            }
+            let key = format!("services[{}].target", idx);
+            let target = match service.target.strip_prefix("unix:") {
+                Some(path) if Path::new(path).is_absolute() => ServiceTarget::Unix(path.into()),
+                Some(path) => {
+                    return Err(invalid(key, format!("{:?} is not an absolute path", path)))
+                }
+                None => ServiceTarget::Address(
+                    service
+                        .target
+                        .parse::<TargetAddress>()
+                        .map_err(|e| invalid(key, e))?,
+                ),
+            };
+            services.push((service.name.clone(), target));
+        }
+        Ok(services)
+    }
+
+    pub fn socket_options(&self) -> Result<SocketOptions, ConfigError> {
+        let socket = &self.socket;
+        if socket.dscp.is_some_and(|dscp| dscp > 63) {
+            return Err(invalid("socket.dscp", "must be between 0 and 63"));
+        }
+        if socket.ttl.is_some_and(|ttl| ttl == 0 || ttl > 255) {
+            return Err(invalid("socket.ttl", "must be between 1 and 255"));
+        }
+        if socket
+            .bind_device
+            .as_ref()
+            .is_some_and(|device| device.is_empty())
+        {
+            return Err(invalid("socket.bind_device", "must not be empty"));
+        }
+        let positive_secs = |key: &str, value: Option<u64>| match value {
+            Some(0) => Err(invalid(key, "must be greater than zero")),
+            value => Ok(value.map(Duration::from_secs)),
+        };
+        Ok(SocketOptions {
+            source_ipv4: socket.source_ipv4,
+            source_ipv6: socket.source_ipv6,
+            bind_device: socket.bind_device.clone(),
+            mark: socket.mark,
+            nodelay: socket.nodelay,
+            keepalive_time: positive_secs(
+                "socket.keepalive_time_secs",
+                socket.keepalive_time_secs,
+            )?,
+            keepalive_interval: positive_secs(
+                "socket.keepalive_interval_secs",
+                socket.keepalive_interval_secs,
+            )?,
+            keepalive_retries: socket.keepalive_retries,
+            send_buffer_size: socket.send_buffer_size,
+            recv_buffer_size: socket.recv_buffer_size,
+            ttl: socket.ttl,
+            dscp: socket.dscp,
+            freebind: socket.freebind,
+        })
+    }
+
+    pub fn quota_limits(&self) -> QuotaLimits {
+        QuotaLimits {
+            daily_bytes: self.limits.daily_bytes,
+            monthly_bytes: self.limits.monthly_bytes,
+            total_bytes: self.limits.total_bytes,
+        }
+    }
+
+    // The part of the configuration `S2pProtocol::apply_config` can change at runtime.
+    // PROXY protocol headers are not configured here and keep their current policy.
+    pub fn reloadable_settings(&self) -> Result<ReloadableSettings, ConfigError> {
+        Ok(ReloadableSettings {
+            proxy_timeouts: self.proxy_timeouts()?,
+            udp: self.udp_settings()?,
+            target_policy: self.target_policy()?,
+            allow_relay: self.policy.allow_relay,
+            unix_sockets: self.unix_socket_policy()?,
+            proxy_protocol: Default::default(),
+        })
+    }
+
+    fn node_ids(&self) -> Result<Vec<NodeId>, ConfigError> {
+        let AuthenticatorConfig::Nodes { nodes } = &self.authenticator else {
+            return Ok(Vec::new());
+        };
+        nodes
+            .iter()
+            .enumerate()
+            .map(|(idx, node)| {
+                NodeId::from_str(node).map_err(|e| {
+                    invalid(
+                        format!("authenticator.nodes[{}]", idx),
+                        format!("invalid node id {:?}: {}", node, e),
+                    )
+                })
+            })
+            .collect()
+    }
+
+    async fn node_authenticator(&self) -> Result<Arc<dyn NodeAuthenticator>, ConfigError> {
+        match &self.authenticator {
+            AuthenticatorConfig::AllowAll => Ok(AllowAllNodeAuthenticator::arc()),
+            AuthenticatorConfig::Allowlist {
+                path,
+                watch_interval_secs,
+            } => {
+                let authenticator = FileNodeAuthenticator::arc(path).await?;
+                match watch_interval_secs.unwrap_or(5) {
+                    0 => {}
+                    secs => authenticator.watch(Duration::from_secs(secs)),
+                }
+                Ok(authenticator)
+            }
+            AuthenticatorConfig::Nodes { .. } => {
+                Ok(DynamicNodeAuthenticator::arc(self.node_ids()?))
+            }
+        }
+    }
+
+    fn dns_resolver(&self) -> Arc<dyn DnsResolver> {
+        match self.dns.resolver {
+            DnsResolverKind::System if self.dns.hosts.is_empty() => DefaultDnsResolver::arc(),
+            DnsResolverKind::System => {
+                StaticDnsResolver::arc(self.dns.hosts.clone(), Some(DefaultDnsResolver::arc()))
+            }
+            DnsResolverKind::Static => StaticDnsResolver::arc(self.dns.hosts.clone(), None),
+        }
+    }
+
+    fn socket_factory(&self) -> Result<Arc<dyn TransportFactory>, ConfigError> {
+        if self.socket == SocketConfig::default() {
+            return Ok(DefaultSocketFactory::transport());
+        }
+        Ok(Arc::new(TunedSocketFactory::new(self.socket_options()?)))
+    }
+
+    fn persist_interval(&self) -> Result<Duration, ConfigError> {
+        secs(
+            "limits.persist_interval_secs",
+            self.limits.persist_interval_secs,
+            Duration::from_secs(60),
+        )
+    }
+
+    fn quota_manager(&self) -> Result<Option<Arc<QuotaManager>>, ConfigError> {
+        let limits = self.quota_limits();
+        if limits == QuotaLimits::unlimited() {
+            return Ok(None);
+        }
+        let store = match &self.limits.accounting_file {
+            Some(path) => FileAccountingStore::arc(path),
+            None => InMemoryAccountingStore::arc(),
+        };
+        let quota_manager = QuotaManager::arc(limits, store);
+        quota_manager.persist_periodically(self.persist_interval()?);
+        Ok(Some(quota_manager))
+    }
+}
+
+impl S2pProtocol {
+    pub async fn from_config(config: &S2pConfig) -> Result<Self, ConfigError> {
+        Ok(Self::builder_from_config(config).await?.build()?)
+    }
+
+    // Builder preset from `config`, for settings that have no configuration keys such as
+    // the endpoint or an audit sink.
+    pub async fn builder_from_config(
+        config: &S2pConfig,
+    ) -> Result<S2pProtocolBuilder, ConfigError> {
+        config.validate()?;
+        let mut builder = Self::builder();
+        builder
+            .proxy_timeouts(config.proxy_timeouts()?)
+            .udp(config.udp_settings()?)
+            .target_policy(config.target_policy()?)
+            .allow_relay(config.policy.allow_relay)
+            .unix_sockets(config.unix_socket_policy()?)
+            .socket_factory(config.socket_factory()?)
+            .dns_resolver(config.dns_resolver())
+            .node_authenticator(config.node_authenticator().await?);
+        if let Some(quota_manager) = config.quota_manager()? {
+            builder.quota_manager(quota_manager);
+        }
+        let registry = ServiceRegistry::new();
+        registry.replace(config.services()?);
+        builder.service_registry(Arc::new(registry));
+        Ok(builder)
+    }
+
+    // Applies the reloadable part of `config` to this protocol and all its clones: timeouts,
+    // UDP settings, target and Unix socket policy, relaying, services and the default
+    // quota limits. The authenticator, DNS resolver, socket options and whether quotas are
+    // enforced at all stay as the protocol was built. Nothing is changed if `config` is
+    // invalid.
+    pub fn apply_config(&self, config: &S2pConfig) -> Result<(), ConfigError> {
+        config.validate()?;
+        let mut settings = config.reloadable_settings()?;
+        settings.proxy_protocol = self.settings().proxy_protocol.clone();
+        let services = config.services()?;
+        self.apply_settings(settings)?;
+
+        if let Some(registry) = &self.service_registry {
+            registry.replace(services);
+        }
+        if let Some(quota_manager) = &self.quota_manager {
+            quota_manager.set_default_limits(config.quota_limits());
+        }
+        Ok(())
+    }
+}
+
+fn invalid(key: impl Into<String>, message: impl Display) -> ConfigError {
+    ConfigError::InvalidValue {
+        key: key.into(),
+        message: message.to_string(),
+    }
+}
+
+fn secs(key: &str, value: Option<u64>, default: Duration) -> Result<Duration, ConfigError> {
+    match value {
+        Some(0) => Err(invalid(key, "must be greater than zero")),
+        Some(secs) => Ok(Duration::from_secs(secs)),
+        None => Ok(default),
+    }
+}
+
+fn parse_patterns(key: &str, patterns: &[String]) -> Result<Vec<TargetPattern>, ConfigError> {
+    patterns
+        .iter()
+        .enumerate()
+        .map(|(idx, pattern)| {
+            pattern
+                .parse::<TargetPattern>()
+                .map_err(|e| invalid(format!("{}[{}]", key, idx), e))
+        })
+        .collect()
+}
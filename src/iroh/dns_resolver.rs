@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::future::Future;
 use std::io;
 use std::net::IpAddr;
@@ -41,3 +42,53 @@ impl Default for DefaultDnsResolver {
         Self::new()
     }
 }
+
+// Resolves names from a fixed table. Names missing from the table are passed to
+// `fallback`, or fail when there is none.
+#[derive(Debug)]
+pub struct StaticDnsResolver {
+    hosts: HashMap<String, Vec<IpAddr>>,
+    fallback: Option<Arc<dyn DnsResolver>>,
+}
+
+impl StaticDnsResolver {
+    pub fn new(
+        hosts: HashMap<String, Vec<IpAddr>>,
+        fallback: Option<Arc<dyn DnsResolver>>,
+    ) -> Self {
+        let hosts = hosts
+            .into_iter()
+            .map(|(name, addrs)| (name.to_ascii_lowercase(), addrs))
+            .collect();
+        Self { hosts, fallback }
+    }
+
+    pub fn arc(
+        hosts: HashMap<String, Vec<IpAddr>>,
+        fallback: Option<Arc<dyn DnsResolver>>,
+    ) -> Arc<dyn DnsResolver> {
+        Arc::new(Self::new(hosts, fallback))
+    }
+}
+
+impl DnsResolver for StaticDnsResolver {
+    fn lookup_host<'a>(
+        &'a self,
+        host: &'a str,
+    ) -> Pin<Box<dyn Future<Output = io::Result<Vec<IpAddr>>> + Send + 'a>> {
+        Box::pin(async move {
+            // Lookups are made for `host:port`.
+            let name = host.rsplit_once(':').map_or(host, |(name, _)| name);
+            if let Some(addrs) = self.hosts.get(&name.to_ascii_lowercase()) {
+                return Ok(addrs.clone());
+            }
+            match &self.fallback {
+                Some(fallback) => fallback.lookup_host(host).await,
+                None => Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no static address for {}", name),
+                )),
+            }
+        })
+    }
+}
@@ -0,0 +1,287 @@
+use super::node_authenticator::{AuthContext, AuthDecision, NodeAuthenticator, NodeProfile};
+use iroh::NodeId;
+use serde::Deserialize;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::RwLock;
+use tokio::task::AbortHandle;
+use tracing::{error, info};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllowlistEntry {
+    pub node_id: NodeId,
+    pub label: Option<String>,
+    pub expires_at: Option<SystemTime>,
+}
+
+impl AllowlistEntry {
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AllowlistError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Invalid allowlist entry on line {line}: {message}")]
+    InvalidEntry { line: usize, message: String },
+
+    #[error("Invalid TOML allowlist: {0}")]
+    InvalidToml(#[from] toml::de::Error),
+
+    #[error("Duplicate allowlist entry for node {0}")]
+    DuplicateNode(NodeId),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlAllowlist {
+    #[serde(default)]
+    nodes: Vec<TomlAllowlistEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlAllowlistEntry {
+    id: String,
+    label: Option<String>,
+    // Unix timestamp in seconds
+    expires_at: Option<u64>,
+}
+
+// Parses an allowlist. Files with a `.toml` extension hold a `[[nodes]]` array of tables
+// with `id`, `label` and `expires_at` keys. Any other file holds one node per line:
+// `<node_id> [label=<label>] [expires_at=<unix seconds>]`, with `#` starting a comment.
+pub fn parse_allowlist(
+    path: &Path,
+    contents: &str,
+) -> Result<HashMap<NodeId, AllowlistEntry>, AllowlistError> {
+    let entries = if path.extension().is_some_and(|ext| ext == "toml") {
+        parse_toml_allowlist(contents)?
+    } else {
+        parse_plain_allowlist(contents)?
+    };
+
+    let mut allowlist = HashMap::with_capacity(entries.len());
+    for entry in entries {
+        if allowlist.insert(entry.node_id, entry.clone()).is_some() {
+            return Err(AllowlistError::DuplicateNode(entry.node_id));
+        }
+    }
+    Ok(allowlist)
+}
+
+fn parse_toml_allowlist(contents: &str) -> Result<Vec<AllowlistEntry>, AllowlistError> {
+    let allowlist: TomlAllowlist = toml::from_str(contents)?;
+    allowlist
+        .nodes
+        .into_iter()
+        .enumerate()
+        .map(|(idx, entry)| {
+            let node_id =
+                NodeId::from_str(&entry.id).map_err(|e| AllowlistError::InvalidEntry {
+                    line: idx + 1,
+                    message: format!("invalid node id {:?}: {}", entry.id, e),
+                })?;
+            let expires_at = entry
+                .expires_at
+                .map(|secs| {
+                    unix_time(secs).ok_or_else(|| AllowlistError::InvalidEntry {
+                        line: idx + 1,
+                        message: format!("expiry {} out of range", secs),
+                    })
+                })
+                .transpose()?;
+            Ok(AllowlistEntry {
+                node_id,
+                label: entry.label,
+                expires_at,
+            })
+        })
+        .collect()
+}
+
+fn parse_plain_allowlist(contents: &str) -> Result<Vec<AllowlistEntry>, AllowlistError> {
+    let mut entries = Vec::new();
+
+    for (idx, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let invalid = |message: String| AllowlistError::InvalidEntry {
+            line: idx + 1,
+            message,
+        };
+
+        let mut fields = line.split_whitespace();
+        let id = fields.next().unwrap_or_default();
+        let node_id = NodeId::from_str(id)
+            .map_err(|e| invalid(format!("invalid node id {:?}: {}", id, e)))?;
+
+        let mut entry = AllowlistEntry {
+            node_id,
+            label: None,
+            expires_at: None,
+        };
+        for field in fields {
+            match field.split_once('=') {
+                Some(("label", label)) => entry.label = Some(label.to_string()),
+                Some(("expires_at", expires_at)) => {
+                    let secs = expires_at
+                        .parse::<u64>()
+                        .map_err(|_| invalid(format!("invalid expiry {:?}", expires_at)))?;
+                    let expires_at = unix_time(secs)
+                        .ok_or_else(|| invalid(format!("expiry {} out of range", secs)))?;
+                    entry.expires_at = Some(expires_at);
+                }
+                _ => return Err(invalid(format!("unknown field {:?}", field))),
+            }
+        }
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+fn unix_time(secs: u64) -> Option<SystemTime> {
+    UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+}
+
+// Allowlist authenticator backed by a file. `watch` polls the file and swaps in the new
+// allowlist when it changes; an update that fails to parse is logged and the last good
+// allowlist stays active.
+#[derive(Debug)]
+pub struct FileNodeAuthenticator {
+    path: PathBuf,
+    entries: Arc<RwLock<HashMap<NodeId, AllowlistEntry>>>,
+    watch_task: Mutex<Option<AbortHandle>>,
+}
+
+impl FileNodeAuthenticator {
+    pub async fn open(path: impl Into<PathBuf>) -> Result<Self, AllowlistError> {
+        let path = path.into();
+        let entries = Self::read(&path).await?;
+        Ok(Self {
+            path,
+            entries: Arc::new(RwLock::new(entries)),
+            watch_task: Mutex::new(None),
+        })
+    }
+
+    pub async fn arc(path: impl Into<PathBuf>) -> Result<Arc<Self>, AllowlistError> {
+        Ok(Arc::new(Self::open(path).await?))
+    }
+
+    async fn read(path: &Path) -> Result<HashMap<NodeId, AllowlistEntry>, AllowlistError> {
+        let contents = tokio::fs::read_to_string(path).await?;
+        parse_allowlist(path, &contents)
+    }
+
+    pub async fn reload(&self) -> Result<usize, AllowlistError> {
+        Self::reload_into(&self.path, &self.entries).await
+    }
+
+    async fn reload_into(
+        path: &Path,
+        entries: &RwLock<HashMap<NodeId, AllowlistEntry>>,
+    ) -> Result<usize, AllowlistError> {
+        let new_entries = Self::read(path).await?;
+        let count = new_entries.len();
+        *entries.write().await = new_entries;
+        Ok(count)
+    }
+
+    pub fn watch(&self, poll_interval: Duration) {
+        let path = self.path.clone();
+        let entries = self.entries.clone();
+
+        let task = tokio::spawn(async move {
+            let mut last_fingerprint = Self::fingerprint(&path).await;
+            let mut interval = tokio::time::interval(poll_interval);
+            loop {
+                interval.tick().await;
+                let fingerprint = Self::fingerprint(&path).await;
+                if fingerprint == last_fingerprint {
+                    continue;
+                }
+                last_fingerprint = fingerprint;
+
+                match Self::reload_into(&path, &entries).await {
+                    Ok(count) => info!("Reloaded allowlist {:?} with {} nodes", path, count),
+                    Err(e) => error!(
+                        "Rejected allowlist update {:?}, keeping previous allowlist: {}",
+                        path, e
+                    ),
+                }
+            }
+        });
+
+        if let Some(previous) = self.watch_task.lock().unwrap().replace(task.abort_handle()) {
+            previous.abort();
+        }
+    }
+
+    // Modification times can be too coarse to tell quick rewrites apart, so the length
+    // and a hash of the contents are compared as well.
+    async fn fingerprint(path: &Path) -> Option<(Option<SystemTime>, u64, u64)> {
+        let modified = tokio::fs::metadata(path)
+            .await
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        let contents = tokio::fs::read(path).await.ok()?;
+        let mut hasher = DefaultHasher::new();
+        contents.hash(&mut hasher);
+        Some((modified, contents.len() as u64, hasher.finish()))
+    }
+
+    pub async fn get_entries(&self) -> Vec<AllowlistEntry> {
+        self.entries.read().await.values().cloned().collect()
+    }
+
+    async fn lookup(&self, node_id: &NodeId) -> AuthDecision {
+        match self.entries.read().await.get(node_id) {
+            None => AuthDecision::reject("node is not in the allowlist"),
+            Some(entry) if entry.is_expired(SystemTime::now()) => {
+                AuthDecision::reject("allowlist entry has expired")
+            }
+            Some(entry) => AuthDecision::accept_with(NodeProfile {
+                label: entry.label.clone(),
+                ..NodeProfile::default()
+            }),
+        }
+    }
+}
+
+impl Drop for FileNodeAuthenticator {
+    fn drop(&mut self) {
+        if let Some(task) = self.watch_task.lock().unwrap().take() {
+            task.abort();
+        }
+    }
+}
+
+impl NodeAuthenticator for FileNodeAuthenticator {
+    fn should_accept(&self, node_id: &NodeId) -> Pin<Box<dyn Future<Output = bool> + Send + '_>> {
+        let node_id = *node_id;
+        Box::pin(async move { self.lookup(&node_id).await.is_accept() })
+    }
+
+    fn authenticate<'a>(
+        &'a self,
+        context: &'a AuthContext,
+    ) -> Pin<Box<dyn Future<Output = AuthDecision> + Send + 'a>> {
+        Box::pin(self.lookup(&context.node_id))
+    }
+}
@@ -1,11 +1,13 @@
+use crate::iroh::node_authenticator::{AuthContext, AuthDecision};
 use crate::iroh::tcp_handler::TcpProxyHandlerHandler;
 use crate::iroh::types::S2pProtocol;
 use crate::iroh::udp_handler::UdpProxyHandlerHandler;
 use iroh::endpoint::Connection;
 use iroh::protocol::AcceptError::NotAllowed;
 use iroh::protocol::{AcceptError, ProtocolHandler};
+use iroh::{NodeId, Watcher};
 use std::future::Future;
-use tracing::{error, info};
+use tracing::{error, field, info, info_span, Instrument, Span};
 
 impl ProtocolHandler for S2pProtocol {
     fn accept(
@@ -21,50 +23,147 @@ impl ProtocolHandler for S2pProtocol {
                 }
             };
 
-            if !self.node_authenticator.should_accept(&remote_node_id).await {
-                info!("Connection declined from node: {}", remote_node_id);
+            let connection_span = info_span!(
+                "s2p_connection",
+                remote_node_id = %remote_node_id,
+                connection_id = connection.stable_id(),
+                profile = field::Empty
+            );
+            self.handle_connection(connection, remote_node_id)
+                .instrument(connection_span)
+                .await
+        })
+    }
+}
+
+impl S2pProtocol {
+    async fn handle_connection(
+        &self,
+        connection: Connection,
+        remote_node_id: NodeId,
+    ) -> Result<(), AcceptError> {
+        if self
+            .ban_list
+            .as_ref()
+            .is_some_and(|ban_list| ban_list.is_banned(&remote_node_id))
+        {
+            info!("Connection declined from banned node {}", remote_node_id);
+            connection.close(0u32.into(), b"node is banned");
+            return Err(NotAllowed {});
+        }
+
+        let mut auth_context =
+            AuthContext::new(remote_node_id, connection.alpn().unwrap_or_default());
+        if let Some(connection_type) = self
+            .endpoint
+            .as_ref()
+            .and_then(|endpoint| endpoint.conn_type(remote_node_id))
+            .map(|mut connection_type| connection_type.get())
+        {
+            auth_context = auth_context.with_connection_type(connection_type);
+        }
+
+        let decision = match self.node_authenticator.authenticate(&auth_context).await {
+            AuthDecision::Reject(reason) => match &self.capability_verifier {
+                Some(verifier) => match verifier
+                    .accept_presented(&connection, &remote_node_id)
+                    .await
+                {
+                    Ok(capability) => AuthDecision::accept_with(capability.to_profile()),
+                    Err(e) => AuthDecision::reject(format!("{}; {}", reason, e)),
+                },
+                None => AuthDecision::Reject(reason),
+            },
+            decision => decision,
+        };
+
+        let profile = match decision {
+            AuthDecision::Accept(profile) => profile,
+            AuthDecision::Reject(reason) => {
+                info!(
+                    "Connection declined from node {}: {}",
+                    remote_node_id, reason
+                );
+                connection.close(0u32.into(), reason.as_bytes());
                 return Err(NotAllowed {});
             }
+        };
+        if let Some(label) = &profile.label {
+            Span::current().record("profile", label.as_str());
+        }
 
-            let connection_clone = connection.clone();
-            let handler_clone = self.clone();
-            let socket_factory_clone = self.socket_factory.clone();
-            let dns_resolver_clone = self.dns_resolver.clone();
+        if let Some(quota_manager) = &self.quota_manager {
+            if let Err(e) = quota_manager.load(&remote_node_id).await {
+                error!(
+                    "Failed to load data transfer usage for node {}: {}",
+                    remote_node_id, e
+                );
+                return Err(AcceptError::from(e));
+            }
+        }
+
+        let remote_addr = auth_context.remote_addr;
+        let connection_clone = connection.clone();
+        let ban_connection = connection.clone();
+        let handler_clone = self.clone();
+        let udp_handler = UdpProxyHandlerHandler::new(self, remote_node_id, profile.clone());
 
-            let bi_stream_task = tokio::spawn(async move {
+        let bi_stream_task = tokio::spawn(
+            async move {
                 while let Ok((writer, reader)) = connection.accept_bi().await {
                     let handler_clone = handler_clone.clone();
-                    tokio::spawn(async move {
-                        TcpProxyHandlerHandler::with_timeouts_and_socket_factory(
-                            handler_clone.proxy_timeouts,
-                            handler_clone.socket_factory.clone(),
-                            handler_clone.dns_resolver.clone(),
-                        )
-                        .handle_stream(writer, reader)
-                        .await;
-                    });
+                    let profile = profile.clone();
+                    let stream_span = info_span!(
+                        "s2p_stream",
+                        stream_id = writer.id().index(),
+                        target = field::Empty
+                    );
+                    tokio::spawn(
+                        async move {
+                            TcpProxyHandlerHandler::new(
+                                &handler_clone,
+                                remote_node_id,
+                                remote_addr,
+                                profile,
+                            )
+                            .handle_stream(writer, reader)
+                            .await;
+                        }
+                        .instrument(stream_span),
+                    );
                 }
-            });
+            }
+            .in_current_span(),
+        );
 
-            let datagram_task = tokio::spawn(async move {
-                let udp_handler = UdpProxyHandlerHandler::with_socket_factory(
-                    socket_factory_clone,
-                    dns_resolver_clone,
-                );
+        let datagram_task = tokio::spawn(
+            async move {
                 while let Ok(datagram) = connection_clone.read_datagram().await {
                     udp_handler
                         .handle_datagram(&connection_clone, datagram)
                         .await;
                 }
-            });
+            }
+            .in_current_span(),
+        );
 
-            // Wait for either task to complete (they run concurrently)
-            tokio::select! {
-                _ = bi_stream_task => {},
-                _ = datagram_task => {},
+        let banned = async {
+            match &self.ban_list {
+                Some(ban_list) => ban_list.wait_for_ban(&remote_node_id).await,
+                None => std::future::pending().await,
             }
+        };
 
-            Ok(())
-        })
+        // Wait for either task to complete (they run concurrently)
+        tokio::select! {
+            _ = bi_stream_task => {},
+            _ = datagram_task => {},
+            _ = banned => {
+                info!("Closing connection from banned node {}", remote_node_id);
+                ban_connection.close(0u32.into(), b"node is banned");
+            },
+        }
+
+        Ok(())
     }
 }
@@ -0,0 +1,111 @@
+use super::types::S2pProtocol;
+use super::ALPN_S2P_V1;
+use crypto_box::aead::rand_core::RngCore;
+use iroh::protocol::Router;
+use iroh::{Endpoint, NodeAddr, NodeId, SecretKey, Watcher};
+use iroh_base::ticket::NodeTicket;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tracing::info;
+
+#[derive(Debug, thiserror::Error)]
+pub enum IdentityError {
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Invalid secret key in {0:?}")]
+    InvalidKey(PathBuf),
+
+    #[error("Secret key file {0:?} is accessible by other users")]
+    InsecurePermissions(PathBuf),
+
+    #[error("{0:?} is neither a node id nor a node ticket")]
+    InvalidTicket(String),
+}
+
+pub fn generate_secret_key() -> SecretKey {
+    let mut bytes = [0u8; 32];
+    crypto_box::aead::OsRng.fill_bytes(&mut bytes);
+    SecretKey::from_bytes(&bytes)
+}
+
+// Reads the hex encoded secret key at `path`, or generates one and writes it there on
+// first use, so the node keeps its id across restarts. On Unix the file is created with
+// mode 0600 and refused if other users can access it.
+pub fn load_or_generate_secret_key(path: impl AsRef<Path>) -> Result<SecretKey, IdentityError> {
+    let path = path.as_ref();
+    match std::fs::read_to_string(path) {
+        Ok(contents) => {
+            check_permissions(path)?;
+            SecretKey::from_str(contents.trim())
+                .map_err(|_| IdentityError::InvalidKey(path.to_path_buf()))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let secret_key = generate_secret_key();
+            let encoded: String = secret_key
+                .to_bytes()
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect();
+            write_private_file(path, &encoded)?;
+            info!("Generated new secret key at {:?}", path);
+            Ok(secret_key)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(unix)]
+fn check_permissions(path: &Path) -> Result<(), IdentityError> {
+    use std::os::unix::fs::PermissionsExt;
+    if std::fs::metadata(path)?.permissions().mode() & 0o077 != 0 {
+        return Err(IdentityError::InsecurePermissions(path.to_path_buf()));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_path: &Path) -> Result<(), IdentityError> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn write_private_file(path: &Path, contents: &str) -> io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(contents.as_bytes())
+}
+
+#[cfg(not(unix))]
+fn write_private_file(path: &Path, contents: &str) -> io::Result<()> {
+    std::fs::write(path, contents)
+}
+
+// Ticket clients can connect with, carrying the node id together with the relay URL and
+// direct addresses the endpoint currently knows. Waits until the endpoint has found them.
+pub async fn node_ticket(endpoint: &Endpoint) -> NodeTicket {
+    NodeTicket::new(endpoint.node_addr().initialized().await)
+}
+
+// Accepts a node ticket or a bare node id. A bare id leaves address lookup to discovery.
+pub fn parse_node_addr(value: &str) -> Result<NodeAddr, IdentityError> {
+    if let Ok(node_id) = NodeId::from_str(value) {
+        return Ok(NodeAddr::new(node_id));
+    }
+    NodeTicket::from_str(value)
+        .map(NodeAddr::from)
+        .map_err(|_| IdentityError::InvalidTicket(value.to_string()))
+}
+
+// Serves `protocol` on `endpoint` under `ALPN_S2P_V1`.
+pub fn spawn_router(endpoint: Endpoint, protocol: S2pProtocol) -> Router {
+    Router::builder(endpoint)
+        .accept(ALPN_S2P_V1, protocol)
+        .spawn()
+}
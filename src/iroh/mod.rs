@@ -1,17 +1,87 @@
+mod audit;
+mod ban_list;
+mod capability;
+mod config;
 mod dns_resolver;
+mod file_authenticator;
 mod handler;
+mod identity;
 mod node_authenticator;
+mod proxy_protocol;
+mod quota;
+mod secure_channel;
+mod service_registry;
 mod socket_factory;
+mod source_address;
+mod target_router;
 mod tcp_client;
+mod tcp_client_pool;
 mod tcp_handler;
+#[cfg(target_os = "linux")]
+mod transparent_proxy;
+mod tuned_socket_factory;
 mod types;
 mod udp_handler;
+mod unix_socket_policy;
+mod upstream_proxy;
 
-pub const ALPN_S2P_V1: &'static str = "s2p/1";
-pub use dns_resolver::{DefaultDnsResolver, DnsResolver};
+pub const ALPN_S2P_V1: &str = "s2p/1";
+pub use audit::{
+    AuditProtocol, AuditRecord, AuditSink, CloseReason, JsonLinesAuditSink, NoopAuditSink,
+    TracingAuditSink,
+};
+pub use ban_list::{BanList, BanPolicy, Violation};
+pub use capability::{
+    Capability, CapabilityError, CapabilitySubject, CapabilityToken, CapabilityVerifier,
+    BANDWIDTH_CLASS_ATTRIBUTE,
+};
+pub use config::{
+    AuthenticatorConfig, ConfigError, DnsConfig, DnsResolverKind, LimitsConfig, PolicyConfig,
+    S2pConfig, ServiceConfig, SocketConfig, TimeoutsConfig, UdpConfig,
+};
+pub use dns_resolver::{DefaultDnsResolver, DnsResolver, StaticDnsResolver};
+pub use file_authenticator::{
+    parse_allowlist, AllowlistEntry, AllowlistError, FileNodeAuthenticator,
+};
+pub use identity::{
+    generate_secret_key, load_or_generate_secret_key, node_ticket, parse_node_addr, spawn_router,
+    IdentityError,
+};
 pub use node_authenticator::{
-    AllowAllNodeAuthenticator, DynamicNodeAuthenticator, NodeAuthenticator,
+    AllowAllNodeAuthenticator, AuthContext, AuthDecision, DynamicNodeAuthenticator,
+    NodeAuthenticator, NodeProfile,
+};
+pub use proxy_protocol::{
+    encode_header as encode_proxy_protocol_header, ProxyProtocolPolicy, ProxyProtocolVersion,
+    PP2_TYPE_NODE_ID,
+};
+pub use quota::{
+    AccountingStore, FileAccountingStore, InMemoryAccountingStore, MeteredStream, NodeUsage,
+    QuotaLimits, QuotaManager,
+};
+pub use secure_channel::SecureChannel;
+pub use service_registry::{ServiceRegistry, ServiceTarget};
+pub use socket_factory::{
+    BoxedDatagramSocket, BoxedProxyStream, DatagramSocket, DefaultSocketFactory, ProxyStream,
+    RecvFromFuture, SocketFactory, SocketFactoryAdapter, TransportFactory,
+};
+pub use source_address::{SourceAddressFactory, SourceAddressPool, SourceSelection};
+pub use target_router::{DirectConnector, RouteAction, RouteRule, TargetConnector, TargetRouter};
+pub use tcp_client::{
+    TcpClient, TcpClientError, TcpClientOptions, TcpClientOptionsBuilder, TcpClientTimeouts,
+};
+pub use tcp_client_pool::{
+    ExitStatus, LoadBalancing, PooledStream, TcpClientPool, TcpClientPoolOptions,
+    TcpClientPoolOptionsBuilder,
+};
+#[cfg(target_os = "linux")]
+pub use transparent_proxy::{is_redirect_loop, TransparentMode, TransparentProxy};
+pub use tuned_socket_factory::{SocketOptions, SocketOptionsBuilder, TunedSocketFactory};
+pub use types::{
+    BuildError, ProxyTimeouts, ProxyTimeoutsBuilder, ReloadableSettings, S2pProtocol,
+    S2pProtocolBuilder, TargetPolicy, UdpSettings,
+};
+pub use unix_socket_policy::UnixSocketPolicy;
+pub use upstream_proxy::{
+    HttpConnectTransportFactory, ProxyCredentials, Socks5TransportFactory, TransportRouter,
 };
-pub use socket_factory::{DefaultSocketFactory, SocketFactory};
-pub use tcp_client::{TcpClient, TcpClientError, TcpClientTimeouts};
-pub use types::S2pProtocol;
@@ -1,11 +1,134 @@
-use iroh::NodeId;
+use super::types::resolved_target;
+use crate::message_types::TargetAddress;
+use crate::target_pattern::TargetPattern;
+use iroh::endpoint::ConnectionType;
+use iroh::{NodeId, RelayUrl};
+use std::collections::HashMap;
 use std::future::Future;
+use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::Arc;
+use std::time::SystemTime;
 use tokio::sync::RwLock;
 
+#[derive(Debug, Clone)]
+pub struct AuthContext {
+    pub node_id: NodeId,
+    pub alpn: Vec<u8>,
+    // The path to the node is only known when `S2pProtocol::endpoint` is set; otherwise
+    // `remote_addr` and `relay_url` are always `None`.
+    pub remote_addr: Option<SocketAddr>,
+    pub relay_url: Option<RelayUrl>,
+    pub connected_at: SystemTime,
+}
+
+impl AuthContext {
+    pub fn new(node_id: NodeId, alpn: Vec<u8>) -> Self {
+        Self {
+            node_id,
+            alpn,
+            remote_addr: None,
+            relay_url: None,
+            connected_at: SystemTime::now(),
+        }
+    }
+
+    pub fn with_connection_type(mut self, connection_type: ConnectionType) -> Self {
+        match connection_type {
+            ConnectionType::Direct(addr) => self.remote_addr = Some(addr),
+            ConnectionType::Relay(relay_url) => self.relay_url = Some(relay_url),
+            ConnectionType::Mixed(addr, relay_url) => {
+                self.remote_addr = Some(addr);
+                self.relay_url = Some(relay_url);
+            }
+            ConnectionType::None => {}
+        }
+        self
+    }
+}
+
+// Policy attached to an accepted node. Handlers of the node's streams and datagrams can
+// read it to vary authorization per peer.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NodeProfile {
+    pub label: Option<String>,
+    pub attributes: HashMap<String, String>,
+    // When set, only targets matching one of the patterns may be proxied to.
+    pub allowed_targets: Option<Vec<TargetPattern>>,
+}
+
+impl NodeProfile {
+    pub fn labeled(label: impl Into<String>) -> Self {
+        Self {
+            label: Some(label.into()),
+            ..Self::default()
+        }
+    }
+
+    pub fn with_attribute(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.attributes.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn attribute(&self, key: &str) -> Option<&str> {
+        self.attributes.get(key).map(String::as_str)
+    }
+
+    pub fn allows_target(&self, target: &TargetAddress) -> bool {
+        match &self.allowed_targets {
+            Some(patterns) => patterns.iter().any(|pattern| pattern.matches(target)),
+            None => true,
+        }
+    }
+
+    // Like `allows_target`, accepting a pattern match on either the requested target or
+    // the address it resolved to.
+    pub fn allows_resolved(&self, target: &TargetAddress, resolved: SocketAddr) -> bool {
+        self.allows_target(target) || self.allows_target(&resolved_target(resolved))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum AuthDecision {
+    Accept(Arc<NodeProfile>),
+    Reject(String),
+}
+
+impl AuthDecision {
+    pub fn accept() -> Self {
+        AuthDecision::Accept(Arc::new(NodeProfile::default()))
+    }
+
+    pub fn accept_with(profile: NodeProfile) -> Self {
+        AuthDecision::Accept(Arc::new(profile))
+    }
+
+    pub fn reject(reason: impl Into<String>) -> Self {
+        AuthDecision::Reject(reason.into())
+    }
+
+    pub fn is_accept(&self) -> bool {
+        matches!(self, AuthDecision::Accept(_))
+    }
+}
+
 pub trait NodeAuthenticator: Send + Sync + std::fmt::Debug {
     fn should_accept(&self, node_id: &NodeId) -> Pin<Box<dyn Future<Output = bool> + Send + '_>>;
+
+    // Full authentication hook. The default implementation accepts with an empty profile
+    // whenever `should_accept` does.
+    fn authenticate<'a>(
+        &'a self,
+        context: &'a AuthContext,
+    ) -> Pin<Box<dyn Future<Output = AuthDecision> + Send + 'a>> {
+        Box::pin(async move {
+            if self.should_accept(&context.node_id).await {
+                AuthDecision::accept()
+            } else {
+                AuthDecision::reject("node is not allowed")
+            }
+        })
+    }
 }
 
 #[derive(Debug, Clone)]
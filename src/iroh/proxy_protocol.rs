@@ -0,0 +1,127 @@
+use crate::message_types::TargetAddress;
+use crate::target_pattern::TargetPattern;
+use bytes::{BufMut, BytesMut};
+use iroh::NodeId;
+use std::net::{IpAddr, SocketAddr};
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_VERSION_PROXY_COMMAND: u8 = 0x21;
+const V2_FAMILY_UNSPEC: u8 = 0x00;
+const V2_FAMILY_TCP4: u8 = 0x11;
+const V2_FAMILY_TCP6: u8 = 0x21;
+
+// First TLV type of the range reserved for application specific data. Carries the
+// client's node id as its string encoding.
+pub const PP2_TYPE_NODE_ID: u8 = 0xE0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyProtocolVersion {
+    V1,
+    V2,
+}
+
+// Selects the PROXY protocol header, if any, sent to a target before proxied data. The
+// first rule matching the target wins.
+#[derive(Debug, Clone, Default)]
+pub struct ProxyProtocolPolicy {
+    rules: Vec<(TargetPattern, ProxyProtocolVersion)>,
+}
+
+impl ProxyProtocolPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn for_all(version: ProxyProtocolVersion) -> Self {
+        Self::new().with_rule(TargetPattern::any(), version)
+    }
+
+    pub fn with_rule(mut self, pattern: TargetPattern, version: ProxyProtocolVersion) -> Self {
+        self.rules.push((pattern, version));
+        self
+    }
+
+    pub fn version_for(&self, target: &TargetAddress) -> Option<ProxyProtocolVersion> {
+        self.rules
+            .iter()
+            .find(|(pattern, _)| pattern.matches(target))
+            .map(|(_, version)| *version)
+    }
+}
+
+// Encodes a header for a connection from `source` to `destination`. Without a known
+// source address v1 sends `UNKNOWN` and v2 an unspecified address family; v2 carries the
+// node id either way.
+pub fn encode_header(
+    version: ProxyProtocolVersion,
+    source: Option<SocketAddr>,
+    destination: SocketAddr,
+    node_id: &NodeId,
+) -> Vec<u8> {
+    let addresses = source.map(|source| unify_families(source, destination));
+    match version {
+        ProxyProtocolVersion::V1 => encode_v1(addresses),
+        ProxyProtocolVersion::V2 => encode_v2(addresses, node_id),
+    }
+}
+
+fn unify_families(source: SocketAddr, destination: SocketAddr) -> (SocketAddr, SocketAddr) {
+    let to_v6 = |addr: SocketAddr| match addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port()),
+        IpAddr::V6(_) => addr,
+    };
+    if source.is_ipv4() == destination.is_ipv4() {
+        (source, destination)
+    } else {
+        (to_v6(source), to_v6(destination))
+    }
+}
+
+fn encode_v1(addresses: Option<(SocketAddr, SocketAddr)>) -> Vec<u8> {
+    let header = match addresses {
+        Some((source, destination)) => format!(
+            "PROXY {} {} {} {} {}\r\n",
+            if source.is_ipv4() { "TCP4" } else { "TCP6" },
+            source.ip(),
+            destination.ip(),
+            source.port(),
+            destination.port()
+        ),
+        None => "PROXY UNKNOWN\r\n".to_string(),
+    };
+    header.into_bytes()
+}
+
+fn encode_v2(addresses: Option<(SocketAddr, SocketAddr)>, node_id: &NodeId) -> Vec<u8> {
+    let mut body = BytesMut::new();
+    let family = match addresses {
+        Some((SocketAddr::V4(source), SocketAddr::V4(destination))) => {
+            body.put_slice(&source.ip().octets());
+            body.put_slice(&destination.ip().octets());
+            body.put_u16(source.port());
+            body.put_u16(destination.port());
+            V2_FAMILY_TCP4
+        }
+        Some((SocketAddr::V6(source), SocketAddr::V6(destination))) => {
+            body.put_slice(&source.ip().octets());
+            body.put_slice(&destination.ip().octets());
+            body.put_u16(source.port());
+            body.put_u16(destination.port());
+            V2_FAMILY_TCP6
+        }
+        _ => V2_FAMILY_UNSPEC,
+    };
+
+    let node_id = node_id.to_string();
+    body.put_u8(PP2_TYPE_NODE_ID);
+    body.put_u16(node_id.len() as u16);
+    body.put_slice(node_id.as_bytes());
+
+    let mut header = Vec::with_capacity(16 + body.len());
+    header.extend_from_slice(V2_SIGNATURE);
+    header.push(V2_VERSION_PROXY_COMMAND);
+    header.push(family);
+    header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    header.extend_from_slice(&body);
+    header
+}
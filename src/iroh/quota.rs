@@ -0,0 +1,552 @@
+use super::types::BuildError;
+use iroh::NodeId;
+use pin_project::pin_project;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::io;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::RwLock;
+use tokio::task::AbortHandle;
+use tracing::error;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QuotaLimits {
+    pub daily_bytes: Option<u64>,
+    pub monthly_bytes: Option<u64>,
+    pub total_bytes: Option<u64>,
+}
+
+impl QuotaLimits {
+    pub fn unlimited() -> Self {
+        Self::default()
+    }
+
+    // A shorter period may not allow more than a longer one, and no limit may be zero.
+    pub fn validate(&self) -> Result<(), BuildError> {
+        let limits = [
+            ("daily_bytes", self.daily_bytes),
+            ("monthly_bytes", self.monthly_bytes),
+            ("total_bytes", self.total_bytes),
+        ];
+        for (idx, (field, limit)) in limits.iter().enumerate() {
+            let Some(limit) = limit else {
+                continue;
+            };
+            if *limit == 0 {
+                return Err(BuildError::invalid(*field, "must be greater than zero"));
+            }
+            if let Some((longer, longer_limit)) = limits[idx + 1..]
+                .iter()
+                .find_map(|(longer, longer_limit)| longer_limit.map(|l| (longer, l)))
+            {
+                if *limit > longer_limit {
+                    return Err(BuildError::invalid(
+                        *field,
+                        format!("exceeds {} ({} > {})", longer, limit, longer_limit),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn remaining(&self, usage: &NodeUsage) -> Option<u64> {
+        [
+            self.daily_bytes
+                .map(|limit| limit.saturating_sub(usage.daily_bytes)),
+            self.monthly_bytes
+                .map(|limit| limit.saturating_sub(usage.monthly_bytes)),
+            self.total_bytes
+                .map(|limit| limit.saturating_sub(usage.total_bytes)),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NodeUsage {
+    // Days and months since the unix epoch (UTC) the daily and monthly counters belong to.
+    pub day: u32,
+    pub month: u32,
+    pub daily_bytes: u64,
+    pub monthly_bytes: u64,
+    pub total_bytes: u64,
+}
+
+impl NodeUsage {
+    pub fn roll_over(&mut self, now: SystemTime) {
+        let (day, month) = accounting_period(now);
+        if self.day != day {
+            self.day = day;
+            self.daily_bytes = 0;
+        }
+        if self.month != month {
+            self.month = month;
+            self.monthly_bytes = 0;
+        }
+    }
+
+    fn add(&mut self, bytes: u64) {
+        self.daily_bytes = self.daily_bytes.saturating_add(bytes);
+        self.monthly_bytes = self.monthly_bytes.saturating_add(bytes);
+        self.total_bytes = self.total_bytes.saturating_add(bytes);
+    }
+}
+
+fn accounting_period(now: SystemTime) -> (u32, u32) {
+    let days = now
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_secs() / 86_400)
+        .unwrap_or(0) as i64;
+
+    // Civil-from-days conversion (proleptic Gregorian calendar, UTC)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (days as u32, ((year - 1970) * 12 + month - 1) as u32)
+}
+
+pub trait AccountingStore: Send + Sync + std::fmt::Debug {
+    fn load_usage(
+        &self,
+        node_id: &NodeId,
+    ) -> Pin<Box<dyn Future<Output = io::Result<Option<NodeUsage>>> + Send + '_>>;
+
+    fn store_usage(
+        &self,
+        node_id: &NodeId,
+        usage: NodeUsage,
+    ) -> Pin<Box<dyn Future<Output = io::Result<()>> + Send + '_>>;
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryAccountingStore {
+    usage: Arc<RwLock<HashMap<NodeId, NodeUsage>>>,
+}
+
+impl InMemoryAccountingStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn arc() -> Arc<dyn AccountingStore> {
+        Arc::new(Self::new())
+    }
+}
+
+impl AccountingStore for InMemoryAccountingStore {
+    fn load_usage(
+        &self,
+        node_id: &NodeId,
+    ) -> Pin<Box<dyn Future<Output = io::Result<Option<NodeUsage>>> + Send + '_>> {
+        let node_id = *node_id;
+        Box::pin(async move { Ok(self.usage.read().await.get(&node_id).copied()) })
+    }
+
+    fn store_usage(
+        &self,
+        node_id: &NodeId,
+        usage: NodeUsage,
+    ) -> Pin<Box<dyn Future<Output = io::Result<()>> + Send + '_>> {
+        let node_id = *node_id;
+        Box::pin(async move {
+            self.usage.write().await.insert(node_id, usage);
+            Ok(())
+        })
+    }
+}
+
+// Stores usage as one whitespace separated line per node:
+// `<node_id> <day> <month> <daily_bytes> <monthly_bytes> <total_bytes>`
+#[derive(Debug)]
+pub struct FileAccountingStore {
+    path: PathBuf,
+    usage: tokio::sync::Mutex<Option<HashMap<NodeId, NodeUsage>>>,
+}
+
+impl FileAccountingStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            usage: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    pub fn arc(path: impl Into<PathBuf>) -> Arc<dyn AccountingStore> {
+        Arc::new(Self::new(path))
+    }
+
+    async fn read_file(&self) -> io::Result<HashMap<NodeId, NodeUsage>> {
+        let contents = match tokio::fs::read_to_string(&self.path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(e) => return Err(e),
+        };
+
+        contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(Self::parse_line)
+            .collect()
+    }
+
+    fn parse_line(line: &str) -> io::Result<(NodeId, NodeUsage)> {
+        let invalid = || {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid accounting record: {}", line),
+            )
+        };
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 6 {
+            return Err(invalid());
+        }
+
+        let node_id = NodeId::from_str(fields[0]).map_err(|_| invalid())?;
+        let number = |idx: usize| fields[idx].parse::<u64>().map_err(|_| invalid());
+        let usage = NodeUsage {
+            day: number(1)? as u32,
+            month: number(2)? as u32,
+            daily_bytes: number(3)?,
+            monthly_bytes: number(4)?,
+            total_bytes: number(5)?,
+        };
+
+        Ok((node_id, usage))
+    }
+
+    async fn write_file(&self, usage: &HashMap<NodeId, NodeUsage>) -> io::Result<()> {
+        let mut contents = String::new();
+        for (node_id, usage) in usage {
+            contents.push_str(&format!(
+                "{} {} {} {} {} {}\n",
+                node_id,
+                usage.day,
+                usage.month,
+                usage.daily_bytes,
+                usage.monthly_bytes,
+                usage.total_bytes
+            ));
+        }
+
+        let tmp_path = self.path.with_extension("tmp");
+        tokio::fs::write(&tmp_path, contents).await?;
+        tokio::fs::rename(&tmp_path, &self.path).await
+    }
+}
+
+impl AccountingStore for FileAccountingStore {
+    fn load_usage(
+        &self,
+        node_id: &NodeId,
+    ) -> Pin<Box<dyn Future<Output = io::Result<Option<NodeUsage>>> + Send + '_>> {
+        let node_id = *node_id;
+        Box::pin(async move {
+            let mut cached = self.usage.lock().await;
+            if cached.is_none() {
+                *cached = Some(self.read_file().await?);
+            }
+            Ok(cached
+                .as_ref()
+                .and_then(|usage| usage.get(&node_id).copied()))
+        })
+    }
+
+    fn store_usage(
+        &self,
+        node_id: &NodeId,
+        usage: NodeUsage,
+    ) -> Pin<Box<dyn Future<Output = io::Result<()>> + Send + '_>> {
+        let node_id = *node_id;
+        Box::pin(async move {
+            let mut cached = self.usage.lock().await;
+            if cached.is_none() {
+                *cached = Some(self.read_file().await?);
+            }
+            let all_usage = cached.get_or_insert_with(HashMap::new);
+            all_usage.insert(node_id, usage);
+            self.write_file(all_usage).await
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct QuotaManager {
+    default_limits: Mutex<QuotaLimits>,
+    node_limits: Mutex<HashMap<NodeId, QuotaLimits>>,
+    store: Arc<dyn AccountingStore>,
+    ledger: Arc<Mutex<HashMap<NodeId, NodeUsage>>>,
+    // Nodes charged since their usage was last persisted.
+    dirty: Arc<Mutex<HashSet<NodeId>>>,
+    persist_task: Mutex<Option<AbortHandle>>,
+}
+
+impl QuotaManager {
+    pub fn new(default_limits: QuotaLimits, store: Arc<dyn AccountingStore>) -> Self {
+        Self {
+            default_limits: Mutex::new(default_limits),
+            node_limits: Mutex::new(HashMap::new()),
+            store,
+            ledger: Arc::new(Mutex::new(HashMap::new())),
+            dirty: Arc::new(Mutex::new(HashSet::new())),
+            persist_task: Mutex::new(None),
+        }
+    }
+
+    pub fn arc(default_limits: QuotaLimits, store: Arc<dyn AccountingStore>) -> Arc<Self> {
+        Arc::new(Self::new(default_limits, store))
+    }
+
+    pub fn default_limits(&self) -> QuotaLimits {
+        *self.default_limits.lock().unwrap()
+    }
+
+    pub fn set_default_limits(&self, limits: QuotaLimits) {
+        *self.default_limits.lock().unwrap() = limits;
+    }
+
+    pub fn set_node_limits(&self, node_id: NodeId, limits: QuotaLimits) {
+        self.node_limits.lock().unwrap().insert(node_id, limits);
+    }
+
+    pub fn remove_node_limits(&self, node_id: &NodeId) {
+        self.node_limits.lock().unwrap().remove(node_id);
+    }
+
+    pub fn limits_for(&self, node_id: &NodeId) -> QuotaLimits {
+        self.node_limits
+            .lock()
+            .unwrap()
+            .get(node_id)
+            .copied()
+            .unwrap_or_else(|| *self.default_limits.lock().unwrap())
+    }
+
+    // Pulls the persisted usage of a node into the in-memory ledger. Must be called
+    // before the node's traffic is charged, otherwise accounting starts from zero.
+    pub async fn load(&self, node_id: &NodeId) -> io::Result<()> {
+        if self.ledger.lock().unwrap().contains_key(node_id) {
+            return Ok(());
+        }
+
+        let usage = self.store.load_usage(node_id).await?.unwrap_or_default();
+        self.ledger.lock().unwrap().entry(*node_id).or_insert(usage);
+        Ok(())
+    }
+
+    pub fn usage(&self, node_id: &NodeId) -> NodeUsage {
+        let mut usage = self
+            .ledger
+            .lock()
+            .unwrap()
+            .get(node_id)
+            .copied()
+            .unwrap_or_default();
+        usage.roll_over(SystemTime::now());
+        usage
+    }
+
+    // Returns `None` when the node has no limits configured.
+    pub fn remaining(&self, node_id: &NodeId) -> Option<u64> {
+        let usage = self.usage(node_id);
+        self.limits_for(node_id).remaining(&usage)
+    }
+
+    pub fn is_exhausted(&self, node_id: &NodeId) -> bool {
+        self.remaining(node_id) == Some(0)
+    }
+
+    // Records transferred bytes and returns whether the node is still within its quota.
+    pub fn charge(&self, node_id: &NodeId, bytes: u64) -> bool {
+        let limits = self.limits_for(node_id);
+        let mut ledger = self.ledger.lock().unwrap();
+        let usage = ledger.entry(*node_id).or_default();
+        usage.roll_over(SystemTime::now());
+        usage.add(bytes);
+        self.dirty.lock().unwrap().insert(*node_id);
+        limits.remaining(usage) != Some(0)
+    }
+
+    pub async fn persist(&self, node_id: &NodeId) -> io::Result<()> {
+        self.dirty.lock().unwrap().remove(node_id);
+        let usage = self.usage(node_id);
+        let result = self.store.store_usage(node_id, usage).await;
+        if result.is_err() {
+            self.dirty.lock().unwrap().insert(*node_id);
+        }
+        result
+    }
+
+    // Persists the usage of every node charged since its last persist once per `interval`,
+    // so long-lived sessions are not only accounted for when they close.
+    pub fn persist_periodically(&self, interval: Duration) {
+        let store = self.store.clone();
+        let ledger = self.ledger.clone();
+        let dirty = self.dirty.clone();
+
+        let task = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let nodes: Vec<NodeId> = dirty.lock().unwrap().drain().collect();
+                for node_id in nodes {
+                    let Some(usage) = ledger.lock().unwrap().get(&node_id).copied() else {
+                        continue;
+                    };
+                    if let Err(e) = store.store_usage(&node_id, usage).await {
+                        error!(
+                            "Failed to persist data transfer usage for node {}: {}",
+                            node_id, e
+                        );
+                        dirty.lock().unwrap().insert(node_id);
+                    }
+                }
+            }
+        });
+
+        if let Some(previous) = self
+            .persist_task
+            .lock()
+            .unwrap()
+            .replace(task.abort_handle())
+        {
+            previous.abort();
+        }
+    }
+}
+
+impl Drop for QuotaManager {
+    fn drop(&mut self) {
+        if let Some(task) = self.persist_task.lock().unwrap().take() {
+            task.abort();
+        }
+    }
+}
+
+// Counts every byte read from or written to the wrapped stream. With a quota attached the
+// bytes are also charged to the node, and I/O fails with `PermissionDenied` once the quota
+// is exhausted.
+#[derive(Debug)]
+#[pin_project]
+pub struct MeteredStream<S> {
+    #[pin]
+    inner: S,
+    quota: Option<(Arc<QuotaManager>, NodeId)>,
+    bytes_read: u64,
+    bytes_written: u64,
+}
+
+impl<S> MeteredStream<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            quota: None,
+            bytes_read: 0,
+            bytes_written: 0,
+        }
+    }
+
+    pub fn with_quota(inner: S, quota: Arc<QuotaManager>, node_id: NodeId) -> Self {
+        Self {
+            quota: Some((quota, node_id)),
+            ..Self::new(inner)
+        }
+    }
+
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+fn quota_exhausted() -> io::Error {
+    io::Error::new(ErrorKind::PermissionDenied, "data transfer quota exhausted")
+}
+
+fn check_quota(quota: &Option<(Arc<QuotaManager>, NodeId)>) -> io::Result<()> {
+    match quota {
+        Some((quota, node_id)) if quota.is_exhausted(node_id) => Err(quota_exhausted()),
+        _ => Ok(()),
+    }
+}
+
+fn charge_quota(quota: &Option<(Arc<QuotaManager>, NodeId)>, bytes: u64) {
+    if let Some((quota, node_id)) = quota {
+        quota.charge(node_id, bytes);
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for MeteredStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        if let Err(e) = check_quota(this.quota) {
+            return Poll::Ready(Err(e));
+        }
+
+        let filled_before = buf.filled().len();
+        let result = this.inner.poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            let read = (buf.filled().len() - filled_before) as u64;
+            *this.bytes_read += read;
+            charge_quota(this.quota, read);
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for MeteredStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        if let Err(e) = check_quota(this.quota) {
+            return Poll::Ready(Err(e));
+        }
+
+        let result = this.inner.poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            *this.bytes_written += written as u64;
+            charge_quota(this.quota, written as u64);
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
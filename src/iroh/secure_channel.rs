@@ -0,0 +1,263 @@
+use crypto_box::aead::{Aead, Nonce, OsRng};
+use crypto_box::ChaChaBox;
+use iroh::{NodeId, SecretKey};
+use iroh_base::Signature;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf};
+use tracing::debug;
+
+// End-to-end encrypted channel between a client and one hop of a multi-hop path, carried
+// over the stream relayed by the previous hops. The client sends an ephemeral X25519 key,
+// the hop answers with its own ephemeral key signed by its node key, and both sides then
+// exchange length-prefixed XChaCha20-Poly1305 frames. A sender ends its direction with a
+// frame of empty plaintext, so a stream cut by a relaying hop is not taken for the end.
+
+const TRANSCRIPT_CONTEXT: &[u8] = b"s2p-hop-channel-v1";
+const MAX_PLAINTEXT_LEN: usize = 16 * 1024;
+const TAG_LEN: usize = 16;
+
+const INITIATOR_TO_RESPONDER: u8 = 0;
+const RESPONDER_TO_INITIATOR: u8 = 1;
+
+pub(crate) async fn initiate<R, W>(
+    mut reader: R,
+    mut writer: W,
+    responder: &NodeId,
+) -> io::Result<SecureChannel>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let secret = crypto_box::SecretKey::generate(&mut OsRng);
+    let public = secret.public_key();
+    writer.write_all(public.as_bytes()).await?;
+    writer.flush().await?;
+
+    let mut peer_public = [0u8; 32];
+    reader.read_exact(&mut peer_public).await?;
+    let mut signature = [0u8; 64];
+    reader.read_exact(&mut signature).await?;
+
+    responder
+        .verify(
+            &transcript(public.as_bytes(), &peer_public),
+            &Signature::from_bytes(&signature),
+        )
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "hop failed to prove its node identity",
+            )
+        })?;
+
+    let cipher = ChaChaBox::new(&crypto_box::PublicKey::from_bytes(peer_public), &secret);
+    Ok(spawn_pumps(
+        reader,
+        writer,
+        cipher,
+        INITIATOR_TO_RESPONDER,
+        RESPONDER_TO_INITIATOR,
+    ))
+}
+
+pub(crate) async fn respond<R, W>(
+    mut reader: R,
+    mut writer: W,
+    identity: &SecretKey,
+) -> io::Result<SecureChannel>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let mut peer_public = [0u8; 32];
+    reader.read_exact(&mut peer_public).await?;
+
+    let secret = crypto_box::SecretKey::generate(&mut OsRng);
+    let public = secret.public_key();
+    let signature = identity.sign(&transcript(&peer_public, public.as_bytes()));
+    writer.write_all(public.as_bytes()).await?;
+    writer.write_all(&signature.to_bytes()).await?;
+    writer.flush().await?;
+
+    let cipher = ChaChaBox::new(&crypto_box::PublicKey::from_bytes(peer_public), &secret);
+    Ok(spawn_pumps(
+        reader,
+        writer,
+        cipher,
+        RESPONDER_TO_INITIATOR,
+        INITIATOR_TO_RESPONDER,
+    ))
+}
+
+fn transcript(initiator_public: &[u8; 32], responder_public: &[u8; 32]) -> Vec<u8> {
+    let mut transcript = Vec::with_capacity(TRANSCRIPT_CONTEXT.len() + 64);
+    transcript.extend_from_slice(TRANSCRIPT_CONTEXT);
+    transcript.extend_from_slice(initiator_public);
+    transcript.extend_from_slice(responder_public);
+    transcript
+}
+
+fn nonce(direction: u8, counter: u64) -> Nonce<ChaChaBox> {
+    let mut nonce = Nonce::<ChaChaBox>::default();
+    nonce[0] = direction;
+    nonce[16..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+// Plaintext side of a channel. Reading fails instead of ending if the peer's frames
+// stopped without a close frame or could not be opened.
+#[derive(Debug)]
+pub struct SecureChannel {
+    stream: DuplexStream,
+    failure: Arc<Mutex<Option<(io::ErrorKind, String)>>>,
+}
+
+impl AsyncRead for SecureChannel {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.stream).poll_read(cx, buf))?;
+        if buf.filled().len() == filled && buf.remaining() > 0 {
+            if let Some((kind, message)) = self.failure.lock().unwrap().as_ref() {
+                return Poll::Ready(Err(io::Error::new(*kind, message.clone())));
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for SecureChannel {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+// Returns the plaintext side of the channel; two tasks move frames between it and the
+// underlying stream until either side closes.
+fn spawn_pumps<R, W>(
+    reader: R,
+    writer: W,
+    cipher: ChaChaBox,
+    send_direction: u8,
+    recv_direction: u8,
+) -> SecureChannel
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let (local, remote) = tokio::io::duplex(4 * MAX_PLAINTEXT_LEN);
+    let (plain_reader, mut plain_writer) = tokio::io::split(remote);
+    let cipher = Arc::new(cipher);
+    let failure = Arc::new(Mutex::new(None));
+
+    let seal_cipher = cipher.clone();
+    tokio::spawn(async move {
+        if let Err(e) = seal_frames(plain_reader, writer, &seal_cipher, send_direction).await {
+            debug!("Secure channel send side closed: {}", e);
+        }
+    });
+    let recv_failure = failure.clone();
+    tokio::spawn(async move {
+        if let Err(e) = open_frames(reader, &mut plain_writer, &cipher, recv_direction).await {
+            debug!("Secure channel receive side closed: {}", e);
+            // Recorded before the plaintext side sees the end of the stream.
+            *recv_failure.lock().unwrap() = Some((e.kind(), e.to_string()));
+            let _ = plain_writer.shutdown().await;
+        }
+    });
+
+    SecureChannel {
+        stream: local,
+        failure,
+    }
+}
+
+async fn seal_frames<R, W>(
+    mut plain_reader: R,
+    mut writer: W,
+    cipher: &ChaChaBox,
+    direction: u8,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buffer = vec![0u8; MAX_PLAINTEXT_LEN];
+    let mut counter = 0u64;
+    loop {
+        let len = plain_reader.read(&mut buffer).await?;
+        let frame = cipher
+            .encrypt(&nonce(direction, counter), &buffer[..len])
+            .map_err(|_| io::Error::other("failed to seal frame"))?;
+        counter += 1;
+
+        writer.write_u16(frame.len() as u16).await?;
+        writer.write_all(&frame).await?;
+        writer.flush().await?;
+        if len == 0 {
+            return writer.shutdown().await;
+        }
+    }
+}
+
+async fn open_frames<R, W>(
+    mut reader: R,
+    plain_writer: &mut W,
+    cipher: &ChaChaBox,
+    direction: u8,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buffer = vec![0u8; MAX_PLAINTEXT_LEN + TAG_LEN];
+    let mut counter = 0u64;
+    loop {
+        let len = match reader.read_u16().await {
+            Ok(len) => len as usize,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "secure channel ended without a close frame",
+                ));
+            }
+            Err(e) => return Err(e),
+        };
+        if len > buffer.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "secure channel frame too large",
+            ));
+        }
+        reader.read_exact(&mut buffer[..len]).await?;
+
+        let plaintext = cipher
+            .decrypt(&nonce(direction, counter), &buffer[..len])
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "failed to open frame"))?;
+        counter += 1;
+        if plaintext.is_empty() {
+            return plain_writer.shutdown().await;
+        }
+
+        plain_writer.write_all(&plaintext).await?;
+        plain_writer.flush().await?;
+    }
+}
@@ -0,0 +1,53 @@
+use crate::message_types::TargetAddress;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServiceTarget {
+    Address(TargetAddress),
+    Unix(PathBuf),
+}
+
+// Services this node exposes by name. Clients address them with `Host::Service`, so they
+// never learn internal addresses, and names that are not registered are unreachable.
+#[derive(Debug, Default)]
+pub struct ServiceRegistry {
+    services: RwLock<HashMap<String, ServiceTarget>>,
+}
+
+impl ServiceRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn arc() -> Arc<Self> {
+        Arc::new(Self::new())
+    }
+
+    pub fn with_service(self, name: impl Into<String>, target: ServiceTarget) -> Self {
+        self.register(name, target);
+        self
+    }
+
+    pub fn register(&self, name: impl Into<String>, target: ServiceTarget) {
+        self.services.write().unwrap().insert(name.into(), target);
+    }
+
+    // Swaps in a new set of services at once, e.g. after a configuration reload.
+    pub fn replace(&self, services: impl IntoIterator<Item = (String, ServiceTarget)>) {
+        *self.services.write().unwrap() = services.into_iter().collect();
+    }
+
+    pub fn unregister(&self, name: &str) -> Option<ServiceTarget> {
+        self.services.write().unwrap().remove(name)
+    }
+
+    pub fn resolve(&self, name: &str) -> Option<ServiceTarget> {
+        self.services.read().unwrap().get(name).cloned()
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.services.read().unwrap().keys().cloned().collect()
+    }
+}
@@ -1,10 +1,134 @@
+use crate::message_types::TargetAddress;
+use iroh::NodeId;
 use std::future::Future;
 use std::io;
 use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpStream, UdpSocket};
 
+pub trait ProxyStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> ProxyStream for T {}
+
+pub type BoxedProxyStream = Box<dyn ProxyStream>;
+
+pub type RecvFromFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<(usize, SocketAddr), io::Error>> + Send + 'a>>;
+
+// Connectionless socket a UDP flow relays through. `send_to` may be called while another
+// task is waiting in `recv_from`.
+pub trait DatagramSocket: Send + Sync + std::fmt::Debug {
+    fn send_to<'a>(
+        &'a self,
+        buf: &'a [u8],
+        target: SocketAddr,
+    ) -> Pin<Box<dyn Future<Output = Result<usize, io::Error>> + Send + 'a>>;
+
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> RecvFromFuture<'a>;
+
+    fn local_addr(&self) -> Result<SocketAddr, io::Error>;
+}
+
+pub type BoxedDatagramSocket = Box<dyn DatagramSocket>;
+
+impl DatagramSocket for UdpSocket {
+    fn send_to<'a>(
+        &'a self,
+        buf: &'a [u8],
+        target: SocketAddr,
+    ) -> Pin<Box<dyn Future<Output = Result<usize, io::Error>> + Send + 'a>> {
+        Box::pin(UdpSocket::send_to(self, buf, target))
+    }
+
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> RecvFromFuture<'a> {
+        Box::pin(UdpSocket::recv_from(self, buf))
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        UdpSocket::local_addr(self)
+    }
+}
+
+// Opens the outbound side of proxied streams and UDP flows. Unlike `SocketFactory` the
+// transports are not tied to tokio sockets, so targets can be reached over TLS, through
+// upstream proxies or over in-memory transports.
+pub trait TransportFactory: Send + Sync + std::fmt::Debug {
+    fn connect_stream(
+        &self,
+        addr: SocketAddr,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedProxyStream, io::Error>> + Send + '_>>;
+
+    fn bind_datagram(
+        &self,
+        bind_addr: &str,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedDatagramSocket, io::Error>> + Send + '_>>;
+
+    // The proxy handlers open sockets through these, passing the client node the socket
+    // is opened for. Factories that do not depend on the client keep the defaults.
+    fn connect_stream_for(
+        &self,
+        node_id: NodeId,
+        addr: SocketAddr,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedProxyStream, io::Error>> + Send + '_>> {
+        let _ = node_id;
+        self.connect_stream(addr)
+    }
+
+    fn bind_datagram_for(
+        &self,
+        node_id: NodeId,
+        bind_addr: &str,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedDatagramSocket, io::Error>> + Send + '_>> {
+        let _ = node_id;
+        self.bind_datagram(bind_addr)
+    }
+
+    // Connects to `target`, which the proxy handler has checked against its policies and,
+    // unless `resolves_names` holds for it, resolved to `addr`. Transports that resolve
+    // names themselves, such as upstream proxies, use the original target; all others
+    // connect to `addr`.
+    fn connect_target_for<'a>(
+        &'a self,
+        node_id: NodeId,
+        target: &'a TargetAddress,
+        addr: Option<SocketAddr>,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedProxyStream, io::Error>> + Send + 'a>> {
+        connect_resolved(self, node_id, target, addr)
+    }
+
+    // Whether `connect_target_for` reaches `target` without a resolved address, so the
+    // proxy handler does not resolve it locally.
+    fn resolves_names(&self, target: &TargetAddress) -> bool {
+        let _ = target;
+        false
+    }
+}
+
+// Connects to the address the proxy handler resolved `target` to.
+pub(crate) fn connect_resolved<'a, F: TransportFactory + ?Sized>(
+    factory: &'a F,
+    node_id: NodeId,
+    target: &TargetAddress,
+    addr: Option<SocketAddr>,
+) -> Pin<Box<dyn Future<Output = Result<BoxedProxyStream, io::Error>> + Send + 'a>> {
+    match addr {
+        Some(addr) => factory.connect_stream_for(node_id, addr),
+        None => {
+            let error = io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("target {} was not resolved", target),
+            );
+            Box::pin(async move { Err(error) })
+        }
+    }
+}
+
+// Opens outbound tokio sockets; superseded by `TransportFactory`. Existing factories keep
+// working through `SocketFactoryAdapter`, which `S2pProtocolBuilder::legacy_socket_factory`
+// applies. To migrate, implement `connect_stream` and `bind_datagram` with the bodies of
+// `create_tcp_connection` and `create_udp_socket`, boxing the sockets they return.
 pub trait SocketFactory: Send + Sync + std::fmt::Debug {
     fn create_tcp_connection(
         &self,
@@ -17,6 +141,45 @@ pub trait SocketFactory: Send + Sync + std::fmt::Debug {
     ) -> Pin<Box<dyn Future<Output = Result<UdpSocket, io::Error>> + Send + '_>>;
 }
 
+// Exposes a `SocketFactory` as a `TransportFactory`.
+#[derive(Debug, Clone)]
+pub struct SocketFactoryAdapter {
+    inner: Arc<dyn SocketFactory>,
+}
+
+impl SocketFactoryAdapter {
+    pub fn new(inner: Arc<dyn SocketFactory>) -> Self {
+        Self { inner }
+    }
+
+    pub fn arc(inner: Arc<dyn SocketFactory>) -> Arc<dyn TransportFactory> {
+        Arc::new(Self::new(inner))
+    }
+}
+
+impl TransportFactory for SocketFactoryAdapter {
+    fn connect_stream(
+        &self,
+        addr: SocketAddr,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedProxyStream, io::Error>> + Send + '_>> {
+        Box::pin(async move {
+            let stream = self.inner.create_tcp_connection(addr).await?;
+            Ok(Box::new(stream) as BoxedProxyStream)
+        })
+    }
+
+    fn bind_datagram(
+        &self,
+        bind_addr: &str,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedDatagramSocket, io::Error>> + Send + '_>> {
+        let bind_addr = bind_addr.to_string();
+        Box::pin(async move {
+            let socket = self.inner.create_udp_socket(&bind_addr).await?;
+            Ok(Box::new(socket) as BoxedDatagramSocket)
+        })
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct DefaultSocketFactory;
 
@@ -28,6 +191,10 @@ impl DefaultSocketFactory {
     pub fn arc() -> Arc<dyn SocketFactory> {
         Arc::new(Self::new())
     }
+
+    pub fn transport() -> Arc<dyn TransportFactory> {
+        Arc::new(Self::new())
+    }
 }
 
 impl SocketFactory for DefaultSocketFactory {
@@ -47,6 +214,29 @@ impl SocketFactory for DefaultSocketFactory {
     }
 }
 
+impl TransportFactory for DefaultSocketFactory {
+    fn connect_stream(
+        &self,
+        addr: SocketAddr,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedProxyStream, io::Error>> + Send + '_>> {
+        Box::pin(async move {
+            let stream = TcpStream::connect(addr).await?;
+            Ok(Box::new(stream) as BoxedProxyStream)
+        })
+    }
+
+    fn bind_datagram(
+        &self,
+        bind_addr: &str,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedDatagramSocket, io::Error>> + Send + '_>> {
+        let bind_addr = bind_addr.to_string();
+        Box::pin(async move {
+            let socket = UdpSocket::bind(bind_addr).await?;
+            Ok(Box::new(socket) as BoxedDatagramSocket)
+        })
+    }
+}
+
 impl Default for DefaultSocketFactory {
     fn default() -> Self {
         Self::new()
@@ -0,0 +1,169 @@
+use super::socket_factory::{BoxedDatagramSocket, BoxedProxyStream, TransportFactory};
+use super::tuned_socket_factory::SocketOptions;
+use iroh::NodeId;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SourceAddressPool {
+    // Addresses configured on this host; each connection uses one of the destination's
+    // address family.
+    Addresses(Vec<IpAddr>),
+    // A routed IPv6 prefix. Addresses are generated inside it and bound with
+    // `IP_FREEBIND`, so they do not have to be configured on an interface. Only used for
+    // IPv6 destinations.
+    Ipv6Prefix { network: Ipv6Addr, prefix_len: u8 },
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SourceSelection {
+    // Each client node always gets the same source address, derived from its node id.
+    #[default]
+    Pinned,
+    // Every connection or UDP flow takes another address of the pool.
+    Rotating,
+}
+
+// Picks the source address of outbound sockets per client node from a pool of addresses.
+#[derive(Debug)]
+pub struct SourceAddressFactory {
+    pool: SourceAddressPool,
+    selection: SourceSelection,
+    options: SocketOptions,
+    next: AtomicU64,
+}
+
+impl SourceAddressFactory {
+    pub fn new(
+        pool: SourceAddressPool,
+        selection: SourceSelection,
+        options: SocketOptions,
+    ) -> Self {
+        let mut options = options;
+        if matches!(pool, SourceAddressPool::Ipv6Prefix { .. }) {
+            options.freebind = Some(true);
+        }
+        Self {
+            pool,
+            selection,
+            options,
+            next: AtomicU64::new(0),
+        }
+    }
+
+    pub fn arc(
+        pool: SourceAddressPool,
+        selection: SourceSelection,
+        options: SocketOptions,
+    ) -> Arc<dyn TransportFactory> {
+        Arc::new(Self::new(pool, selection, options))
+    }
+
+    // Source address for a socket of the given family opened for `node_id`. Without a
+    // node, or with a rotating selection, every call takes a different pool entry.
+    pub fn source_for(&self, node_id: Option<&NodeId>, ipv4: bool) -> Option<IpAddr> {
+        let key = match (self.selection, node_id) {
+            (SourceSelection::Pinned, Some(node_id)) => node_key(node_id),
+            _ => splitmix64(self.next.fetch_add(1, Ordering::Relaxed)),
+        };
+
+        match &self.pool {
+            SourceAddressPool::Addresses(addresses) => {
+                let candidates: Vec<&IpAddr> = addresses
+                    .iter()
+                    .filter(|address| address.is_ipv4() == ipv4)
+                    .collect();
+                if candidates.is_empty() {
+                    return None;
+                }
+                Some(*candidates[(key % candidates.len() as u64) as usize])
+            }
+            SourceAddressPool::Ipv6Prefix { .. } if ipv4 => None,
+            SourceAddressPool::Ipv6Prefix {
+                network,
+                prefix_len,
+            } => {
+                let host_bits = 128u32.saturating_sub(u32::from(*prefix_len));
+                let host_mask = u128::MAX.checked_shr(128 - host_bits).unwrap_or(0);
+                let host = (u128::from(key) << 64 | u128::from(splitmix64(key))) & host_mask;
+                Some(IpAddr::V6(Ipv6Addr::from(
+                    (u128::from(*network) & !host_mask) | host,
+                )))
+            }
+        }
+    }
+
+    async fn connect(
+        &self,
+        node_id: Option<NodeId>,
+        addr: SocketAddr,
+    ) -> io::Result<BoxedProxyStream> {
+        let source = self.source_for(node_id.as_ref(), addr.is_ipv4());
+        let stream = self
+            .options
+            .tcp_socket(&addr, source)?
+            .connect(addr)
+            .await?;
+        Ok(Box::new(stream))
+    }
+
+    fn bind(&self, node_id: Option<NodeId>, bind_addr: &str) -> io::Result<BoxedDatagramSocket> {
+        let bind_addr = bind_addr
+            .parse::<SocketAddr>()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let source = self.source_for(node_id.as_ref(), bind_addr.is_ipv4());
+        Ok(Box::new(self.options.udp_socket(bind_addr, source)?))
+    }
+}
+
+impl TransportFactory for SourceAddressFactory {
+    fn connect_stream(
+        &self,
+        addr: SocketAddr,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedProxyStream, io::Error>> + Send + '_>> {
+        Box::pin(self.connect(None, addr))
+    }
+
+    fn bind_datagram(
+        &self,
+        bind_addr: &str,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedDatagramSocket, io::Error>> + Send + '_>> {
+        let socket = self.bind(None, bind_addr);
+        Box::pin(async move { socket })
+    }
+
+    fn connect_stream_for(
+        &self,
+        node_id: NodeId,
+        addr: SocketAddr,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedProxyStream, io::Error>> + Send + '_>> {
+        Box::pin(self.connect(Some(node_id), addr))
+    }
+
+    fn bind_datagram_for(
+        &self,
+        node_id: NodeId,
+        bind_addr: &str,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedDatagramSocket, io::Error>> + Send + '_>> {
+        let socket = self.bind(Some(node_id), bind_addr);
+        Box::pin(async move { socket })
+    }
+}
+
+// Node ids are public keys, so their leading bytes are already uniformly distributed.
+fn node_key(node_id: &NodeId) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&node_id.as_bytes()[..8]);
+    u64::from_be_bytes(bytes)
+}
+
+fn splitmix64(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
@@ -0,0 +1,250 @@
+use super::dns_resolver::{DefaultDnsResolver, DnsResolver};
+use super::socket_factory::{BoxedProxyStream, DefaultSocketFactory, TransportFactory};
+use super::tcp_client::{TcpClient, TcpClientError};
+use super::tcp_client_pool::TcpClientPool;
+use crate::message_types::{ConnectStatusCode, Host, TargetAddress};
+use crate::target_pattern::TargetPattern;
+use iroh::NodeId;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing::debug;
+
+// Opens a stream to a target, either directly or through an s2p exit.
+pub trait TargetConnector: Send + Sync {
+    fn connect(
+        &self,
+        target: TargetAddress,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedProxyStream, TcpClientError>> + Send + '_>>;
+}
+
+impl TargetConnector for TcpClient {
+    fn connect(
+        &self,
+        target: TargetAddress,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedProxyStream, TcpClientError>> + Send + '_>> {
+        Box::pin(async move {
+            let stream = TcpClient::connect(self, target).await?;
+            Ok(Box::new(stream) as BoxedProxyStream)
+        })
+    }
+}
+
+impl TargetConnector for TcpClientPool {
+    fn connect(
+        &self,
+        target: TargetAddress,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedProxyStream, TcpClientError>> + Send + '_>> {
+        Box::pin(async move {
+            let stream = TcpClientPool::connect(self, target).await?;
+            Ok(Box::new(stream) as BoxedProxyStream)
+        })
+    }
+}
+
+// Connects to targets from this host without going through s2p.
+#[derive(Debug, Clone)]
+pub struct DirectConnector {
+    socket_factory: Arc<dyn TransportFactory>,
+    dns_resolver: Arc<dyn DnsResolver>,
+}
+
+impl DirectConnector {
+    pub fn new(
+        socket_factory: Arc<dyn TransportFactory>,
+        dns_resolver: Arc<dyn DnsResolver>,
+    ) -> Self {
+        Self {
+            socket_factory,
+            dns_resolver,
+        }
+    }
+
+    pub fn arc(
+        socket_factory: Arc<dyn TransportFactory>,
+        dns_resolver: Arc<dyn DnsResolver>,
+    ) -> Arc<dyn TargetConnector> {
+        Arc::new(Self::new(socket_factory, dns_resolver))
+    }
+
+    async fn resolve(&self, target: &TargetAddress) -> Result<SocketAddr, TcpClientError> {
+        let ip = match &target.host {
+            Host::IPv4(ip) => (*ip).into(),
+            Host::IPv6(ip) => (*ip).into(),
+            Host::Domain(domain) => *self
+                .dns_resolver
+                .lookup_host(&format!("{}:{}", domain, target.port))
+                .await?
+                .first()
+                .ok_or(TcpClientError::ProtocolError(
+                    ConnectStatusCode::HostUnreachable,
+                ))?,
+            Host::Node(_) | Host::Service(_) | Host::UnixSocket(_) => {
+                return Err(TcpClientError::ProtocolError(
+                    ConnectStatusCode::AddressTypeNotSupported,
+                ))
+            }
+        };
+        Ok(SocketAddr::new(ip, target.port))
+    }
+}
+
+impl Default for DirectConnector {
+    fn default() -> Self {
+        Self::new(DefaultSocketFactory::transport(), DefaultDnsResolver::arc())
+    }
+}
+
+impl TargetConnector for DirectConnector {
+    fn connect(
+        &self,
+        target: TargetAddress,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedProxyStream, TcpClientError>> + Send + '_>> {
+        Box::pin(async move {
+            let addr = self.resolve(&target).await?;
+            Ok(self.socket_factory.connect_stream(addr).await?)
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RouteAction {
+    Direct,
+    Exit(NodeId),
+    Reject,
+}
+
+impl fmt::Display for RouteAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RouteAction::Direct => write!(f, "direct"),
+            RouteAction::Exit(node_id) => write!(f, "exit:{}", node_id),
+            RouteAction::Reject => write!(f, "reject"),
+        }
+    }
+}
+
+// Parses `direct`, `reject` or `exit:<node id>`.
+impl FromStr for RouteAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "direct" => Ok(RouteAction::Direct),
+            "reject" => Ok(RouteAction::Reject),
+            _ => match s.strip_prefix("exit:") {
+                Some(node_id) => NodeId::from_str(node_id)
+                    .map(RouteAction::Exit)
+                    .map_err(|e| format!("invalid exit node id {:?}: {}", node_id, e)),
+                None => Err(format!("unknown route action {:?}", s)),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteRule {
+    pub pattern: TargetPattern,
+    pub action: RouteAction,
+}
+
+impl RouteRule {
+    pub fn new(pattern: TargetPattern, action: RouteAction) -> Self {
+        Self { pattern, action }
+    }
+}
+
+// Picks how to reach a target from an ordered list of rules; the first matching rule
+// wins and `default_action` applies when none match.
+#[derive(Clone)]
+pub struct TargetRouter {
+    rules: Vec<RouteRule>,
+    default_action: RouteAction,
+    direct: Arc<dyn TargetConnector>,
+    exits: HashMap<NodeId, Arc<dyn TargetConnector>>,
+}
+
+impl TargetRouter {
+    pub fn new(default_action: RouteAction) -> Self {
+        Self {
+            rules: Vec::new(),
+            default_action,
+            direct: Arc::new(DirectConnector::default()),
+            exits: HashMap::new(),
+        }
+    }
+
+    pub fn with_rule(mut self, pattern: TargetPattern, action: RouteAction) -> Self {
+        self.rules.push(RouteRule::new(pattern, action));
+        self
+    }
+
+    pub fn with_rules(mut self, rules: impl IntoIterator<Item = RouteRule>) -> Self {
+        self.rules.extend(rules);
+        self
+    }
+
+    pub fn with_direct_connector(mut self, direct: Arc<dyn TargetConnector>) -> Self {
+        self.direct = direct;
+        self
+    }
+
+    pub fn with_exit(mut self, node_id: NodeId, connector: Arc<dyn TargetConnector>) -> Self {
+        self.exits.insert(node_id, connector);
+        self
+    }
+
+    pub fn rules(&self) -> &[RouteRule] {
+        &self.rules
+    }
+
+    pub fn route(&self, target: &TargetAddress) -> &RouteAction {
+        self.rules
+            .iter()
+            .find(|rule| rule.pattern.matches(target))
+            .map(|rule| &rule.action)
+            .unwrap_or(&self.default_action)
+    }
+
+    pub async fn connect(&self, target: TargetAddress) -> Result<BoxedProxyStream, TcpClientError> {
+        let action = self.route(&target);
+        debug!("Routing {} via {}", target, action);
+        match action {
+            RouteAction::Direct => self.direct.connect(target).await,
+            RouteAction::Exit(node_id) => match self.exits.get(node_id) {
+                Some(connector) => connector.connect(target).await,
+                None => Err(TcpClientError::IoError(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no connector registered for exit {}", node_id),
+                ))),
+            },
+            RouteAction::Reject => Err(TcpClientError::ProtocolError(
+                ConnectStatusCode::ConnectionNotAllowed,
+            )),
+        }
+    }
+}
+
+impl fmt::Debug for TargetRouter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TargetRouter")
+            .field("rules", &self.rules)
+            .field("default_action", &self.default_action)
+            .field("exits", &self.exits.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl TargetConnector for TargetRouter {
+    fn connect(
+        &self,
+        target: TargetAddress,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedProxyStream, TcpClientError>> + Send + '_>> {
+        Box::pin(TargetRouter::connect(self, target))
+    }
+}
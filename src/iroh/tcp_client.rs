@@ -1,25 +1,34 @@
-use crate::codec::{CodecError, TcpConnectRequestCodec, TcpConnectResponseCodec};
+use crate::codec::TcpConnectRequestCodec;
+use crate::iroh::capability::CapabilityToken;
+use crate::iroh::secure_channel::{self, SecureChannel};
+use crate::iroh::types::{non_zero, BuildError};
+use crate::iroh::ALPN_S2P_V1;
 use crate::iroh_stream::IrohStream;
-use crate::message_types::{
-    ConnectStatusCode, TargetAddress, TcpConnectRequest, TcpConnectResponse,
-};
-use iroh::endpoint::{Connection, RecvStream, SendStream};
-use n0_future::SinkExt;
+use crate::message_types::{ConnectStatusCode, Host, TargetAddress, TcpConnectRequest};
+use bytes::BytesMut;
+use derive_builder::Builder;
+use iroh::endpoint::Connection;
+use iroh::{Endpoint, NodeAddr, NodeId};
 use std::io;
 use std::time::Duration;
-use tokio::io::copy_bidirectional;
-use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::time::timeout;
-use tokio_stream::StreamExt;
-use tokio_util::codec::{FramedRead, FramedWrite};
-use tracing::{error, info};
+use tokio_util::codec::Encoder;
+use tracing::info;
 
-#[derive(Clone)]
+#[derive(Debug, Clone)]
 pub struct TcpClientTimeouts {
     pub request_timeout: Duration,
     pub response_timeout: Duration,
 }
 
+impl TcpClientTimeouts {
+    pub fn validate(&self) -> Result<(), BuildError> {
+        non_zero("request_timeout", self.request_timeout)?;
+        non_zero("response_timeout", self.response_timeout)
+    }
+}
+
 impl Default for TcpClientTimeouts {
     fn default() -> Self {
         Self {
@@ -29,6 +38,67 @@ impl Default for TcpClientTimeouts {
     }
 }
 
+#[derive(Debug, Clone, Builder)]
+#[builder(
+    setter(into),
+    build_fn(validate = "Self::validate", error = "BuildError")
+)]
+pub struct TcpClientOptions {
+    #[builder(default)]
+    pub timeouts: TcpClientTimeouts,
+    // ALPN `TcpClient::open` connects with. Connections passed to
+    // `TcpClient::with_options` must have been opened with it.
+    #[builder(default = "ALPN_S2P_V1.as_bytes().to_vec()")]
+    pub alpn: Vec<u8>,
+    #[builder(default = "Duration::from_secs(10)")]
+    pub connect_timeout: Duration,
+    // Presented before any stream is opened, for servers that do not otherwise recognise
+    // this node.
+    #[builder(default, setter(into, strip_option))]
+    pub capability: Option<CapabilityToken>,
+}
+
+impl TcpClientOptions {
+    pub fn builder() -> TcpClientOptionsBuilder {
+        TcpClientOptionsBuilder::default()
+    }
+
+    pub fn validate(&self) -> Result<(), BuildError> {
+        self.timeouts.validate()?;
+        non_zero("connect_timeout", self.connect_timeout)?;
+        if self.alpn.is_empty() {
+            return Err(BuildError::invalid("alpn", "must not be empty"));
+        }
+        Ok(())
+    }
+}
+
+impl TcpClientOptionsBuilder {
+    fn validate(&self) -> Result<(), BuildError> {
+        if let Some(timeouts) = &self.timeouts {
+            timeouts.validate()?;
+        }
+        if let Some(connect_timeout) = self.connect_timeout {
+            non_zero("connect_timeout", connect_timeout)?;
+        }
+        if self.alpn.as_ref().is_some_and(|alpn| alpn.is_empty()) {
+            return Err(BuildError::invalid("alpn", "must not be empty"));
+        }
+        Ok(())
+    }
+}
+
+impl Default for TcpClientOptions {
+    fn default() -> Self {
+        Self {
+            timeouts: TcpClientTimeouts::default(),
+            alpn: ALPN_S2P_V1.as_bytes().to_vec(),
+            connect_timeout: Duration::from_secs(10),
+            capability: None,
+        }
+    }
+}
+
 pub struct TcpClient {
     connection: Connection,
     timeouts: TcpClientTimeouts,
@@ -49,71 +119,174 @@ impl TcpClient {
         }
     }
 
+    pub async fn with_options(
+        connection: Connection,
+        options: TcpClientOptions,
+    ) -> Result<Self, TcpClientError> {
+        if connection
+            .alpn()
+            .is_some_and(|alpn| alpn != options.alpn.as_slice())
+        {
+            return Err(
+                BuildError::invalid("alpn", "connection was opened with a different ALPN").into(),
+            );
+        }
+        let client = Self::with_timeouts(connection, options.timeouts);
+        if let Some(token) = &options.capability {
+            client.present_capability(token).await?;
+        }
+        Ok(client)
+    }
+
+    // Connects to `node` with the ALPN and timeout of `options`.
+    pub async fn open(
+        endpoint: &Endpoint,
+        node: impl Into<NodeAddr>,
+        options: TcpClientOptions,
+    ) -> Result<Self, TcpClientError> {
+        let connection = timeout(
+            options.connect_timeout,
+            endpoint.connect(node, options.alpn.as_slice()),
+        )
+        .await
+        .map_err(|_| {
+            TcpClientError::IoError(io::Error::new(io::ErrorKind::TimedOut, "connect timeout"))
+        })?
+        .map_err(|e| TcpClientError::IoError(io::Error::other(e)))?;
+        Self::with_options(connection, options).await
+    }
+
+    // Sends a capability token to a server that does not otherwise recognise this node.
+    // Must be called before opening any streams on the connection.
+    pub async fn present_capability(&self, token: &CapabilityToken) -> Result<(), TcpClientError> {
+        let bytes = token
+            .to_bytes()
+            .map_err(|e| TcpClientError::IoError(io::Error::new(io::ErrorKind::InvalidInput, e)))?;
+        let mut writer = self
+            .connection
+            .open_uni()
+            .await
+            .map_err(|e| TcpClientError::IoError(io::Error::other(e)))?;
+
+        timeout(self.timeouts.request_timeout, writer.write_all(&bytes))
+            .await
+            .map_err(|_| {
+                TcpClientError::IoError(io::Error::new(io::ErrorKind::TimedOut, "request timeout"))
+            })?
+            .map_err(|e| TcpClientError::IoError(io::Error::other(e)))?;
+        writer
+            .finish()
+            .map_err(|e| TcpClientError::IoError(io::Error::other(e)))?;
+        Ok(())
+    }
+
     pub async fn connect(&self, target: TargetAddress) -> Result<IrohStream, TcpClientError> {
-        let (writer, reader) = self
+        let (mut writer, mut reader) = self
             .connection
             .open_bi()
             .await
-            .map_err(|e| TcpClientError::IoError(io::Error::new(io::ErrorKind::Other, e)))?;
+            .map_err(|e| TcpClientError::IoError(io::Error::other(e)))?;
 
-        let mut framed_writer = FramedWrite::new(writer, TcpConnectRequestCodec);
-        let mut framed_reader = FramedRead::new(reader, TcpConnectResponseCodec);
+        // The target may speak first, so the response is read without buffering past it.
+        self.request(&mut tokio::io::join(&mut reader, &mut writer), target)
+            .await?;
 
-        let connect_request = TcpConnectRequest { target };
+        info!("Successfully established connection to target");
 
-        timeout(
-            self.timeouts.request_timeout,
-            framed_writer.send(connect_request),
-        )
-        .await
-        .map_err(|_| {
-            TcpClientError::IoError(io::Error::new(io::ErrorKind::TimedOut, "request timeout"))
-        })?
-        .map_err(|e| {
-            TcpClientError::IoError(io::Error::new(io::ErrorKind::Other, e.to_string()))
-        })?;
+        Ok(IrohStream::new(reader, writer))
+    }
 
-        let response = self.read_connect_response(&mut framed_reader).await?;
+    // Builds a path through `hops`, ending at the last one, which connects to `target`.
+    // The node this client is connected to relays to the first hop, and every hop relays
+    // to the next. Each hop terminates an end-to-end channel, so a hop only learns the
+    // node that follows it.
+    pub async fn connect_through(
+        &self,
+        hops: &[NodeId],
+        target: TargetAddress,
+    ) -> Result<SecureChannel, TcpClientError> {
+        let (first_hop, next_hops) = hops.split_first().ok_or(TcpClientError::InvalidRequest)?;
+        let (mut writer, mut reader) = self
+            .connection
+            .open_bi()
+            .await
+            .map_err(|e| TcpClientError::IoError(io::Error::other(e)))?;
 
-        if response.status != ConnectStatusCode::Success {
-            return Err(TcpClientError::ProtocolError(response.status));
+        self.open_hop(&mut reader, &mut writer, first_hop).await?;
+        let mut channel = secure_channel::initiate(reader, writer, first_hop).await?;
+        for hop in next_hops {
+            let (mut reader, mut writer) = tokio::io::split(channel);
+            self.open_hop(&mut reader, &mut writer, hop).await?;
+            channel = secure_channel::initiate(reader, writer, hop).await?;
         }
 
-        info!("Successfully established connection to target");
-
-        Ok(IrohStream::new(
-            framed_reader.into_inner(),
-            framed_writer.into_inner(),
-        ))
+        self.request(&mut channel, target).await?;
+        info!(
+            "Successfully established connection to target through {} hops",
+            hops.len()
+        );
+        Ok(channel)
     }
 
-    async fn read_connect_response(
+    async fn open_hop<R, W>(
         &self,
-        framed_reader: &mut FramedRead<RecvStream, TcpConnectResponseCodec>,
-    ) -> Result<TcpConnectResponse, TcpClientError> {
-        match timeout(self.timeouts.response_timeout, framed_reader.next()).await {
-            Ok(Some(Ok(response))) => {
-                info!("Received connect response: {:?}", response.status);
-                Ok(response)
-            }
-            Ok(Some(Err(e))) => {
-                error!("Codec error reading response: {:?}", e);
-                Err(TcpClientError::InvalidRequest)
-            }
-            Ok(None) => {
-                error!("Stream ended during response");
-                Err(TcpClientError::IoError(io::Error::new(
-                    io::ErrorKind::UnexpectedEof,
-                    "stream ended during response",
-                )))
-            }
-            Err(_) => {
-                error!("Response timed out");
-                Err(TcpClientError::IoError(io::Error::new(
+        reader: &mut R,
+        writer: &mut W,
+        hop: &NodeId,
+    ) -> Result<(), TcpClientError>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let hop_address = TargetAddress {
+            host: Host::Node(*hop),
+            port: 0,
+        };
+        // The first request is relayed by the current node, the second is answered by the
+        // hop itself and starts the channel handshake.
+        self.request(
+            &mut tokio::io::join(&mut *reader, &mut *writer),
+            hop_address.clone(),
+        )
+        .await?;
+        self.request(
+            &mut tokio::io::join(&mut *reader, &mut *writer),
+            hop_address,
+        )
+        .await
+    }
+
+    // Sends a connect request and reads its single byte response without buffering past it.
+    async fn request<S>(&self, stream: &mut S, target: TargetAddress) -> Result<(), TcpClientError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut request = BytesMut::new();
+        TcpConnectRequestCodec
+            .encode(TcpConnectRequest { target }, &mut request)
+            .map_err(|_| TcpClientError::InvalidRequest)?;
+
+        timeout(self.timeouts.request_timeout, async {
+            stream.write_all(&request).await?;
+            stream.flush().await
+        })
+        .await
+        .map_err(|_| {
+            TcpClientError::IoError(io::Error::new(io::ErrorKind::TimedOut, "request timeout"))
+        })??;
+
+        let status = timeout(self.timeouts.response_timeout, stream.read_u8())
+            .await
+            .map_err(|_| {
+                TcpClientError::IoError(io::Error::new(
                     io::ErrorKind::TimedOut,
                     "response timed out",
-                )))
-            }
+                ))
+            })??;
+        match ConnectStatusCode::try_from(status) {
+            Ok(ConnectStatusCode::Success) => Ok(()),
+            Ok(status) => Err(TcpClientError::ProtocolError(status)),
+            Err(_) => Err(TcpClientError::InvalidRequest),
         }
     }
 }
@@ -128,4 +301,7 @@ pub enum TcpClientError {
 
     #[error("Invalid request")]
     InvalidRequest,
+
+    #[error("Invalid client options: {0}")]
+    InvalidOptions(#[from] BuildError),
 }
@@ -0,0 +1,452 @@
+use super::tcp_client::{TcpClient, TcpClientError, TcpClientOptions};
+use super::types::{non_zero, BuildError};
+use crate::iroh_stream::IrohStream;
+use crate::message_types::{ConnectStatusCode, TargetAddress};
+use derive_builder::Builder;
+use iroh::endpoint::Connection;
+use iroh::{Endpoint, NodeId};
+use n0_future::join_all;
+use pin_project::pin_project;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::task::AbortHandle;
+use tokio::time::timeout;
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LoadBalancing {
+    #[default]
+    RoundRobin,
+    LeastSessions,
+    LowestLatency,
+}
+
+#[derive(Debug, Clone, Builder)]
+#[builder(
+    setter(into),
+    build_fn(validate = "Self::validate", error = "BuildError")
+)]
+pub struct TcpClientPoolOptions {
+    #[builder(default)]
+    pub load_balancing: LoadBalancing,
+    // ALPN, timeouts and capability token every exit connection is opened with.
+    #[builder(default)]
+    pub client_options: TcpClientOptions,
+    #[builder(default = "Duration::from_secs(5)")]
+    pub health_check_interval: Duration,
+    #[builder(default = "Duration::from_millis(500)")]
+    pub min_backoff: Duration,
+    #[builder(default = "Duration::from_secs(60)")]
+    pub max_backoff: Duration,
+    // Total number of exits tried for a single `connect` call.
+    #[builder(default = "3")]
+    pub max_attempts: usize,
+    #[builder(default = "TcpClientPoolOptions::default_retryable_status_codes()")]
+    pub retryable_status_codes: Vec<ConnectStatusCode>,
+}
+
+impl TcpClientPoolOptions {
+    pub fn builder() -> TcpClientPoolOptionsBuilder {
+        TcpClientPoolOptionsBuilder::default()
+    }
+
+    fn default_retryable_status_codes() -> Vec<ConnectStatusCode> {
+        vec![
+            ConnectStatusCode::GeneralFailure,
+            ConnectStatusCode::NetworkUnreachable,
+            ConnectStatusCode::TTLExpired,
+        ]
+    }
+}
+
+impl TcpClientPoolOptionsBuilder {
+    fn validate(&self) -> Result<(), BuildError> {
+        if let Some(client_options) = &self.client_options {
+            client_options.validate()?;
+        }
+        if let Some(health_check_interval) = self.health_check_interval {
+            non_zero("health_check_interval", health_check_interval)?;
+        }
+        if self.max_attempts == Some(0) {
+            return Err(BuildError::invalid("max_attempts", "must be at least 1"));
+        }
+        let min_backoff = self.min_backoff.unwrap_or(Duration::from_millis(500));
+        let max_backoff = self.max_backoff.unwrap_or(Duration::from_secs(60));
+        if min_backoff > max_backoff {
+            return Err(BuildError::invalid(
+                "min_backoff",
+                "must not exceed max_backoff",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for TcpClientPoolOptions {
+    fn default() -> Self {
+        Self {
+            load_balancing: LoadBalancing::default(),
+            client_options: TcpClientOptions::default(),
+            health_check_interval: Duration::from_secs(5),
+            min_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(60),
+            max_attempts: 3,
+            retryable_status_codes: Self::default_retryable_status_codes(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ExitState {
+    connection: Option<Connection>,
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Exit {
+    node_id: NodeId,
+    state: Mutex<ExitState>,
+    // Held while connecting, so `connect` and `keep_warm` never open two connections to
+    // the same exit.
+    reconnecting: tokio::sync::Mutex<()>,
+    active_sessions: Arc<AtomicUsize>,
+}
+
+impl Exit {
+    fn live_connection(&self) -> Option<Connection> {
+        let mut state = self.state.lock().unwrap();
+        if state
+            .connection
+            .as_ref()
+            .is_some_and(|connection| connection.close_reason().is_some())
+        {
+            state.connection = None;
+        }
+        state.connection.clone()
+    }
+
+    fn is_backing_off(&self, now: Instant) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .retry_at
+            .is_some_and(|retry_at| retry_at > now)
+    }
+
+    fn latency(&self) -> Duration {
+        self.live_connection()
+            .map(|connection| connection.rtt())
+            .unwrap_or(Duration::MAX)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ExitStatus {
+    pub node_id: NodeId,
+    pub connected: bool,
+    pub active_sessions: usize,
+    pub rtt: Option<Duration>,
+}
+
+// Client over several exit nodes. Connections are kept warm in the background and
+// re-established with exponential backoff; `connect` picks an exit according to the
+// load balancing strategy and fails over to another exit on connection errors or
+// retryable status codes.
+#[derive(Debug)]
+pub struct TcpClientPool {
+    inner: Arc<PoolInner>,
+    maintenance_task: AbortHandle,
+}
+
+#[derive(Debug)]
+struct PoolInner {
+    endpoint: Endpoint,
+    exits: Vec<Exit>,
+    options: TcpClientPoolOptions,
+    next_exit: AtomicUsize,
+}
+
+impl TcpClientPool {
+    pub fn new(
+        endpoint: Endpoint,
+        exits: impl IntoIterator<Item = NodeId>,
+        options: TcpClientPoolOptions,
+    ) -> Self {
+        let inner = Arc::new(PoolInner {
+            endpoint,
+            exits: exits
+                .into_iter()
+                .map(|node_id| Exit {
+                    node_id,
+                    state: Mutex::new(ExitState::default()),
+                    reconnecting: tokio::sync::Mutex::new(()),
+                    active_sessions: Arc::new(AtomicUsize::new(0)),
+                })
+                .collect(),
+            options,
+            next_exit: AtomicUsize::new(0),
+        });
+
+        let maintained = inner.clone();
+        let maintenance_task = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(maintained.options.health_check_interval);
+            loop {
+                interval.tick().await;
+                maintained.keep_warm().await;
+            }
+        })
+        .abort_handle();
+
+        Self {
+            inner,
+            maintenance_task,
+        }
+    }
+
+    pub fn exit_status(&self) -> Vec<ExitStatus> {
+        self.inner
+            .exits
+            .iter()
+            .map(|exit| {
+                let connection = exit.live_connection();
+                ExitStatus {
+                    node_id: exit.node_id,
+                    connected: connection.is_some(),
+                    active_sessions: exit.active_sessions.load(Ordering::Relaxed),
+                    rtt: connection.map(|connection| connection.rtt()),
+                }
+            })
+            .collect()
+    }
+
+    pub async fn connect(&self, target: TargetAddress) -> Result<PooledStream, TcpClientError> {
+        let inner = &self.inner;
+        let mut last_error = TcpClientError::IoError(io::Error::new(
+            io::ErrorKind::NotConnected,
+            "no exit nodes configured",
+        ));
+
+        for exit in inner
+            .exit_order()
+            .into_iter()
+            .take(inner.options.max_attempts)
+        {
+            let connection = match inner.connection_for(exit).await {
+                Ok(connection) => connection,
+                Err(error) => {
+                    last_error = error;
+                    continue;
+                }
+            };
+
+            let client =
+                TcpClient::with_timeouts(connection, inner.options.client_options.timeouts.clone());
+            match client.connect(target.clone()).await {
+                Ok(stream) => {
+                    return Ok(PooledStream::new(
+                        stream,
+                        exit.node_id,
+                        exit.active_sessions.clone(),
+                    ))
+                }
+                Err(TcpClientError::ProtocolError(status))
+                    if !inner.options.retryable_status_codes.contains(&status) =>
+                {
+                    return Err(TcpClientError::ProtocolError(status));
+                }
+                Err(error) => {
+                    warn!(
+                        "Connect to {} through exit {} failed, trying next exit: {}",
+                        target, exit.node_id, error
+                    );
+                    if matches!(error, TcpClientError::IoError(_)) {
+                        inner.mark_failed(exit);
+                    }
+                    last_error = error;
+                }
+            }
+        }
+
+        Err(last_error)
+    }
+}
+
+impl Drop for TcpClientPool {
+    fn drop(&mut self) {
+        self.maintenance_task.abort();
+    }
+}
+
+impl PoolInner {
+    // Connected exits in load balancing order, followed by exits that are not connected
+    // yet, with exits in backoff last.
+    fn exit_order(&self) -> Vec<&Exit> {
+        let now = Instant::now();
+        let mut exits: Vec<&Exit> = self.exits.iter().collect();
+        if !exits.is_empty() {
+            let start = self.next_exit.fetch_add(1, Ordering::Relaxed) % exits.len();
+            exits.rotate_left(start);
+        }
+
+        match self.options.load_balancing {
+            LoadBalancing::RoundRobin => {}
+            LoadBalancing::LeastSessions => {
+                exits.sort_by_key(|exit| exit.active_sessions.load(Ordering::Relaxed))
+            }
+            LoadBalancing::LowestLatency => exits.sort_by_key(|exit| exit.latency()),
+        }
+        exits.sort_by_key(|exit| (exit.is_backing_off(now), exit.live_connection().is_none()));
+        exits
+    }
+
+    async fn connection_for(&self, exit: &Exit) -> Result<Connection, TcpClientError> {
+        if let Some(connection) = exit.live_connection() {
+            return Ok(connection);
+        }
+        self.reconnect(exit).await
+    }
+
+    async fn reconnect(&self, exit: &Exit) -> Result<Connection, TcpClientError> {
+        let _reconnecting = exit.reconnecting.lock().await;
+        // Another caller may have connected while this one waited.
+        if let Some(connection) = exit.live_connection() {
+            return Ok(connection);
+        }
+
+        let options = &self.options.client_options;
+        let result = timeout(
+            options.connect_timeout,
+            self.endpoint.connect(exit.node_id, options.alpn.as_slice()),
+        )
+        .await;
+
+        match result {
+            Ok(Ok(connection)) => {
+                // Presents the capability, if any, before streams are opened.
+                if let Err(e) = TcpClient::with_options(connection.clone(), options.clone()).await {
+                    warn!(
+                        "Failed to set up connection to exit {}: {}",
+                        exit.node_id, e
+                    );
+                    connection.close(0u32.into(), b"setup failed");
+                    self.mark_failed(exit);
+                    return Err(e);
+                }
+                info!("Connected to exit {}", exit.node_id);
+                let mut state = exit.state.lock().unwrap();
+                state.connection = Some(connection.clone());
+                state.failures = 0;
+                state.retry_at = None;
+                Ok(connection)
+            }
+            Ok(Err(e)) => {
+                warn!("Failed to connect to exit {}: {}", exit.node_id, e);
+                self.mark_failed(exit);
+                Err(TcpClientError::IoError(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    e,
+                )))
+            }
+            Err(_) => {
+                warn!("Connecting to exit {} timed out", exit.node_id);
+                self.mark_failed(exit);
+                Err(TcpClientError::IoError(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "exit connection timed out",
+                )))
+            }
+        }
+    }
+
+    fn mark_failed(&self, exit: &Exit) {
+        let mut state = exit.state.lock().unwrap();
+        state.connection = None;
+        state.failures = state.failures.saturating_add(1);
+        let backoff = self
+            .options
+            .min_backoff
+            .saturating_mul(2u32.saturating_pow(state.failures - 1))
+            .min(self.options.max_backoff);
+        state.retry_at = Some(Instant::now() + backoff);
+    }
+
+    // Reconnects all disconnected exits at once, so one unreachable exit does not delay
+    // the others by its connect timeout.
+    async fn keep_warm(&self) {
+        let now = Instant::now();
+        join_all(
+            self.exits
+                .iter()
+                .filter(|exit| exit.live_connection().is_none() && !exit.is_backing_off(now))
+                .map(|exit| self.reconnect(exit)),
+        )
+        .await;
+    }
+}
+
+// Stream to a target through one of the pool's exits. Counts as an active session of
+// that exit until dropped.
+#[derive(Debug)]
+#[pin_project(PinnedDrop)]
+pub struct PooledStream {
+    #[pin]
+    stream: IrohStream,
+    exit: NodeId,
+    active_sessions: Arc<AtomicUsize>,
+}
+
+impl PooledStream {
+    fn new(stream: IrohStream, exit: NodeId, active_sessions: Arc<AtomicUsize>) -> Self {
+        active_sessions.fetch_add(1, Ordering::Relaxed);
+        Self {
+            stream,
+            exit,
+            active_sessions,
+        }
+    }
+
+    pub fn exit(&self) -> NodeId {
+        self.exit
+    }
+}
+
+#[pin_project::pinned_drop]
+impl PinnedDrop for PooledStream {
+    fn drop(self: Pin<&mut Self>) {
+        self.active_sessions.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl AsyncRead for PooledStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.project().stream.poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for PooledStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.project().stream.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().stream.poll_shutdown(cx)
+    }
+}
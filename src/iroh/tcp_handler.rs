@@ -1,42 +1,78 @@
 use crate::codec::{CodecError, TcpConnectRequestCodec, TcpConnectResponseCodec};
+use crate::iroh::audit::{AuditProtocol, AuditRecord, AuditSink, CloseReason};
+use crate::iroh::ban_list::{BanList, Violation};
 use crate::iroh::dns_resolver::DnsResolver;
-use crate::iroh::socket_factory::SocketFactory;
-use crate::iroh::types::ProxyTimeouts;
+use crate::iroh::node_authenticator::NodeProfile;
+use crate::iroh::proxy_protocol::encode_header;
+use crate::iroh::quota::{MeteredStream, QuotaManager};
+use crate::iroh::secure_channel::{self, SecureChannel};
+use crate::iroh::service_registry::{ServiceRegistry, ServiceTarget};
+use crate::iroh::socket_factory::{BoxedProxyStream, TransportFactory};
+use crate::iroh::types::{ReloadableSettings, S2pProtocol};
+use crate::iroh::ALPN_S2P_V1;
 use crate::iroh_stream::IrohStream;
-use crate::message_types::{ConnectStatusCode, Host, TcpConnectRequest, TcpConnectResponse};
-use iroh::endpoint::{RecvStream, SendStream};
+use crate::message_types::{
+    ConnectStatusCode, Host, TargetAddress, TcpConnectRequest, TcpConnectResponse,
+};
+use iroh::{Endpoint, NodeId};
 use n0_future::SinkExt;
+use std::future::Future;
 use std::io;
 use std::io::ErrorKind;
 use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
 use std::sync::Arc;
-use tokio::io::copy_bidirectional;
-use tokio::net::TcpStream;
+use std::time::{Instant, SystemTime};
+use tokio::io::{copy_bidirectional, AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::time::timeout;
 use tokio_stream::StreamExt;
 use tokio_util::codec::{FramedRead, FramedWrite};
-use tracing::{error, info};
+use tracing::{error, field, info, Span};
 
 pub struct TcpProxyHandlerHandler {
-    timeouts: ProxyTimeouts,
-    socket_factory: Arc<dyn SocketFactory>,
+    settings: Arc<ReloadableSettings>,
+    socket_factory: Arc<dyn TransportFactory>,
     dns_resolver: Arc<dyn DnsResolver>,
+    quota_manager: Option<Arc<QuotaManager>>,
+    audit_sink: Arc<dyn AuditSink>,
+    ban_list: Option<Arc<BanList>>,
+    endpoint: Option<Endpoint>,
+    service_registry: Option<Arc<ServiceRegistry>>,
+    remote_node_id: NodeId,
+    remote_addr: Option<SocketAddr>,
+    profile: Arc<NodeProfile>,
 }
 
 impl TcpProxyHandlerHandler {
-    pub fn with_timeouts_and_socket_factory(
-        timeouts: ProxyTimeouts,
-        socket_factory: Arc<dyn SocketFactory>,
-        dns_resolver: Arc<dyn DnsResolver>,
+    pub fn new(
+        protocol: &S2pProtocol,
+        remote_node_id: NodeId,
+        remote_addr: Option<SocketAddr>,
+        profile: Arc<NodeProfile>,
     ) -> Self {
         Self {
-            timeouts,
-            socket_factory,
-            dns_resolver,
+            settings: protocol.settings(),
+            socket_factory: protocol.socket_factory.clone(),
+            dns_resolver: protocol.dns_resolver.clone(),
+            quota_manager: protocol.quota_manager.clone(),
+            audit_sink: protocol.audit_sink.clone(),
+            ban_list: protocol.ban_list.clone(),
+            endpoint: protocol.endpoint.clone(),
+            service_registry: protocol.service_registry.clone(),
+            remote_node_id,
+            remote_addr,
+            profile,
         }
     }
 
-    pub async fn handle_stream(&self, writer: SendStream, reader: RecvStream) {
+    pub async fn handle_stream<R, W>(&self, writer: W, reader: R)
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let timestamp = SystemTime::now();
+        let started_at = Instant::now();
         let mut framed_writer = FramedWrite::new(writer, TcpConnectResponseCodec);
         let mut framed_reader = FramedRead::new(reader, TcpConnectRequestCodec);
 
@@ -58,47 +94,356 @@ impl TcpProxyHandlerHandler {
             }
         };
 
-        let mut target_stream = match self
-            .establish_connection_to_target(handshake_request.clone())
-            .await
-        {
-            Ok(stream) => stream,
-            Err(StreamError::IoError(error)) => {
-                error!("Stream IO error establishing connection: {:?}", error);
+        let target = handshake_request.target;
+        Span::current().record("target", field::display(&target));
+        let audit_record = |resolved_address: Option<SocketAddr>,
+                            status: ConnectStatusCode,
+                            close_reason: CloseReason| AuditRecord {
+            timestamp,
+            remote_node_id: self.remote_node_id,
+            profile_label: self.profile.label.clone(),
+            protocol: AuditProtocol::Tcp,
+            target: target.clone(),
+            resolved_address,
+            status,
+            duration: started_at.elapsed(),
+            bytes_to_target: 0,
+            bytes_from_target: 0,
+            close_reason,
+        };
+
+        if !self.profile.allows_target(&target) || !self.settings.target_policy.allows(&target) {
+            info!(
+                "Target {} is not allowed for node {}",
+                target, self.remote_node_id
+            );
+            self.record_violation(Violation::PolicyDenied);
+            let status = Self::send_failure(
+                &mut framed_writer,
+                StreamError::ProtocolError(ConnectStatusCode::ConnectionNotAllowed),
+            )
+            .await;
+            self.audit_sink
+                .record(audit_record(None, status, CloseReason::TargetNotAllowed))
+                .await;
+            return;
+        }
+
+        if let Some(quota_manager) = &self.quota_manager {
+            if quota_manager.is_exhausted(&self.remote_node_id) {
+                info!(
+                    "Data transfer quota exhausted for node: {}",
+                    self.remote_node_id
+                );
+                let status = Self::send_failure(
+                    &mut framed_writer,
+                    StreamError::ProtocolError(ConnectStatusCode::ConnectionNotAllowed),
+                )
+                .await;
+                self.audit_sink
+                    .record(audit_record(None, status, CloseReason::QuotaExhausted))
+                    .await;
                 return;
             }
-            Err(StreamError::ProtocolError(status_code)) => {
-                error!("Protocol error establishing connection: {:?}", status_code);
-                let response = TcpConnectResponse::new(status_code);
-                if let Err(e) = framed_writer.send(response).await {
-                    error!("Failed to send error response: {:?}", e);
+        }
+
+        if let Host::Node(node_id) = &target.host {
+            if let Some(endpoint) = self.endpoint.as_ref().filter(|e| e.node_id() == *node_id) {
+                // The client opens an end-to-end channel to this node and sends its next
+                // request through it.
+                let _ = framed_writer.send(TcpConnectResponse::success()).await;
+                match secure_channel::respond(
+                    framed_reader.into_inner(),
+                    framed_writer.into_inner(),
+                    endpoint.secret_key(),
+                )
+                .await
+                {
+                    Ok(channel) => self.handle_channel(channel).await,
+                    Err(e) => error!("Failed to establish secure channel: {}", e),
                 }
                 return;
             }
+        }
+
+        let (resolved_address, mut target_stream) = match self.open_target(&target).await {
+            Ok(opened) => opened,
+            Err((resolved_address, error)) => {
+                let status = Self::send_failure(&mut framed_writer, error).await;
+                let close_reason = match status {
+                    ConnectStatusCode::ConnectionNotAllowed => CloseReason::TargetNotAllowed,
+                    _ => CloseReason::ConnectFailed,
+                };
+                self.audit_sink
+                    .record(audit_record(resolved_address, status, close_reason))
+                    .await;
+                return;
+            }
         };
+
+        if let (Some(version), Some(destination)) = (
+            self.settings.proxy_protocol.version_for(&target),
+            resolved_address,
+        ) {
+            let header =
+                encode_header(version, self.remote_addr, destination, &self.remote_node_id);
+            if let Err(e) = target_stream.write_all(&header).await {
+                error!("Failed to send PROXY protocol header: {}", e);
+                let status = Self::send_failure(
+                    &mut framed_writer,
+                    StreamError::ProtocolError(ConnectStatusCode::GeneralFailure),
+                )
+                .await;
+                self.audit_sink
+                    .record(audit_record(
+                        resolved_address,
+                        status,
+                        CloseReason::IoError(e.to_string()),
+                    ))
+                    .await;
+                return;
+            }
+        }
         let _ = framed_writer.send(TcpConnectResponse::success()).await;
 
-        let mut iroh_stream =
-            IrohStream::new(framed_reader.into_inner(), framed_writer.into_inner());
+        let mut client_stream =
+            tokio::io::join(framed_reader.into_inner(), framed_writer.into_inner());
+        let mut target_stream = match &self.quota_manager {
+            Some(quota_manager) => {
+                MeteredStream::with_quota(target_stream, quota_manager.clone(), self.remote_node_id)
+            }
+            None => MeteredStream::new(target_stream),
+        };
+
         info!("Starting bi directional stream copy");
-        if let Err(error) = copy_bidirectional(&mut iroh_stream, &mut target_stream).await {
-            error!("Stream IO error during copy: {:?}", error);
+        let close_reason = match copy_bidirectional(&mut client_stream, &mut target_stream).await {
+            Ok(_) => CloseReason::Completed,
+            Err(error)
+                if error.kind() == ErrorKind::PermissionDenied
+                    && self.quota_manager.as_ref().is_some_and(|quota_manager| {
+                        quota_manager.is_exhausted(&self.remote_node_id)
+                    }) =>
+            {
+                info!(
+                    "Data transfer quota exhausted for node: {}",
+                    self.remote_node_id
+                );
+                CloseReason::QuotaExhausted
+            }
+            Err(error) => {
+                error!("Stream IO error during copy: {:?}", error);
+                CloseReason::IoError(error.to_string())
+            }
+        };
+
+        if let Some(quota_manager) = &self.quota_manager {
+            if let Err(error) = quota_manager.persist(&self.remote_node_id).await {
+                error!("Failed to persist data transfer usage: {:?}", error);
+            }
         }
+
+        self.audit_sink
+            .record(AuditRecord {
+                bytes_to_target: target_stream.bytes_written(),
+                bytes_from_target: target_stream.bytes_read(),
+                ..audit_record(resolved_address, ConnectStatusCode::Success, close_reason)
+            })
+            .await;
     }
 
-    async fn establish_connection_to_target(
+    fn handle_channel(
+        &self,
+        channel: SecureChannel,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(async move {
+            let (reader, writer) = tokio::io::split(channel);
+            self.handle_stream(writer, reader).await;
+        })
+    }
+
+    async fn open_target(
+        &self,
+        target: &TargetAddress,
+    ) -> Result<(Option<SocketAddr>, BoxedProxyStream), (Option<SocketAddr>, StreamError)> {
+        match &target.host {
+            Host::Node(node_id) => {
+                let stream = self
+                    .connect_to_next_hop(*node_id)
+                    .await
+                    .map_err(|error| (None, error))?;
+                Ok((None, Box::new(stream)))
+            }
+            Host::Service(name) => match self.resolve_service(name) {
+                Some(ServiceTarget::Address(address)) => self.open_address(target, &address).await,
+                Some(ServiceTarget::Unix(path)) => {
+                    let stream = self
+                        .connect_to_unix_socket(&path)
+                        .await
+                        .map_err(|error| (None, error))?;
+                    Ok((None, stream))
+                }
+                None => {
+                    info!("Unknown service {:?}", name);
+                    Err((
+                        None,
+                        StreamError::ProtocolError(ConnectStatusCode::HostUnreachable),
+                    ))
+                }
+            },
+            Host::UnixSocket(path) => {
+                if !self.settings.unix_sockets.allows(path) {
+                    info!("Unix socket {:?} is not allowed", path);
+                    return Err((
+                        None,
+                        StreamError::ProtocolError(ConnectStatusCode::ConnectionNotAllowed),
+                    ));
+                }
+                let stream = self
+                    .connect_to_unix_socket(path)
+                    .await
+                    .map_err(|error| (None, error))?;
+                Ok((None, stream))
+            }
+            _ => self.open_address(target, target).await,
+        }
+    }
+
+    fn resolve_service(&self, name: &str) -> Option<ServiceTarget> {
+        self.service_registry
+            .as_ref()
+            .and_then(|registry| registry.resolve(name))
+    }
+
+    // Connects to `target` on behalf of `requested`, which differs from it when a service
+    // name was requested. Allow rules match the requested target; deny rules still apply
+    // to the address it resolves to.
+    async fn open_address(
         &self,
-        handshake_request: TcpConnectRequest,
-    ) -> Result<TcpStream, StreamError> {
-        let target_address = handshake_request.target;
+        requested: &TargetAddress,
+        target: &TargetAddress,
+    ) -> Result<(Option<SocketAddr>, BoxedProxyStream), (Option<SocketAddr>, StreamError)> {
+        if self.socket_factory.resolves_names(target) {
+            // The transport resolves the name itself, e.g. at an upstream proxy, so the
+            // target policy only applies to the requested name.
+            let stream = self
+                .establish_connection_to_target(target, None)
+                .await
+                .map_err(|error| (None, error))?;
+            return Ok((None, stream));
+        }
 
-        let socket_addr = self
-            .resolve_address(&target_address.host, target_address.port)
-            .await?;
+        let resolved_address = self
+            .resolve_address(&target.host, target.port)
+            .await
+            .map_err(|error| (None, error))?;
+        if !self.profile.allows_resolved(requested, resolved_address)
+            || !self
+                .settings
+                .target_policy
+                .allows_resolved(requested, resolved_address)
+        {
+            info!(
+                "Target {} resolved to {}, which is not allowed for node {}",
+                requested, resolved_address, self.remote_node_id
+            );
+            self.record_violation(Violation::PolicyDenied);
+            return Err((
+                Some(resolved_address),
+                StreamError::ProtocolError(ConnectStatusCode::ConnectionNotAllowed),
+            ));
+        }
+        let stream = self
+            .establish_connection_to_target(target, Some(resolved_address))
+            .await
+            .map_err(|error| (Some(resolved_address), error))?;
+        Ok((Some(resolved_address), stream))
+    }
+
+    #[cfg(unix)]
+    async fn connect_to_unix_socket(&self, path: &Path) -> Result<BoxedProxyStream, StreamError> {
+        let stream = timeout(
+            self.settings.proxy_timeouts.tcp_connection_timeout,
+            tokio::net::UnixStream::connect(path),
+        )
+        .await
+        .map_err(|_| StreamError::ProtocolError(ConnectStatusCode::TTLExpired))?
+        .map_err(|e| {
+            error!("Failed to connect to unix socket {:?}: {}", path, e);
+            match e.kind() {
+                ErrorKind::ConnectionRefused => {
+                    StreamError::ProtocolError(ConnectStatusCode::ConnectionRefused)
+                }
+                _ => StreamError::ProtocolError(ConnectStatusCode::HostUnreachable),
+            }
+        })?;
+        Ok(Box::new(stream))
+    }
+
+    #[cfg(not(unix))]
+    async fn connect_to_unix_socket(&self, path: &Path) -> Result<BoxedProxyStream, StreamError> {
+        error!("Unix socket {:?} is not supported on this platform", path);
+        Err(StreamError::ProtocolError(
+            ConnectStatusCode::AddressTypeNotSupported,
+        ))
+    }
+
+    async fn connect_to_next_hop(&self, node_id: NodeId) -> Result<IrohStream, StreamError> {
+        let endpoint = match &self.endpoint {
+            Some(endpoint) if self.settings.allow_relay => endpoint,
+            _ => {
+                info!("Relaying to node {} is not enabled", node_id);
+                return Err(StreamError::ProtocolError(
+                    ConnectStatusCode::ConnectionNotAllowed,
+                ));
+            }
+        };
 
+        let connection = timeout(
+            self.settings.proxy_timeouts.tcp_connection_timeout,
+            endpoint.connect(node_id, ALPN_S2P_V1.as_bytes()),
+        )
+        .await
+        .map_err(|_| StreamError::ProtocolError(ConnectStatusCode::TTLExpired))?
+        .map_err(|e| {
+            error!("Failed to connect to next hop {}: {}", node_id, e);
+            StreamError::ProtocolError(ConnectStatusCode::HostUnreachable)
+        })?;
+        let (send, recv) = connection
+            .open_bi()
+            .await
+            .map_err(|e| StreamError::IoError(io::Error::other(e)))?;
+        Ok(IrohStream::new(recv, send))
+    }
+
+    async fn send_failure<W: AsyncWrite + Unpin>(
+        framed_writer: &mut FramedWrite<W, TcpConnectResponseCodec>,
+        error: StreamError,
+    ) -> ConnectStatusCode {
+        match error {
+            StreamError::IoError(error) => {
+                error!("Stream IO error establishing connection: {:?}", error);
+                ConnectStatusCode::GeneralFailure
+            }
+            StreamError::ProtocolError(status_code) => {
+                error!("Protocol error establishing connection: {:?}", status_code);
+                let response = TcpConnectResponse::new(status_code);
+                if let Err(e) = framed_writer.send(response).await {
+                    error!("Failed to send error response: {:?}", e);
+                }
+                status_code
+            }
+        }
+    }
+
+    async fn establish_connection_to_target(
+        &self,
+        target: &TargetAddress,
+        socket_addr: Option<SocketAddr>,
+    ) -> Result<BoxedProxyStream, StreamError> {
         let tcp_stream = timeout(
-            self.timeouts.tcp_connection_timeout,
-            self.socket_factory.create_tcp_connection(socket_addr),
+            self.settings.proxy_timeouts.tcp_connection_timeout,
+            self.socket_factory
+                .connect_target_for(self.remote_node_id, target, socket_addr),
         )
         .await
         .map_err(|_| StreamError::ProtocolError(ConnectStatusCode::TTLExpired))?
@@ -110,6 +455,10 @@ impl TcpProxyHandlerHandler {
             ErrorKind::NotFound | ErrorKind::AddrNotAvailable => {
                 StreamError::ProtocolError(ConnectStatusCode::HostUnreachable)
             }
+            ErrorKind::InvalidInput => {
+                info!("Refusing to connect to target: {}", e);
+                StreamError::ProtocolError(ConnectStatusCode::AddressTypeNotSupported)
+            }
             _ => {
                 error!("Unexpected error during connection establishment: {:?}", e);
                 StreamError::ProtocolError(ConnectStatusCode::GeneralFailure)
@@ -133,7 +482,7 @@ impl TcpProxyHandlerHandler {
                 info!("Resolving domain: {}:{}", domain, port);
                 let host_with_port = format!("{}:{}", domain, port);
                 match timeout(
-                    self.timeouts.dns_resolution_timeout,
+                    self.settings.proxy_timeouts.dns_resolution_timeout,
                     self.dns_resolver.lookup_host(&host_with_port),
                 )
                 .await
@@ -164,15 +513,24 @@ impl TcpProxyHandlerHandler {
                     }
                 }
             }
+            Host::Node(_) | Host::Service(_) | Host::UnixSocket(_) => Err(
+                StreamError::ProtocolError(ConnectStatusCode::AddressTypeNotSupported),
+            ),
+        }
+    }
+
+    fn record_violation(&self, violation: Violation) {
+        if let Some(ban_list) = &self.ban_list {
+            ban_list.record_violation(&self.remote_node_id, violation);
         }
     }
 
-    async fn read_handshake_request(
+    async fn read_handshake_request<R: AsyncRead + Unpin>(
         &self,
-        framed_reader: &mut FramedRead<RecvStream, TcpConnectRequestCodec>,
+        framed_reader: &mut FramedRead<R, TcpConnectRequestCodec>,
     ) -> Result<TcpConnectRequest, StreamError> {
         match timeout(
-            self.timeouts.tcp_proxy_handshake_timeout,
+            self.settings.proxy_timeouts.tcp_proxy_handshake_timeout,
             framed_reader.next(),
         )
         .await
@@ -187,6 +545,7 @@ impl TcpProxyHandlerHandler {
             }
             Ok(Some(Err(codec_error))) => {
                 error!("Codec error reading handshake: {:?}", codec_error);
+                self.record_violation(Violation::MalformedHandshake);
                 Err(StreamError::ProtocolError(ConnectStatusCode::from(
                     codec_error,
                 )))
@@ -200,6 +559,7 @@ impl TcpProxyHandlerHandler {
             }
             Err(_) => {
                 error!("Handshake request timed out");
+                self.record_violation(Violation::HandshakeTimeout);
                 Err(StreamError::IoError(io::Error::new(
                     ErrorKind::TimedOut,
                     "handshake request timed out",
@@ -221,6 +581,9 @@ impl From<CodecError> for ConnectStatusCode {
             CodecError::DomainTooLong(_) => ConnectStatusCode::HostUnreachable,
             CodecError::InvalidDomainEncoding => ConnectStatusCode::HostUnreachable,
             CodecError::InvalidAddressType(_) => ConnectStatusCode::AddressTypeNotSupported,
+            CodecError::InvalidExtendedAddressKind(_) => ConnectStatusCode::AddressTypeNotSupported,
+            CodecError::InvalidNodeId => ConnectStatusCode::HostUnreachable,
+            CodecError::InvalidPath => ConnectStatusCode::HostUnreachable,
             _ => ConnectStatusCode::GeneralFailure,
         }
     }
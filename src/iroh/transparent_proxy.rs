@@ -0,0 +1,184 @@
+use super::target_router::TargetConnector;
+use crate::message_types::TargetAddress;
+use socket2::{SockAddr, SockRef};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::copy_bidirectional;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tracing::{error, info, info_span, warn, Instrument};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransparentMode {
+    // Connections redirected with `-j REDIRECT`; the original destination is read with
+    // `SO_ORIGINAL_DST`.
+    Redirect,
+    // Connections diverted with `-j TPROXY`; the listener is bound with `IP_TRANSPARENT`
+    // and the original destination is the accepted socket's local address.
+    Tproxy,
+}
+
+// Client-side listener for connections redirected by iptables/nftables. Each connection
+// is forwarded to its original destination through the connector, e.g. a `TcpClient` or
+// a `TargetRouter`.
+pub struct TransparentProxy {
+    listener: TcpListener,
+    mode: TransparentMode,
+    connector: Arc<dyn TargetConnector>,
+}
+
+impl TransparentProxy {
+    pub async fn bind(
+        addr: SocketAddr,
+        mode: TransparentMode,
+        connector: Arc<dyn TargetConnector>,
+    ) -> io::Result<Self> {
+        let listener = match mode {
+            TransparentMode::Redirect => TcpListener::bind(addr).await?,
+            TransparentMode::Tproxy => {
+                let socket = if addr.is_ipv4() {
+                    TcpSocket::new_v4()?
+                } else {
+                    TcpSocket::new_v6()?
+                };
+                // Linux keeps a single transparent flag per socket, which IP_TRANSPARENT
+                // sets for IPv6 sockets as well.
+                SockRef::from(&socket).set_ip_transparent(true)?;
+                socket.set_reuseaddr(true)?;
+                socket.bind(addr)?;
+                socket.listen(1024)?
+            }
+        };
+
+        Ok(Self {
+            listener,
+            mode,
+            connector,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub async fn run(self) -> io::Result<()> {
+        let listen_addr = self.listener.local_addr()?;
+        info!(
+            "Transparent proxy listening on {} ({:?})",
+            listen_addr, self.mode
+        );
+
+        let mut backoff = MIN_ACCEPT_BACKOFF;
+        loop {
+            // Accept errors such as EMFILE or ECONNABORTED are usually transient, so keep
+            // serving after a pause instead of ending the proxy.
+            let (stream, peer_addr) = match self.listener.accept().await {
+                Ok(accepted) => {
+                    backoff = MIN_ACCEPT_BACKOFF;
+                    accepted
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to accept connection, retrying in {:?}: {}",
+                        backoff, e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                    continue;
+                }
+            };
+            let original_dst = match original_destination(&stream, self.mode) {
+                Ok(addr) if is_redirect_loop(addr, listen_addr, is_local_address) => {
+                    error!(
+                        "Connection from {} was not redirected, refusing to loop",
+                        peer_addr
+                    );
+                    continue;
+                }
+                Ok(addr) => addr,
+                Err(e) => {
+                    error!(
+                        "Failed to recover original destination for {}: {}",
+                        peer_addr, e
+                    );
+                    continue;
+                }
+            };
+
+            let connector = self.connector.clone();
+            let span = info_span!("s2p_transparent", peer = %peer_addr, target = %original_dst);
+            tokio::spawn(
+                async move {
+                    if let Err(e) = forward(stream, original_dst, connector).await {
+                        error!("Transparent proxy session failed: {}", e);
+                    }
+                }
+                .instrument(span),
+            );
+        }
+    }
+}
+
+async fn forward(
+    mut stream: TcpStream,
+    original_dst: SocketAddr,
+    connector: Arc<dyn TargetConnector>,
+) -> io::Result<()> {
+    let mut target_stream = connector
+        .connect(TargetAddress::from(original_dst))
+        .await
+        .map_err(io::Error::other)?;
+    copy_bidirectional(&mut stream, &mut target_stream).await?;
+    Ok(())
+}
+
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+// Whether a connection's original destination is the proxy itself, i.e. it reached the
+// listener without being redirected. A listener bound to 0.0.0.0 or [::] is reachable on
+// every local address, so the port is compared together with `is_local`.
+pub fn is_redirect_loop(
+    original_dst: SocketAddr,
+    listen_addr: SocketAddr,
+    is_local: impl Fn(IpAddr) -> bool,
+) -> bool {
+    if original_dst.port() != listen_addr.port() {
+        return false;
+    }
+    let original_ip = original_dst.ip().to_canonical();
+    let listen_ip = listen_addr.ip().to_canonical();
+    original_ip == listen_ip
+        || (listen_ip.is_unspecified() && (original_ip.is_loopback() || is_local(original_ip)))
+}
+
+// Whether `ip` is assigned to one of the host's interfaces, in which case a socket can be
+// bound to it.
+fn is_local_address(ip: IpAddr) -> bool {
+    std::net::UdpSocket::bind(SocketAddr::new(ip, 0)).is_ok()
+}
+
+fn original_destination(stream: &TcpStream, mode: TransparentMode) -> io::Result<SocketAddr> {
+    match mode {
+        TransparentMode::Tproxy => stream.local_addr(),
+        TransparentMode::Redirect => {
+            let socket = SockRef::from(stream);
+            let original_dst = if stream.local_addr()?.is_ipv4() {
+                socket.original_dst()?
+            } else {
+                socket.original_dst_ipv6()?
+            };
+            socket_addr(&original_dst)
+        }
+    }
+}
+
+fn socket_addr(addr: &SockAddr) -> io::Result<SocketAddr> {
+    addr.as_socket().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported address family {}", addr.family()),
+        )
+    })
+}
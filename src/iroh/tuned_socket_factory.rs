@@ -0,0 +1,311 @@
+use super::socket_factory::{
+    BoxedDatagramSocket, BoxedProxyStream, SocketFactory, TransportFactory,
+};
+use derive_builder::Builder;
+use socket2::{Domain, Protocol, SockAddr, Socket, TcpKeepalive, Type};
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+
+// Options applied to every outbound TCP connection and UDP relay socket. Unset options
+// keep the operating system defaults.
+#[derive(Debug, Clone, Default, Builder)]
+#[builder(setter(into, strip_option), default)]
+pub struct SocketOptions {
+    // Source addresses per family; a connection binds the one matching its destination.
+    pub source_ipv4: Option<Ipv4Addr>,
+    pub source_ipv6: Option<Ipv6Addr>,
+    // Linux only: `SO_BINDTODEVICE` and `SO_MARK`.
+    pub bind_device: Option<String>,
+    pub mark: Option<u32>,
+    pub nodelay: Option<bool>,
+    pub keepalive_time: Option<Duration>,
+    pub keepalive_interval: Option<Duration>,
+    pub keepalive_retries: Option<u32>,
+    pub send_buffer_size: Option<usize>,
+    pub recv_buffer_size: Option<usize>,
+    // IPv4 TTL or IPv6 unicast hop limit.
+    pub ttl: Option<u32>,
+    // DSCP code point (0-63), written to the upper six bits of the TOS / traffic class.
+    pub dscp: Option<u8>,
+    // Linux only: `IP_FREEBIND`, allowing source addresses that are not configured on an
+    // interface, e.g. from a routed IPv6 prefix.
+    pub freebind: Option<bool>,
+}
+
+impl SocketOptions {
+    pub fn builder() -> SocketOptionsBuilder {
+        SocketOptionsBuilder::default()
+    }
+
+    fn source_for(&self, destination: &SocketAddr) -> Option<IpAddr> {
+        match destination {
+            SocketAddr::V4(_) => self.source_ipv4.map(IpAddr::V4),
+            SocketAddr::V6(_) => self.source_ipv6.map(IpAddr::V6),
+        }
+    }
+
+    fn apply(&self, socket: &Socket, ipv4: bool, tcp: bool) -> io::Result<()> {
+        if let Some(device) = &self.bind_device {
+            bind_device(socket, device)?;
+        }
+        if let Some(mark) = self.mark {
+            set_mark(socket, mark)?;
+        }
+        if let Some(freebind) = self.freebind {
+            set_freebind(socket, freebind, ipv4)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(ttl) = self.ttl {
+            if ipv4 {
+                socket.set_ttl(ttl)?;
+            } else {
+                socket.set_unicast_hops_v6(ttl)?;
+            }
+        }
+        if let Some(dscp) = self.dscp {
+            if dscp > 63 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid DSCP value {}", dscp),
+                ));
+            }
+            set_dscp(socket, u32::from(dscp) << 2, ipv4)?;
+        }
+
+        if tcp {
+            if let Some(nodelay) = self.nodelay {
+                socket.set_nodelay(nodelay)?;
+            }
+            if let Some(keepalive) = self.keepalive()? {
+                socket.set_tcp_keepalive(&keepalive)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn keepalive(&self) -> io::Result<Option<TcpKeepalive>> {
+        if self.keepalive_time.is_none()
+            && self.keepalive_interval.is_none()
+            && self.keepalive_retries.is_none()
+        {
+            return Ok(None);
+        }
+
+        let mut keepalive = TcpKeepalive::new();
+        if let Some(time) = self.keepalive_time {
+            keepalive = keepalive.with_time(time);
+        }
+        keepalive =
+            with_keepalive_probes(keepalive, self.keepalive_interval, self.keepalive_retries)?;
+        Ok(Some(keepalive))
+    }
+
+    pub(crate) fn tcp_socket(
+        &self,
+        destination: &SocketAddr,
+        source: Option<IpAddr>,
+    ) -> io::Result<TcpSocket> {
+        let socket = Socket::new(
+            Domain::for_address(*destination),
+            Type::STREAM,
+            Some(Protocol::TCP),
+        )?;
+        self.apply(&socket, destination.is_ipv4(), true)?;
+        if let Some(source) = source.or_else(|| self.source_for(destination)) {
+            bind_source(&socket, SocketAddr::new(source, 0))?;
+        }
+        socket.set_nonblocking(true)?;
+        Ok(TcpSocket::from_std_stream(socket.into()))
+    }
+
+    pub(crate) fn udp_socket(
+        &self,
+        bind_addr: SocketAddr,
+        source: Option<IpAddr>,
+    ) -> io::Result<UdpSocket> {
+        let socket = Socket::new(
+            Domain::for_address(bind_addr),
+            Type::DGRAM,
+            Some(Protocol::UDP),
+        )?;
+        self.apply(&socket, bind_addr.is_ipv4(), false)?;
+        let bind_addr = match source.or_else(|| self.source_for(&bind_addr)) {
+            Some(source) if bind_addr.ip().is_unspecified() => {
+                SocketAddr::new(source, bind_addr.port())
+            }
+            _ => bind_addr,
+        };
+        bind_source(&socket, bind_addr)?;
+        socket.set_nonblocking(true)?;
+        UdpSocket::from_std(socket.into())
+    }
+}
+
+// Socket factory for multi-homed exits that applies `SocketOptions` to outbound sockets.
+#[derive(Debug, Clone, Default)]
+pub struct TunedSocketFactory {
+    options: SocketOptions,
+}
+
+impl TunedSocketFactory {
+    pub fn new(options: SocketOptions) -> Self {
+        Self { options }
+    }
+
+    pub fn arc(options: SocketOptions) -> Arc<dyn TransportFactory> {
+        Arc::new(Self::new(options))
+    }
+
+    pub fn options(&self) -> &SocketOptions {
+        &self.options
+    }
+
+    async fn connect(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        self.options.tcp_socket(&addr, None)?.connect(addr).await
+    }
+
+    fn bind(&self, bind_addr: &str) -> io::Result<UdpSocket> {
+        let bind_addr = bind_addr
+            .parse::<SocketAddr>()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        self.options.udp_socket(bind_addr, None)
+    }
+}
+
+impl SocketFactory for TunedSocketFactory {
+    fn create_tcp_connection(
+        &self,
+        addr: SocketAddr,
+    ) -> Pin<Box<dyn Future<Output = Result<TcpStream, io::Error>> + Send + '_>> {
+        Box::pin(self.connect(addr))
+    }
+
+    fn create_udp_socket(
+        &self,
+        bind_addr: &str,
+    ) -> Pin<Box<dyn Future<Output = Result<UdpSocket, io::Error>> + Send + '_>> {
+        let socket = self.bind(bind_addr);
+        Box::pin(async move { socket })
+    }
+}
+
+impl TransportFactory for TunedSocketFactory {
+    fn connect_stream(
+        &self,
+        addr: SocketAddr,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedProxyStream, io::Error>> + Send + '_>> {
+        Box::pin(async move {
+            let stream = self.connect(addr).await?;
+            Ok(Box::new(stream) as BoxedProxyStream)
+        })
+    }
+
+    fn bind_datagram(
+        &self,
+        bind_addr: &str,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedDatagramSocket, io::Error>> + Send + '_>> {
+        let socket = self.bind(bind_addr);
+        Box::pin(async move { Ok(Box::new(socket?) as BoxedDatagramSocket) })
+    }
+}
+
+fn bind_source(socket: &Socket, addr: SocketAddr) -> io::Result<()> {
+    socket.bind(&SockAddr::from(addr))
+}
+
+#[cfg(target_os = "linux")]
+fn bind_device(socket: &Socket, device: &str) -> io::Result<()> {
+    socket.bind_device(Some(device.as_bytes()))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_device(_socket: &Socket, _device: &str) -> io::Result<()> {
+    Err(unsupported("SO_BINDTODEVICE"))
+}
+
+#[cfg(target_os = "linux")]
+fn set_mark(socket: &Socket, mark: u32) -> io::Result<()> {
+    socket.set_mark(mark)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_mark(_socket: &Socket, _mark: u32) -> io::Result<()> {
+    Err(unsupported("SO_MARK"))
+}
+
+#[cfg(target_os = "linux")]
+fn set_freebind(socket: &Socket, freebind: bool, ipv4: bool) -> io::Result<()> {
+    if ipv4 {
+        socket.set_freebind(freebind)
+    } else {
+        socket.set_freebind_ipv6(freebind)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_freebind(_socket: &Socket, _freebind: bool, _ipv4: bool) -> io::Result<()> {
+    Err(unsupported("IP_FREEBIND"))
+}
+
+#[cfg(target_os = "linux")]
+fn set_dscp(socket: &Socket, tos: u32, ipv4: bool) -> io::Result<()> {
+    if ipv4 {
+        socket.set_tos(tos)
+    } else {
+        socket.set_tclass_v6(tos)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_dscp(socket: &Socket, tos: u32, ipv4: bool) -> io::Result<()> {
+    if ipv4 {
+        socket.set_tos(tos)
+    } else {
+        Err(unsupported("IPV6_TCLASS"))
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd"))]
+fn with_keepalive_probes(
+    mut keepalive: TcpKeepalive,
+    interval: Option<Duration>,
+    retries: Option<u32>,
+) -> io::Result<TcpKeepalive> {
+    if let Some(interval) = interval {
+        keepalive = keepalive.with_interval(interval);
+    }
+    if let Some(retries) = retries {
+        keepalive = keepalive.with_retries(retries);
+    }
+    Ok(keepalive)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "freebsd")))]
+fn with_keepalive_probes(
+    keepalive: TcpKeepalive,
+    interval: Option<Duration>,
+    retries: Option<u32>,
+) -> io::Result<TcpKeepalive> {
+    if interval.is_some() || retries.is_some() {
+        return Err(unsupported("TCP keepalive interval and retries"));
+    }
+    Ok(keepalive)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unsupported(option: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{} is not supported on this platform", option),
+    )
+}
@@ -1,25 +1,164 @@
+use super::audit::AuditSink;
+use super::ban_list::BanList;
+use super::capability::CapabilityVerifier;
 use super::dns_resolver::DnsResolver;
 use super::node_authenticator::NodeAuthenticator;
-use super::socket_factory::SocketFactory;
-use derive_builder::Builder;
-use std::sync::Arc;
+use super::proxy_protocol::ProxyProtocolPolicy;
+use super::quota::QuotaManager;
+use super::service_registry::ServiceRegistry;
+use super::socket_factory::{SocketFactory, SocketFactoryAdapter, TransportFactory};
+use super::unix_socket_policy::UnixSocketPolicy;
+use crate::message_types::TargetAddress;
+use crate::target_pattern::TargetPattern;
+use derive_builder::{Builder, UninitializedFieldError};
+use iroh::Endpoint;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum BuildError {
+    #[error("Missing required field `{0}`")]
+    MissingField(String),
+
+    #[error("Invalid value for `{field}`: {reason}")]
+    InvalidValue { field: String, reason: String },
+}
+
+impl BuildError {
+    pub(crate) fn invalid(field: impl Into<String>, reason: impl Into<String>) -> Self {
+        BuildError::InvalidValue {
+            field: field.into(),
+            reason: reason.into(),
+        }
+    }
+}
+
+impl From<UninitializedFieldError> for BuildError {
+    fn from(error: UninitializedFieldError) -> Self {
+        BuildError::MissingField(error.field_name().to_string())
+    }
+}
+
 #[derive(Debug, Clone, Builder)]
-#[builder(setter(into))]
+#[builder(
+    setter(into),
+    build_fn(validate = "Self::validate", error = "BuildError")
+)]
 pub struct S2pProtocol {
     #[builder(default)]
     pub proxy_timeouts: ProxyTimeouts,
-    #[builder(default = "super::socket_factory::DefaultSocketFactory::arc()")]
-    pub socket_factory: Arc<dyn SocketFactory>,
+    #[builder(default = "super::socket_factory::DefaultSocketFactory::transport()")]
+    pub socket_factory: Arc<dyn TransportFactory>,
     #[builder(default = "super::node_authenticator::AllowAllNodeAuthenticator::arc()")]
     pub node_authenticator: Arc<dyn NodeAuthenticator>,
     #[builder(default = "super::dns_resolver::DefaultDnsResolver::arc()")]
     pub dns_resolver: Arc<dyn DnsResolver>,
+    #[builder(default, setter(into, strip_option))]
+    pub quota_manager: Option<Arc<QuotaManager>>,
+    #[builder(default = "super::audit::NoopAuditSink::arc()")]
+    pub audit_sink: Arc<dyn AuditSink>,
+    // Nodes rejected by the authenticator may still be admitted by presenting a
+    // capability token signed by a trusted issuer.
+    #[builder(default, setter(into, strip_option))]
+    pub capability_verifier: Option<Arc<CapabilityVerifier>>,
+    // Banned nodes are declined before the authenticator or a capability token is
+    // consulted, so neither can admit them while the ban lasts. Their live connections
+    // are closed when the ban starts.
+    #[builder(default, setter(into, strip_option))]
+    pub ban_list: Option<Arc<BanList>>,
+    // Lets authenticators see the remote address or relay path of a connection, and
+    // terminates end-to-end channels of multi-hop paths ending at this node.
+    #[builder(default, setter(into, strip_option))]
+    pub endpoint: Option<Endpoint>,
+    // Forward streams addressed to other s2p nodes, acting as an intermediate hop.
+    // Requires `endpoint`.
+    #[builder(default)]
+    pub allow_relay: bool,
+    // PROXY protocol headers sent to targets, so backends see the client's address and
+    // node id instead of this node's.
+    #[builder(default)]
+    pub proxy_protocol: ProxyProtocolPolicy,
+    #[builder(default, setter(into, strip_option))]
+    pub service_registry: Option<Arc<ServiceRegistry>>,
+    #[builder(default)]
+    pub unix_sockets: UnixSocketPolicy,
+    #[builder(default)]
+    pub udp: UdpSettings,
+    // Applies to every node, on top of the allowed targets of its profile.
+    #[builder(default)]
+    pub target_policy: TargetPolicy,
+    // Live values of the reloadable fields above, replaced by `apply_settings`. Shared by
+    // all clones, so it also reaches the copy held by a running router.
+    #[builder(default, setter(skip))]
+    reloaded: Arc<RwLock<Option<Arc<ReloadableSettings>>>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct UdpSettings {
+    // A flow is closed when its target sends nothing back for this long.
+    pub flow_idle_timeout: Duration,
+    pub dns_resolution_timeout: Duration,
+    // Maximum number of concurrent flows per connection.
+    pub max_flows: Option<usize>,
+}
+
+// Targets reachable through this node, whoever the client is. Deny patterns take
+// precedence; without allow patterns every other target is allowed.
+#[derive(Debug, Clone, Default)]
+pub struct TargetPolicy {
+    pub allow: Option<Vec<TargetPattern>>,
+    pub deny: Vec<TargetPattern>,
+}
+
+impl TargetPolicy {
+    pub fn allows(&self, target: &TargetAddress) -> bool {
+        if self.deny.iter().any(|pattern| pattern.matches(target)) {
+            return false;
+        }
+        match &self.allow {
+            Some(patterns) => patterns.iter().any(|pattern| pattern.matches(target)),
+            None => true,
+        }
+    }
+
+    // Checks the address a target resolved to before anything is sent to it, so a domain
+    // resolving into a denied range is refused. Allow patterns accept either the
+    // requested target or the address.
+    pub fn allows_resolved(&self, target: &TargetAddress, resolved: SocketAddr) -> bool {
+        let resolved = resolved_target(resolved);
+        if self.deny.iter().any(|pattern| pattern.matches(&resolved)) {
+            return false;
+        }
+        self.allows(target) || self.allows(&resolved)
+    }
+}
+
+// IPv4-mapped IPv6 addresses are matched as the IPv4 address they carry.
+pub(crate) fn resolved_target(resolved: SocketAddr) -> TargetAddress {
+    TargetAddress::from(SocketAddr::new(
+        resolved.ip().to_canonical(),
+        resolved.port(),
+    ))
+}
+
+// Settings that can be changed on a running protocol. Streams read them when they start
+// and UDP flows for every datagram; established connections to targets are unaffected.
+#[derive(Debug, Clone)]
+pub struct ReloadableSettings {
+    pub proxy_timeouts: ProxyTimeouts,
+    pub udp: UdpSettings,
+    pub target_policy: TargetPolicy,
+    pub allow_relay: bool,
+    pub unix_sockets: UnixSocketPolicy,
+    pub proxy_protocol: ProxyProtocolPolicy,
 }
 
 #[derive(Debug, Clone, Builder)]
-#[builder(setter(into))]
+#[builder(
+    setter(into),
+    build_fn(validate = "Self::validate", error = "BuildError")
+)]
 pub struct ProxyTimeouts {
     #[builder(default = "Duration::from_secs(10)")]
     pub tcp_connection_timeout: Duration,
@@ -31,53 +170,187 @@ pub struct ProxyTimeouts {
 
 impl S2pProtocol {
     pub fn new() -> Self {
-        Self::builder().build().unwrap()
+        Self::default()
     }
 
     pub fn builder() -> S2pProtocolBuilder {
         S2pProtocolBuilder::default()
     }
 
-    pub fn with_timeouts(proxy_timeouts: ProxyTimeouts) -> Self {
-        Self::builder()
-            .proxy_timeouts(proxy_timeouts)
-            .build()
+    // Current reloadable settings: the last ones applied, or otherwise those the protocol
+    // was built with, captured on first use.
+    pub fn settings(&self) -> Arc<ReloadableSettings> {
+        if let Some(settings) = self.reloaded.read().unwrap().as_ref() {
+            return settings.clone();
+        }
+        self.reloaded
+            .write()
             .unwrap()
+            .get_or_insert_with(|| {
+                Arc::new(ReloadableSettings {
+                    proxy_timeouts: self.proxy_timeouts.clone(),
+                    udp: self.udp.clone(),
+                    target_policy: self.target_policy.clone(),
+                    allow_relay: self.allow_relay,
+                    unix_sockets: self.unix_sockets.clone(),
+                    proxy_protocol: self.proxy_protocol.clone(),
+                })
+            })
+            .clone()
+    }
+
+    // Replaces the live settings after checking them with the builder's rules. Nothing
+    // is changed if they are invalid.
+    pub fn apply_settings(&self, settings: ReloadableSettings) -> Result<(), BuildError> {
+        settings.proxy_timeouts.validate()?;
+        settings.udp.validate()?;
+        if settings.allow_relay && self.endpoint.is_none() {
+            return Err(BuildError::invalid(
+                "allow_relay",
+                "relaying requires an endpoint",
+            ));
+        }
+        *self.reloaded.write().unwrap() = Some(Arc::new(settings));
+        Ok(())
+    }
+
+    pub fn with_timeouts(proxy_timeouts: ProxyTimeouts) -> Result<Self, BuildError> {
+        Self::builder().proxy_timeouts(proxy_timeouts).build()
     }
 
     pub fn with_socket_factory(
         proxy_timeouts: ProxyTimeouts,
         socket_factory: Arc<dyn SocketFactory>,
-    ) -> Self {
+    ) -> Result<Self, BuildError> {
         Self::builder()
             .proxy_timeouts(proxy_timeouts)
-            .socket_factory(socket_factory)
+            .legacy_socket_factory(socket_factory)
             .build()
-            .unwrap()
     }
 
     pub fn with_node_authenticator(
         proxy_timeouts: ProxyTimeouts,
         node_authenticator: Arc<dyn NodeAuthenticator>,
-    ) -> Self {
+    ) -> Result<Self, BuildError> {
         Self::builder()
             .proxy_timeouts(proxy_timeouts)
             .node_authenticator(node_authenticator)
             .build()
-            .unwrap()
     }
 
     pub fn with_socket_factory_and_node_authenticator(
         proxy_timeouts: ProxyTimeouts,
         socket_factory: Arc<dyn SocketFactory>,
         node_authenticator: Arc<dyn NodeAuthenticator>,
-    ) -> Self {
+    ) -> Result<Self, BuildError> {
         Self::builder()
             .proxy_timeouts(proxy_timeouts)
-            .socket_factory(socket_factory)
+            .legacy_socket_factory(socket_factory)
             .node_authenticator(node_authenticator)
             .build()
-            .unwrap()
+    }
+}
+
+impl S2pProtocolBuilder {
+    // Sets the socket factory from a `SocketFactory`, the hook used before
+    // `TransportFactory` existed.
+    pub fn legacy_socket_factory(&mut self, socket_factory: Arc<dyn SocketFactory>) -> &mut Self {
+        self.socket_factory(SocketFactoryAdapter::arc(socket_factory))
+    }
+
+    fn validate(&self) -> Result<(), BuildError> {
+        if let Some(proxy_timeouts) = &self.proxy_timeouts {
+            proxy_timeouts.validate()?;
+        }
+        if let Some(udp) = &self.udp {
+            udp.validate()?;
+        }
+        if self.allow_relay == Some(true) && !matches!(self.endpoint, Some(Some(_))) {
+            return Err(BuildError::invalid(
+                "allow_relay",
+                "relaying requires an endpoint",
+            ));
+        }
+        if let Some(Some(quota_manager)) = &self.quota_manager {
+            quota_manager.default_limits().validate()?;
+        }
+        Ok(())
+    }
+}
+
+impl ProxyTimeouts {
+    pub fn builder() -> ProxyTimeoutsBuilder {
+        ProxyTimeoutsBuilder::default()
+    }
+
+    pub fn validate(&self) -> Result<(), BuildError> {
+        non_zero("tcp_connection_timeout", self.tcp_connection_timeout)?;
+        non_zero("dns_resolution_timeout", self.dns_resolution_timeout)?;
+        non_zero(
+            "tcp_proxy_handshake_timeout",
+            self.tcp_proxy_handshake_timeout,
+        )
+    }
+}
+
+impl ProxyTimeoutsBuilder {
+    fn validate(&self) -> Result<(), BuildError> {
+        for (field, value) in [
+            ("tcp_connection_timeout", self.tcp_connection_timeout),
+            ("dns_resolution_timeout", self.dns_resolution_timeout),
+            (
+                "tcp_proxy_handshake_timeout",
+                self.tcp_proxy_handshake_timeout,
+            ),
+        ] {
+            if let Some(value) = value {
+                non_zero(field, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl UdpSettings {
+    pub fn validate(&self) -> Result<(), BuildError> {
+        non_zero("udp.flow_idle_timeout", self.flow_idle_timeout)?;
+        non_zero("udp.dns_resolution_timeout", self.dns_resolution_timeout)?;
+        // Flow ids are a single byte.
+        if self
+            .max_flows
+            .is_some_and(|max_flows| !(1..=256).contains(&max_flows))
+        {
+            return Err(BuildError::invalid(
+                "udp.max_flows",
+                "must be between 1 and 256",
+            ));
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn non_zero(field: &str, value: Duration) -> Result<(), BuildError> {
+    if value.is_zero() {
+        return Err(BuildError::invalid(field, "must be greater than zero"));
+    }
+    Ok(())
+}
+
+impl Default for S2pProtocol {
+    fn default() -> Self {
+        S2pProtocolBuilder::default()
+            .build()
+            .expect("every S2pProtocol field has a valid default")
+    }
+}
+
+impl Default for UdpSettings {
+    fn default() -> Self {
+        Self {
+            flow_idle_timeout: Duration::from_secs(60),
+            dns_resolution_timeout: Duration::from_secs(5),
+            max_flows: None,
+        }
     }
 }
 
@@ -1,42 +1,115 @@
 use crate::codec::{CodecError, UdpDatagramCodec};
+use crate::iroh::audit::{AuditProtocol, AuditRecord, AuditSink, CloseReason};
+use crate::iroh::ban_list::{BanList, Violation};
 use crate::iroh::dns_resolver::DnsResolver;
-use crate::iroh::socket_factory::SocketFactory;
-use crate::message_types::{ConnectStatusCode, Host, UdpDatagram};
+use crate::iroh::node_authenticator::NodeProfile;
+use crate::iroh::quota::QuotaManager;
+use crate::iroh::service_registry::{ServiceRegistry, ServiceTarget};
+use crate::iroh::socket_factory::{BoxedDatagramSocket, DatagramSocket, TransportFactory};
+use crate::iroh::types::S2pProtocol;
+use crate::message_types::{ConnectStatusCode, Host, TargetAddress, UdpDatagram};
 use bytes::{Bytes, BytesMut};
 use iroh::endpoint::Connection;
-use std::collections::HashMap;
+use iroh::NodeId;
+use std::collections::{HashMap, HashSet};
 use std::io;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::net::UdpSocket;
+use std::time::{Instant, SystemTime};
 use tokio::sync::Mutex;
 use tokio::time::timeout;
 use tokio_util::codec::{Decoder, Encoder};
-use tracing::{error, info};
+use tracing::{error, info, info_span, trace, Instrument, Span};
 
+// Dropped datagrams are audited once per flow and target, so a client sending to a
+// denied target cannot flood the audit sink. The set of audited rejections is cleared
+// when it reaches this size.
+const MAX_AUDITED_REJECTIONS: usize = 1024;
+
+#[derive(Clone)]
 pub struct UdpProxyHandlerHandler {
-    flows: Arc<Mutex<HashMap<u8, Arc<UdpSocket>>>>,
-    socket_factory: Arc<dyn SocketFactory>,
+    flows: Arc<Mutex<HashMap<u8, Arc<UdpFlow>>>>,
+    rejections: Arc<std::sync::Mutex<HashSet<(u8, TargetAddress)>>>,
+    socket_factory: Arc<dyn TransportFactory>,
     dns_resolver: Arc<dyn DnsResolver>,
+    quota_manager: Option<Arc<QuotaManager>>,
+    audit_sink: Arc<dyn AuditSink>,
+    ban_list: Option<Arc<BanList>>,
+    service_registry: Option<Arc<ServiceRegistry>>,
+    protocol: S2pProtocol,
+    remote_node_id: NodeId,
+    profile: Arc<NodeProfile>,
 }
 
-impl UdpProxyHandlerHandler {
-    pub fn with_socket_factory(
-        socket_factory: Arc<dyn SocketFactory>,
-        dns_resolver: Arc<dyn DnsResolver>,
+struct UdpFlow {
+    socket: Arc<dyn DatagramSocket>,
+    ipv4: bool,
+    destination: std::sync::Mutex<FlowDestination>,
+    span: Span,
+}
+
+impl UdpFlow {
+    fn new(
+        flow_id: u8,
+        socket: BoxedDatagramSocket,
+        target: TargetAddress,
+        resolved_address: SocketAddr,
     ) -> Self {
+        Self {
+            span: info_span!("s2p_udp_flow", flow_id, target = %target),
+            socket: Arc::from(socket),
+            ipv4: resolved_address.is_ipv4(),
+            destination: std::sync::Mutex::new(FlowDestination::new(target, resolved_address)),
+        }
+    }
+}
+
+// The destination a flow currently sends to; each destination is audited separately.
+struct FlowDestination {
+    target: TargetAddress,
+    resolved_address: SocketAddr,
+    status: ConnectStatusCode,
+    timestamp: SystemTime,
+    started_at: Instant,
+    bytes_to_target: u64,
+    bytes_from_target: u64,
+}
+
+impl FlowDestination {
+    fn new(target: TargetAddress, resolved_address: SocketAddr) -> Self {
+        Self {
+            target,
+            resolved_address,
+            status: ConnectStatusCode::Success,
+            timestamp: SystemTime::now(),
+            started_at: Instant::now(),
+            bytes_to_target: 0,
+            bytes_from_target: 0,
+        }
+    }
+}
+
+impl UdpProxyHandlerHandler {
+    pub fn new(protocol: &S2pProtocol, remote_node_id: NodeId, profile: Arc<NodeProfile>) -> Self {
         Self {
             flows: Arc::new(Mutex::new(HashMap::new())),
-            socket_factory,
-            dns_resolver,
+            rejections: Arc::new(std::sync::Mutex::new(HashSet::new())),
+            socket_factory: protocol.socket_factory.clone(),
+            dns_resolver: protocol.dns_resolver.clone(),
+            quota_manager: protocol.quota_manager.clone(),
+            audit_sink: protocol.audit_sink.clone(),
+            ban_list: protocol.ban_list.clone(),
+            service_registry: protocol.service_registry.clone(),
+            protocol: protocol.clone(),
+            remote_node_id,
+            profile,
         }
     }
 
     pub async fn handle_datagram(&self, connection: &Connection, datagram: Bytes) {
         match self.process_datagram(connection, datagram).await {
-            Ok(_) => info!("Successfully processed UDP datagram"),
-            Err(e) => error!("Failed to process UDP datagram: {:?}", e),
+            Ok(_) => trace!("Successfully processed UDP datagram"),
+            Err(e) => error!("Failed to process UDP datagram: {}", e),
         }
     }
 
@@ -45,68 +118,273 @@ impl UdpProxyHandlerHandler {
         connection: &Connection,
         datagram: Bytes,
     ) -> Result<(), UdpError> {
-        let udp_datagram = self.parse_udp_datagram(datagram)?;
+        let udp_datagram = match self.parse_udp_datagram(datagram) {
+            Ok(udp_datagram) => udp_datagram,
+            Err(e) => {
+                self.record_violation(Violation::MalformedDatagram);
+                return Err(e);
+            }
+        };
         let flow_id = udp_datagram.flow_id;
+        let settings = self.protocol.settings();
 
-        let target_clone = udp_datagram.target.clone();
-        let socket = {
-            let mut flows = self.flows.lock().await;
-            if let Some(existing_socket) = flows.get(&flow_id) {
-                existing_socket.clone()
-            } else {
-                let new_socket = Arc::new(
-                    self.socket_factory
-                        .create_udp_socket("0.0.0.0:0")
-                        .await
-                        .map_err(UdpError::IoError)?,
-                );
-
-                let socket_clone = new_socket.clone();
-                let connection_clone = connection.clone();
-                let flows_clone = self.flows.clone();
-
-                tokio::spawn(async move {
-                    Self::listen_for_responses(
-                        flow_id,
-                        socket_clone,
-                        connection_clone,
-                        flows_clone,
-                        target_clone,
-                    )
+        if !self.profile.allows_target(&udp_datagram.target)
+            || !settings.target_policy.allows(&udp_datagram.target)
+        {
+            self.record_violation(Violation::PolicyDenied);
+            self.reject(
+                &udp_datagram,
+                None,
+                ConnectStatusCode::ConnectionNotAllowed,
+                CloseReason::TargetNotAllowed,
+            )
+            .await;
+            return Err(UdpError::Protocol(ConnectStatusCode::ConnectionNotAllowed));
+        }
+
+        if let Some(quota_manager) = &self.quota_manager {
+            if quota_manager.is_exhausted(&self.remote_node_id) {
+                self.reject(
+                    &udp_datagram,
+                    None,
+                    ConnectStatusCode::ConnectionNotAllowed,
+                    CloseReason::QuotaExhausted,
+                )
+                .await;
+                return Err(UdpError::Protocol(ConnectStatusCode::ConnectionNotAllowed));
+            }
+        }
+
+        let socket_addr = match self.resolve_target(&udp_datagram.target).await {
+            Ok(socket_addr) => socket_addr,
+            Err(e) => {
+                let status = e.status();
+                self.reject(&udp_datagram, None, status, CloseReason::ConnectFailed)
                     .await;
-                });
+                return Err(e);
+            }
+        };
+        if !self
+            .profile
+            .allows_resolved(&udp_datagram.target, socket_addr)
+            || !settings
+                .target_policy
+                .allows_resolved(&udp_datagram.target, socket_addr)
+        {
+            self.record_violation(Violation::PolicyDenied);
+            self.reject(
+                &udp_datagram,
+                Some(socket_addr),
+                ConnectStatusCode::ConnectionNotAllowed,
+                CloseReason::TargetNotAllowed,
+            )
+            .await;
+            return Err(UdpError::Protocol(ConnectStatusCode::ConnectionNotAllowed));
+        }
 
-                flows.insert(flow_id, new_socket.clone());
-                new_socket
+        let flow = match self
+            .flow_for(connection, flow_id, &udp_datagram.target, socket_addr)
+            .await
+        {
+            Ok(flow) => flow,
+            Err(e) => {
+                let status = e.status();
+                self.reject(
+                    &udp_datagram,
+                    Some(socket_addr),
+                    status,
+                    CloseReason::ConnectFailed,
+                )
+                .await;
+                return Err(e);
             }
         };
 
-        let target_address = &udp_datagram.target;
-        let socket_addr = self
-            .resolve_address(&target_address.host, target_address.port)
-            .await?;
+        let flow_span = flow.span.clone();
+        self.forward_to_target(&flow, udp_datagram, socket_addr)
+            .instrument(flow_span)
+            .await
+    }
 
-        socket
-            .send_to(&udp_datagram.data, socket_addr)
+    async fn flow_for(
+        &self,
+        connection: &Connection,
+        flow_id: u8,
+        target: &TargetAddress,
+        socket_addr: SocketAddr,
+    ) -> Result<Arc<UdpFlow>, UdpError> {
+        let max_flows = self.protocol.settings().udp.max_flows;
+        let mut flows = self.flows.lock().await;
+        if let Some(existing_flow) = flows.get(&flow_id) {
+            // A flow's socket is bound for the address family of its first target.
+            if existing_flow.ipv4 != socket_addr.is_ipv4() {
+                return Err(UdpError::Protocol(
+                    ConnectStatusCode::AddressTypeNotSupported,
+                ));
+            }
+            return Ok(existing_flow.clone());
+        }
+        if max_flows.is_some_and(|max_flows| flows.len() >= max_flows) {
+            return Err(UdpError::Protocol(ConnectStatusCode::ConnectionNotAllowed));
+        }
+        let bind_addr = if socket_addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let new_socket = self
+            .socket_factory
+            .bind_datagram_for(self.remote_node_id, bind_addr)
             .await
-            .map_err(UdpError::IoError)?;
+            .map_err(UdpError::Io)?;
+        let new_flow = Arc::new(UdpFlow::new(
+            flow_id,
+            new_socket,
+            target.clone(),
+            socket_addr,
+        ));
+        info!(parent: &new_flow.span, "Opened UDP flow");
+
+        let handler_clone = self.clone();
+        let flow_clone = new_flow.clone();
+        let connection_clone = connection.clone();
+        let flow_span = new_flow.span.clone();
+
+        tokio::spawn(
+            async move {
+                handler_clone
+                    .listen_for_responses(flow_id, flow_clone, connection_clone)
+                    .await;
+            }
+            .instrument(flow_span),
+        );
 
-        info!(
+        flows.insert(flow_id, new_flow.clone());
+        Ok(new_flow)
+    }
+
+    async fn forward_to_target(
+        &self,
+        flow: &UdpFlow,
+        udp_datagram: UdpDatagram,
+        socket_addr: SocketAddr,
+    ) -> Result<(), UdpError> {
+        let previous = {
+            let mut destination = flow.destination.lock().unwrap();
+            if destination.target == udp_datagram.target
+                && destination.resolved_address == socket_addr
+            {
+                None
+            } else {
+                let next = FlowDestination::new(udp_datagram.target.clone(), socket_addr);
+                Some(std::mem::replace(&mut *destination, next))
+            }
+        };
+        if let Some(previous) = previous {
+            info!(destination = %udp_datagram.target, "UDP flow changed destination");
+            self.audit_sink
+                .record(self.destination_record(
+                    udp_datagram.flow_id,
+                    &previous,
+                    CloseReason::TargetChanged,
+                ))
+                .await;
+        }
+
+        if let Err(e) = flow.socket.send_to(&udp_datagram.data, socket_addr).await {
+            flow.destination.lock().unwrap().status = ConnectStatusCode::GeneralFailure;
+            return Err(UdpError::Io(e));
+        }
+
+        let sent = udp_datagram.data.len() as u64;
+        flow.destination.lock().unwrap().bytes_to_target += sent;
+        if let Some(quota_manager) = &self.quota_manager {
+            quota_manager.charge(&self.remote_node_id, sent);
+        }
+
+        trace!(
             "Sent {} bytes to target for flow_id {}",
             udp_datagram.data.len(),
-            flow_id
+            udp_datagram.flow_id
         );
         Ok(())
     }
 
+    fn destination_record(
+        &self,
+        flow_id: u8,
+        destination: &FlowDestination,
+        close_reason: CloseReason,
+    ) -> AuditRecord {
+        AuditRecord {
+            timestamp: destination.timestamp,
+            remote_node_id: self.remote_node_id,
+            profile_label: self.profile.label.clone(),
+            protocol: AuditProtocol::Udp { flow_id },
+            target: destination.target.clone(),
+            resolved_address: Some(destination.resolved_address),
+            status: destination.status,
+            duration: destination.started_at.elapsed(),
+            bytes_to_target: destination.bytes_to_target,
+            bytes_from_target: destination.bytes_from_target,
+            close_reason,
+        }
+    }
+
+    // Audits a datagram that was dropped before reaching its target, unless one for the
+    // same flow and target already was.
+    async fn reject(
+        &self,
+        udp_datagram: &UdpDatagram,
+        resolved_address: Option<SocketAddr>,
+        status: ConnectStatusCode,
+        close_reason: CloseReason,
+    ) {
+        {
+            let mut rejections = self.rejections.lock().unwrap();
+            let rejection = (udp_datagram.flow_id, udp_datagram.target.clone());
+            if rejections.contains(&rejection) {
+                return;
+            }
+            if rejections.len() >= MAX_AUDITED_REJECTIONS {
+                rejections.clear();
+            }
+            rejections.insert(rejection);
+        }
+
+        self.audit_sink
+            .record(AuditRecord {
+                timestamp: SystemTime::now(),
+                remote_node_id: self.remote_node_id,
+                profile_label: self.profile.label.clone(),
+                protocol: AuditProtocol::Udp {
+                    flow_id: udp_datagram.flow_id,
+                },
+                target: udp_datagram.target.clone(),
+                resolved_address,
+                status,
+                duration: Default::default(),
+                bytes_to_target: 0,
+                bytes_from_target: 0,
+                close_reason,
+            })
+            .await;
+    }
+
+    fn record_violation(&self, violation: Violation) {
+        if let Some(ban_list) = &self.ban_list {
+            ban_list.record_violation(&self.remote_node_id, violation);
+        }
+    }
+
     fn parse_udp_datagram(&self, datagram: Bytes) -> Result<UdpDatagram, UdpError> {
         let mut codec = UdpDatagramCodec;
         let mut buf = BytesMut::from(datagram.as_ref());
 
         match codec.decode(&mut buf) {
             Ok(Some(udp_datagram)) => Ok(udp_datagram),
-            Ok(None) => Err(UdpError::ProtocolError(ConnectStatusCode::GeneralFailure)),
-            Err(e) => Err(UdpError::CodecError(e)),
+            Ok(None) => Err(UdpError::Protocol(ConnectStatusCode::GeneralFailure)),
+            Err(e) => Err(UdpError::Codec(e)),
         }
     }
 
@@ -114,91 +392,122 @@ impl UdpProxyHandlerHandler {
         let mut codec = UdpDatagramCodec;
         let mut buf = BytesMut::new();
 
-        codec
-            .encode(datagram, &mut buf)
-            .map_err(UdpError::CodecError)?;
+        codec.encode(datagram, &mut buf).map_err(UdpError::Codec)?;
 
         Ok(buf.freeze())
     }
 
-    async fn listen_for_responses(
-        flow_id: u8,
-        socket: Arc<UdpSocket>,
-        connection: Connection,
-        flows: Arc<Mutex<HashMap<u8, Arc<UdpSocket>>>>,
-        target: crate::message_types::TargetAddress,
-    ) {
+    async fn listen_for_responses(&self, flow_id: u8, flow: Arc<UdpFlow>, connection: Connection) {
         let mut buffer = [0u8; 65536];
+        let idle_timeout = self.protocol.settings().udp.flow_idle_timeout;
 
-        loop {
-            match timeout(Duration::from_secs(60), socket.recv_from(&mut buffer)).await {
+        let close_reason = loop {
+            match timeout(idle_timeout, flow.socket.recv_from(&mut buffer)).await {
                 Ok(Ok((len, _from_addr))) => {
+                    let target = {
+                        let mut destination = flow.destination.lock().unwrap();
+                        destination.bytes_from_target += len as u64;
+                        destination.target.clone()
+                    };
+                    if let Some(quota_manager) = &self.quota_manager {
+                        if !quota_manager.charge(&self.remote_node_id, len as u64) {
+                            info!(
+                                "Data transfer quota exhausted for node {}, closing flow_id {}",
+                                self.remote_node_id, flow_id
+                            );
+                            break CloseReason::QuotaExhausted;
+                        }
+                    }
+
                     let response_data = buffer[..len].to_vec();
                     let response_datagram = UdpDatagram {
                         flow_id,
-                        target: target.clone(),
+                        target,
                         data: response_data,
                     };
 
-                    if let Ok(encoded_response) =
-                        Self::encode_udp_datagram_static(response_datagram)
-                    {
-                        if let Err(e) = connection.send_datagram(encoded_response) {
-                            error!(
-                                "Failed to send UDP response for flow_id {}: {:?}",
-                                flow_id, e
-                            );
-                            break;
-                        } else {
-                            info!("Sent {} bytes back to client for flow_id {}", len, flow_id);
+                    match self.encode_udp_datagram(response_datagram) {
+                        Ok(encoded_response) => {
+                            if let Err(e) = connection.send_datagram(encoded_response) {
+                                error!(
+                                    "Failed to send UDP response for flow_id {}: {:?}",
+                                    flow_id, e
+                                );
+                                break CloseReason::IoError(e.to_string());
+                            } else {
+                                trace!("Sent {} bytes back to client for flow_id {}", len, flow_id);
+                            }
+                        }
+                        Err(e) => {
+                            error!("Failed to encode UDP response for flow_id {}", flow_id);
+                            break CloseReason::IoError(format!("{:?}", e));
                         }
-                    } else {
-                        error!("Failed to encode UDP response for flow_id {}", flow_id);
-                        break;
                     }
                 }
                 Ok(Err(e)) => {
                     error!("Socket error for flow_id {}: {:?}", flow_id, e);
-                    break;
+                    flow.destination.lock().unwrap().status = ConnectStatusCode::GeneralFailure;
+                    break CloseReason::IoError(e.to_string());
                 }
                 Err(_) => {
                     info!("UDP socket timeout for flow_id {}, cleaning up", flow_id);
-                    break;
+                    break CloseReason::IdleTimeout;
                 }
             }
-        }
+        };
 
-        let mut flows_guard = flows.lock().await;
+        let mut flows_guard = self.flows.lock().await;
         flows_guard.remove(&flow_id);
+        drop(flows_guard);
         info!("Removed flow_id {} from active flows", flow_id);
-    }
 
-    fn encode_udp_datagram_static(datagram: UdpDatagram) -> Result<Bytes, UdpError> {
-        let mut codec = UdpDatagramCodec;
-        let mut buf = BytesMut::new();
+        if let Some(quota_manager) = &self.quota_manager {
+            if let Err(e) = quota_manager.persist(&self.remote_node_id).await {
+                error!("Failed to persist data transfer usage: {:?}", e);
+            }
+        }
 
-        codec
-            .encode(datagram, &mut buf)
-            .map_err(UdpError::CodecError)?;
+        let record = {
+            let destination = flow.destination.lock().unwrap();
+            self.destination_record(flow_id, &destination, close_reason)
+        };
+        self.audit_sink.record(record).await;
+    }
 
-        Ok(buf.freeze())
+    async fn resolve_target(&self, target: &TargetAddress) -> Result<SocketAddr, UdpError> {
+        let Host::Service(name) = &target.host else {
+            return self.resolve_address(&target.host, target.port).await;
+        };
+        match self
+            .service_registry
+            .as_ref()
+            .and_then(|registry| registry.resolve(name))
+        {
+            Some(ServiceTarget::Address(address)) => {
+                self.resolve_address(&address.host, address.port).await
+            }
+            Some(ServiceTarget::Unix(_)) => Err(UdpError::Protocol(
+                ConnectStatusCode::AddressTypeNotSupported,
+            )),
+            None => Err(UdpError::Protocol(ConnectStatusCode::HostUnreachable)),
+        }
     }
 
     async fn resolve_address(&self, address: &Host, port: u16) -> Result<SocketAddr, UdpError> {
         match address {
             Host::IPv4(ip) => {
-                info!("Using IPv4 address: {}:{}", ip, port);
+                trace!("Using IPv4 address: {}:{}", ip, port);
                 Ok(SocketAddr::from((*ip, port)))
             }
             Host::IPv6(ip) => {
-                info!("Using IPv6 address: [{}]:{}", ip, port);
+                trace!("Using IPv6 address: [{}]:{}", ip, port);
                 Ok(SocketAddr::from((*ip, port)))
             }
             Host::Domain(domain) => {
-                info!("Resolving domain: {}:{}", domain, port);
+                trace!("Resolving domain: {}:{}", domain, port);
                 let host_with_port = format!("{}:{}", domain, port);
                 match timeout(
-                    Duration::from_secs(5),
+                    self.protocol.settings().udp.dns_resolution_timeout,
                     self.dns_resolver.lookup_host(&host_with_port),
                 )
                 .await
@@ -206,30 +515,47 @@ impl UdpProxyHandlerHandler {
                     Ok(Ok(addrs)) => {
                         if let Some(ip) = addrs.first() {
                             let resolved = SocketAddr::from((*ip, port));
-                            info!("Domain {} resolved to {}", domain, resolved);
+                            trace!("Domain {} resolved to {}", domain, resolved);
                             Ok(resolved)
                         } else {
                             error!("DNS resolution for {} returned no results", domain);
-                            Err(UdpError::ProtocolError(ConnectStatusCode::HostUnreachable))
+                            Err(UdpError::Protocol(ConnectStatusCode::HostUnreachable))
                         }
                     }
                     Ok(Err(e)) => {
                         error!("DNS resolution failed for {}: {}", domain, e);
-                        Err(UdpError::ProtocolError(ConnectStatusCode::HostUnreachable))
+                        Err(UdpError::Protocol(ConnectStatusCode::HostUnreachable))
                     }
                     Err(_) => {
                         error!("DNS resolution for {} timed out", domain);
-                        Err(UdpError::ProtocolError(ConnectStatusCode::HostUnreachable))
+                        Err(UdpError::Protocol(ConnectStatusCode::HostUnreachable))
                     }
                 }
             }
+            Host::Node(_) | Host::Service(_) | Host::UnixSocket(_) => Err(UdpError::Protocol(
+                ConnectStatusCode::AddressTypeNotSupported,
+            )),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 enum UdpError {
-    IoError(io::Error),
-    ProtocolError(ConnectStatusCode),
-    CodecError(CodecError),
+    #[error("IO error: {0}")]
+    Io(io::Error),
+
+    #[error("Rejected with status {0:?}")]
+    Protocol(ConnectStatusCode),
+
+    #[error("Codec error: {0}")]
+    Codec(CodecError),
+}
+
+impl UdpError {
+    fn status(&self) -> ConnectStatusCode {
+        match self {
+            UdpError::Protocol(status) => *status,
+            _ => ConnectStatusCode::GeneralFailure,
+        }
+    }
 }
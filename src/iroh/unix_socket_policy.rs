@@ -0,0 +1,36 @@
+use std::path::{Component, Path, PathBuf};
+
+// Unix socket paths clients may address directly with `Host::UnixSocket`. Nothing is
+// allowed by default. Only absolute paths without `.` or `..` components are considered,
+// so a path cannot escape an allowed prefix.
+#[derive(Debug, Clone, Default)]
+pub struct UnixSocketPolicy {
+    allowed_prefixes: Vec<PathBuf>,
+}
+
+impl UnixSocketPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn allow(mut self, prefix: impl Into<PathBuf>) -> Self {
+        self.allowed_prefixes.push(prefix.into());
+        self
+    }
+
+    pub fn allowed_prefixes(&self) -> &[PathBuf] {
+        &self.allowed_prefixes
+    }
+
+    pub fn allows(&self, path: &Path) -> bool {
+        let normalized = path.is_absolute()
+            && path
+                .components()
+                .all(|component| matches!(component, Component::RootDir | Component::Normal(_)));
+        normalized
+            && self
+                .allowed_prefixes
+                .iter()
+                .any(|prefix| path.starts_with(prefix))
+    }
+}
@@ -0,0 +1,707 @@
+use super::socket_factory::{
+    connect_resolved, BoxedDatagramSocket, BoxedProxyStream, DatagramSocket, DefaultSocketFactory,
+    RecvFromFuture, TransportFactory,
+};
+use crate::message_types::{Host, TargetAddress};
+use crate::target_pattern::TargetPattern;
+use base64::Engine;
+use iroh::NodeId;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+const SOCKS_VERSION: u8 = 0x05;
+const SOCKS_AUTH_NONE: u8 = 0x00;
+const SOCKS_AUTH_PASSWORD: u8 = 0x02;
+const SOCKS_AUTH_UNACCEPTABLE: u8 = 0xFF;
+const SOCKS_CMD_CONNECT: u8 = 0x01;
+const SOCKS_CMD_UDP_ASSOCIATE: u8 = 0x03;
+const SOCKS_ATYP_IPV4: u8 = 0x01;
+const SOCKS_ATYP_DOMAIN: u8 = 0x03;
+const SOCKS_ATYP_IPV6: u8 = 0x04;
+const MAX_HTTP_RESPONSE_HEADER: usize = 8192;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl ProxyCredentials {
+    pub fn new(username: impl Into<String>, password: impl Into<String>) -> Self {
+        Self {
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+}
+
+// Reaches targets through an upstream SOCKS5 proxy, with optional username/password
+// authentication. UDP flows are relayed with UDP ASSOCIATE.
+#[derive(Debug, Clone)]
+pub struct Socks5TransportFactory {
+    proxy_addr: SocketAddr,
+    credentials: Option<ProxyCredentials>,
+}
+
+impl Socks5TransportFactory {
+    pub fn new(proxy_addr: SocketAddr, credentials: Option<ProxyCredentials>) -> Self {
+        Self {
+            proxy_addr,
+            credentials,
+        }
+    }
+
+    pub fn arc(
+        proxy_addr: SocketAddr,
+        credentials: Option<ProxyCredentials>,
+    ) -> Arc<dyn TransportFactory> {
+        Arc::new(Self::new(proxy_addr, credentials))
+    }
+
+    // `address` is the encoded `ATYP ADDR PORT` of the request.
+    async fn open_session(
+        &self,
+        command: u8,
+        address: &[u8],
+    ) -> io::Result<(TcpStream, SocketAddr)> {
+        let mut stream = TcpStream::connect(self.proxy_addr).await?;
+        self.negotiate_auth(&mut stream).await?;
+
+        let mut request = vec![SOCKS_VERSION, command, 0x00];
+        request.extend_from_slice(address);
+        stream.write_all(&request).await?;
+
+        let mut reply = [0u8; 3];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != SOCKS_VERSION {
+            return Err(protocol_error("unexpected SOCKS version in reply"));
+        }
+        if reply[1] != 0x00 {
+            return Err(socks_reply_error(reply[1]));
+        }
+        let bound = read_socks_address(&mut stream).await?;
+        Ok((stream, bound))
+    }
+
+    async fn negotiate_auth(&self, stream: &mut TcpStream) -> io::Result<()> {
+        let method = match self.credentials {
+            Some(_) => SOCKS_AUTH_PASSWORD,
+            None => SOCKS_AUTH_NONE,
+        };
+        stream.write_all(&[SOCKS_VERSION, 1, method]).await?;
+
+        let mut choice = [0u8; 2];
+        stream.read_exact(&mut choice).await?;
+        if choice[0] != SOCKS_VERSION {
+            return Err(protocol_error(
+                "unexpected SOCKS version in method selection",
+            ));
+        }
+        if choice[1] == SOCKS_AUTH_UNACCEPTABLE || choice[1] != method {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "SOCKS proxy rejected the authentication method",
+            ));
+        }
+
+        if let Some(credentials) = &self.credentials {
+            let username = credentials.username.as_bytes();
+            let password = credentials.password.as_bytes();
+            if username.len() > 255 || password.len() > 255 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "SOCKS credentials longer than 255 bytes",
+                ));
+            }
+            let mut request = vec![0x01, username.len() as u8];
+            request.extend_from_slice(username);
+            request.push(password.len() as u8);
+            request.extend_from_slice(password);
+            stream.write_all(&request).await?;
+
+            let mut status = [0u8; 2];
+            stream.read_exact(&mut status).await?;
+            if status[1] != 0x00 {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "SOCKS proxy rejected the credentials",
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl TransportFactory for Socks5TransportFactory {
+    fn connect_stream(
+        &self,
+        addr: SocketAddr,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedProxyStream, io::Error>> + Send + '_>> {
+        Box::pin(async move {
+            let mut address = Vec::new();
+            write_socks_address(&mut address, addr);
+            let (stream, _) = self.open_session(SOCKS_CMD_CONNECT, &address).await?;
+            Ok(Box::new(stream) as BoxedProxyStream)
+        })
+    }
+
+    // Domain names are sent as they are, so the proxy resolves them itself.
+    fn connect_target_for<'a>(
+        &'a self,
+        node_id: NodeId,
+        target: &'a TargetAddress,
+        addr: Option<SocketAddr>,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedProxyStream, io::Error>> + Send + 'a>> {
+        let Host::Domain(domain) = &target.host else {
+            return connect_resolved(self, node_id, target, addr);
+        };
+        Box::pin(async move {
+            check_hostname(domain)?;
+            let mut address = vec![SOCKS_ATYP_DOMAIN, domain.len() as u8];
+            address.extend_from_slice(domain.as_bytes());
+            address.extend_from_slice(&target.port.to_be_bytes());
+            let (stream, _) = self.open_session(SOCKS_CMD_CONNECT, &address).await?;
+            Ok(Box::new(stream) as BoxedProxyStream)
+        })
+    }
+
+    fn resolves_names(&self, target: &TargetAddress) -> bool {
+        matches!(target.host, Host::Domain(_))
+    }
+
+    // The local socket only ever talks to the relay, so it is bound for the relay's
+    // address family rather than the target's, and `bind_addr` is ignored.
+    fn bind_datagram(
+        &self,
+        _bind_addr: &str,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedDatagramSocket, io::Error>> + Send + '_>> {
+        Box::pin(async move {
+            // The client address is not known in advance, so the relay is asked to accept
+            // datagrams from any address.
+            let mut unspecified = Vec::new();
+            write_socks_address(
+                &mut unspecified,
+                SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+            );
+            let (control, mut relay) = self
+                .open_session(SOCKS_CMD_UDP_ASSOCIATE, &unspecified)
+                .await?;
+            if relay.ip().is_unspecified() {
+                relay.set_ip(self.proxy_addr.ip());
+            }
+            let local_ip: IpAddr = match relay {
+                SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+                SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+            };
+            let socket = UdpSocket::bind(SocketAddr::new(local_ip, 0)).await?;
+            Ok(Box::new(Socks5Datagram {
+                socket,
+                relay,
+                _control: control,
+            }) as BoxedDatagramSocket)
+        })
+    }
+}
+
+// UDP association through a SOCKS5 relay. The association lasts as long as the control
+// connection is open.
+#[derive(Debug)]
+struct Socks5Datagram {
+    socket: UdpSocket,
+    relay: SocketAddr,
+    _control: TcpStream,
+}
+
+impl DatagramSocket for Socks5Datagram {
+    fn send_to<'a>(
+        &'a self,
+        buf: &'a [u8],
+        target: SocketAddr,
+    ) -> Pin<Box<dyn Future<Output = Result<usize, io::Error>> + Send + 'a>> {
+        Box::pin(async move {
+            let mut packet = vec![0x00, 0x00, 0x00];
+            write_socks_address(&mut packet, target);
+            let header_len = packet.len();
+            packet.extend_from_slice(buf);
+            let sent = self.socket.send_to(&packet, self.relay).await?;
+            Ok(sent.saturating_sub(header_len))
+        })
+    }
+
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> RecvFromFuture<'a> {
+        Box::pin(async move {
+            let mut packet = vec![0u8; buf.len() + 262];
+            loop {
+                let (len, from) = self.socket.recv_from(&mut packet).await?;
+                if from != self.relay {
+                    continue;
+                }
+                // Fragmented datagrams are not supported and dropped.
+                if len < 4 || packet[2] != 0x00 {
+                    continue;
+                }
+                let Some((source, header_len)) = parse_socks_address(&packet[3..len]) else {
+                    continue;
+                };
+                let payload = &packet[3 + header_len..len];
+                let copied = payload.len().min(buf.len());
+                buf[..copied].copy_from_slice(&payload[..copied]);
+                return Ok((copied, source));
+            }
+        })
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.socket.local_addr()
+    }
+}
+
+// Reaches targets through an upstream HTTP proxy with the CONNECT method. HTTP proxies
+// cannot relay UDP, so `bind_datagram` fails.
+#[derive(Debug, Clone)]
+pub struct HttpConnectTransportFactory {
+    proxy_addr: SocketAddr,
+    credentials: Option<ProxyCredentials>,
+}
+
+impl HttpConnectTransportFactory {
+    pub fn new(proxy_addr: SocketAddr, credentials: Option<ProxyCredentials>) -> Self {
+        Self {
+            proxy_addr,
+            credentials,
+        }
+    }
+
+    pub fn arc(
+        proxy_addr: SocketAddr,
+        credentials: Option<ProxyCredentials>,
+    ) -> Arc<dyn TransportFactory> {
+        Arc::new(Self::new(proxy_addr, credentials))
+    }
+
+    // `addr` is the `host:port` authority of the CONNECT request.
+    async fn connect(&self, addr: &str) -> io::Result<TcpStream> {
+        let mut stream = TcpStream::connect(self.proxy_addr).await?;
+
+        let mut request = format!("CONNECT {addr} HTTP/1.1\r\nHost: {addr}\r\n");
+        if let Some(credentials) = &self.credentials {
+            let token = base64::engine::general_purpose::STANDARD
+                .encode(format!("{}:{}", credentials.username, credentials.password));
+            request.push_str(&format!("Proxy-Authorization: Basic {token}\r\n"));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        // Read byte by byte so nothing the target sends after the headers is consumed.
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            if response.len() >= MAX_HTTP_RESPONSE_HEADER {
+                return Err(protocol_error("HTTP proxy response header too long"));
+            }
+            response.push(stream.read_u8().await?);
+        }
+
+        let status_line = response
+            .split(|byte| *byte == b'\n')
+            .next()
+            .and_then(|line| std::str::from_utf8(line).ok())
+            .unwrap_or_default();
+        let status = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|code| code.parse::<u16>().ok())
+            .ok_or_else(|| protocol_error("malformed HTTP proxy response"))?;
+
+        match status {
+            200..=299 => Ok(stream),
+            407 => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "HTTP proxy requires authentication",
+            )),
+            502 | 504 => Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("HTTP proxy could not reach {addr}: {}", status_line.trim()),
+            )),
+            _ => Err(io::Error::other(format!(
+                "HTTP proxy refused CONNECT: {}",
+                status_line.trim()
+            ))),
+        }
+    }
+}
+
+impl TransportFactory for HttpConnectTransportFactory {
+    fn connect_stream(
+        &self,
+        addr: SocketAddr,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedProxyStream, io::Error>> + Send + '_>> {
+        Box::pin(async move {
+            let stream = self.connect(&addr.to_string()).await?;
+            Ok(Box::new(stream) as BoxedProxyStream)
+        })
+    }
+
+    // Domain names are sent as they are, so the proxy resolves them itself.
+    fn connect_target_for<'a>(
+        &'a self,
+        node_id: NodeId,
+        target: &'a TargetAddress,
+        addr: Option<SocketAddr>,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedProxyStream, io::Error>> + Send + 'a>> {
+        let Host::Domain(domain) = &target.host else {
+            return connect_resolved(self, node_id, target, addr);
+        };
+        Box::pin(async move {
+            check_hostname(domain)?;
+            let stream = self.connect(&format!("{}:{}", domain, target.port)).await?;
+            Ok(Box::new(stream) as BoxedProxyStream)
+        })
+    }
+
+    fn resolves_names(&self, target: &TargetAddress) -> bool {
+        matches!(target.host, Host::Domain(_))
+    }
+
+    fn bind_datagram(
+        &self,
+        _bind_addr: &str,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedDatagramSocket, io::Error>> + Send + '_>> {
+        Box::pin(async move {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "UDP is not supported through an HTTP CONNECT proxy",
+            ))
+        })
+    }
+}
+
+// Picks the transport for each destination from an ordered list of rules; the first
+// matching rule wins. Streams are routed on the requested target, so domain patterns
+// apply, falling back to the resolved address when no rule matches the name. Datagrams
+// are routed on their resolved destination only.
+#[derive(Debug, Clone)]
+pub struct TransportRouter {
+    rules: Vec<(TargetPattern, Arc<dyn TransportFactory>)>,
+    default: Arc<dyn TransportFactory>,
+}
+
+impl TransportRouter {
+    pub fn new(default: Arc<dyn TransportFactory>) -> Self {
+        Self {
+            rules: Vec::new(),
+            default,
+        }
+    }
+
+    pub fn with_rule(mut self, pattern: TargetPattern, factory: Arc<dyn TransportFactory>) -> Self {
+        self.rules.push((pattern, factory));
+        self
+    }
+
+    pub fn factory_for(&self, target: &TargetAddress) -> &Arc<dyn TransportFactory> {
+        self.factory(self.route_for(target))
+    }
+
+    fn factory(&self, route: Option<usize>) -> &Arc<dyn TransportFactory> {
+        match route {
+            Some(rule) => &self.rules[rule].1,
+            None => &self.default,
+        }
+    }
+
+    // Index of the first rule matching `target`, or `None` for the default transport.
+    fn route_for(&self, target: &TargetAddress) -> Option<usize> {
+        self.rules
+            .iter()
+            .position(|(pattern, _)| pattern.matches(target))
+    }
+
+    // Route for `target`, or for the address it resolved to if no rule matches the name.
+    fn route_for_resolved(
+        &self,
+        target: &TargetAddress,
+        addr: Option<SocketAddr>,
+    ) -> Option<usize> {
+        self.route_for(target)
+            .or_else(|| addr.and_then(|addr| self.route_for(&TargetAddress::from(addr))))
+    }
+}
+
+impl Default for TransportRouter {
+    fn default() -> Self {
+        Self::new(DefaultSocketFactory::transport())
+    }
+}
+
+impl TransportRouter {
+    fn routed_datagram(&self, node_id: Option<NodeId>, bind_addr: &str) -> BoxedDatagramSocket {
+        let (sender, received) = mpsc::unbounded_channel();
+        Box::new(RoutedDatagram {
+            router: self.clone(),
+            node_id,
+            bind_addr: bind_addr.to_string(),
+            routes: Mutex::new(HashMap::new()),
+            sender,
+            received: tokio::sync::Mutex::new(received),
+        })
+    }
+}
+
+impl TransportFactory for TransportRouter {
+    fn connect_stream(
+        &self,
+        addr: SocketAddr,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedProxyStream, io::Error>> + Send + '_>> {
+        self.factory_for(&addr.into()).connect_stream(addr)
+    }
+
+    fn bind_datagram(
+        &self,
+        bind_addr: &str,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedDatagramSocket, io::Error>> + Send + '_>> {
+        let socket = self.routed_datagram(None, bind_addr);
+        Box::pin(async move { Ok(socket) })
+    }
+
+    fn connect_stream_for(
+        &self,
+        node_id: NodeId,
+        addr: SocketAddr,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedProxyStream, io::Error>> + Send + '_>> {
+        self.factory_for(&addr.into())
+            .connect_stream_for(node_id, addr)
+    }
+
+    fn connect_target_for<'a>(
+        &'a self,
+        node_id: NodeId,
+        target: &'a TargetAddress,
+        addr: Option<SocketAddr>,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedProxyStream, io::Error>> + Send + 'a>> {
+        self.factory(self.route_for_resolved(target, addr))
+            .connect_target_for(node_id, target, addr)
+    }
+
+    fn resolves_names(&self, target: &TargetAddress) -> bool {
+        self.factory_for(target).resolves_names(target)
+    }
+
+    fn bind_datagram_for(
+        &self,
+        node_id: NodeId,
+        bind_addr: &str,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedDatagramSocket, io::Error>> + Send + '_>> {
+        let socket = self.routed_datagram(Some(node_id), bind_addr);
+        Box::pin(async move { Ok(socket) })
+    }
+}
+
+// A datagram received on one of the route sockets, or the error that ended its reader.
+type Received = io::Result<(Vec<u8>, SocketAddr)>;
+
+// A UDP flow may send to destinations on different routes, so the underlying sockets are
+// bound lazily, one per route, on the first datagram sent over it. Datagrams received on
+// any of them are handed out by `recv_from` in arrival order.
+#[derive(Debug)]
+struct RoutedDatagram {
+    router: TransportRouter,
+    node_id: Option<NodeId>,
+    bind_addr: String,
+    routes: Mutex<HashMap<Option<usize>, RouteSocket>>,
+    sender: mpsc::UnboundedSender<Received>,
+    received: tokio::sync::Mutex<mpsc::UnboundedReceiver<Received>>,
+}
+
+#[derive(Debug)]
+struct RouteSocket {
+    socket: Arc<dyn DatagramSocket>,
+    reader: JoinHandle<()>,
+}
+
+impl RoutedDatagram {
+    async fn socket_for(&self, target: SocketAddr) -> io::Result<Arc<dyn DatagramSocket>> {
+        let route = self.router.route_for(&target.into());
+        if let Some(route) = self.routes.lock().unwrap().get(&route) {
+            return Ok(route.socket.clone());
+        }
+
+        let factory = self.router.factory(route);
+        let socket: Arc<dyn DatagramSocket> = Arc::from(match self.node_id {
+            Some(node_id) => factory.bind_datagram_for(node_id, &self.bind_addr).await?,
+            None => factory.bind_datagram(&self.bind_addr).await?,
+        });
+        // Another datagram may have bound the route meanwhile; the socket bound first wins.
+        let mut routes = self.routes.lock().unwrap();
+        let route = routes.entry(route).or_insert_with(|| RouteSocket {
+            reader: tokio::spawn(forward_datagrams(socket.clone(), self.sender.clone())),
+            socket,
+        });
+        Ok(route.socket.clone())
+    }
+}
+
+async fn forward_datagrams(
+    socket: Arc<dyn DatagramSocket>,
+    sender: mpsc::UnboundedSender<Received>,
+) {
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let received = socket
+            .recv_from(&mut buf)
+            .await
+            .map(|(len, from)| (buf[..len].to_vec(), from));
+        let failed = received.is_err();
+        if sender.send(received).is_err() || failed {
+            return;
+        }
+    }
+}
+
+impl Drop for RoutedDatagram {
+    fn drop(&mut self) {
+        for route in self.routes.get_mut().unwrap().values() {
+            route.reader.abort();
+        }
+    }
+}
+
+impl DatagramSocket for RoutedDatagram {
+    fn send_to<'a>(
+        &'a self,
+        buf: &'a [u8],
+        target: SocketAddr,
+    ) -> Pin<Box<dyn Future<Output = Result<usize, io::Error>> + Send + 'a>> {
+        Box::pin(async move { self.socket_for(target).await?.send_to(buf, target).await })
+    }
+
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> RecvFromFuture<'a> {
+        Box::pin(async move {
+            // The channel stays open while `self.sender` exists, so this waits for the
+            // first socket to be bound.
+            let (data, from) = self
+                .received
+                .lock()
+                .await
+                .recv()
+                .await
+                .ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))??;
+            let len = data.len().min(buf.len());
+            buf[..len].copy_from_slice(&data[..len]);
+            Ok((len, from))
+        })
+    }
+
+    // Address of the first route's socket.
+    fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        let routes = self.routes.lock().unwrap();
+        match routes.values().next() {
+            Some(route) => route.socket.local_addr(),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "datagram socket is bound on first send",
+            )),
+        }
+    }
+}
+
+fn write_socks_address(buf: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.push(SOCKS_ATYP_IPV4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(SOCKS_ATYP_IPV6);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+async fn read_socks_address(stream: &mut TcpStream) -> io::Result<SocketAddr> {
+    let atyp = stream.read_u8().await?;
+    let ip = match atyp {
+        SOCKS_ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            stream.read_exact(&mut octets).await?;
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        SOCKS_ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            stream.read_exact(&mut octets).await?;
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        // A bound domain name is of no use to us; skip it.
+        SOCKS_ATYP_DOMAIN => {
+            let len = stream.read_u8().await? as usize;
+            let mut domain = vec![0u8; len];
+            stream.read_exact(&mut domain).await?;
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        }
+        _ => return Err(protocol_error("invalid SOCKS address type")),
+    };
+    let port = stream.read_u16().await?;
+    Ok(SocketAddr::new(ip, port))
+}
+
+// Parses `ATYP ADDR PORT`, returning the address and the number of bytes consumed.
+fn parse_socks_address(data: &[u8]) -> Option<(SocketAddr, usize)> {
+    let (ip, len) = match *data.first()? {
+        SOCKS_ATYP_IPV4 => {
+            let octets: [u8; 4] = data.get(1..5)?.try_into().ok()?;
+            (IpAddr::V4(Ipv4Addr::from(octets)), 5)
+        }
+        SOCKS_ATYP_IPV6 => {
+            let octets: [u8; 16] = data.get(1..17)?.try_into().ok()?;
+            (IpAddr::V6(Ipv6Addr::from(octets)), 17)
+        }
+        _ => return None,
+    };
+    let port = u16::from_be_bytes(data.get(len..len + 2)?.try_into().ok()?);
+    Some((SocketAddr::new(ip, port), len + 2))
+}
+
+fn socks_reply_error(reply: u8) -> io::Error {
+    let kind = match reply {
+        0x02 => io::ErrorKind::PermissionDenied,
+        0x03 | 0x04 => io::ErrorKind::AddrNotAvailable,
+        0x05 => io::ErrorKind::ConnectionRefused,
+        0x06 => io::ErrorKind::TimedOut,
+        0x07 | 0x08 => io::ErrorKind::Unsupported,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, format!("SOCKS proxy replied with error {reply:#04x}"))
+}
+
+// Domain names come from clients and are copied into the proxy request, so anything that
+// could end the name early or change the request is refused.
+fn check_hostname(domain: &str) -> io::Result<()> {
+    if domain.is_empty() || domain.len() > 255 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "domain name must be 1 to 255 bytes long",
+        ));
+    }
+    if domain
+        .chars()
+        .any(|c| c.is_control() || c.is_whitespace() || matches!(c, ':' | '/' | '@'))
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid domain name {domain:?}"),
+        ));
+    }
+    Ok(())
+}
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
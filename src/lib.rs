@@ -2,6 +2,9 @@ pub mod codec;
 pub mod iroh;
 pub mod iroh_stream;
 pub mod message_types;
+pub mod target_pattern;
+#[cfg(feature = "test-util")]
+pub mod test_util;
 
 // Re-export commonly used items for convenience
 pub use codec::{CodecError, TcpConnectRequestCodec, TcpConnectResponseCodec, UdpDatagramCodec};
@@ -1,4 +1,8 @@
-use std::net::{Ipv4Addr, Ipv6Addr};
+use iroh::NodeId;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct UdpDatagram {
@@ -17,17 +21,23 @@ pub struct TcpConnectResponse {
     pub status: ConnectStatusCode,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct TargetAddress {
     pub host: Host,
     pub port: u16,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Host {
     IPv4(Ipv4Addr),
     IPv6(Ipv6Addr),
     Domain(String),
+    // Another s2p node, used to build multi-hop paths
+    Node(NodeId),
+    // A service published by name in the server's service registry
+    Service(String),
+    // A Unix domain socket on the server, subject to its unix socket policy
+    UnixSocket(PathBuf),
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -54,3 +64,62 @@ impl TcpConnectResponse {
         }
     }
 }
+
+impl fmt::Display for Host {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Host::IPv4(ip) => write!(f, "{}", ip),
+            Host::IPv6(ip) => write!(f, "[{}]", ip),
+            Host::Domain(domain) => write!(f, "{}", domain),
+            Host::Node(node_id) => write!(f, "{}", node_id),
+            Host::Service(name) => write!(f, "service:{}", name),
+            Host::UnixSocket(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl fmt::Display for TargetAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.host, self.port)
+    }
+}
+
+impl From<SocketAddr> for TargetAddress {
+    fn from(addr: SocketAddr) -> Self {
+        let host = match addr.ip() {
+            IpAddr::V4(ip) => Host::IPv4(ip),
+            IpAddr::V6(ip) => Host::IPv6(ip),
+        };
+        Self {
+            host,
+            port: addr.port(),
+        }
+    }
+}
+
+// Parses `host:port`, with IPv6 addresses in brackets: `[::1]:22`.
+impl FromStr for TargetAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, port) = s
+            .rsplit_once(':')
+            .ok_or_else(|| format!("missing port in {:?}", s))?;
+        let port = port
+            .parse::<u16>()
+            .map_err(|_| format!("invalid port in {:?}", s))?;
+        let host = if let Some(ip) = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+            Host::IPv6(
+                ip.parse()
+                    .map_err(|_| format!("invalid IPv6 address in {:?}", s))?,
+            )
+        } else if let Ok(ip) = host.parse::<Ipv4Addr>() {
+            Host::IPv4(ip)
+        } else if host.is_empty() || host.contains(':') {
+            return Err(format!("invalid host in {:?}", s));
+        } else {
+            Host::Domain(host.to_string())
+        };
+        Ok(Self { host, port })
+    }
+}
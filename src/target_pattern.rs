@@ -0,0 +1,216 @@
+use crate::message_types::{Host, TargetAddress};
+use std::fmt;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostPattern {
+    Any,
+    // Case-insensitive domain glob where `*` matches any sequence of characters,
+    // e.g. `*.corp.internal`
+    Domain(String),
+    Cidr { network: IpAddr, prefix_len: u8 },
+    // Glob over service names, written as `service:<glob>`
+    Service(String),
+}
+
+impl HostPattern {
+    pub fn matches(&self, host: &Host) -> bool {
+        match (self, host) {
+            (HostPattern::Any, _) => true,
+            (HostPattern::Domain(pattern), Host::Domain(domain)) => {
+                glob_matches(pattern.as_bytes(), domain.to_ascii_lowercase().as_bytes())
+            }
+            (HostPattern::Service(pattern), Host::Service(name)) => {
+                glob_matches(pattern.as_bytes(), name.as_bytes())
+            }
+            (
+                HostPattern::Cidr {
+                    network,
+                    prefix_len,
+                },
+                Host::IPv4(ip),
+            ) => cidr_contains(network, *prefix_len, &IpAddr::V4(*ip)),
+            (
+                HostPattern::Cidr {
+                    network,
+                    prefix_len,
+                },
+                Host::IPv6(ip),
+            ) => cidr_contains(network, *prefix_len, &IpAddr::V6(*ip)),
+            _ => false,
+        }
+    }
+}
+
+fn glob_matches(pattern: &[u8], value: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => value.is_empty(),
+        Some((b'*', rest)) => (0..=value.len()).any(|skip| glob_matches(rest, &value[skip..])),
+        Some((c, rest)) => value
+            .split_first()
+            .is_some_and(|(v, value_rest)| v == c && glob_matches(rest, value_rest)),
+    }
+}
+
+fn cidr_contains(network: &IpAddr, prefix_len: u8, ip: &IpAddr) -> bool {
+    match (network, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => {
+            let mask = u32::MAX
+                .checked_shl(32 - u32::from(prefix_len))
+                .unwrap_or(0);
+            u32::from(*network) & mask == u32::from(*ip) & mask
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) => {
+            let mask = u128::MAX
+                .checked_shl(128 - u32::from(prefix_len))
+                .unwrap_or(0);
+            u128::from(*network) & mask == u128::from(*ip) & mask
+        }
+        _ => false,
+    }
+}
+
+// Matches a `TargetAddress` by host and port. Written as `host[:ports]`, where `host` is
+// `*`, a domain glob, an IP address or a CIDR block (IPv6 in brackets) and `ports` is `*`,
+// a single port or an inclusive range like `8000-8100`, e.g. `*.corp.internal:443`,
+// `10.0.0.0/8`, `[fd00::/8]:22`. Services are matched by name as `service:<glob>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TargetPattern {
+    pub host: HostPattern,
+    pub ports: Option<RangeInclusive<u16>>,
+}
+
+impl TargetPattern {
+    pub fn any() -> Self {
+        Self {
+            host: HostPattern::Any,
+            ports: None,
+        }
+    }
+
+    pub fn matches(&self, target: &TargetAddress) -> bool {
+        let port_matches = match &self.ports {
+            Some(ports) => ports.contains(&target.port),
+            None => true,
+        };
+        port_matches && self.host.matches(&target.host)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("Invalid target pattern {pattern:?}: {reason}")]
+pub struct TargetPatternError {
+    pub pattern: String,
+    pub reason: &'static str,
+}
+
+impl FromStr for TargetPattern {
+    type Err = TargetPatternError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = |reason| TargetPatternError {
+            pattern: s.to_string(),
+            reason,
+        };
+
+        if let Some(service) = s.strip_prefix("service:") {
+            if service.is_empty() {
+                return Err(error("empty service name"));
+            }
+            return Ok(Self {
+                host: HostPattern::Service(service.to_string()),
+                ports: None,
+            });
+        }
+
+        let (host, ports) = if let Some(bracketed) = s.strip_prefix('[') {
+            let (host, rest) = bracketed
+                .split_once(']')
+                .ok_or_else(|| error("missing closing bracket"))?;
+            match rest {
+                "" => (host, None),
+                _ => (
+                    host,
+                    Some(
+                        rest.strip_prefix(':')
+                            .ok_or_else(|| error("expected ':' after bracketed host"))?,
+                    ),
+                ),
+            }
+        } else {
+            match s.split_once(':') {
+                Some((host, ports)) => (host, Some(ports)),
+                None => (s, None),
+            }
+        };
+
+        let ports = match ports {
+            None | Some("*") => None,
+            Some(ports) => {
+                let (start, end) = ports.split_once('-').unwrap_or((ports, ports));
+                let start = start.parse::<u16>().map_err(|_| error("invalid port"))?;
+                let end = end.parse::<u16>().map_err(|_| error("invalid port"))?;
+                if start > end {
+                    return Err(error("port range start is greater than its end"));
+                }
+                Some(start..=end)
+            }
+        };
+
+        let host = if host == "*" {
+            HostPattern::Any
+        } else if let Some((network, prefix_len)) = host.split_once('/') {
+            let network = network
+                .parse::<IpAddr>()
+                .map_err(|_| error("invalid CIDR network address"))?;
+            let prefix_len = prefix_len
+                .parse::<u8>()
+                .map_err(|_| error("invalid CIDR prefix length"))?;
+            let max_prefix_len = if network.is_ipv4() { 32 } else { 128 };
+            if prefix_len > max_prefix_len {
+                return Err(error("CIDR prefix length is too long"));
+            }
+            HostPattern::Cidr {
+                network,
+                prefix_len,
+            }
+        } else if let Ok(ip) = host.parse::<IpAddr>() {
+            HostPattern::Cidr {
+                network: ip,
+                prefix_len: if ip.is_ipv4() { 32 } else { 128 },
+            }
+        } else if host.is_empty() {
+            return Err(error("empty host"));
+        } else {
+            HostPattern::Domain(host.to_ascii_lowercase())
+        };
+
+        Ok(Self { host, ports })
+    }
+}
+
+impl fmt::Display for TargetPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.host {
+            HostPattern::Any => write!(f, "*")?,
+            HostPattern::Domain(domain) => write!(f, "{}", domain)?,
+            HostPattern::Service(service) => write!(f, "service:{}", service)?,
+            HostPattern::Cidr {
+                network: IpAddr::V4(ip),
+                prefix_len,
+            } => write!(f, "{}/{}", ip, prefix_len)?,
+            HostPattern::Cidr {
+                network: IpAddr::V6(ip),
+                prefix_len,
+            } => write!(f, "[{}/{}]", ip, prefix_len)?,
+        }
+
+        match &self.ports {
+            None => Ok(()),
+            Some(ports) if ports.start() == ports.end() => write!(f, ":{}", ports.start()),
+            Some(ports) => write!(f, ":{}-{}", ports.start(), ports.end()),
+        }
+    }
+}
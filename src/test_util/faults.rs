@@ -0,0 +1,331 @@
+// Wrappers that inject failures into another transport factory or DNS resolver.
+use super::host_name;
+use crate::iroh::{
+    BoxedDatagramSocket, BoxedProxyStream, DatagramSocket, DnsResolver, RecvFromFuture,
+    TransportFactory,
+};
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectFault {
+    // Fails with `ConnectionRefused`.
+    Refused,
+    // Fails with `TimedOut`.
+    TimedOut,
+    // Fails with `AddrNotAvailable`.
+    Unreachable,
+    // Never completes, leaving the proxy's connect timeout to fire.
+    Hang,
+    // Connects through the inner factory after the delay.
+    Slow(Duration),
+    // Connects through the inner factory, then reads fail with `ConnectionReset` once
+    // this many bytes have been read from the target.
+    ResetAfter(usize),
+}
+
+// Faults applied to datagrams received from targets, counting from 1 per socket.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DatagramFaults {
+    pub drop_every: Option<usize>,
+    pub duplicate_every: Option<usize>,
+    // Swaps consecutive datagrams: the first of each pair is held back until the second
+    // has been delivered.
+    pub reorder_pairs: bool,
+}
+
+#[derive(Debug, Default)]
+struct FaultPlan {
+    once: VecDeque<ConnectFault>,
+    always: Option<ConnectFault>,
+}
+
+// Transport factory that fails connections to selected addresses and disturbs datagram
+// delivery before handing over to `inner`. One-shot faults are used up first, so a
+// fault can be followed by a successful attempt to test recovery.
+#[derive(Debug, Clone)]
+pub struct FaultySocketFactory {
+    inner: Arc<dyn TransportFactory>,
+    faults: Arc<Mutex<HashMap<SocketAddr, FaultPlan>>>,
+    datagram_faults: DatagramFaults,
+}
+
+impl FaultySocketFactory {
+    pub fn new(inner: Arc<dyn TransportFactory>) -> Self {
+        Self {
+            inner,
+            faults: Arc::new(Mutex::new(HashMap::new())),
+            datagram_faults: DatagramFaults::default(),
+        }
+    }
+
+    pub fn with_fault(self, addr: SocketAddr, fault: ConnectFault) -> Self {
+        self.faults.lock().unwrap().entry(addr).or_default().always = Some(fault);
+        self
+    }
+
+    pub fn with_faults_once(
+        self,
+        addr: SocketAddr,
+        faults: impl IntoIterator<Item = ConnectFault>,
+    ) -> Self {
+        self.faults
+            .lock()
+            .unwrap()
+            .entry(addr)
+            .or_default()
+            .once
+            .extend(faults);
+        self
+    }
+
+    pub fn with_datagram_faults(self, datagram_faults: DatagramFaults) -> Self {
+        Self {
+            datagram_faults,
+            ..self
+        }
+    }
+
+    pub fn clear_faults(&self) {
+        self.faults.lock().unwrap().clear();
+    }
+
+    fn next_fault(&self, addr: &SocketAddr) -> Option<ConnectFault> {
+        let mut faults = self.faults.lock().unwrap();
+        let plan = faults.get_mut(addr)?;
+        plan.once.pop_front().or_else(|| plan.always.clone())
+    }
+
+    async fn connect(&self, addr: SocketAddr) -> io::Result<BoxedProxyStream> {
+        let reset_after = match self.next_fault(&addr) {
+            None => None,
+            Some(ConnectFault::Refused) => {
+                return Err(fault_error(io::ErrorKind::ConnectionRefused))
+            }
+            Some(ConnectFault::TimedOut) => return Err(fault_error(io::ErrorKind::TimedOut)),
+            Some(ConnectFault::Unreachable) => {
+                return Err(fault_error(io::ErrorKind::AddrNotAvailable))
+            }
+            Some(ConnectFault::Hang) => std::future::pending().await,
+            Some(ConnectFault::Slow(delay)) => {
+                tokio::time::sleep(delay).await;
+                None
+            }
+            Some(ConnectFault::ResetAfter(bytes)) => Some(bytes),
+        };
+
+        let stream = self.inner.connect_stream(addr).await?;
+        Ok(match reset_after {
+            Some(remaining) => Box::new(ResetStream {
+                inner: stream,
+                remaining,
+            }),
+            None => stream,
+        })
+    }
+
+    async fn bind(&self, bind_addr: &str) -> io::Result<BoxedDatagramSocket> {
+        let socket = self.inner.bind_datagram(bind_addr).await?;
+        Ok(Box::new(FaultyDatagramSocket {
+            inner: socket,
+            faults: self.datagram_faults.clone(),
+            state: tokio::sync::Mutex::new(DatagramState::default()),
+        }))
+    }
+}
+
+impl TransportFactory for FaultySocketFactory {
+    fn connect_stream(
+        &self,
+        addr: SocketAddr,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedProxyStream, io::Error>> + Send + '_>> {
+        Box::pin(self.connect(addr))
+    }
+
+    fn bind_datagram(
+        &self,
+        bind_addr: &str,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedDatagramSocket, io::Error>> + Send + '_>> {
+        let bind_addr = bind_addr.to_string();
+        Box::pin(async move { self.bind(&bind_addr).await })
+    }
+}
+
+fn fault_error(kind: io::ErrorKind) -> io::Error {
+    io::Error::new(kind, "injected fault")
+}
+
+struct ResetStream {
+    inner: BoxedProxyStream,
+    remaining: usize,
+}
+
+impl AsyncRead for ResetStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.remaining == 0 {
+            return Poll::Ready(Err(fault_error(io::ErrorKind::ConnectionReset)));
+        }
+        let limit = self.remaining.min(buf.remaining());
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(limit));
+        ready!(Pin::new(&mut self.inner).poll_read(cx, &mut limited))?;
+        let read = limited.filled().len();
+        buf.advance(read);
+        self.remaining -= read;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for ResetStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[derive(Debug, Default)]
+struct DatagramState {
+    received: usize,
+    held: Option<(Vec<u8>, SocketAddr)>,
+    ready: VecDeque<(Vec<u8>, SocketAddr)>,
+}
+
+#[derive(Debug)]
+struct FaultyDatagramSocket {
+    inner: BoxedDatagramSocket,
+    faults: DatagramFaults,
+    state: tokio::sync::Mutex<DatagramState>,
+}
+
+fn hits(every: Option<usize>, count: usize) -> bool {
+    matches!(every, Some(n) if n > 0 && count % n == 0)
+}
+
+impl FaultyDatagramSocket {
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut state = self.state.lock().await;
+        let (data, from) = loop {
+            if let Some(datagram) = state.ready.pop_front() {
+                break datagram;
+            }
+
+            let mut data = vec![0u8; 65535];
+            let (len, from) = self.inner.recv_from(&mut data).await?;
+            data.truncate(len);
+            state.received += 1;
+            let count = state.received;
+            if hits(self.faults.drop_every, count) {
+                continue;
+            }
+            if self.faults.reorder_pairs && state.held.is_none() {
+                state.held = Some((data, from));
+                continue;
+            }
+
+            if hits(self.faults.duplicate_every, count) {
+                state.ready.push_back((data.clone(), from));
+            }
+            if let Some(held) = state.held.take() {
+                state.ready.push_back(held);
+            }
+            break (data, from);
+        };
+
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok((len, from))
+    }
+}
+
+impl DatagramSocket for FaultyDatagramSocket {
+    fn send_to<'a>(
+        &'a self,
+        buf: &'a [u8],
+        target: SocketAddr,
+    ) -> Pin<Box<dyn Future<Output = Result<usize, io::Error>> + Send + 'a>> {
+        self.inner.send_to(buf, target)
+    }
+
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> RecvFromFuture<'a> {
+        Box::pin(self.recv(buf))
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.inner.local_addr()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DnsFault {
+    // Fails with `NotFound`, as resolvers report NXDOMAIN.
+    NxDomain,
+    // Never answers, leaving the proxy's resolution timeout to fire.
+    Timeout,
+    // Answers without any addresses.
+    Empty,
+    // Answers through the inner resolver after the delay.
+    Slow(Duration),
+}
+
+// DNS resolver that answers selected names with a fault instead of asking `inner`.
+#[derive(Debug)]
+pub struct FaultyDnsResolver {
+    inner: Arc<dyn DnsResolver>,
+    faults: Mutex<HashMap<String, DnsFault>>,
+}
+
+impl FaultyDnsResolver {
+    pub fn new(inner: Arc<dyn DnsResolver>) -> Self {
+        Self {
+            inner,
+            faults: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn with_fault(self, name: impl Into<String>, fault: DnsFault) -> Self {
+        self.faults.lock().unwrap().insert(name.into(), fault);
+        self
+    }
+
+    async fn lookup(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        let fault = self.faults.lock().unwrap().get(host_name(host)).cloned();
+        match fault {
+            None => {}
+            Some(DnsFault::NxDomain) => return Err(fault_error(io::ErrorKind::NotFound)),
+            Some(DnsFault::Timeout) => std::future::pending().await,
+            Some(DnsFault::Empty) => return Ok(Vec::new()),
+            Some(DnsFault::Slow(delay)) => tokio::time::sleep(delay).await,
+        }
+        self.inner.lookup_host(host).await
+    }
+}
+
+impl DnsResolver for FaultyDnsResolver {
+    fn lookup_host<'a>(
+        &'a self,
+        host: &'a str,
+    ) -> Pin<Box<dyn Future<Output = io::Result<Vec<IpAddr>>> + Send + 'a>> {
+        Box::pin(self.lookup(host))
+    }
+}
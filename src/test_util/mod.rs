@@ -0,0 +1,410 @@
+// Offline test support: loopback endpoints without relays or discovery, scriptable
+// targets and DNS, and helpers to check the outcome of s2p requests.
+use crate::codec::UdpDatagramCodec;
+use crate::iroh::{
+    spawn_router, AuditRecord, AuditSink, BoxedDatagramSocket, BoxedProxyStream, DatagramSocket,
+    DnsResolver, RecvFromFuture, S2pProtocol, TcpClient, TcpClientError, TransportFactory,
+    ALPN_S2P_V1,
+};
+use crate::message_types::{ConnectStatusCode, TargetAddress, UdpDatagram};
+use bytes::BytesMut;
+use iroh::endpoint::Connection;
+use iroh::protocol::Router;
+use iroh::{Endpoint, NodeAddr, RelayMode};
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+use tokio_util::codec::{Decoder, Encoder};
+
+mod faults;
+
+pub use faults::{ConnectFault, DatagramFaults, DnsFault, FaultyDnsResolver, FaultySocketFactory};
+
+pub type TestResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+// Endpoint bound to the loopback interfaces, with relays and discovery disabled.
+pub async fn loopback_endpoint() -> TestResult<Endpoint> {
+    Ok(Endpoint::builder()
+        .relay_mode(RelayMode::Disabled)
+        .clear_discovery()
+        .bind_addr_v4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+        .bind_addr_v6(SocketAddrV6::new(Ipv6Addr::LOCALHOST, 0, 0, 0))
+        .bind()
+        .await?)
+}
+
+// Address of `endpoint` made of its bound sockets only.
+pub fn direct_addr(endpoint: &Endpoint) -> NodeAddr {
+    NodeAddr::from_parts(endpoint.node_id(), None, endpoint.bound_sockets())
+}
+
+// A server running `protocol` and a client connected to it over loopback.
+#[derive(Debug)]
+pub struct TestNodes {
+    pub client: Endpoint,
+    pub server: Endpoint,
+    pub router: Router,
+    pub connection: Connection,
+}
+
+impl TestNodes {
+    pub async fn start(protocol: S2pProtocol) -> TestResult<Self> {
+        Self::start_with(|_| protocol).await
+    }
+
+    // Like `start`, for protocols built around the server's endpoint, e.g. to relay.
+    pub async fn start_with(protocol: impl FnOnce(&Endpoint) -> S2pProtocol) -> TestResult<Self> {
+        let server = loopback_endpoint().await?;
+        let client = loopback_endpoint().await?;
+        let router = spawn_router(server.clone(), protocol(&server));
+        let connection = client
+            .connect(direct_addr(&server), ALPN_S2P_V1.as_bytes())
+            .await?;
+        Ok(Self {
+            client,
+            server,
+            router,
+            connection,
+        })
+    }
+
+    pub fn tcp_client(&self) -> TcpClient {
+        TcpClient::new(self.connection.clone())
+    }
+
+    pub async fn shutdown(self) {
+        self.connection.close(0u32.into(), b"done");
+        let _ = self.router.shutdown().await;
+        self.client.close().await;
+    }
+}
+
+// Status the server answers a connect request for `target` with.
+pub async fn connect_status(
+    connection: &Connection,
+    target: TargetAddress,
+) -> Result<ConnectStatusCode, TcpClientError> {
+    match TcpClient::new(connection.clone()).connect(target).await {
+        Ok(_) => Ok(ConnectStatusCode::Success),
+        Err(TcpClientError::ProtocolError(status)) => Ok(status),
+        Err(e) => Err(e),
+    }
+}
+
+pub async fn assert_connect_status(
+    connection: &Connection,
+    target: TargetAddress,
+    expected: ConnectStatusCode,
+) {
+    let status = connect_status(connection, target.clone())
+        .await
+        .unwrap_or_else(|e| panic!("connect request for {} failed: {}", target, e));
+    assert_eq!(status, expected, "unexpected status for {}", target);
+}
+
+// Sends one datagram of `flow_id` and waits up to `wait` for the next datagram coming
+// back on the connection.
+pub async fn udp_round_trip(
+    connection: &Connection,
+    flow_id: u8,
+    target: TargetAddress,
+    data: &[u8],
+    wait: Duration,
+) -> TestResult<UdpDatagram> {
+    let mut buf = BytesMut::new();
+    UdpDatagramCodec.encode(
+        UdpDatagram {
+            flow_id,
+            target,
+            data: data.to_vec(),
+        },
+        &mut buf,
+    )?;
+    connection.send_datagram(buf.freeze())?;
+
+    let reply = timeout(wait, connection.read_datagram()).await??;
+    UdpDatagramCodec
+        .decode(&mut BytesMut::from(reply.as_ref()))?
+        .ok_or_else(|| "incomplete datagram".into())
+}
+
+#[derive(Debug, Clone)]
+pub enum MockTarget {
+    // Echoes streams and datagrams back to the sender.
+    Echo,
+    // Sends the bytes as soon as a stream is opened, then echoes.
+    Greeting(Vec<u8>),
+    // Connecting fails with an error of this kind.
+    Fail(io::ErrorKind),
+    // Connecting never completes.
+    Hang,
+}
+
+#[derive(Debug)]
+struct MockState {
+    targets: Mutex<HashMap<SocketAddr, MockTarget>>,
+    default_target: MockTarget,
+    connections: Mutex<Vec<SocketAddr>>,
+    datagrams: Mutex<Vec<(SocketAddr, Vec<u8>)>>,
+    binds: Mutex<Vec<String>>,
+}
+
+impl MockState {
+    fn target(&self, addr: &SocketAddr) -> MockTarget {
+        self.targets
+            .lock()
+            .unwrap()
+            .get(addr)
+            .cloned()
+            .unwrap_or_else(|| self.default_target.clone())
+    }
+}
+
+// Transport factory with in-memory targets. Addresses without a scripted target refuse
+// connections and drop datagrams.
+#[derive(Debug, Clone)]
+pub struct MockSocketFactory {
+    state: Arc<MockState>,
+}
+
+impl MockSocketFactory {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(MockState {
+                targets: Mutex::new(HashMap::new()),
+                default_target: MockTarget::Fail(io::ErrorKind::ConnectionRefused),
+                connections: Mutex::new(Vec::new()),
+                datagrams: Mutex::new(Vec::new()),
+                binds: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    pub fn arc() -> Arc<Self> {
+        Arc::new(Self::new())
+    }
+
+    pub fn with_target(self, addr: SocketAddr, target: MockTarget) -> Self {
+        self.set_target(addr, target);
+        self
+    }
+
+    pub fn set_target(&self, addr: SocketAddr, target: MockTarget) {
+        self.state.targets.lock().unwrap().insert(addr, target);
+    }
+
+    // Addresses of all stream connection attempts, in order.
+    pub fn connections(&self) -> Vec<SocketAddr> {
+        self.state.connections.lock().unwrap().clone()
+    }
+
+    // Every datagram sent, with its destination.
+    pub fn datagrams(&self) -> Vec<(SocketAddr, Vec<u8>)> {
+        self.state.datagrams.lock().unwrap().clone()
+    }
+
+    // Addresses of all datagram sockets bound, in order.
+    pub fn binds(&self) -> Vec<String> {
+        self.state.binds.lock().unwrap().clone()
+    }
+
+    async fn connect(&self, addr: SocketAddr) -> io::Result<BoxedProxyStream> {
+        self.state.connections.lock().unwrap().push(addr);
+        let greeting = match self.state.target(&addr) {
+            MockTarget::Echo => Vec::new(),
+            MockTarget::Greeting(greeting) => greeting,
+            MockTarget::Fail(kind) => return Err(io::Error::new(kind, "mock target failure")),
+            MockTarget::Hang => std::future::pending().await,
+        };
+
+        let (local, remote) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move {
+            let (mut reader, mut writer) = tokio::io::split(remote);
+            if writer.write_all(&greeting).await.is_ok() {
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            }
+        });
+        Ok(Box::new(local))
+    }
+}
+
+impl Default for MockSocketFactory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TransportFactory for MockSocketFactory {
+    fn connect_stream(
+        &self,
+        addr: SocketAddr,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedProxyStream, io::Error>> + Send + '_>> {
+        Box::pin(self.connect(addr))
+    }
+
+    fn bind_datagram(
+        &self,
+        bind_addr: &str,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedDatagramSocket, io::Error>> + Send + '_>> {
+        self.state.binds.lock().unwrap().push(bind_addr.to_string());
+        let local_addr = bind_addr
+            .parse::<SocketAddr>()
+            .unwrap_or_else(|_| SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)));
+        let (replies, received) = mpsc::unbounded_channel();
+        let socket = MockDatagramSocket {
+            state: self.state.clone(),
+            local_addr,
+            replies,
+            received: tokio::sync::Mutex::new(received),
+        };
+        Box::pin(async move { Ok(Box::new(socket) as BoxedDatagramSocket) })
+    }
+}
+
+#[derive(Debug)]
+struct MockDatagramSocket {
+    state: Arc<MockState>,
+    local_addr: SocketAddr,
+    replies: mpsc::UnboundedSender<(Vec<u8>, SocketAddr)>,
+    received: tokio::sync::Mutex<mpsc::UnboundedReceiver<(Vec<u8>, SocketAddr)>>,
+}
+
+impl DatagramSocket for MockDatagramSocket {
+    fn send_to<'a>(
+        &'a self,
+        buf: &'a [u8],
+        target: SocketAddr,
+    ) -> Pin<Box<dyn Future<Output = Result<usize, io::Error>> + Send + 'a>> {
+        Box::pin(async move {
+            self.state
+                .datagrams
+                .lock()
+                .unwrap()
+                .push((target, buf.to_vec()));
+            match self.state.target(&target) {
+                MockTarget::Echo | MockTarget::Greeting(_) => {
+                    let _ = self.replies.send((buf.to_vec(), target));
+                }
+                MockTarget::Fail(kind) => return Err(io::Error::new(kind, "mock target failure")),
+                MockTarget::Hang => {}
+            }
+            Ok(buf.len())
+        })
+    }
+
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> RecvFromFuture<'a> {
+        Box::pin(async move {
+            let (data, from) =
+                self.received.lock().await.recv().await.ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::BrokenPipe, "mock socket closed")
+                })?;
+            let len = data.len().min(buf.len());
+            buf[..len].copy_from_slice(&data[..len]);
+            Ok((len, from))
+        })
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        Ok(self.local_addr)
+    }
+}
+
+// DNS resolver answering from a script and recording every lookup. Unknown names fail
+// with `NotFound`.
+#[derive(Debug, Default)]
+pub struct MockDnsResolver {
+    answers: Mutex<HashMap<String, Result<Vec<IpAddr>, io::ErrorKind>>>,
+    lookups: Mutex<Vec<String>>,
+}
+
+impl MockDnsResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_host(self, name: impl Into<String>, addrs: Vec<IpAddr>) -> Self {
+        self.answers.lock().unwrap().insert(name.into(), Ok(addrs));
+        self
+    }
+
+    pub fn with_failure(self, name: impl Into<String>, kind: io::ErrorKind) -> Self {
+        self.answers.lock().unwrap().insert(name.into(), Err(kind));
+        self
+    }
+
+    // Names looked up so far, without ports.
+    pub fn lookups(&self) -> Vec<String> {
+        self.lookups.lock().unwrap().clone()
+    }
+}
+
+impl DnsResolver for MockDnsResolver {
+    fn lookup_host<'a>(
+        &'a self,
+        host: &'a str,
+    ) -> Pin<Box<dyn Future<Output = io::Result<Vec<IpAddr>>> + Send + 'a>> {
+        Box::pin(async move {
+            let name = host_name(host);
+            self.lookups.lock().unwrap().push(name.to_string());
+            match self.answers.lock().unwrap().get(name) {
+                Some(Ok(addrs)) => Ok(addrs.clone()),
+                Some(Err(kind)) => Err(io::Error::new(*kind, "mock lookup failure")),
+                None => Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no mock answer for {}", name),
+                )),
+            }
+        })
+    }
+}
+
+// Audit sink keeping every record in memory.
+#[derive(Debug, Default)]
+pub struct CollectingAuditSink {
+    records: Mutex<Vec<AuditRecord>>,
+}
+
+impl CollectingAuditSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn records(&self) -> Vec<AuditRecord> {
+        self.records.lock().unwrap().clone()
+    }
+
+    // Records are written when sessions close; waits up to `wait` for `count` of them.
+    pub async fn wait_for(&self, count: usize, wait: Duration) -> TestResult<Vec<AuditRecord>> {
+        timeout(wait, async {
+            loop {
+                let records = self.records();
+                if records.len() >= count {
+                    return records;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .map_err(|_| format!("fewer than {} audit records", count).into())
+    }
+}
+
+impl AuditSink for CollectingAuditSink {
+    fn record(&self, record: AuditRecord) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(async move {
+            self.records.lock().unwrap().push(record);
+        })
+    }
+}
+
+// Resolvers are asked for "host:port"; scripts are keyed by the host alone.
+fn host_name(host: &str) -> &str {
+    host.rsplit_once(':').map_or(host, |(name, _)| name)
+}
@@ -0,0 +1,257 @@
+use ::iroh::SecretKey;
+use s2p::iroh::{
+    AuditProtocol, AuditRecord, AuditSink, CloseReason, JsonLinesAuditSink, S2pProtocol,
+    TargetPolicy, TransportFactory, UdpSettings,
+};
+use s2p::message_types::{ConnectStatusCode, TargetAddress};
+use s2p::test_util::{
+    assert_connect_status, udp_round_trip, CollectingAuditSink, MockSocketFactory, MockTarget,
+    TestNodes,
+};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const ECHO: &str = "192.0.2.70:7";
+const OTHER_ECHO: &str = "192.0.2.71:7";
+const FAILING: &str = "192.0.2.72:7";
+const DENIED: &str = "192.0.2.73:7";
+
+fn protocol(sink: &Arc<CollectingAuditSink>) -> S2pProtocol {
+    let socket_factory: Arc<dyn TransportFactory> = Arc::new(
+        MockSocketFactory::new()
+            .with_target(ECHO.parse().unwrap(), MockTarget::Echo)
+            .with_target(OTHER_ECHO.parse().unwrap(), MockTarget::Echo)
+            .with_target(
+                FAILING.parse().unwrap(),
+                MockTarget::Fail(io::ErrorKind::ConnectionRefused),
+            ),
+    );
+    let audit_sink: Arc<dyn AuditSink> = sink.clone();
+    S2pProtocol::builder()
+        .socket_factory(socket_factory)
+        .audit_sink(audit_sink)
+        .target_policy(TargetPolicy {
+            allow: None,
+            deny: vec![DENIED.parse().unwrap()],
+        })
+        .udp(UdpSettings {
+            flow_idle_timeout: Duration::from_millis(300),
+            ..Default::default()
+        })
+        .build()
+        .unwrap()
+}
+
+fn target(s: &str) -> TargetAddress {
+    s.parse().unwrap()
+}
+
+fn udp_records(records: &[AuditRecord], flow_id: u8) -> Vec<&AuditRecord> {
+    records
+        .iter()
+        .filter(|record| record.protocol == AuditProtocol::Udp { flow_id })
+        .collect()
+}
+
+#[tokio::test]
+async fn test_tcp_sessions_are_audited() {
+    let sink = Arc::new(CollectingAuditSink::new());
+    let nodes = TestNodes::start(protocol(&sink)).await.unwrap();
+
+    let mut stream = nodes.tcp_client().connect(target(ECHO)).await.unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf).await.unwrap();
+    stream.shutdown().await.unwrap();
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).await.unwrap();
+
+    assert_connect_status(
+        &nodes.connection,
+        target(DENIED),
+        ConnectStatusCode::ConnectionNotAllowed,
+    )
+    .await;
+
+    let records = sink.wait_for(2, Duration::from_secs(5)).await.unwrap();
+    let session = records
+        .iter()
+        .find(|record| record.target == target(ECHO))
+        .unwrap();
+    assert_eq!(session.protocol, AuditProtocol::Tcp);
+    assert_eq!(session.remote_node_id, nodes.client.node_id());
+    assert_eq!(session.resolved_address, Some(ECHO.parse().unwrap()));
+    assert_eq!(session.status, ConnectStatusCode::Success);
+    assert_eq!(session.bytes_to_target, 4);
+    assert_eq!(session.bytes_from_target, 4);
+    assert_eq!(session.close_reason, CloseReason::Completed);
+
+    let denied = records
+        .iter()
+        .find(|record| record.target == target(DENIED))
+        .unwrap();
+    assert_eq!(denied.status, ConnectStatusCode::ConnectionNotAllowed);
+    assert_eq!(denied.close_reason, CloseReason::TargetNotAllowed);
+    assert_eq!(denied.bytes_to_target, 0);
+
+    nodes.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_udp_flows_are_audited_per_destination() {
+    let sink = Arc::new(CollectingAuditSink::new());
+    let nodes = TestNodes::start(protocol(&sink)).await.unwrap();
+    let wait = Duration::from_secs(5);
+
+    udp_round_trip(&nodes.connection, 1, target(ECHO), b"ping", wait)
+        .await
+        .unwrap();
+    // The same flow moves on to another destination, which closes the first record.
+    let reply = udp_round_trip(&nodes.connection, 1, target(OTHER_ECHO), b"hello", wait)
+        .await
+        .unwrap();
+    assert_eq!(reply.target, target(OTHER_ECHO));
+
+    let records = sink.wait_for(2, wait).await.unwrap();
+    let flow = udp_records(&records, 1);
+    assert_eq!(flow.len(), 2);
+    assert_eq!(flow[0].target, target(ECHO));
+    assert_eq!(flow[0].resolved_address, Some(ECHO.parse().unwrap()));
+    assert_eq!(flow[0].status, ConnectStatusCode::Success);
+    assert_eq!(flow[0].bytes_to_target, 4);
+    assert_eq!(flow[0].bytes_from_target, 4);
+    assert_eq!(flow[0].close_reason, CloseReason::TargetChanged);
+    assert_eq!(flow[1].target, target(OTHER_ECHO));
+    assert_eq!(flow[1].bytes_to_target, 5);
+    assert_eq!(flow[1].bytes_from_target, 5);
+    assert_eq!(flow[1].close_reason, CloseReason::IdleTimeout);
+
+    nodes.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_udp_failures_are_audited() {
+    let sink = Arc::new(CollectingAuditSink::new());
+    let nodes = TestNodes::start(protocol(&sink)).await.unwrap();
+    let wait = Duration::from_millis(300);
+
+    // Only the first denied datagram of a flow is audited.
+    for _ in 0..3 {
+        assert!(
+            udp_round_trip(&nodes.connection, 2, target(DENIED), b"ping", wait)
+                .await
+                .is_err()
+        );
+    }
+    assert!(
+        udp_round_trip(&nodes.connection, 3, target(FAILING), b"ping", wait)
+            .await
+            .is_err()
+    );
+
+    // The denied datagram is audited at once, the failing flow once it idles out.
+    let records = sink.wait_for(2, Duration::from_secs(5)).await.unwrap();
+    let denied = udp_records(&records, 2);
+    assert_eq!(denied.len(), 1);
+    assert_eq!(denied[0].status, ConnectStatusCode::ConnectionNotAllowed);
+    assert_eq!(denied[0].close_reason, CloseReason::TargetNotAllowed);
+
+    let failing = udp_records(&records, 3);
+    assert_eq!(failing.len(), 1);
+    assert_eq!(failing[0].status, ConnectStatusCode::GeneralFailure);
+    assert_eq!(failing[0].bytes_to_target, 0);
+
+    nodes.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_json_lines_audit_sink() {
+    let path = std::env::temp_dir().join(format!("s2p-audit-{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let remote_node_id = SecretKey::from_bytes(&[5u8; 32]).public();
+    let resolved: SocketAddr = ECHO.parse().unwrap();
+
+    let sink = JsonLinesAuditSink::open(&path).await.unwrap();
+    sink.record(AuditRecord {
+        timestamp: SystemTime::UNIX_EPOCH + Duration::from_millis(1_500),
+        remote_node_id,
+        profile_label: Some("ops".to_string()),
+        protocol: AuditProtocol::Tcp,
+        target: target(ECHO),
+        resolved_address: Some(resolved),
+        status: ConnectStatusCode::Success,
+        duration: Duration::from_millis(250),
+        bytes_to_target: 10,
+        bytes_from_target: 20,
+        close_reason: CloseReason::Completed,
+    })
+    .await;
+    sink.record(AuditRecord {
+        timestamp: SystemTime::UNIX_EPOCH,
+        remote_node_id,
+        profile_label: None,
+        protocol: AuditProtocol::Udp { flow_id: 9 },
+        target: target(DENIED),
+        resolved_address: None,
+        status: ConnectStatusCode::ConnectionNotAllowed,
+        duration: Duration::ZERO,
+        bytes_to_target: 0,
+        bytes_from_target: 0,
+        close_reason: CloseReason::TargetNotAllowed,
+    })
+    .await;
+    drop(sink);
+
+    let contents = std::fs::read_to_string(&path).unwrap();
+    let lines: Vec<serde_json::Value> = contents
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 2);
+
+    assert_eq!(lines[0]["timestamp_ms"], 1_500);
+    assert_eq!(lines[0]["remote_node_id"], remote_node_id.to_string());
+    assert_eq!(lines[0]["profile"], "ops");
+    assert_eq!(lines[0]["protocol"], "tcp");
+    assert!(lines[0]["flow_id"].is_null());
+    assert_eq!(lines[0]["target"], target(ECHO).to_string());
+    assert_eq!(lines[0]["resolved_address"], resolved.to_string());
+    assert_eq!(lines[0]["status"], "Success");
+    assert_eq!(lines[0]["duration_ms"], 250);
+    assert_eq!(lines[0]["bytes_to_target"], 10);
+    assert_eq!(lines[0]["bytes_from_target"], 20);
+    assert_eq!(lines[0]["close_reason"], "completed");
+
+    assert_eq!(lines[1]["protocol"], "udp");
+    assert_eq!(lines[1]["flow_id"], 9);
+    assert!(lines[1]["profile"].is_null());
+    assert!(lines[1]["resolved_address"].is_null());
+    assert_eq!(lines[1]["status"], "ConnectionNotAllowed");
+    assert_eq!(lines[1]["close_reason"], "target_not_allowed");
+
+    // Reopening appends rather than truncating.
+    let sink = JsonLinesAuditSink::open(&path).await.unwrap();
+    sink.record(AuditRecord {
+        timestamp: SystemTime::UNIX_EPOCH,
+        remote_node_id,
+        profile_label: None,
+        protocol: AuditProtocol::Udp { flow_id: 9 },
+        target: target(ECHO),
+        resolved_address: Some(resolved),
+        status: ConnectStatusCode::Success,
+        duration: Duration::ZERO,
+        bytes_to_target: 0,
+        bytes_from_target: 0,
+        close_reason: CloseReason::IdleTimeout,
+    })
+    .await;
+    drop(sink);
+    let contents = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(contents.lines().count(), 3);
+    assert!(contents.lines().last().unwrap().contains("idle_timeout"));
+
+    let _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,154 @@
+use ::iroh::endpoint::{Connection, ConnectionError};
+use ::iroh::SecretKey;
+use bytes::Bytes;
+use s2p::iroh::{BanList, BanPolicy, TransportFactory, Violation};
+use s2p::test_util::{direct_addr, MockSocketFactory, MockTarget, TestNodes};
+use s2p::{S2pProtocol, ALPN_S2P_V1};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const ECHO: &str = "192.0.2.80:7";
+
+#[tokio::test]
+async fn test_node_is_banned_after_repeated_violations() {
+    let node_id = SecretKey::from_bytes(&[1u8; 32]).public();
+    let other_node_id = SecretKey::from_bytes(&[2u8; 32]).public();
+    let ban_list = BanList::arc(BanPolicy {
+        max_violations: 3,
+        window: Duration::from_secs(60),
+        ban_duration: Duration::from_millis(200),
+    });
+
+    let waiter = {
+        let ban_list = ban_list.clone();
+        tokio::spawn(async move { ban_list.wait_for_ban(&node_id).await })
+    };
+
+    assert!(!ban_list.record_violation(&node_id, Violation::MalformedHandshake));
+    assert!(!ban_list.record_violation(&other_node_id, Violation::PolicyDenied));
+    assert!(!ban_list.record_violation(&node_id, Violation::HandshakeTimeout));
+    assert!(!ban_list.is_banned(&node_id));
+    assert!(ban_list.record_violation(&node_id, Violation::MalformedDatagram));
+
+    assert!(ban_list.is_banned(&node_id));
+    assert!(!ban_list.is_banned(&other_node_id));
+    assert_eq!(ban_list.banned_nodes(), vec![node_id]);
+    tokio::time::timeout(Duration::from_secs(1), waiter)
+        .await
+        .unwrap()
+        .unwrap();
+
+    tokio::time::sleep(Duration::from_millis(250)).await;
+    assert!(!ban_list.is_banned(&node_id));
+}
+
+#[tokio::test]
+async fn test_violations_expire_from_window() {
+    let node_id = SecretKey::from_bytes(&[3u8; 32]).public();
+    let ban_list = BanList::new(BanPolicy {
+        max_violations: 2,
+        window: Duration::from_millis(100),
+        ban_duration: Duration::from_secs(60),
+    });
+
+    assert!(!ban_list.record_violation(&node_id, Violation::PolicyDenied));
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    // The first violation has left the window.
+    assert!(!ban_list.record_violation(&node_id, Violation::PolicyDenied));
+    assert!(!ban_list.is_banned(&node_id));
+
+    assert!(ban_list.record_violation(&node_id, Violation::PolicyDenied));
+    assert!(ban_list.is_banned(&node_id));
+}
+
+#[tokio::test]
+async fn test_expired_ban_is_lifted_for_new_violations() {
+    let node_id = SecretKey::from_bytes(&[4u8; 32]).public();
+    let ban_list = BanList::new(BanPolicy {
+        max_violations: 2,
+        window: Duration::from_secs(60),
+        ban_duration: Duration::from_millis(100),
+    });
+
+    ban_list.ban(&node_id, Duration::from_millis(100));
+    assert_eq!(ban_list.banned_nodes(), vec![node_id]);
+    tokio::time::sleep(Duration::from_millis(150)).await;
+
+    // A violation below the threshold after the ban ran out does not renew it.
+    assert!(!ban_list.record_violation(&node_id, Violation::PolicyDenied));
+    assert!(!ban_list.is_banned(&node_id));
+    assert!(ban_list.banned_nodes().is_empty());
+
+    assert!(ban_list.record_violation(&node_id, Violation::PolicyDenied));
+    assert_eq!(ban_list.banned_nodes(), vec![node_id]);
+}
+
+fn banning_protocol(ban_list: &Arc<BanList>) -> S2pProtocol {
+    let socket_factory: Arc<dyn TransportFactory> =
+        Arc::new(MockSocketFactory::new().with_target(ECHO.parse().unwrap(), MockTarget::Echo));
+    S2pProtocol::builder()
+        .socket_factory(socket_factory)
+        .ban_list(ban_list.clone())
+        .build()
+        .unwrap()
+}
+
+async fn assert_closed_as_banned(connection: &Connection) {
+    let error = tokio::time::timeout(Duration::from_secs(5), connection.closed())
+        .await
+        .unwrap();
+    assert!(
+        matches!(&error, ConnectionError::ApplicationClosed(close) if &close.reason[..] == b"node is banned"),
+        "unexpected close: {:?}",
+        error
+    );
+}
+
+#[tokio::test]
+async fn test_ban_closes_live_connection() {
+    let ban_list = BanList::arc(BanPolicy::default());
+    let nodes = TestNodes::start(banning_protocol(&ban_list)).await.unwrap();
+
+    let mut stream = nodes
+        .tcp_client()
+        .connect(ECHO.parse().unwrap())
+        .await
+        .unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf).await.unwrap();
+
+    ban_list.ban(&nodes.client.node_id(), Duration::from_secs(60));
+    assert_closed_as_banned(&nodes.connection).await;
+
+    // New connections are declined for as long as the ban lasts.
+    let connection = nodes
+        .client
+        .connect(direct_addr(&nodes.server), ALPN_S2P_V1.as_bytes())
+        .await
+        .unwrap();
+    assert_closed_as_banned(&connection).await;
+
+    nodes.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_repeated_malformed_datagrams_ban_node() {
+    let ban_list = BanList::arc(BanPolicy {
+        max_violations: 3,
+        ..BanPolicy::default()
+    });
+    let nodes = TestNodes::start(banning_protocol(&ban_list)).await.unwrap();
+
+    for _ in 0..3 {
+        nodes
+            .connection
+            .send_datagram(Bytes::from_static(&[0xff, 0xff]))
+            .unwrap();
+    }
+    assert_closed_as_banned(&nodes.connection).await;
+    assert!(ban_list.is_banned(&nodes.client.node_id()));
+
+    nodes.shutdown().await;
+}
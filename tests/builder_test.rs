@@ -0,0 +1,125 @@
+use s2p::iroh::{
+    BuildError, InMemoryAccountingStore, ProxyTimeouts, QuotaLimits, QuotaManager,
+    TcpClientOptions, TcpClientPoolOptions, UdpSettings,
+};
+use s2p::{S2pProtocol, ALPN_S2P_V1};
+use std::time::Duration;
+
+fn invalid_field(error: BuildError) -> String {
+    match error {
+        BuildError::InvalidValue { field, .. } => field,
+        other => panic!("expected an invalid value error, got {}", other),
+    }
+}
+
+#[test]
+fn test_protocol_builder_validates_timeouts() {
+    let error = ProxyTimeouts::builder()
+        .dns_resolution_timeout(Duration::ZERO)
+        .build()
+        .unwrap_err();
+    assert_eq!(invalid_field(error), "dns_resolution_timeout");
+
+    let timeouts = ProxyTimeouts {
+        tcp_connection_timeout: Duration::ZERO,
+        ..ProxyTimeouts::default()
+    };
+    let error = S2pProtocol::with_timeouts(timeouts).unwrap_err();
+    assert_eq!(invalid_field(error), "tcp_connection_timeout");
+
+    let error = S2pProtocol::builder()
+        .udp(UdpSettings {
+            max_flows: Some(0),
+            ..UdpSettings::default()
+        })
+        .build()
+        .unwrap_err();
+    assert_eq!(invalid_field(error), "udp.max_flows");
+
+    assert!(S2pProtocol::with_timeouts(ProxyTimeouts::default()).is_ok());
+}
+
+#[test]
+fn test_protocol_builder_validates_dependencies() {
+    let error = S2pProtocol::builder()
+        .allow_relay(true)
+        .build()
+        .unwrap_err();
+    assert_eq!(invalid_field(error), "allow_relay");
+
+    let limits = QuotaLimits {
+        daily_bytes: Some(10_000),
+        monthly_bytes: Some(1_000),
+        total_bytes: None,
+    };
+    let error = S2pProtocol::builder()
+        .quota_manager(QuotaManager::arc(limits, InMemoryAccountingStore::arc()))
+        .build()
+        .unwrap_err();
+    assert_eq!(invalid_field(error), "daily_bytes");
+}
+
+#[test]
+fn test_apply_settings_validates() {
+    let protocol = S2pProtocol::new();
+    let valid = protocol.settings();
+
+    let mut settings = (*valid).clone();
+    settings.proxy_timeouts.dns_resolution_timeout = Duration::ZERO;
+    let error = protocol.apply_settings(settings).unwrap_err();
+    assert_eq!(invalid_field(error), "dns_resolution_timeout");
+
+    let mut settings = (*valid).clone();
+    settings.udp.max_flows = Some(257);
+    let error = protocol.apply_settings(settings).unwrap_err();
+    assert_eq!(invalid_field(error), "udp.max_flows");
+
+    let mut settings = (*valid).clone();
+    settings.allow_relay = true;
+    let error = protocol.apply_settings(settings).unwrap_err();
+    assert_eq!(invalid_field(error), "allow_relay");
+    assert!(!protocol.settings().allow_relay);
+
+    let mut settings = (*valid).clone();
+    settings.proxy_timeouts.tcp_connection_timeout = Duration::from_secs(1);
+    protocol.apply_settings(settings).unwrap();
+    assert_eq!(
+        protocol.settings().proxy_timeouts.tcp_connection_timeout,
+        Duration::from_secs(1)
+    );
+}
+
+#[test]
+fn test_client_options_builder() {
+    let options = TcpClientOptions::builder().build().unwrap();
+    assert_eq!(options.alpn, ALPN_S2P_V1.as_bytes());
+    assert!(options.capability.is_none());
+
+    let error = TcpClientOptions::builder()
+        .alpn(Vec::new())
+        .build()
+        .unwrap_err();
+    assert_eq!(invalid_field(error), "alpn");
+
+    let error = TcpClientOptions::builder()
+        .connect_timeout(Duration::ZERO)
+        .build()
+        .unwrap_err();
+    assert_eq!(invalid_field(error), "connect_timeout");
+
+    let error = TcpClientPoolOptions::builder()
+        .min_backoff(Duration::from_secs(120))
+        .build()
+        .unwrap_err();
+    assert_eq!(invalid_field(error), "min_backoff");
+
+    let client_options = TcpClientOptions {
+        alpn: Vec::new(),
+        ..TcpClientOptions::default()
+    };
+    let error = TcpClientPoolOptions::builder()
+        .client_options(client_options)
+        .build()
+        .unwrap_err();
+    assert_eq!(invalid_field(error), "alpn");
+}
@@ -0,0 +1,207 @@
+use ::iroh::{Endpoint, NodeId, RelayMode, SecretKey};
+use s2p::iroh::{
+    Capability, CapabilityError, CapabilitySubject, CapabilityToken, CapabilityVerifier,
+};
+use s2p::test_util::{direct_addr, loopback_endpoint};
+use s2p::{Host, TargetAddress, ALPN_S2P_V1};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+fn capability(subject: CapabilitySubject) -> Capability {
+    Capability {
+        subject,
+        allowed_targets: vec![
+            "*.corp.internal:443".parse().unwrap(),
+            "10.0.0.0/8:8000-8100".parse().unwrap(),
+        ],
+        expires_at: UNIX_EPOCH + Duration::from_secs(2_000_000_000),
+        bandwidth_class: Some("bulk".to_string()),
+    }
+}
+
+#[test]
+fn test_capability_token_round_trip_and_verify() {
+    let issuer = SecretKey::from_bytes(&[1u8; 32]);
+    let node_id = SecretKey::from_bytes(&[2u8; 32]).public();
+    let other_node_id = SecretKey::from_bytes(&[3u8; 32]).public();
+    let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+    let token =
+        CapabilityToken::issue(&issuer, capability(CapabilitySubject::Node(node_id))).unwrap();
+    let parsed: CapabilityToken = token.to_string().parse().unwrap();
+    assert_eq!(parsed, token);
+
+    let verifier = CapabilityVerifier::new(vec![issuer.public()]);
+    let verified = verifier.verify(&parsed, &node_id, now).unwrap();
+    let profile = verified.to_profile();
+    assert_eq!(profile.attribute("bandwidth_class"), Some("bulk"));
+    assert!(profile.allows_target(&TargetAddress {
+        host: Host::Domain("api.corp.internal".to_string()),
+        port: 443,
+    }));
+    assert!(!profile.allows_target(&TargetAddress {
+        host: Host::Domain("example.com".to_string()),
+        port: 443,
+    }));
+
+    assert!(matches!(
+        verifier.verify(&token, &other_node_id, now),
+        Err(CapabilityError::SubjectMismatch)
+    ));
+    assert!(matches!(
+        verifier.verify(
+            &token,
+            &node_id,
+            SystemTime::now() + Duration::from_secs(1 << 32)
+        ),
+        Err(CapabilityError::Expired)
+    ));
+
+    let untrusted = CapabilityVerifier::new(vec![SecretKey::from_bytes(&[4u8; 32]).public()]);
+    assert!(matches!(
+        untrusted.verify(&token, &node_id, now),
+        Err(CapabilityError::UntrustedIssuer(_))
+    ));
+}
+
+#[test]
+fn test_tampered_capability_token_is_rejected() {
+    let issuer = SecretKey::from_bytes(&[1u8; 32]);
+    let node_id = SecretKey::from_bytes(&[2u8; 32]).public();
+    let verifier = CapabilityVerifier::new(vec![issuer.public()]);
+
+    let mut token =
+        CapabilityToken::issue(&issuer, capability(CapabilitySubject::AnyNode)).unwrap();
+    token.capability.allowed_targets = vec!["*".parse().unwrap()];
+
+    assert!(matches!(
+        verifier.verify(&token, &node_id, SystemTime::now()),
+        Err(CapabilityError::InvalidSignature)
+    ));
+}
+
+// Presents `token` from a client node over a fresh connection and returns the client's
+// node id with the result of `verifier.accept_presented` on the server side.
+async fn present(
+    token: Option<&CapabilityToken>,
+    verifier: &CapabilityVerifier,
+) -> (NodeId, Result<Capability, CapabilityError>) {
+    present_bytes(token.map(|token| token.to_bytes().unwrap()), verifier).await
+}
+
+// Like `present`, with the raw bytes of a token, which need not be well formed.
+async fn present_bytes(
+    token: Option<Vec<u8>>,
+    verifier: &CapabilityVerifier,
+) -> (NodeId, Result<Capability, CapabilityError>) {
+    let server = Endpoint::builder()
+        .relay_mode(RelayMode::Disabled)
+        .clear_discovery()
+        .alpns(vec![ALPN_S2P_V1.as_bytes().to_vec()])
+        .bind_addr_v4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+        .bind()
+        .await
+        .unwrap();
+    let client = loopback_endpoint().await.unwrap();
+
+    let (connection, incoming) = tokio::join!(
+        client.connect(direct_addr(&server), ALPN_S2P_V1.as_bytes()),
+        async { server.accept().await.unwrap().await.unwrap() }
+    );
+    let connection = connection.unwrap();
+    if let Some(token) = token {
+        let mut send = connection.open_uni().await.unwrap();
+        send.write_all(&token).await.unwrap();
+        send.finish().unwrap();
+    }
+    let result = verifier
+        .accept_presented(&incoming, &client.node_id())
+        .await;
+
+    connection.close(0u32.into(), b"done");
+    client.close().await;
+    server.close().await;
+    (client.node_id(), result)
+}
+
+#[tokio::test]
+async fn test_accept_presented_token() {
+    let issuer = SecretKey::from_bytes(&[1u8; 32]);
+    let verifier = CapabilityVerifier::new(vec![issuer.public()]);
+    let token = CapabilityToken::issue(&issuer, capability(CapabilitySubject::AnyNode)).unwrap();
+
+    let (_, result) = present(Some(&token), &verifier).await;
+    assert_eq!(result.unwrap(), token.capability);
+}
+
+#[tokio::test]
+async fn test_accept_presented_rejects_expired_token() {
+    let issuer = SecretKey::from_bytes(&[1u8; 32]);
+    let verifier = CapabilityVerifier::new(vec![issuer.public()]);
+    let token = CapabilityToken::issue(
+        &issuer,
+        Capability {
+            expires_at: UNIX_EPOCH + Duration::from_secs(1),
+            ..capability(CapabilitySubject::AnyNode)
+        },
+    )
+    .unwrap();
+
+    let (_, result) = present(Some(&token), &verifier).await;
+    assert!(matches!(result, Err(CapabilityError::Expired)));
+}
+
+#[tokio::test]
+async fn test_accept_presented_rejects_out_of_range_expiry() {
+    let issuer = SecretKey::from_bytes(&[1u8; 32]);
+    let verifier = CapabilityVerifier::new(vec![issuer.public()]);
+    let token = CapabilityToken::issue(&issuer, capability(CapabilitySubject::AnyNode)).unwrap();
+    // The expiry follows the version and subject bytes of a token for any node.
+    let mut bytes = token.to_bytes().unwrap();
+    bytes[2..10].copy_from_slice(&u64::MAX.to_be_bytes());
+
+    let (_, result) = present_bytes(Some(bytes), &verifier).await;
+    assert!(matches!(result, Err(CapabilityError::Malformed(_))));
+}
+
+#[tokio::test]
+async fn test_accept_presented_rejects_wrong_issuer() {
+    let issuer = SecretKey::from_bytes(&[1u8; 32]);
+    let other_issuer = SecretKey::from_bytes(&[4u8; 32]);
+    let verifier = CapabilityVerifier::new(vec![issuer.public()]);
+    let token =
+        CapabilityToken::issue(&other_issuer, capability(CapabilitySubject::AnyNode)).unwrap();
+
+    let (_, result) = present(Some(&token), &verifier).await;
+    assert!(matches!(
+        result,
+        Err(CapabilityError::UntrustedIssuer(key)) if key == other_issuer.public()
+    ));
+}
+
+#[tokio::test]
+async fn test_accept_presented_rejects_wrong_subject() {
+    let issuer = SecretKey::from_bytes(&[1u8; 32]);
+    let verifier = CapabilityVerifier::new(vec![issuer.public()]);
+    // Issued to a node other than the client presenting it.
+    let subject = SecretKey::from_bytes(&[2u8; 32]).public();
+    let token =
+        CapabilityToken::issue(&issuer, capability(CapabilitySubject::Node(subject))).unwrap();
+
+    let (client, result) = present(Some(&token), &verifier).await;
+    assert_ne!(client, subject);
+    assert!(matches!(result, Err(CapabilityError::SubjectMismatch)));
+}
+
+#[tokio::test]
+async fn test_accept_presented_times_out_without_token() {
+    let issuer = SecretKey::from_bytes(&[1u8; 32]);
+    let verifier = CapabilityVerifier::new(vec![issuer.public()])
+        .with_presentation_timeout(Duration::from_millis(100));
+
+    let (_, result) = present(None, &verifier).await;
+    assert!(matches!(
+        result,
+        Err(CapabilityError::Io(e)) if e.kind() == std::io::ErrorKind::TimedOut
+    ));
+}
@@ -0,0 +1,47 @@
+use bytes::BytesMut;
+use s2p::codec::{CodecError, TcpConnectRequestCodec, TcpConnectResponseCodec, UdpDatagramCodec};
+use std::fmt::Debug;
+use std::path::Path;
+use tokio_util::codec::Decoder;
+
+// Replays the fuzz corpus. File names start with the expected outcome: `ok`, `partial`
+// or the name of the `CodecError` variant the input must be rejected with.
+fn replay<D>(target: &str, mut decoder: D)
+where
+    D: Decoder<Error = CodecError>,
+    D::Item: Debug,
+{
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("fuzz/corpus")
+        .join(target);
+    let mut replayed = 0;
+    for entry in std::fs::read_dir(&dir).unwrap() {
+        let path = entry.unwrap().path();
+        let name = path.file_name().unwrap().to_str().unwrap().to_string();
+        let expected = name.split('-').next().unwrap();
+        let mut buf = BytesMut::from(&std::fs::read(&path).unwrap()[..]);
+
+        match (expected, decoder.decode(&mut buf)) {
+            ("ok", Ok(Some(_))) | ("partial", Ok(None)) => {}
+            (expected, Err(e)) if format!("{:?}", e).starts_with(expected) => {}
+            (_, outcome) => panic!("{}/{}: unexpected outcome {:?}", target, name, outcome),
+        }
+        replayed += 1;
+    }
+    assert!(replayed > 0, "no corpus in {:?}", dir);
+}
+
+#[test]
+fn test_tcp_connect_request_corpus() {
+    replay("tcp_connect_request", TcpConnectRequestCodec);
+}
+
+#[test]
+fn test_tcp_connect_response_corpus() {
+    replay("tcp_connect_response", TcpConnectResponseCodec);
+}
+
+#[test]
+fn test_udp_datagram_corpus() {
+    replay("udp_datagram", UdpDatagramCodec);
+}
@@ -0,0 +1,137 @@
+use ::iroh::SecretKey;
+use bytes::BytesMut;
+use proptest::prelude::*;
+use s2p::codec::{TcpConnectRequestCodec, TcpConnectResponseCodec, UdpDatagramCodec};
+use s2p::message_types::{
+    ConnectStatusCode, Host, TargetAddress, TcpConnectRequest, TcpConnectResponse, UdpDatagram,
+};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use tokio_util::codec::{Decoder, Encoder};
+
+// Strings of at most 255 bytes, the longest the length prefix allows.
+fn short_string() -> impl Strategy<Value = String> {
+    "\\PC{0,80}".prop_filter("longer than 255 bytes", |s| s.len() <= 255)
+}
+
+fn host() -> impl Strategy<Value = Host> {
+    prop_oneof![
+        any::<u32>().prop_map(|ip| Host::IPv4(Ipv4Addr::from(ip))),
+        any::<u128>().prop_map(|ip| Host::IPv6(Ipv6Addr::from(ip))),
+        short_string().prop_map(Host::Domain),
+        any::<[u8; 32]>().prop_map(|key| Host::Node(SecretKey::from_bytes(&key).public())),
+        short_string().prop_map(Host::Service),
+        short_string().prop_map(|path| Host::UnixSocket(path.into())),
+    ]
+}
+
+fn target() -> impl Strategy<Value = TargetAddress> {
+    (host(), any::<u16>()).prop_map(|(host, port)| TargetAddress { host, port })
+}
+
+fn status() -> impl Strategy<Value = ConnectStatusCode> {
+    (0u8..=7).prop_map(|code| ConnectStatusCode::try_from(code).unwrap())
+}
+
+fn encode_request(target: &TargetAddress) -> BytesMut {
+    let mut buf = BytesMut::new();
+    TcpConnectRequestCodec
+        .encode(
+            TcpConnectRequest {
+                target: target.clone(),
+            },
+            &mut buf,
+        )
+        .unwrap();
+    buf
+}
+
+proptest! {
+    #[test]
+    fn request_round_trip(target in target()) {
+        let mut buf = encode_request(&target);
+        let request = TcpConnectRequestCodec.decode(&mut buf).unwrap().unwrap();
+        prop_assert_eq!(request.target, target);
+        prop_assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn request_decodes_byte_by_byte(targets in prop::collection::vec(target(), 1..4)) {
+        let encoded: Vec<u8> = targets.iter().flat_map(|t| encode_request(t).to_vec()).collect();
+        let mut buf = BytesMut::new();
+        let mut decoded = Vec::new();
+        for byte in encoded {
+            buf.extend_from_slice(&[byte]);
+            if let Some(request) = TcpConnectRequestCodec.decode(&mut buf).unwrap() {
+                decoded.push(request.target);
+                prop_assert!(buf.is_empty());
+            }
+        }
+        prop_assert_eq!(decoded, targets);
+    }
+
+    #[test]
+    fn response_round_trip(statuses in prop::collection::vec(status(), 1..8)) {
+        let mut buf = BytesMut::new();
+        for status in &statuses {
+            TcpConnectResponseCodec.encode(TcpConnectResponse::new(*status), &mut buf).unwrap();
+        }
+        let mut decoded = Vec::new();
+        while let Some(response) = TcpConnectResponseCodec.decode(&mut buf).unwrap() {
+            decoded.push(response.status);
+        }
+        prop_assert_eq!(decoded, statuses);
+    }
+
+    #[test]
+    fn datagram_round_trip(
+        flow_id in any::<u8>(),
+        target in target(),
+        data in prop::collection::vec(any::<u8>(), 0..512),
+    ) {
+        let datagram = UdpDatagram { flow_id, target, data };
+        let mut buf = BytesMut::new();
+        UdpDatagramCodec.encode(datagram.clone(), &mut buf).unwrap();
+        prop_assert_eq!(UdpDatagramCodec.decode(&mut buf).unwrap(), Some(datagram));
+        prop_assert!(buf.is_empty());
+    }
+
+    // A datagram cut inside its header is reported as incomplete and left untouched.
+    #[test]
+    fn datagram_truncated_header(flow_id in any::<u8>(), target in target()) {
+        let mut encoded = BytesMut::new();
+        UdpDatagramCodec
+            .encode(UdpDatagram { flow_id, target, data: Vec::new() }, &mut encoded)
+            .unwrap();
+        for len in 0..encoded.len() {
+            let mut buf = BytesMut::from(&encoded[..len]);
+            prop_assert_eq!(UdpDatagramCodec.decode(&mut buf).unwrap(), None);
+            prop_assert_eq!(buf.len(), len);
+        }
+    }
+
+    // Arbitrary input never panics, and whatever decodes encodes back to as many bytes as
+    // were consumed.
+    #[test]
+    fn request_decodes_arbitrary_input(data in prop::collection::vec(any::<u8>(), 0..300)) {
+        let mut buf = BytesMut::from(&data[..]);
+        loop {
+            let before = buf.len();
+            match TcpConnectRequestCodec.decode(&mut buf) {
+                Ok(Some(request)) => {
+                    prop_assert_eq!(encode_request(&request.target).len(), before - buf.len());
+                }
+                _ => break,
+            }
+        }
+    }
+
+    #[test]
+    fn datagram_decodes_arbitrary_input(data in prop::collection::vec(any::<u8>(), 0..300)) {
+        let mut buf = BytesMut::from(&data[..]);
+        if let Ok(Some(datagram)) = UdpDatagramCodec.decode(&mut buf) {
+            let mut encoded = BytesMut::new();
+            UdpDatagramCodec.encode(datagram, &mut encoded).unwrap();
+            prop_assert_eq!(encoded.len(), data.len());
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use ::iroh::SecretKey;
+use bytes::BytesMut;
+use s2p::codec::{TcpConnectRequestCodec, UdpDatagramCodec};
+use s2p::message_types::{Host, TargetAddress};
+use s2p::{TcpConnectRequest, UdpDatagram};
+use tokio_util::codec::{Decoder, Encoder};
+
+#[test]
+fn test_node_address_round_trip() {
+    let target = TargetAddress {
+        host: Host::Node(SecretKey::from_bytes(&[1u8; 32]).public()),
+        port: 0,
+    };
+
+    let mut buf = BytesMut::new();
+    TcpConnectRequestCodec
+        .encode(
+            TcpConnectRequest {
+                target: target.clone(),
+            },
+            &mut buf,
+        )
+        .unwrap();
+    let mut partial = buf.split_to(10);
+    assert!(TcpConnectRequestCodec
+        .decode(&mut partial)
+        .unwrap()
+        .is_none());
+    partial.unsplit(buf);
+    let request = TcpConnectRequestCodec
+        .decode(&mut partial)
+        .unwrap()
+        .unwrap();
+    assert_eq!(request.target, target);
+    assert!(partial.is_empty());
+
+    let datagram = UdpDatagram {
+        flow_id: 7,
+        target,
+        data: b"payload".to_vec(),
+    };
+    let mut buf = BytesMut::new();
+    UdpDatagramCodec.encode(datagram.clone(), &mut buf).unwrap();
+    assert_eq!(UdpDatagramCodec.decode(&mut buf).unwrap(), Some(datagram));
+}
+
+#[test]
+fn test_service_address_round_trip() {
+    let target = TargetAddress {
+        host: Host::Service("postgres".to_string()),
+        port: 0,
+    };
+
+    let mut buf = BytesMut::new();
+    TcpConnectRequestCodec
+        .encode(
+            TcpConnectRequest {
+                target: target.clone(),
+            },
+            &mut buf,
+        )
+        .unwrap();
+    let request = TcpConnectRequestCodec.decode(&mut buf).unwrap().unwrap();
+    assert_eq!(request.target, target);
+    assert!(buf.is_empty());
+
+    let pattern: s2p::target_pattern::TargetPattern = "service:post*".parse().unwrap();
+    assert!(pattern.matches(&target));
+    assert_eq!(pattern.to_string(), "service:post*");
+}
+
+#[test]
+fn test_target_address_from_str() {
+    let v4: TargetAddress = "127.0.0.1:8080".parse().unwrap();
+    assert_eq!(v4.host, Host::IPv4("127.0.0.1".parse().unwrap()));
+    assert_eq!(v4.port, 8080);
+
+    let v6: TargetAddress = "[::1]:443".parse().unwrap();
+    assert_eq!(v6.host, Host::IPv6("::1".parse().unwrap()));
+
+    let domain: TargetAddress = "example.com:22".parse().unwrap();
+    assert_eq!(domain.host, Host::Domain("example.com".to_string()));
+
+    assert!("example.com".parse::<TargetAddress>().is_err());
+    assert!("::1:443".parse::<TargetAddress>().is_err());
+    assert!("example.com:99999".parse::<TargetAddress>().is_err());
+}
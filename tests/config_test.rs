@@ -0,0 +1,211 @@
+use s2p::iroh::{
+    AuthenticatorConfig, BuildError, ConfigError, DnsResolver, S2pConfig, ServiceTarget,
+    StaticDnsResolver,
+};
+use s2p::{S2pProtocol, TargetAddress};
+use std::collections::HashMap;
+use std::time::Duration;
+
+const CONFIG: &str = r#"
+[timeouts]
+tcp_connection_secs = 3
+handshake_secs = 12
+
+[udp]
+flow_idle_timeout_secs = 20
+
+[authenticator]
+type = "nodes"
+nodes = []
+
+[dns]
+resolver = "static"
+hosts = { "db.internal" = ["10.0.0.5"] }
+
+[socket]
+nodelay = true
+dscp = 46
+
+[policy]
+allow_targets = ["*.example.com:443", "10.0.0.0/8"]
+deny_targets = ["10.0.0.1"]
+unix_sockets = ["/run/app"]
+
+[limits]
+daily_bytes = 1000
+max_udp_flows = 16
+
+[[services]]
+name = "db"
+target = "db.internal:5432"
+"#;
+
+fn invalid_key(error: ConfigError) -> String {
+    match error {
+        ConfigError::InvalidValue { key, .. } => key,
+        other => panic!("expected an invalid value error, got {}", other),
+    }
+}
+
+#[test]
+fn test_parse_toml_config() {
+    let config = S2pConfig::from_toml(CONFIG).unwrap();
+    assert_eq!(
+        config.authenticator,
+        AuthenticatorConfig::Nodes { nodes: Vec::new() }
+    );
+
+    let timeouts = config.proxy_timeouts().unwrap();
+    assert_eq!(timeouts.tcp_connection_timeout, Duration::from_secs(3));
+    assert_eq!(timeouts.dns_resolution_timeout, Duration::from_secs(5));
+    assert_eq!(
+        timeouts.tcp_proxy_handshake_timeout,
+        Duration::from_secs(12)
+    );
+
+    let udp = config.udp_settings().unwrap();
+    assert_eq!(udp.flow_idle_timeout, Duration::from_secs(20));
+    assert_eq!(udp.max_flows, Some(16));
+
+    let policy = config.target_policy().unwrap();
+    assert!(policy.allows(&"api.example.com:443".parse().unwrap()));
+    assert!(policy.allows(&"10.1.2.3:80".parse().unwrap()));
+    assert!(!policy.allows(&"10.0.0.1:80".parse().unwrap()));
+    assert!(!policy.allows(&"api.example.com:80".parse().unwrap()));
+
+    let options = config.socket_options().unwrap();
+    assert_eq!(options.nodelay, Some(true));
+    assert_eq!(options.dscp, Some(46));
+
+    assert_eq!(
+        config.services().unwrap(),
+        vec![(
+            "db".to_string(),
+            ServiceTarget::Address("db.internal:5432".parse().unwrap())
+        )]
+    );
+}
+
+#[test]
+fn test_parse_json_config() {
+    let config = S2pConfig::from_json(
+        r#"{"timeouts": {"dns_resolution_secs": 2}, "policy": {"allow_relay": true}}"#,
+    )
+    .unwrap();
+    assert!(config.policy.allow_relay);
+    assert_eq!(
+        config.proxy_timeouts().unwrap().dns_resolution_timeout,
+        Duration::from_secs(2)
+    );
+}
+
+#[test]
+fn test_invalid_config_names_key() {
+    let error = S2pConfig::from_toml("[timeouts]\ntcp_connection_secs = 0").unwrap_err();
+    assert_eq!(invalid_key(error), "timeouts.tcp_connection_secs");
+
+    let error = S2pConfig::from_toml("[policy]\ndeny_targets = [\"*:1-\"]").unwrap_err();
+    assert_eq!(invalid_key(error), "policy.deny_targets[0]");
+
+    let error = S2pConfig::from_toml("[socket]\ndscp = 64").unwrap_err();
+    assert_eq!(invalid_key(error), "socket.dscp");
+
+    let error = S2pConfig::from_toml(
+        "[[services]]\nname = \"a\"\ntarget = \"a:1\"\n[[services]]\nname = \"b\"\ntarget = \"unix:run/b\"",
+    )
+    .unwrap_err();
+    assert_eq!(invalid_key(error), "services[1].target");
+
+    let error =
+        S2pConfig::from_toml("[authenticator]\ntype = \"nodes\"\nnodes = [\"nope\"]").unwrap_err();
+    assert_eq!(invalid_key(error), "authenticator.nodes[0]");
+
+    let error = S2pConfig::from_toml("[limits]\npersist_interval_secs = 0").unwrap_err();
+    assert_eq!(invalid_key(error), "limits.persist_interval_secs");
+
+    let error = S2pConfig::from_toml("[timeouts]\ntcp_connect_secs = 3").unwrap_err();
+    assert!(matches!(error, ConfigError::Toml(_)));
+    assert!(error.to_string().contains("tcp_connect_secs"));
+}
+
+#[tokio::test]
+async fn test_apply_config_to_running_protocol() {
+    let protocol = S2pProtocol::from_config(&S2pConfig::from_toml(CONFIG).unwrap())
+        .await
+        .unwrap();
+    let running = protocol.clone();
+    assert_eq!(
+        running.settings().proxy_timeouts.tcp_connection_timeout,
+        Duration::from_secs(3)
+    );
+
+    let updated = S2pConfig::from_toml(
+        r#"
+        [timeouts]
+        tcp_connection_secs = 7
+
+        [policy]
+        deny_targets = ["*.example.com"]
+
+        [[services]]
+        name = "cache"
+        target = "unix:/run/cache.sock"
+        "#,
+    )
+    .unwrap();
+    protocol.apply_config(&updated).unwrap();
+
+    let settings = running.settings();
+    assert_eq!(
+        settings.proxy_timeouts.tcp_connection_timeout,
+        Duration::from_secs(7)
+    );
+    assert!(!settings
+        .target_policy
+        .allows(&"api.example.com:443".parse::<TargetAddress>().unwrap()));
+    let registry = running.service_registry.as_ref().unwrap();
+    assert_eq!(registry.names(), vec!["cache".to_string()]);
+
+    let invalid = S2pConfig {
+        limits: s2p::iroh::LimitsConfig {
+            max_udp_flows: Some(0),
+            ..Default::default()
+        },
+        ..updated
+    };
+    assert_eq!(
+        invalid_key(protocol.apply_config(&invalid).unwrap_err()),
+        "limits.max_udp_flows"
+    );
+
+    // Relaying needs an endpoint, which a protocol built from config does not have.
+    let mut relaying = S2pConfig::from_toml(
+        r#"
+        [[services]]
+        name = "db"
+        target = "127.0.0.1:5432"
+        "#,
+    )
+    .unwrap();
+    relaying.policy.allow_relay = true;
+    assert!(matches!(
+        protocol.apply_config(&relaying).unwrap_err(),
+        ConfigError::Build(BuildError::InvalidValue { field, .. }) if field == "allow_relay"
+    ));
+    assert_eq!(registry.names(), vec!["cache".to_string()]);
+    assert_eq!(
+        running.settings().proxy_timeouts.tcp_connection_timeout,
+        Duration::from_secs(7)
+    );
+}
+
+#[tokio::test]
+async fn test_static_dns_resolver() {
+    let hosts = HashMap::from([("DB.internal".to_string(), vec!["10.0.0.5".parse().unwrap()])]);
+    let resolver = StaticDnsResolver::new(hosts, None);
+    assert_eq!(
+        resolver.lookup_host("db.internal:5432").await.unwrap(),
+        vec!["10.0.0.5".parse::<std::net::IpAddr>().unwrap()]
+    );
+    assert!(resolver.lookup_host("other.internal:80").await.is_err());
+}
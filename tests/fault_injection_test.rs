@@ -0,0 +1,193 @@
+use s2p::iroh::{DnsResolver, ProxyTimeouts, S2pProtocol, TransportFactory};
+use s2p::message_types::{ConnectStatusCode, TargetAddress};
+use s2p::test_util::{
+    assert_connect_status, udp_round_trip, ConnectFault, DatagramFaults, DnsFault,
+    FaultyDnsResolver, FaultySocketFactory, MockDnsResolver, MockSocketFactory, MockTarget,
+    TestNodes,
+};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const TARGET: &str = "192.0.2.40:80";
+
+fn protocol(factory: FaultySocketFactory, resolver: FaultyDnsResolver) -> S2pProtocol {
+    let socket_factory: Arc<dyn TransportFactory> = Arc::new(factory);
+    let dns_resolver: Arc<dyn DnsResolver> = Arc::new(resolver);
+    S2pProtocol::builder()
+        .socket_factory(socket_factory)
+        .dns_resolver(dns_resolver)
+        .proxy_timeouts(
+            ProxyTimeouts::builder()
+                .tcp_connection_timeout(Duration::from_millis(200))
+                .dns_resolution_timeout(Duration::from_millis(200))
+                .build()
+                .unwrap(),
+        )
+        .build()
+        .unwrap()
+}
+
+fn echo_factory() -> FaultySocketFactory {
+    let mock = MockSocketFactory::new().with_target(TARGET.parse().unwrap(), MockTarget::Echo);
+    FaultySocketFactory::new(Arc::new(mock))
+}
+
+fn resolver() -> FaultyDnsResolver {
+    let target: SocketAddr = TARGET.parse().unwrap();
+    let mock = MockDnsResolver::new()
+        .with_host("nxdomain.test", vec![target.ip()])
+        .with_host("timeout.test", vec![target.ip()])
+        .with_host("empty.test", vec![target.ip()])
+        .with_host("slow.test", vec![target.ip()]);
+    FaultyDnsResolver::new(Arc::new(mock))
+        .with_fault("nxdomain.test", DnsFault::NxDomain)
+        .with_fault("timeout.test", DnsFault::Timeout)
+        .with_fault("empty.test", DnsFault::Empty)
+        .with_fault("slow.test", DnsFault::Slow(Duration::from_millis(20)))
+}
+
+#[tokio::test]
+async fn test_connect_faults_map_to_status() {
+    let addr: SocketAddr = TARGET.parse().unwrap();
+    let factory = echo_factory().with_faults_once(
+        addr,
+        [
+            ConnectFault::Refused,
+            ConnectFault::TimedOut,
+            ConnectFault::Unreachable,
+            ConnectFault::Hang,
+            ConnectFault::Slow(Duration::from_secs(1)),
+            ConnectFault::Slow(Duration::from_millis(20)),
+        ],
+    );
+    let nodes = TestNodes::start(protocol(factory, resolver()))
+        .await
+        .unwrap();
+
+    for expected in [
+        ConnectStatusCode::ConnectionRefused,
+        ConnectStatusCode::TTLExpired,
+        ConnectStatusCode::HostUnreachable,
+        ConnectStatusCode::TTLExpired,
+        ConnectStatusCode::TTLExpired,
+        ConnectStatusCode::Success,
+        ConnectStatusCode::Success,
+    ] {
+        assert_connect_status(&nodes.connection, TARGET.parse().unwrap(), expected).await;
+    }
+
+    nodes.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_dns_faults_map_to_status() {
+    let nodes = TestNodes::start(protocol(echo_factory(), resolver()))
+        .await
+        .unwrap();
+
+    for (name, expected) in [
+        ("nxdomain.test", ConnectStatusCode::HostUnreachable),
+        ("timeout.test", ConnectStatusCode::HostUnreachable),
+        ("empty.test", ConnectStatusCode::HostUnreachable),
+        ("slow.test", ConnectStatusCode::Success),
+    ] {
+        let target: TargetAddress = format!("{}:80", name).parse().unwrap();
+        assert_connect_status(&nodes.connection, target, expected).await;
+    }
+
+    nodes.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_client_recovers_from_reset() {
+    let factory =
+        echo_factory().with_faults_once(TARGET.parse().unwrap(), [ConnectFault::ResetAfter(4)]);
+    let nodes = TestNodes::start(protocol(factory, resolver()))
+        .await
+        .unwrap();
+    let client = nodes.tcp_client();
+
+    let mut stream = client.connect(TARGET.parse().unwrap()).await.unwrap();
+    stream.write_all(b"hello").await.unwrap();
+    let mut received = Vec::new();
+    let _ = stream.read_to_end(&mut received).await;
+    assert!(b"hell".starts_with(&received));
+
+    let mut stream = client.connect(TARGET.parse().unwrap()).await.unwrap();
+    stream.write_all(b"hello").await.unwrap();
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello");
+
+    nodes.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_udp_flow_survives_datagram_faults() {
+    let factory = echo_factory().with_datagram_faults(DatagramFaults {
+        drop_every: Some(2),
+        duplicate_every: Some(3),
+        reorder_pairs: false,
+    });
+    let nodes = TestNodes::start(protocol(factory, resolver()))
+        .await
+        .unwrap();
+    let wait = Duration::from_millis(500);
+    let target: TargetAddress = TARGET.parse().unwrap();
+
+    let first = udp_round_trip(&nodes.connection, 1, target.clone(), b"one", wait)
+        .await
+        .unwrap();
+    assert_eq!(first.data, b"one");
+
+    // The second reply is dropped; the flow keeps working afterwards.
+    assert!(
+        udp_round_trip(&nodes.connection, 1, target.clone(), b"two", wait)
+            .await
+            .is_err()
+    );
+    let third = udp_round_trip(&nodes.connection, 1, target.clone(), b"three", wait)
+        .await
+        .unwrap();
+    assert_eq!(third.data, b"three");
+    let duplicate = tokio::time::timeout(wait, nodes.connection.read_datagram())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(duplicate.ends_with(b"three"));
+
+    nodes.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_udp_reordered_datagrams() {
+    let factory = echo_factory().with_datagram_faults(DatagramFaults {
+        reorder_pairs: true,
+        ..DatagramFaults::default()
+    });
+    let nodes = TestNodes::start(protocol(factory, resolver()))
+        .await
+        .unwrap();
+    let wait = Duration::from_millis(500);
+    let target: TargetAddress = TARGET.parse().unwrap();
+
+    // The reply to "a" is held back until "b" has been answered.
+    assert!(
+        udp_round_trip(&nodes.connection, 2, target.clone(), b"a", wait)
+            .await
+            .is_err()
+    );
+    let reply = udp_round_trip(&nodes.connection, 2, target, b"b", wait)
+        .await
+        .unwrap();
+    assert_eq!(reply.data, b"b");
+    let held = tokio::time::timeout(wait, nodes.connection.read_datagram())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(held.ends_with(b"a"));
+
+    nodes.shutdown().await;
+}
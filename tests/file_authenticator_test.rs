@@ -0,0 +1,105 @@
+use ::iroh::{NodeId, SecretKey};
+use s2p::iroh::{parse_allowlist, AuthContext, FileNodeAuthenticator, NodeAuthenticator};
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+#[test]
+fn test_parse_plain_and_toml_allowlists() {
+    let node_a = SecretKey::from_bytes(&[1u8; 32]).public();
+    let node_b = SecretKey::from_bytes(&[2u8; 32]).public();
+
+    let plain = format!(
+        "# allowed clients\n{} label=laptop\n{} expires_at=1700000000\n",
+        node_a, node_b
+    );
+    let entries = parse_allowlist(Path::new("allowlist.txt"), &plain).unwrap();
+    assert_eq!(entries[&node_a].label.as_deref(), Some("laptop"));
+    assert_eq!(
+        entries[&node_b].expires_at,
+        Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+    );
+
+    let toml = format!(
+        "[[nodes]]\nid = \"{}\"\nlabel = \"laptop\"\n\n[[nodes]]\nid = \"{}\"\n",
+        node_a, node_b
+    );
+    let entries = parse_allowlist(Path::new("allowlist.toml"), &toml).unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[&node_b].label, None);
+
+    assert!(parse_allowlist(Path::new("allowlist.txt"), "not-a-node-id\n").is_err());
+    let out_of_range = format!("{} expires_at={}\n", node_a, u64::MAX);
+    assert!(parse_allowlist(Path::new("allowlist.txt"), &out_of_range).is_err());
+    let duplicate = format!("{}\n{}\n", node_a, node_a);
+    assert!(parse_allowlist(Path::new("allowlist.txt"), &duplicate).is_err());
+}
+
+#[tokio::test]
+async fn test_malformed_reload_keeps_last_good_allowlist() {
+    let node_a = SecretKey::from_bytes(&[1u8; 32]).public();
+    let node_b = SecretKey::from_bytes(&[2u8; 32]).public();
+    let path = std::env::temp_dir().join(format!("s2p-allowlist-{}.txt", std::process::id()));
+    std::fs::write(&path, format!("{} label=laptop\n", node_a)).unwrap();
+
+    let authenticator = FileNodeAuthenticator::open(&path).await.unwrap();
+    assert!(authenticator.should_accept(&node_a).await);
+    assert!(!authenticator.should_accept(&node_b).await);
+
+    std::fs::write(&path, format!("{} expires_at=soon\n", node_b)).unwrap();
+    assert!(authenticator.reload().await.is_err());
+    assert!(authenticator.should_accept(&node_a).await);
+
+    std::fs::write(&path, format!("{}\n", node_b)).unwrap();
+    authenticator.reload().await.unwrap();
+    assert!(!authenticator.should_accept(&node_a).await);
+    let decision = authenticator
+        .authenticate(&AuthContext::new(node_b, b"s2p/1".to_vec()))
+        .await;
+    assert!(decision.is_accept());
+
+    let _ = std::fs::remove_file(&path);
+}
+
+async fn wait_until_accepted(authenticator: &FileNodeAuthenticator, node_id: &NodeId) {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !authenticator.should_accept(node_id).await {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_watch_picks_up_rewrites() {
+    let node_a = SecretKey::from_bytes(&[1u8; 32]).public();
+    let node_b = SecretKey::from_bytes(&[2u8; 32]).public();
+    let path = std::env::temp_dir().join(format!("s2p-allowlist-watch-{}.txt", std::process::id()));
+    std::fs::write(&path, format!("{}\n", node_a)).unwrap();
+    let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+
+    let authenticator = FileNodeAuthenticator::open(&path).await.unwrap();
+    authenticator.watch(Duration::from_millis(20));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // Same length and modification time as before; only the contents differ.
+    std::fs::write(&path, format!("{}\n", node_b)).unwrap();
+    std::fs::File::options()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_modified(modified)
+        .unwrap();
+    wait_until_accepted(&authenticator, &node_b).await;
+    assert!(!authenticator.should_accept(&node_a).await);
+
+    // A broken update is skipped and the next good one applies.
+    std::fs::write(&path, format!("{} expires_at=soon\n", node_a)).unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(authenticator.should_accept(&node_b).await);
+    std::fs::write(&path, format!("{} label=laptop\n", node_a)).unwrap();
+    wait_until_accepted(&authenticator, &node_a).await;
+    assert!(!authenticator.should_accept(&node_b).await);
+
+    let _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,45 @@
+use ::iroh::{NodeAddr, SecretKey};
+use iroh_base::ticket::NodeTicket;
+use s2p::iroh::{load_or_generate_secret_key, parse_node_addr, IdentityError};
+
+#[test]
+fn test_secret_key_persists() {
+    let path = std::env::temp_dir().join(format!("s2p-secret-key-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let generated = load_or_generate_secret_key(&path).unwrap();
+    let loaded = load_or_generate_secret_key(&path).unwrap();
+    assert_eq!(generated.public(), loaded.public());
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(matches!(
+            load_or_generate_secret_key(&path),
+            Err(IdentityError::InsecurePermissions(_))
+        ));
+    }
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_parse_node_addr() {
+    let node_id = SecretKey::from_bytes(&[7u8; 32]).public();
+    let addr = NodeAddr::from_parts(node_id, None, vec!["192.0.2.1:4433".parse().unwrap()]);
+
+    let ticket = NodeTicket::new(addr.clone()).to_string();
+    assert_eq!(parse_node_addr(&ticket).unwrap(), addr);
+    assert_eq!(
+        parse_node_addr(&node_id.to_string()).unwrap(),
+        NodeAddr::new(node_id)
+    );
+    assert!(matches!(
+        parse_node_addr("not a ticket"),
+        Err(IdentityError::InvalidTicket(_))
+    ));
+}
@@ -1,65 +1,157 @@
-use ::iroh::endpoint::TransportConfig;
-use ::iroh::protocol::Router;
-use ::iroh::Endpoint;
-use n0_future::{SinkExt, StreamExt};
-use s2p::codec::{TcpConnectRequestCodec, TcpConnectResponseCodec};
-use s2p::iroh::{S2pProtocol, ALPN_S2P_V1};
-use s2p::message_types::{Host, TargetAddress};
-use s2p::TcpConnectRequest;
-use std::net::Ipv4Addr;
-use std::thread::sleep;
+use s2p::iroh::{
+    DnsResolver, ProxyTimeouts, S2pProtocol, S2pProtocolBuilder, TargetPolicy, TransportFactory,
+};
+use s2p::message_types::{ConnectStatusCode, TargetAddress};
+use s2p::test_util::{
+    assert_connect_status, udp_round_trip, MockDnsResolver, MockSocketFactory, MockTarget,
+    TestNodes,
+};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::Duration;
-use tokio_util::codec::{FramedRead, FramedWrite};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+fn builder(factory: &MockSocketFactory, resolver: MockDnsResolver) -> S2pProtocolBuilder {
+    let socket_factory: Arc<dyn TransportFactory> = Arc::new(factory.clone());
+    let dns_resolver: Arc<dyn DnsResolver> = Arc::new(resolver);
+    let mut builder = S2pProtocol::builder();
+    builder
+        .socket_factory(socket_factory)
+        .dns_resolver(dns_resolver)
+        .proxy_timeouts(
+            ProxyTimeouts::builder()
+                .tcp_connection_timeout(Duration::from_millis(200))
+                .build()
+                .unwrap(),
+        );
+    builder
+}
+
+fn protocol(factory: &MockSocketFactory, resolver: MockDnsResolver) -> S2pProtocol {
+    builder(factory, resolver).build().unwrap()
+}
 
 #[tokio::test]
-async fn test_s2p_protocol() {
-    env_logger::init();
-    let client_endp = Endpoint::builder()
-        .transport_config(TransportConfig::default())
-        .discovery_n0()
-        .bind()
+async fn test_tcp_proxy_round_trip() {
+    let echo: SocketAddr = "192.0.2.10:7".parse().unwrap();
+    let factory = MockSocketFactory::new().with_target(echo, MockTarget::Greeting(b"hi ".to_vec()));
+    let resolver = MockDnsResolver::new().with_host("echo.test", vec![echo.ip()]);
+    let nodes = TestNodes::start(protocol(&factory, resolver))
         .await
         .unwrap();
-    let server_endp = Endpoint::builder().discovery_n0().bind().await.unwrap();
-
-    let server_node_id = server_endp.node_id().clone();
 
-    let router = Router::builder(server_endp)
-        .accept(ALPN_S2P_V1, S2pProtocol::new())
-        .spawn();
+    let mut stream = nodes
+        .tcp_client()
+        .connect("echo.test:7".parse().unwrap())
+        .await
+        .unwrap();
+    stream.write_all(b"hello").await.unwrap();
+    let mut buf = [0u8; 8];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hi hello");
+    assert_eq!(factory.connections(), vec![echo]);
 
-    sleep(Duration::from_secs(10));
+    nodes.shutdown().await;
+}
 
-    let connection = client_endp
-        .connect(server_node_id, ALPN_S2P_V1.as_ref())
+#[tokio::test]
+async fn test_tcp_connect_failures() {
+    let refused: SocketAddr = "192.0.2.20:80".parse().unwrap();
+    let hanging: SocketAddr = "192.0.2.21:80".parse().unwrap();
+    let factory = MockSocketFactory::new()
+        .with_target(refused, MockTarget::Fail(io::ErrorKind::ConnectionRefused))
+        .with_target(hanging, MockTarget::Hang);
+    let resolver = MockDnsResolver::new().with_failure("broken.test", io::ErrorKind::Other);
+    let nodes = TestNodes::start(protocol(&factory, resolver))
         .await
         .unwrap();
 
-    let (writer, reader) = connection.open_bi().await.unwrap();
-    let mut framed_writer = FramedWrite::new(writer, TcpConnectRequestCodec);
-    framed_writer
-        .send(TcpConnectRequest {
-            target: TargetAddress {
-                host: Host::IPv4(Ipv4Addr::new(127, 0, 0, 1)),
-                port: 1234,
-            },
-        })
+    let target = |s: &str| s.parse::<TargetAddress>().unwrap();
+    assert_connect_status(
+        &nodes.connection,
+        target("192.0.2.20:80"),
+        ConnectStatusCode::ConnectionRefused,
+    )
+    .await;
+    assert_connect_status(
+        &nodes.connection,
+        target("192.0.2.21:80"),
+        ConnectStatusCode::TTLExpired,
+    )
+    .await;
+    assert_connect_status(
+        &nodes.connection,
+        target("broken.test:80"),
+        ConnectStatusCode::HostUnreachable,
+    )
+    .await;
+
+    nodes.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_udp_proxy_round_trip() {
+    let echo: SocketAddr = "192.0.2.30:53".parse().unwrap();
+    let factory = MockSocketFactory::new().with_target(echo, MockTarget::Echo);
+    let nodes = TestNodes::start(protocol(&factory, MockDnsResolver::new()))
         .await
         .unwrap();
 
-    let mut framed_reader = FramedRead::new(reader, TcpConnectResponseCodec);
-    let option = framed_reader.next().await;
-    println!("Got response kek {:?}", option);
+    let target: TargetAddress = "192.0.2.30:53".parse().unwrap();
+    let reply = udp_round_trip(
+        &nodes.connection,
+        3,
+        target.clone(),
+        b"ping",
+        Duration::from_secs(5),
+    )
+    .await
+    .unwrap();
+    assert_eq!(reply.flow_id, 3);
+    assert_eq!(reply.data, b"ping");
+    assert_eq!(factory.datagrams(), vec![(echo, b"ping".to_vec())]);
+
+    nodes.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_policy_applies_to_resolved_addresses() {
+    let loopback: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+    let factory = MockSocketFactory::new().with_target(loopback, MockTarget::Echo);
+    let resolver = MockDnsResolver::new()
+        .with_host("internal.test", vec![loopback.ip()])
+        .with_host("mapped.test", vec!["::ffff:127.0.0.1".parse().unwrap()]);
+    let policy = TargetPolicy {
+        allow: None,
+        deny: vec!["127.0.0.0/8".parse().unwrap()],
+    };
+    let protocol = builder(&factory, resolver)
+        .target_policy(policy)
+        .build()
+        .unwrap();
+    let nodes = TestNodes::start(protocol).await.unwrap();
 
-    let mut writer = framed_writer.into_inner();
+    for name in ["internal.test:8080", "mapped.test:8080"] {
+        assert_connect_status(
+            &nodes.connection,
+            name.parse().unwrap(),
+            ConnectStatusCode::ConnectionNotAllowed,
+        )
+        .await;
+    }
+    assert!(factory.connections().is_empty());
 
-    println!("Writing hello");
-    writer.write(b"hello").await.unwrap();
-    println!("Wrote hello");
-    sleep(Duration::from_secs(1));
-    let result = writer.write(b"world").await;
-    println!("{:?}", result);
+    assert!(udp_round_trip(
+        &nodes.connection,
+        4,
+        "internal.test:8080".parse().unwrap(),
+        b"ping",
+        Duration::from_millis(300),
+    )
+    .await
+    .is_err());
+    assert!(factory.datagrams().is_empty());
 
-    sleep(Duration::from_secs(5));
-    let _ = router.shutdown();
+    nodes.shutdown().await;
 }
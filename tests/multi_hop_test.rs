@@ -0,0 +1,184 @@
+use ::iroh::endpoint::{RecvStream, SendStream};
+use ::iroh::protocol::Router;
+use ::iroh::{Endpoint, RelayMode, SecretKey};
+use bytes::BytesMut;
+use s2p::codec::{TcpConnectRequestCodec, TcpConnectResponseCodec};
+use s2p::iroh::{spawn_router, TcpClient, TcpClientError, TransportFactory};
+use s2p::message_types::{ConnectStatusCode, Host, TcpConnectResponse};
+use s2p::test_util::{direct_addr, loopback_endpoint, MockSocketFactory, MockTarget, TestNodes};
+use s2p::{S2pProtocol, ALPN_S2P_V1};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_util::codec::{Decoder, Encoder};
+
+const TARGET: &str = "192.0.2.50:7";
+
+fn protocol(endpoint: &Endpoint, allow_relay: bool) -> S2pProtocol {
+    let socket_factory: Arc<dyn TransportFactory> =
+        Arc::new(MockSocketFactory::new().with_target(TARGET.parse().unwrap(), MockTarget::Echo));
+    S2pProtocol::builder()
+        .socket_factory(socket_factory)
+        .endpoint(endpoint.clone())
+        .allow_relay(allow_relay)
+        .build()
+        .unwrap()
+}
+
+// A node running s2p that `entry` knows how to reach.
+async fn hop(entry: &Endpoint, allow_relay: bool) -> (Endpoint, Router) {
+    let endpoint = loopback_endpoint().await.unwrap();
+    let router = spawn_router(endpoint.clone(), protocol(&endpoint, allow_relay));
+    entry.add_node_addr(direct_addr(&endpoint)).unwrap();
+    (endpoint, router)
+}
+
+#[tokio::test]
+async fn test_two_hop_path() {
+    let nodes = TestNodes::start_with(|server| protocol(server, true))
+        .await
+        .unwrap();
+    let (first, first_router) = hop(&nodes.server, true).await;
+    let (second, second_router) = hop(&first, false).await;
+
+    let mut channel = nodes
+        .tcp_client()
+        .connect_through(
+            &[first.node_id(), second.node_id()],
+            TARGET.parse().unwrap(),
+        )
+        .await
+        .unwrap();
+    channel.write_all(b"through two hops").await.unwrap();
+    let mut buf = [0u8; 16];
+    channel.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"through two hops");
+
+    // The last hop ends its side with a close frame once the target closes.
+    channel.shutdown().await.unwrap();
+    let mut rest = Vec::new();
+    channel.read_to_end(&mut rest).await.unwrap();
+    assert!(rest.is_empty());
+
+    first_router.shutdown().await.unwrap();
+    second_router.shutdown().await.unwrap();
+    nodes.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_truncated_channel_is_an_error() {
+    let nodes = TestNodes::start_with(|server| protocol(server, true))
+        .await
+        .unwrap();
+    let (hop, hop_router) = hop(&nodes.server, false).await;
+
+    let mut channel = nodes
+        .tcp_client()
+        .connect_through(&[hop.node_id()], TARGET.parse().unwrap())
+        .await
+        .unwrap();
+    channel.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    channel.read_exact(&mut buf).await.unwrap();
+
+    // The hop goes away without closing the channel; the entry node ends the stream.
+    hop_router.shutdown().await.unwrap();
+    let mut rest = Vec::new();
+    assert!(channel.read_to_end(&mut rest).await.is_err());
+
+    nodes.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_relaying_disabled() {
+    let nodes = TestNodes::start_with(|server| protocol(server, false))
+        .await
+        .unwrap();
+    let (hop, hop_router) = hop(&nodes.server, false).await;
+
+    let error = nodes
+        .tcp_client()
+        .connect_through(&[hop.node_id()], TARGET.parse().unwrap())
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        error,
+        TcpClientError::ProtocolError(ConnectStatusCode::ConnectionNotAllowed)
+    ));
+
+    hop_router.shutdown().await.unwrap();
+    nodes.shutdown().await;
+}
+
+async fn read_request(recv: &mut RecvStream, buf: &mut BytesMut) -> Host {
+    loop {
+        if let Some(request) = TcpConnectRequestCodec.decode(buf).unwrap() {
+            return request.target.host;
+        }
+        let mut chunk = [0u8; 256];
+        let len = recv.read(&mut chunk).await.unwrap().unwrap();
+        buf.extend_from_slice(&chunk[..len]);
+    }
+}
+
+async fn accept_request(send: &mut SendStream) {
+    let mut response = BytesMut::new();
+    TcpConnectResponseCodec
+        .encode(TcpConnectResponse::success(), &mut response)
+        .unwrap();
+    send.write_all(&response).await.unwrap();
+}
+
+// A node that accepts relaying to any hop and then answers the channel handshake itself,
+// signing with its own key instead of the hop's.
+#[tokio::test]
+async fn test_hop_identity_is_verified() {
+    let impostor = Endpoint::builder()
+        .relay_mode(RelayMode::Disabled)
+        .clear_discovery()
+        .alpns(vec![ALPN_S2P_V1.as_bytes().to_vec()])
+        .bind_addr_v4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+        .bind()
+        .await
+        .unwrap();
+    let hop = SecretKey::from_bytes(&[7u8; 32]).public();
+
+    let server = impostor.clone();
+    let task = tokio::spawn(async move {
+        let connection = server.accept().await.unwrap().await.unwrap();
+        let (mut send, mut recv) = connection.accept_bi().await.unwrap();
+        let mut buf = BytesMut::new();
+        for _ in 0..2 {
+            assert_eq!(read_request(&mut recv, &mut buf).await, Host::Node(hop));
+            accept_request(&mut send).await;
+        }
+
+        let mut client_public = [0u8; 32];
+        recv.read_exact(&mut client_public).await.unwrap();
+        let signature = server.secret_key().sign(&client_public);
+        send.write_all(&[1u8; 32]).await.unwrap();
+        send.write_all(&signature.to_bytes()).await.unwrap();
+        // Keep the connection up until the client has checked the signature.
+        let _ = recv.read_to_end(1024).await;
+    });
+
+    let client = loopback_endpoint().await.unwrap();
+    let connection = client
+        .connect(direct_addr(&impostor), ALPN_S2P_V1.as_bytes())
+        .await
+        .unwrap();
+    let error = TcpClient::new(connection.clone())
+        .connect_through(&[hop], TARGET.parse().unwrap())
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        error,
+        TcpClientError::IoError(e) if e.kind() == io::ErrorKind::PermissionDenied
+    ));
+
+    connection.close(0u32.into(), b"done");
+    task.abort();
+    client.close().await;
+    impostor.close().await;
+}
@@ -0,0 +1,229 @@
+use ::iroh::endpoint::ConnectionType;
+use ::iroh::{NodeId, RelayUrl, SecretKey};
+use s2p::iroh::{
+    AuthContext, AuthDecision, NodeAuthenticator, NodeProfile, S2pProtocol, TransportFactory,
+};
+use s2p::message_types::{ConnectStatusCode, TargetAddress};
+use s2p::test_util::{
+    assert_connect_status, udp_round_trip, MockSocketFactory, MockTarget, TestNodes,
+};
+use s2p::ALPN_S2P_V1;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const ALLOWED: &str = "192.0.2.90:443";
+const OTHER: &str = "192.0.2.91:443";
+
+fn target(s: &str) -> TargetAddress {
+    s.parse().unwrap()
+}
+
+#[test]
+fn test_auth_context_connection_types() {
+    let node_id = SecretKey::from_bytes(&[1u8; 32]).public();
+    let addr: SocketAddr = "198.51.100.1:4433".parse().unwrap();
+    let relay_url: RelayUrl = "https://relay.example.com".parse().unwrap();
+
+    let context = AuthContext::new(node_id, b"s2p/1".to_vec());
+    assert_eq!(context.node_id, node_id);
+    assert_eq!(context.alpn, b"s2p/1");
+    assert_eq!(context.remote_addr, None);
+    assert_eq!(context.relay_url, None);
+
+    let direct = context
+        .clone()
+        .with_connection_type(ConnectionType::Direct(addr));
+    assert_eq!(direct.remote_addr, Some(addr));
+    assert_eq!(direct.relay_url, None);
+
+    let relayed = context
+        .clone()
+        .with_connection_type(ConnectionType::Relay(relay_url.clone()));
+    assert_eq!(relayed.remote_addr, None);
+    assert_eq!(relayed.relay_url, Some(relay_url.clone()));
+
+    let mixed = context
+        .clone()
+        .with_connection_type(ConnectionType::Mixed(addr, relay_url.clone()));
+    assert_eq!(mixed.remote_addr, Some(addr));
+    assert_eq!(mixed.relay_url, Some(relay_url));
+
+    let none = context.with_connection_type(ConnectionType::None);
+    assert_eq!(none.remote_addr, None);
+    assert_eq!(none.relay_url, None);
+}
+
+#[test]
+fn test_node_profile_allowed_targets() {
+    let open = NodeProfile::labeled("open").with_attribute("tier", "gold");
+    assert_eq!(open.label.as_deref(), Some("open"));
+    assert_eq!(open.attribute("tier"), Some("gold"));
+    assert_eq!(open.attribute("missing"), None);
+    assert!(open.allows_target(&target(OTHER)));
+
+    let restricted = NodeProfile {
+        allowed_targets: Some(vec![
+            ALLOWED.parse().unwrap(),
+            "*.corp.internal:443".parse().unwrap(),
+        ]),
+        ..NodeProfile::default()
+    };
+    assert!(restricted.allows_target(&target(ALLOWED)));
+    assert!(restricted.allows_target(&target("api.corp.internal:443")));
+    assert!(!restricted.allows_target(&target(OTHER)));
+    assert!(!restricted.allows_target(&target("api.corp.internal:80")));
+
+    // A name is allowed when it resolves to an allowed address.
+    assert!(restricted.allows_resolved(&target("db.example.com:443"), ALLOWED.parse().unwrap()));
+    assert!(!restricted.allows_resolved(&target("db.example.com:443"), OTHER.parse().unwrap()));
+
+    let nothing = NodeProfile {
+        allowed_targets: Some(Vec::new()),
+        ..NodeProfile::default()
+    };
+    assert!(!nothing.allows_target(&target(ALLOWED)));
+}
+
+#[test]
+fn test_auth_decisions() {
+    assert!(AuthDecision::accept().is_accept());
+    assert!(matches!(
+        AuthDecision::accept(),
+        AuthDecision::Accept(profile) if *profile == NodeProfile::default()
+    ));
+    assert!(matches!(
+        AuthDecision::accept_with(NodeProfile::labeled("ops")),
+        AuthDecision::Accept(profile) if profile.label.as_deref() == Some("ops")
+    ));
+
+    let rejected = AuthDecision::reject("not today");
+    assert!(!rejected.is_accept());
+    assert!(matches!(rejected, AuthDecision::Reject(reason) if reason == "not today"));
+}
+
+// Accepts every node with `profile` and keeps the contexts it was asked about.
+#[derive(Debug)]
+struct ProfileAuthenticator {
+    profile: NodeProfile,
+    contexts: Mutex<Vec<AuthContext>>,
+}
+
+impl ProfileAuthenticator {
+    fn arc(profile: NodeProfile) -> Arc<Self> {
+        Arc::new(Self {
+            profile,
+            contexts: Mutex::new(Vec::new()),
+        })
+    }
+}
+
+impl NodeAuthenticator for ProfileAuthenticator {
+    fn should_accept(&self, _node_id: &NodeId) -> Pin<Box<dyn Future<Output = bool> + Send + '_>> {
+        Box::pin(async move { true })
+    }
+
+    fn authenticate<'a>(
+        &'a self,
+        context: &'a AuthContext,
+    ) -> Pin<Box<dyn Future<Output = AuthDecision> + Send + 'a>> {
+        Box::pin(async move {
+            self.contexts.lock().unwrap().push(context.clone());
+            AuthDecision::accept_with(self.profile.clone())
+        })
+    }
+}
+
+fn socket_factory(factory: &MockSocketFactory) -> Arc<dyn TransportFactory> {
+    Arc::new(factory.clone())
+}
+
+#[tokio::test]
+async fn test_profile_allowed_targets_restrict_requests() {
+    let factory = MockSocketFactory::new()
+        .with_target(ALLOWED.parse().unwrap(), MockTarget::Echo)
+        .with_target(OTHER.parse().unwrap(), MockTarget::Echo);
+    let authenticator = ProfileAuthenticator::arc(NodeProfile {
+        allowed_targets: Some(vec![ALLOWED.parse().unwrap()]),
+        ..NodeProfile::labeled("restricted")
+    });
+    let node_authenticator: Arc<dyn NodeAuthenticator> = authenticator.clone();
+    let protocol = S2pProtocol::builder()
+        .socket_factory(socket_factory(&factory))
+        .node_authenticator(node_authenticator)
+        .build()
+        .unwrap();
+    let nodes = TestNodes::start(protocol).await.unwrap();
+
+    assert_connect_status(
+        &nodes.connection,
+        target(ALLOWED),
+        ConnectStatusCode::Success,
+    )
+    .await;
+    assert_connect_status(
+        &nodes.connection,
+        target(OTHER),
+        ConnectStatusCode::ConnectionNotAllowed,
+    )
+    .await;
+    assert_eq!(factory.connections(), vec![ALLOWED.parse().unwrap()]);
+
+    let wait = Duration::from_millis(300);
+    assert!(
+        udp_round_trip(&nodes.connection, 1, target(ALLOWED), b"ping", wait)
+            .await
+            .is_ok()
+    );
+    assert!(
+        udp_round_trip(&nodes.connection, 2, target(OTHER), b"ping", wait)
+            .await
+            .is_err()
+    );
+    assert_eq!(
+        factory.datagrams(),
+        vec![(ALLOWED.parse().unwrap(), b"ping".to_vec())]
+    );
+
+    // Without an endpoint the protocol cannot tell how the node is connected.
+    let contexts = authenticator.contexts.lock().unwrap().clone();
+    assert_eq!(contexts.len(), 1);
+    assert_eq!(contexts[0].node_id, nodes.client.node_id());
+    assert_eq!(contexts[0].alpn, ALPN_S2P_V1.as_bytes());
+    assert_eq!(contexts[0].remote_addr, None);
+
+    nodes.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_auth_context_has_remote_addr_with_endpoint() {
+    let authenticator = ProfileAuthenticator::arc(NodeProfile::default());
+    let node_authenticator: Arc<dyn NodeAuthenticator> = authenticator.clone();
+    let nodes = TestNodes::start_with(|server| {
+        S2pProtocol::builder()
+            .socket_factory(socket_factory(&MockSocketFactory::new()))
+            .node_authenticator(node_authenticator)
+            .endpoint(server.clone())
+            .build()
+            .unwrap()
+    })
+    .await
+    .unwrap();
+    // The connection is accepted once a stream has been served on it.
+    assert_connect_status(
+        &nodes.connection,
+        target(OTHER),
+        ConnectStatusCode::ConnectionRefused,
+    )
+    .await;
+
+    let contexts = authenticator.contexts.lock().unwrap().clone();
+    assert_eq!(contexts.len(), 1);
+    let remote_addr = contexts[0].remote_addr.unwrap();
+    assert!(remote_addr.ip().is_loopback());
+    assert_eq!(contexts[0].relay_url, None);
+
+    nodes.shutdown().await;
+}
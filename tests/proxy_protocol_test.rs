@@ -0,0 +1,76 @@
+use ::iroh::SecretKey;
+use s2p::iroh::{
+    encode_proxy_protocol_header, ProxyProtocolPolicy, ProxyProtocolVersion, PP2_TYPE_NODE_ID,
+};
+use s2p::message_types::{Host, TargetAddress};
+use std::net::SocketAddr;
+
+#[test]
+fn test_v1_header() {
+    let node_id = SecretKey::from_bytes(&[1u8; 32]).public();
+    let source: SocketAddr = "192.0.2.1:40000".parse().unwrap();
+    let destination: SocketAddr = "10.0.0.5:443".parse().unwrap();
+
+    let header = encode_proxy_protocol_header(
+        ProxyProtocolVersion::V1,
+        Some(source),
+        destination,
+        &node_id,
+    );
+    assert_eq!(header, b"PROXY TCP4 192.0.2.1 10.0.0.5 40000 443\r\n");
+
+    let header =
+        encode_proxy_protocol_header(ProxyProtocolVersion::V1, None, destination, &node_id);
+    assert_eq!(header, b"PROXY UNKNOWN\r\n");
+}
+
+#[test]
+fn test_v2_header_carries_node_id() {
+    let node_id = SecretKey::from_bytes(&[1u8; 32]).public();
+    let source: SocketAddr = "192.0.2.1:40000".parse().unwrap();
+    let destination: SocketAddr = "10.0.0.5:443".parse().unwrap();
+
+    let header = encode_proxy_protocol_header(
+        ProxyProtocolVersion::V2,
+        Some(source),
+        destination,
+        &node_id,
+    );
+    assert_eq!(&header[..12], b"\r\n\r\n\0\r\nQUIT\n");
+    assert_eq!(header[12], 0x21);
+    assert_eq!(header[13], 0x11);
+    let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+    assert_eq!(header.len(), 16 + len);
+    assert_eq!(&header[16..20], &[192, 0, 2, 1]);
+    assert_eq!(&header[20..24], &[10, 0, 0, 5]);
+
+    let tlv = &header[28..];
+    assert_eq!(tlv[0], PP2_TYPE_NODE_ID);
+    let node_id_string = node_id.to_string();
+    assert_eq!(
+        u16::from_be_bytes([tlv[1], tlv[2]]) as usize,
+        node_id_string.len()
+    );
+    assert_eq!(&tlv[3..], node_id_string.as_bytes());
+}
+
+#[test]
+fn test_policy_matches_targets() {
+    let policy = ProxyProtocolPolicy::new()
+        .with_rule("*.internal:443".parse().unwrap(), ProxyProtocolVersion::V2)
+        .with_rule("*:80".parse().unwrap(), ProxyProtocolVersion::V1);
+    let target = |host: &str, port| TargetAddress {
+        host: Host::Domain(host.to_string()),
+        port,
+    };
+
+    assert_eq!(
+        policy.version_for(&target("api.internal", 443)),
+        Some(ProxyProtocolVersion::V2)
+    );
+    assert_eq!(
+        policy.version_for(&target("example.com", 80)),
+        Some(ProxyProtocolVersion::V1)
+    );
+    assert_eq!(policy.version_for(&target("example.com", 443)), None);
+}
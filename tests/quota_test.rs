@@ -0,0 +1,116 @@
+use ::iroh::SecretKey;
+use s2p::iroh::{
+    AccountingStore, FileAccountingStore, InMemoryAccountingStore, QuotaLimits, QuotaManager,
+};
+use std::sync::Arc;
+use std::time::Duration;
+
+#[tokio::test]
+async fn test_quota_exhaustion_and_file_persistence() {
+    let node_id = SecretKey::from_bytes(&[7u8; 32]).public();
+    let path = std::env::temp_dir().join(format!("s2p-quota-{}.txt", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let limits = QuotaLimits {
+        daily_bytes: Some(100),
+        monthly_bytes: None,
+        total_bytes: Some(1_000),
+    };
+
+    let quota = QuotaManager::new(limits, FileAccountingStore::arc(&path));
+    quota.load(&node_id).await.unwrap();
+    assert_eq!(quota.remaining(&node_id), Some(100));
+    assert!(quota.charge(&node_id, 60));
+    assert!(!quota.charge(&node_id, 60));
+    assert!(quota.is_exhausted(&node_id));
+    quota.persist(&node_id).await.unwrap();
+
+    let reloaded = QuotaManager::new(limits, FileAccountingStore::arc(&path));
+    reloaded.load(&node_id).await.unwrap();
+    assert_eq!(reloaded.usage(&node_id).total_bytes, 120);
+    assert!(reloaded.is_exhausted(&node_id));
+
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn test_per_node_limits_override_defaults() {
+    let node_id = SecretKey::from_bytes(&[8u8; 32]).public();
+    let store: Arc<dyn AccountingStore> = InMemoryAccountingStore::arc();
+    let quota = QuotaManager::new(QuotaLimits::unlimited(), store.clone());
+
+    assert!(quota.charge(&node_id, 10_000));
+    assert_eq!(quota.remaining(&node_id), None);
+
+    quota.set_node_limits(
+        node_id,
+        QuotaLimits {
+            total_bytes: Some(5_000),
+            ..QuotaLimits::default()
+        },
+    );
+    assert!(quota.is_exhausted(&node_id));
+
+    quota.persist(&node_id).await.unwrap();
+    let stored = store.load_usage(&node_id).await.unwrap().unwrap();
+    assert_eq!(stored.total_bytes, 10_000);
+}
+
+#[tokio::test]
+async fn test_usage_before_load_does_not_skip_load() {
+    let node_id = SecretKey::from_bytes(&[9u8; 32]).public();
+    let store: Arc<dyn AccountingStore> = InMemoryAccountingStore::arc();
+    let persisted = QuotaManager::new(QuotaLimits::unlimited(), store.clone());
+    persisted.charge(&node_id, 500);
+    persisted.persist(&node_id).await.unwrap();
+
+    let quota = QuotaManager::new(QuotaLimits::unlimited(), store);
+    assert_eq!(quota.usage(&node_id).total_bytes, 0);
+    assert_eq!(quota.remaining(&node_id), None);
+    quota.load(&node_id).await.unwrap();
+    assert_eq!(quota.usage(&node_id).total_bytes, 500);
+}
+
+#[tokio::test]
+async fn test_usage_is_persisted_periodically() {
+    let node_id = SecretKey::from_bytes(&[10u8; 32]).public();
+    let store: Arc<dyn AccountingStore> = InMemoryAccountingStore::arc();
+    let quota = QuotaManager::new(QuotaLimits::unlimited(), store.clone());
+    quota.persist_periodically(Duration::from_millis(50));
+
+    quota.load(&node_id).await.unwrap();
+    quota.charge(&node_id, 300);
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while store.load_usage(&node_id).await.unwrap().is_none() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(
+        store
+            .load_usage(&node_id)
+            .await
+            .unwrap()
+            .unwrap()
+            .total_bytes,
+        300
+    );
+
+    // Later charges are picked up by the next round without an explicit persist.
+    quota.charge(&node_id, 200);
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while store
+            .load_usage(&node_id)
+            .await
+            .unwrap()
+            .unwrap()
+            .total_bytes
+            != 500
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+}
@@ -0,0 +1,217 @@
+use ::iroh::NodeId;
+use s2p::iroh::{
+    AuthContext, AuthDecision, NodeAuthenticator, NodeProfile, S2pProtocol, S2pProtocolBuilder,
+    ServiceRegistry, ServiceTarget, TargetPolicy, TransportFactory,
+};
+use s2p::message_types::{ConnectStatusCode, Host, TargetAddress};
+use s2p::test_util::{
+    assert_connect_status, udp_round_trip, MockSocketFactory, MockTarget, TestNodes,
+};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const BACKEND: &str = "192.0.2.100:5432";
+const OTHER_BACKEND: &str = "192.0.2.101:6379";
+
+fn service(name: &str) -> TargetAddress {
+    TargetAddress {
+        host: Host::Service(name.to_string()),
+        port: 0,
+    }
+}
+
+fn builder(factory: &MockSocketFactory, registry: &Arc<ServiceRegistry>) -> S2pProtocolBuilder {
+    let socket_factory: Arc<dyn TransportFactory> = Arc::new(factory.clone());
+    let mut builder = S2pProtocol::builder();
+    builder
+        .socket_factory(socket_factory)
+        .service_registry(registry.clone());
+    builder
+}
+
+async fn start(factory: &MockSocketFactory, registry: &Arc<ServiceRegistry>) -> TestNodes {
+    let protocol = builder(factory, registry).build().unwrap();
+    TestNodes::start(protocol).await.unwrap()
+}
+
+// Two services on separate backends, both echoing.
+fn two_services() -> (MockSocketFactory, Arc<ServiceRegistry>) {
+    let factory = MockSocketFactory::new()
+        .with_target(BACKEND.parse().unwrap(), MockTarget::Echo)
+        .with_target(OTHER_BACKEND.parse().unwrap(), MockTarget::Echo);
+    let registry = Arc::new(
+        ServiceRegistry::new()
+            .with_service("db", ServiceTarget::Address(BACKEND.parse().unwrap()))
+            .with_service(
+                "cache",
+                ServiceTarget::Address(OTHER_BACKEND.parse().unwrap()),
+            ),
+    );
+    (factory, registry)
+}
+
+// Only "db" is reachable over TCP and UDP; "cache" is refused.
+async fn assert_only_db_allowed(nodes: &TestNodes, factory: &MockSocketFactory) {
+    let backend: SocketAddr = BACKEND.parse().unwrap();
+    assert_connect_status(&nodes.connection, service("db"), ConnectStatusCode::Success).await;
+    assert_connect_status(
+        &nodes.connection,
+        service("cache"),
+        ConnectStatusCode::ConnectionNotAllowed,
+    )
+    .await;
+    assert_eq!(factory.connections(), vec![backend]);
+
+    let wait = Duration::from_millis(300);
+    assert!(
+        udp_round_trip(&nodes.connection, 1, service("db"), b"ping", wait)
+            .await
+            .is_ok()
+    );
+    assert!(
+        udp_round_trip(&nodes.connection, 2, service("cache"), b"ping", wait)
+            .await
+            .is_err()
+    );
+    assert_eq!(factory.datagrams(), vec![(backend, b"ping".to_vec())]);
+}
+
+// Accepts every node with `profile`.
+#[derive(Debug)]
+struct ProfileAuthenticator {
+    profile: NodeProfile,
+}
+
+impl NodeAuthenticator for ProfileAuthenticator {
+    fn should_accept(&self, _node_id: &NodeId) -> Pin<Box<dyn Future<Output = bool> + Send + '_>> {
+        Box::pin(async move { true })
+    }
+
+    fn authenticate<'a>(
+        &'a self,
+        _context: &'a AuthContext,
+    ) -> Pin<Box<dyn Future<Output = AuthDecision> + Send + 'a>> {
+        Box::pin(async move { AuthDecision::accept_with(self.profile.clone()) })
+    }
+}
+
+#[tokio::test]
+async fn test_service_names_connect_to_their_backend() {
+    let backend: SocketAddr = BACKEND.parse().unwrap();
+    let factory = MockSocketFactory::new().with_target(backend, MockTarget::Echo);
+    let registry = Arc::new(
+        ServiceRegistry::new().with_service("db", ServiceTarget::Address(BACKEND.parse().unwrap())),
+    );
+    let nodes = start(&factory, &registry).await;
+
+    let mut stream = nodes.tcp_client().connect(service("db")).await.unwrap();
+    stream.write_all(b"select 1").await.unwrap();
+    let mut buf = [0u8; 8];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"select 1");
+    assert_eq!(factory.connections(), vec![backend]);
+
+    let reply = udp_round_trip(
+        &nodes.connection,
+        1,
+        service("db"),
+        b"ping",
+        Duration::from_secs(5),
+    )
+    .await
+    .unwrap();
+    assert_eq!(reply.data, b"ping");
+    assert_eq!(factory.datagrams(), vec![(backend, b"ping".to_vec())]);
+
+    nodes.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_unknown_service_is_unreachable() {
+    let backend: SocketAddr = BACKEND.parse().unwrap();
+    let factory = MockSocketFactory::new().with_target(backend, MockTarget::Echo);
+    let registry = ServiceRegistry::arc();
+    let nodes = start(&factory, &registry).await;
+
+    assert_connect_status(
+        &nodes.connection,
+        service("db"),
+        ConnectStatusCode::HostUnreachable,
+    )
+    .await;
+
+    // Services can be registered and removed while the node is running.
+    registry.register("db", ServiceTarget::Address(BACKEND.parse().unwrap()));
+    assert_connect_status(&nodes.connection, service("db"), ConnectStatusCode::Success).await;
+    registry.unregister("db");
+    assert_connect_status(
+        &nodes.connection,
+        service("db"),
+        ConnectStatusCode::HostUnreachable,
+    )
+    .await;
+    assert_eq!(factory.connections(), vec![backend]);
+
+    nodes.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_profile_can_allow_a_service_by_name() {
+    let (factory, registry) = two_services();
+    let node_authenticator: Arc<dyn NodeAuthenticator> = Arc::new(ProfileAuthenticator {
+        profile: NodeProfile {
+            allowed_targets: Some(vec!["service:db".parse().unwrap()]),
+            ..NodeProfile::labeled("db-only")
+        },
+    });
+    let protocol = builder(&factory, &registry)
+        .node_authenticator(node_authenticator)
+        .build()
+        .unwrap();
+    let nodes = TestNodes::start(protocol).await.unwrap();
+
+    assert_only_db_allowed(&nodes, &factory).await;
+
+    nodes.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_target_policy_can_allow_a_service_by_name() {
+    let (factory, registry) = two_services();
+    let policy = TargetPolicy {
+        allow: Some(vec!["service:db".parse().unwrap()]),
+        deny: Vec::new(),
+    };
+    let protocol = builder(&factory, &registry)
+        .target_policy(policy)
+        .build()
+        .unwrap();
+    let nodes = TestNodes::start(protocol).await.unwrap();
+
+    assert_only_db_allowed(&nodes, &factory).await;
+
+    nodes.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_target_policy_denies_service_backends() {
+    let (factory, registry) = two_services();
+    let policy = TargetPolicy {
+        allow: Some(vec!["service:*".parse().unwrap()]),
+        deny: vec!["192.0.2.101/32".parse().unwrap()],
+    };
+    let protocol = builder(&factory, &registry)
+        .target_policy(policy)
+        .build()
+        .unwrap();
+    let nodes = TestNodes::start(protocol).await.unwrap();
+
+    // "cache" is allowed by name but its backend address is denied.
+    assert_only_db_allowed(&nodes, &factory).await;
+
+    nodes.shutdown().await;
+}
@@ -0,0 +1,94 @@
+use s2p::iroh::{DefaultSocketFactory, S2pProtocol, SocketFactory, SocketFactoryAdapter};
+use s2p::test_util::TestNodes;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+// Plain tokio sockets, counting the TCP connections opened.
+#[derive(Debug, Default)]
+struct CountingSocketFactory {
+    connections: AtomicUsize,
+}
+
+impl SocketFactory for CountingSocketFactory {
+    fn create_tcp_connection(
+        &self,
+        addr: SocketAddr,
+    ) -> Pin<Box<dyn Future<Output = Result<TcpStream, io::Error>> + Send + '_>> {
+        self.connections.fetch_add(1, Ordering::SeqCst);
+        Box::pin(async move { TcpStream::connect(addr).await })
+    }
+
+    fn create_udp_socket(
+        &self,
+        bind_addr: &str,
+    ) -> Pin<Box<dyn Future<Output = Result<UdpSocket, io::Error>> + Send + '_>> {
+        let bind_addr = bind_addr.to_string();
+        Box::pin(async move { UdpSocket::bind(bind_addr).await })
+    }
+}
+
+#[tokio::test]
+async fn test_socket_factory_adapter() {
+    let factory = SocketFactoryAdapter::arc(DefaultSocketFactory::arc());
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        stream.write_all(&buf).await.unwrap();
+    });
+
+    let mut stream = factory.connect_stream(addr).await.unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+    server.await.unwrap();
+
+    let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let socket = factory.bind_datagram("127.0.0.1:0").await.unwrap();
+    socket
+        .send_to(b"hello", peer.local_addr().unwrap())
+        .await
+        .unwrap();
+    let mut buf = [0u8; 16];
+    let (len, from) = peer.recv_from(&mut buf).await.unwrap();
+    assert_eq!(&buf[..len], b"hello");
+    assert_eq!(from, socket.local_addr().unwrap());
+}
+
+#[tokio::test]
+async fn test_builder_accepts_legacy_socket_factory() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let (mut reader, mut writer) = stream.split();
+        tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+    });
+
+    let factory = Arc::new(CountingSocketFactory::default());
+    let legacy: Arc<dyn SocketFactory> = factory.clone();
+    let protocol = S2pProtocol::builder()
+        .legacy_socket_factory(legacy)
+        .build()
+        .unwrap();
+    let nodes = TestNodes::start(protocol).await.unwrap();
+
+    let mut stream = nodes.tcp_client().connect(addr.into()).await.unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+    assert_eq!(factory.connections.load(Ordering::SeqCst), 1);
+
+    nodes.shutdown().await;
+}
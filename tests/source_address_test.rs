@@ -0,0 +1,138 @@
+use ::iroh::SecretKey;
+use s2p::iroh::{
+    SocketOptions, SourceAddressFactory, SourceAddressPool, SourceSelection, TransportFactory,
+};
+use s2p::message_types::TargetAddress;
+use s2p::test_util::{udp_round_trip, MockSocketFactory, MockTarget, TestNodes};
+use s2p::S2pProtocol;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+#[test]
+fn test_pinned_source_addresses() {
+    let addresses: Vec<IpAddr> = vec![
+        "192.0.2.1".parse().unwrap(),
+        "192.0.2.2".parse().unwrap(),
+        "192.0.2.3".parse().unwrap(),
+        "2001:db8::1".parse().unwrap(),
+    ];
+    let factory = SourceAddressFactory::new(
+        SourceAddressPool::Addresses(addresses.clone()),
+        SourceSelection::Pinned,
+        SocketOptions::default(),
+    );
+    let node = SecretKey::from_bytes(&[3u8; 32]).public();
+
+    let v4 = factory.source_for(Some(&node), true).unwrap();
+    assert!(v4.is_ipv4() && addresses.contains(&v4));
+    for _ in 0..10 {
+        assert_eq!(factory.source_for(Some(&node), true), Some(v4));
+    }
+    assert_eq!(
+        factory.source_for(Some(&node), false),
+        Some("2001:db8::1".parse().unwrap())
+    );
+}
+
+#[test]
+fn test_rotating_source_addresses() {
+    let addresses: Vec<IpAddr> = vec!["192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap()];
+    let factory = SourceAddressFactory::new(
+        SourceAddressPool::Addresses(addresses),
+        SourceSelection::Rotating,
+        SocketOptions::default(),
+    );
+    let node = SecretKey::from_bytes(&[3u8; 32]).public();
+
+    let picked: std::collections::HashSet<_> = (0..32)
+        .map(|_| factory.source_for(Some(&node), true).unwrap())
+        .collect();
+    assert_eq!(picked.len(), 2);
+}
+
+#[test]
+fn test_ipv6_prefix_source_addresses() {
+    let network: Ipv6Addr = "2001:db8:1:2::".parse().unwrap();
+    let factory = SourceAddressFactory::new(
+        SourceAddressPool::Ipv6Prefix {
+            network,
+            prefix_len: 64,
+        },
+        SourceSelection::Pinned,
+        SocketOptions::default(),
+    );
+    let node = SecretKey::from_bytes(&[3u8; 32]).public();
+    let other = SecretKey::from_bytes(&[4u8; 32]).public();
+
+    let Some(IpAddr::V6(address)) = factory.source_for(Some(&node), false) else {
+        panic!("expected an IPv6 source address");
+    };
+    assert_eq!(address.segments()[..4], network.segments()[..4]);
+    assert_ne!(
+        factory.source_for(Some(&other), false),
+        Some(IpAddr::V6(address))
+    );
+    assert_eq!(factory.source_for(Some(&node), true), None);
+}
+
+#[tokio::test]
+async fn test_pinned_udp_source_addresses() {
+    let mut addresses: Vec<IpAddr> = vec![
+        "127.0.0.1".parse().unwrap(),
+        "127.0.0.2".parse().unwrap(),
+        "127.0.0.3".parse().unwrap(),
+    ];
+    // Only test IPv6 where the host has a loopback address for it.
+    let ipv6 = std::net::UdpSocket::bind("[::1]:0").is_ok();
+    if ipv6 {
+        addresses.push(IpAddr::V6(Ipv6Addr::LOCALHOST));
+    }
+    let factory = SourceAddressFactory::new(
+        SourceAddressPool::Addresses(addresses),
+        SourceSelection::Pinned,
+        SocketOptions::default(),
+    );
+    let node = SecretKey::from_bytes(&[3u8; 32]).public();
+
+    let pinned = factory.source_for(Some(&node), true).unwrap();
+    for _ in 0..5 {
+        let socket = factory.bind_datagram_for(node, "0.0.0.0:0").await.unwrap();
+        assert_eq!(socket.local_addr().unwrap().ip(), pinned);
+    }
+    if ipv6 {
+        let socket = factory.bind_datagram_for(node, "[::]:0").await.unwrap();
+        assert_eq!(
+            socket.local_addr().unwrap().ip(),
+            IpAddr::V6(Ipv6Addr::LOCALHOST)
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_udp_flows_bind_for_target_family() {
+    let v4: SocketAddr = "192.0.2.7:53".parse().unwrap();
+    let v6: SocketAddr = "[2001:db8::7]:53".parse().unwrap();
+    let factory = MockSocketFactory::new()
+        .with_target(v4, MockTarget::Echo)
+        .with_target(v6, MockTarget::Echo);
+    let socket_factory: Arc<dyn TransportFactory> = Arc::new(factory.clone());
+    let protocol = S2pProtocol::builder()
+        .socket_factory(socket_factory)
+        .build()
+        .unwrap();
+    let nodes = TestNodes::start(protocol).await.unwrap();
+    let wait = Duration::from_secs(2);
+
+    let reply = udp_round_trip(&nodes.connection, 1, TargetAddress::from(v6), b"six", wait)
+        .await
+        .unwrap();
+    assert_eq!(reply.data, b"six");
+    let reply = udp_round_trip(&nodes.connection, 2, TargetAddress::from(v4), b"four", wait)
+        .await
+        .unwrap();
+    assert_eq!(reply.data, b"four");
+    assert_eq!(factory.binds(), vec!["[::]:0", "0.0.0.0:0"]);
+
+    nodes.shutdown().await;
+}
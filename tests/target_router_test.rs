@@ -0,0 +1,38 @@
+use ::iroh::SecretKey;
+use s2p::iroh::{RouteAction, TargetRouter};
+use s2p::message_types::{Host, TargetAddress};
+use std::net::Ipv4Addr;
+
+#[test]
+fn test_first_matching_rule_wins() {
+    let exit_a = SecretKey::from_bytes(&[1u8; 32]).public();
+    let exit_b = SecretKey::from_bytes(&[2u8; 32]).public();
+    let router = TargetRouter::new(RouteAction::Direct)
+        .with_rule(
+            "*.corp.internal".parse().unwrap(),
+            RouteAction::Exit(exit_a),
+        )
+        .with_rule("10.0.0.0/8:22".parse().unwrap(), RouteAction::Reject)
+        .with_rule("10.0.0.0/8".parse().unwrap(), RouteAction::Exit(exit_b));
+
+    let domain = |name: &str| TargetAddress {
+        host: Host::Domain(name.to_string()),
+        port: 443,
+    };
+    let ip = |port| TargetAddress {
+        host: Host::IPv4(Ipv4Addr::new(10, 1, 2, 3)),
+        port,
+    };
+
+    assert_eq!(
+        router.route(&domain("git.corp.internal")),
+        &RouteAction::Exit(exit_a)
+    );
+    assert_eq!(router.route(&domain("example.com")), &RouteAction::Direct);
+    assert_eq!(router.route(&ip(22)), &RouteAction::Reject);
+    assert_eq!(router.route(&ip(80)), &RouteAction::Exit(exit_b));
+
+    let action: RouteAction = format!("exit:{}", exit_a).parse().unwrap();
+    assert_eq!(action, RouteAction::Exit(exit_a));
+    assert!("sideways".parse::<RouteAction>().is_err());
+}
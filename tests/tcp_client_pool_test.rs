@@ -0,0 +1,300 @@
+use ::iroh::protocol::Router;
+use ::iroh::{Endpoint, NodeId, SecretKey};
+use s2p::iroh::{
+    spawn_router, AuthContext, AuthDecision, LoadBalancing, NodeAuthenticator, TcpClientError,
+    TcpClientPool, TcpClientPoolOptions, TransportFactory,
+};
+use s2p::message_types::{ConnectStatusCode, TargetAddress};
+use s2p::test_util::{direct_addr, loopback_endpoint, MockSocketFactory, MockTarget};
+use s2p::S2pProtocol;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+const ECHO: &str = "192.0.2.60:7";
+const REFUSED: &str = "192.0.2.61:7";
+const TIMED_OUT: &str = "192.0.2.62:7";
+
+// Exits sharing one mock factory, so connections through all of them are counted together.
+struct Exits {
+    client: Endpoint,
+    routers: Vec<Router>,
+    node_ids: Vec<NodeId>,
+    factory: MockSocketFactory,
+}
+
+impl Exits {
+    async fn start(count: usize) -> Self {
+        let factory = MockSocketFactory::new()
+            .with_target(ECHO.parse().unwrap(), MockTarget::Echo)
+            .with_target(
+                TIMED_OUT.parse().unwrap(),
+                MockTarget::Fail(io::ErrorKind::TimedOut),
+            );
+        let client = loopback_endpoint().await.unwrap();
+        let mut routers = Vec::new();
+        let mut node_ids = Vec::new();
+        for _ in 0..count {
+            let server = loopback_endpoint().await.unwrap();
+            let socket_factory: Arc<dyn TransportFactory> = Arc::new(factory.clone());
+            let protocol = S2pProtocol::builder()
+                .socket_factory(socket_factory)
+                .build()
+                .unwrap();
+            client.add_node_addr(direct_addr(&server)).unwrap();
+            node_ids.push(server.node_id());
+            routers.push(spawn_router(server, protocol));
+        }
+        Self {
+            client,
+            routers,
+            node_ids,
+            factory,
+        }
+    }
+
+    fn pool(&self, exits: Vec<NodeId>, options: TcpClientPoolOptions) -> TcpClientPool {
+        TcpClientPool::new(self.client.clone(), exits, options)
+    }
+
+    async fn shutdown(self) {
+        for router in self.routers {
+            router.shutdown().await.unwrap();
+        }
+        self.client.close().await;
+    }
+}
+
+// An exit the client has no address for, so connecting to it fails at once.
+fn unreachable_exit() -> NodeId {
+    SecretKey::from_bytes(&[9u8; 32]).public()
+}
+
+fn options(load_balancing: LoadBalancing) -> TcpClientPoolOptions {
+    TcpClientPoolOptions::builder()
+        .load_balancing(load_balancing)
+        .min_backoff(Duration::from_secs(60))
+        .max_backoff(Duration::from_secs(120))
+        .build()
+        .unwrap()
+}
+
+async fn wait_connected(pool: &TcpClientPool, exits: &[NodeId]) {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !pool
+            .exit_status()
+            .iter()
+            .filter(|status| exits.contains(&status.node_id))
+            .all(|status| status.connected)
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+}
+
+fn echo() -> TargetAddress {
+    ECHO.parse().unwrap()
+}
+
+#[tokio::test]
+async fn test_pool_fails_over_to_reachable_exit() {
+    let exits = Exits::start(1).await;
+    let live = exits.node_ids[0];
+    let pool = exits.pool(
+        vec![unreachable_exit(), live],
+        options(LoadBalancing::RoundRobin),
+    );
+
+    for _ in 0..4 {
+        let stream = pool.connect(echo()).await.unwrap();
+        assert_eq!(stream.exit(), live);
+    }
+    let status = pool.exit_status();
+    assert!(!status[0].connected);
+    assert!(status[1].connected);
+
+    drop(pool);
+    exits.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_pool_backs_off_failed_exits() {
+    let exits = Exits::start(1).await;
+    let live = exits.node_ids[0];
+    let options = TcpClientPoolOptions::builder()
+        .max_attempts(1usize)
+        .min_backoff(Duration::from_secs(60))
+        .max_backoff(Duration::from_secs(120))
+        .build()
+        .unwrap();
+    let pool = exits.pool(vec![unreachable_exit(), live], options);
+
+    // With a single attempt per call, only the first try of the unreachable exit can fail;
+    // afterwards it is in backoff and ordered after the reachable one.
+    let mut failures = 0;
+    for _ in 0..8 {
+        match pool.connect(echo()).await {
+            Ok(stream) => assert_eq!(stream.exit(), live),
+            Err(_) => failures += 1,
+        }
+    }
+    assert!(failures <= 1, "{} connects failed", failures);
+
+    drop(pool);
+    exits.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_pool_retries_only_retryable_statuses() {
+    let exits = Exits::start(2).await;
+    let pool = exits.pool(exits.node_ids.clone(), options(LoadBalancing::RoundRobin));
+    wait_connected(&pool, &exits.node_ids).await;
+
+    let error = pool.connect(REFUSED.parse().unwrap()).await.unwrap_err();
+    assert!(matches!(
+        error,
+        TcpClientError::ProtocolError(ConnectStatusCode::ConnectionRefused)
+    ));
+    assert_eq!(exits.factory.connections().len(), 1);
+
+    // Timeouts are retryable, so every exit is tried.
+    let error = pool.connect(TIMED_OUT.parse().unwrap()).await.unwrap_err();
+    assert!(matches!(
+        error,
+        TcpClientError::ProtocolError(ConnectStatusCode::TTLExpired)
+    ));
+    assert_eq!(exits.factory.connections().len(), 3);
+
+    drop(pool);
+    exits.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_pool_round_robin() {
+    let exits = Exits::start(2).await;
+    let pool = exits.pool(exits.node_ids.clone(), options(LoadBalancing::RoundRobin));
+    wait_connected(&pool, &exits.node_ids).await;
+
+    let mut used = Vec::new();
+    for _ in 0..4 {
+        used.push(pool.connect(echo()).await.unwrap().exit());
+    }
+    assert_ne!(used[0], used[1]);
+    assert_eq!(used[0], used[2]);
+    assert_eq!(used[1], used[3]);
+
+    drop(pool);
+    exits.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_pool_least_sessions() {
+    let exits = Exits::start(2).await;
+    let pool = exits.pool(
+        exits.node_ids.clone(),
+        options(LoadBalancing::LeastSessions),
+    );
+    wait_connected(&pool, &exits.node_ids).await;
+
+    let first = pool.connect(echo()).await.unwrap();
+    let second = pool.connect(echo()).await.unwrap();
+    assert_ne!(first.exit(), second.exit());
+
+    let first_exit = first.exit();
+    drop(first);
+    let third = pool.connect(echo()).await.unwrap();
+    assert_eq!(third.exit(), first_exit);
+    let sessions: usize = pool
+        .exit_status()
+        .iter()
+        .map(|status| status.active_sessions)
+        .sum();
+    assert_eq!(sessions, 2);
+
+    drop((second, third));
+    drop(pool);
+    exits.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_pool_lowest_latency() {
+    let exits = Exits::start(2).await;
+    let pool = exits.pool(
+        exits.node_ids.clone(),
+        options(LoadBalancing::LowestLatency),
+    );
+    wait_connected(&pool, &exits.node_ids).await;
+
+    let status = pool.exit_status();
+    let lowest = status.iter().filter_map(|status| status.rtt).min().unwrap();
+    let stream = pool.connect(echo()).await.unwrap();
+    let chosen = status
+        .iter()
+        .find(|status| status.node_id == stream.exit())
+        .unwrap();
+    assert_eq!(chosen.rtt, Some(lowest));
+
+    drop(stream);
+    drop(pool);
+    exits.shutdown().await;
+}
+
+// Accepts every node and counts the connections it is asked about.
+#[derive(Debug, Default)]
+struct CountingAuthenticator {
+    connections: AtomicUsize,
+}
+
+impl NodeAuthenticator for CountingAuthenticator {
+    fn should_accept(&self, _node_id: &NodeId) -> Pin<Box<dyn Future<Output = bool> + Send + '_>> {
+        Box::pin(async move { true })
+    }
+
+    fn authenticate<'a>(
+        &'a self,
+        _context: &'a AuthContext,
+    ) -> Pin<Box<dyn Future<Output = AuthDecision> + Send + 'a>> {
+        Box::pin(async move {
+            self.connections.fetch_add(1, Ordering::Relaxed);
+            AuthDecision::accept()
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_pool_opens_one_connection_per_exit() {
+    let factory = MockSocketFactory::new().with_target(ECHO.parse().unwrap(), MockTarget::Echo);
+    let socket_factory: Arc<dyn TransportFactory> = Arc::new(factory);
+    let authenticator = Arc::new(CountingAuthenticator::default());
+    let protocol = S2pProtocol::builder()
+        .socket_factory(socket_factory)
+        .node_authenticator(authenticator.clone() as Arc<dyn NodeAuthenticator>)
+        .build()
+        .unwrap();
+    let server = loopback_endpoint().await.unwrap();
+    let client = loopback_endpoint().await.unwrap();
+    client.add_node_addr(direct_addr(&server)).unwrap();
+    let exit = server.node_id();
+    let router = spawn_router(server, protocol);
+
+    // The first health check and every `connect` find the exit disconnected at once.
+    let pool = TcpClientPool::new(
+        client.clone(),
+        vec![exit],
+        options(LoadBalancing::RoundRobin),
+    );
+    let streams = n0_future::join_all((0..8).map(|_| pool.connect(echo()))).await;
+    assert!(streams.iter().all(|stream| stream.is_ok()));
+    wait_connected(&pool, &[exit]).await;
+    assert_eq!(authenticator.connections.load(Ordering::Relaxed), 1);
+
+    drop(streams);
+    drop(pool);
+    router.shutdown().await.unwrap();
+    client.close().await;
+}
@@ -0,0 +1,59 @@
+#![cfg(target_os = "linux")]
+
+use s2p::iroh::is_redirect_loop;
+use std::net::{IpAddr, SocketAddr};
+
+fn not_local(_: IpAddr) -> bool {
+    false
+}
+
+#[test]
+fn test_redirect_loop_guard() {
+    let listen: SocketAddr = "127.0.0.1:9040".parse().unwrap();
+    assert!(is_redirect_loop(listen, listen, not_local));
+    assert!(!is_redirect_loop(
+        "127.0.0.1:80".parse().unwrap(),
+        listen,
+        not_local
+    ));
+    assert!(!is_redirect_loop(
+        "192.0.2.1:9040".parse().unwrap(),
+        listen,
+        not_local
+    ));
+
+    // Wildcard listeners are reachable on every local address.
+    let any: SocketAddr = "0.0.0.0:9040".parse().unwrap();
+    assert!(is_redirect_loop(
+        "127.0.0.1:9040".parse().unwrap(),
+        any,
+        not_local
+    ));
+    assert!(is_redirect_loop(
+        "10.1.2.3:9040".parse().unwrap(),
+        any,
+        |ip| ip == "10.1.2.3".parse::<IpAddr>().unwrap()
+    ));
+    assert!(!is_redirect_loop(
+        "192.0.2.1:9040".parse().unwrap(),
+        any,
+        not_local
+    ));
+    assert!(!is_redirect_loop(
+        "10.1.2.3:443".parse().unwrap(),
+        any,
+        |_| true
+    ));
+
+    let any_v6: SocketAddr = "[::]:9040".parse().unwrap();
+    assert!(is_redirect_loop(
+        "[::1]:9040".parse().unwrap(),
+        any_v6,
+        not_local
+    ));
+    assert!(is_redirect_loop(
+        "[::ffff:127.0.0.1]:9040".parse().unwrap(),
+        any_v6,
+        not_local
+    ));
+}
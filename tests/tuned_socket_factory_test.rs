@@ -0,0 +1,61 @@
+use s2p::iroh::{SocketFactory, SocketOptions, TunedSocketFactory};
+use socket2::SockRef;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+#[tokio::test]
+async fn test_tuned_socket_factory_applies_source_address() {
+    let options = SocketOptions::builder()
+        .source_ipv4(Ipv4Addr::LOCALHOST)
+        .nodelay(true)
+        .keepalive_time(Duration::from_secs(30))
+        .send_buffer_size(64 * 1024usize)
+        .ttl(32u32)
+        .dscp(46u8)
+        .build()
+        .unwrap();
+    let factory = TunedSocketFactory::arc(options);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let _stream = factory.connect_stream(addr).await.unwrap();
+    let (_, peer) = listener.accept().await.unwrap();
+    assert_eq!(peer.ip(), Ipv4Addr::LOCALHOST);
+
+    let socket = factory.bind_datagram("0.0.0.0:0").await.unwrap();
+    assert_eq!(socket.local_addr().unwrap().ip(), Ipv4Addr::LOCALHOST);
+}
+
+#[tokio::test]
+async fn test_tuned_socket_factory_rejects_invalid_dscp() {
+    let options = SocketOptions::builder().dscp(64u8).build().unwrap();
+    let factory = TunedSocketFactory::arc(options);
+    assert!(factory.bind_datagram("0.0.0.0:0").await.is_err());
+}
+
+#[tokio::test]
+async fn test_tuned_socket_factory_ipv6_datagrams() {
+    if std::net::UdpSocket::bind("[::1]:0").is_err() {
+        return;
+    }
+    let options = SocketOptions::builder()
+        .source_ipv4(Ipv4Addr::LOCALHOST)
+        .source_ipv6(Ipv6Addr::LOCALHOST)
+        .ttl(17u32)
+        .dscp(46u8)
+        .build()
+        .unwrap();
+    let factory = TunedSocketFactory::new(options);
+
+    let socket = factory.create_udp_socket("[::]:0").await.unwrap();
+    assert_eq!(socket.local_addr().unwrap().ip(), Ipv6Addr::LOCALHOST);
+    let socket = SockRef::from(&socket);
+    assert_eq!(socket.unicast_hops_v6().unwrap(), 17);
+    #[cfg(target_os = "linux")]
+    assert_eq!(socket.tclass_v6().unwrap(), 46 << 2);
+
+    let transport = TunedSocketFactory::arc(factory.options().clone());
+    let socket = transport.bind_datagram("[::]:0").await.unwrap();
+    assert_eq!(socket.local_addr().unwrap().ip(), Ipv6Addr::LOCALHOST);
+}
@@ -0,0 +1,16 @@
+use s2p::iroh::UnixSocketPolicy;
+use std::path::Path;
+
+#[test]
+fn test_unix_socket_policy() {
+    assert!(!UnixSocketPolicy::new().allows(Path::new("/var/run/docker.sock")));
+
+    let policy = UnixSocketPolicy::new()
+        .allow("/var/run/docker.sock")
+        .allow("/run/postgresql");
+    assert!(policy.allows(Path::new("/var/run/docker.sock")));
+    assert!(policy.allows(Path::new("/run/postgresql/.s.PGSQL.5432")));
+    assert!(!policy.allows(Path::new("/run/postgresql/../secrets.sock")));
+    assert!(!policy.allows(Path::new("/run/postgresql-other.sock")));
+    assert!(!policy.allows(Path::new("run/postgresql/.s.PGSQL.5432")));
+}
@@ -0,0 +1,382 @@
+use ::iroh::SecretKey;
+use s2p::iroh::{
+    DefaultSocketFactory, DnsResolver, HttpConnectTransportFactory, ProxyCredentials, S2pProtocol,
+    Socks5TransportFactory, TargetPolicy, TransportFactory, TransportRouter,
+};
+use s2p::message_types::{ConnectStatusCode, Host, TargetAddress};
+use s2p::test_util::{
+    assert_connect_status, MockDnsResolver, MockSocketFactory, MockTarget, TestNodes,
+};
+use std::collections::HashSet;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::oneshot;
+
+async fn echo_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let (mut reader, mut writer) = stream.split();
+        tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+    });
+    addr
+}
+
+// Minimal SOCKS5 server accepting one CONNECT with username/password authentication.
+async fn socks5_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut greeting = [0u8; 3];
+        stream.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(greeting, [5, 1, 2]);
+        stream.write_all(&[5, 2]).await.unwrap();
+
+        let mut auth = vec![0u8; 2 + 4 + 1 + 6];
+        stream.read_exact(&mut auth).await.unwrap();
+        assert_eq!(&auth[..], b"\x01\x04user\x06secret");
+        stream.write_all(&[1, 0]).await.unwrap();
+
+        let mut request = [0u8; 10];
+        stream.read_exact(&mut request).await.unwrap();
+        assert_eq!(&request[..4], &[5, 1, 0, 1]);
+        let target = SocketAddr::from((
+            [request[4], request[5], request[6], request[7]],
+            u16::from_be_bytes([request[8], request[9]]),
+        ));
+        let mut upstream = TcpStream::connect(target).await.unwrap();
+        stream
+            .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0])
+            .await
+            .unwrap();
+        tokio::io::copy_bidirectional(&mut stream, &mut upstream)
+            .await
+            .unwrap();
+    });
+    addr
+}
+
+async fn http_proxy_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            request.push(stream.read_u8().await.unwrap());
+        }
+        let request = String::from_utf8(request).unwrap();
+        assert!(request.contains("Proxy-Authorization: Basic dXNlcjpzZWNyZXQ=\r\n"));
+        let target: SocketAddr = request.split_whitespace().nth(1).unwrap().parse().unwrap();
+        let mut upstream = TcpStream::connect(target).await.unwrap();
+        stream
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await
+            .unwrap();
+        tokio::io::copy_bidirectional(&mut stream, &mut upstream)
+            .await
+            .unwrap();
+    });
+    addr
+}
+
+// SOCKS5 server without authentication that reports the requested domain name and
+// connects every request to `target`.
+async fn socks5_domain_server(target: SocketAddr) -> (SocketAddr, oneshot::Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (requested, received) = oneshot::channel();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut greeting = [0u8; 3];
+        stream.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(greeting, [5, 1, 0]);
+        stream.write_all(&[5, 0]).await.unwrap();
+
+        let mut request = [0u8; 5];
+        stream.read_exact(&mut request).await.unwrap();
+        assert_eq!(&request[..4], &[5, 1, 0, 3]);
+        let mut domain = vec![0u8; request[4] as usize];
+        stream.read_exact(&mut domain).await.unwrap();
+        let port = stream.read_u16().await.unwrap();
+        let _ = requested.send(format!("{}:{}", String::from_utf8(domain).unwrap(), port));
+
+        let mut upstream = TcpStream::connect(target).await.unwrap();
+        stream
+            .write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0])
+            .await
+            .unwrap();
+        tokio::io::copy_bidirectional(&mut stream, &mut upstream)
+            .await
+            .unwrap();
+    });
+    (addr, received)
+}
+
+// SOCKS5 server on IPv4 without authentication accepting one UDP ASSOCIATE. Its relay
+// returns every datagram as it came, so the reply appears to come from the target.
+async fn socks5_udp_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut greeting = [0u8; 3];
+        stream.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(greeting, [5, 1, 0]);
+        stream.write_all(&[5, 0]).await.unwrap();
+
+        let mut request = [0u8; 10];
+        stream.read_exact(&mut request).await.unwrap();
+        assert_eq!(&request[..4], &[5, 3, 0, 1]);
+        let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut reply = vec![5, 0, 0, 1, 127, 0, 0, 1];
+        reply.extend_from_slice(&relay.local_addr().unwrap().port().to_be_bytes());
+        stream.write_all(&reply).await.unwrap();
+
+        let mut packet = [0u8; 1024];
+        loop {
+            let (len, from) = relay.recv_from(&mut packet).await.unwrap();
+            relay.send_to(&packet[..len], from).await.unwrap();
+        }
+    });
+    addr
+}
+
+// HTTP proxy that reports the request line and connects every request to `target`.
+async fn http_domain_server(target: SocketAddr) -> (SocketAddr, oneshot::Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (requested, received) = oneshot::channel();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            request.push(stream.read_u8().await.unwrap());
+        }
+        let request = String::from_utf8(request).unwrap();
+        let _ = requested.send(request.lines().next().unwrap().to_string());
+
+        let mut upstream = TcpStream::connect(target).await.unwrap();
+        stream
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await
+            .unwrap();
+        tokio::io::copy_bidirectional(&mut stream, &mut upstream)
+            .await
+            .unwrap();
+    });
+    (addr, received)
+}
+
+#[tokio::test]
+async fn test_socks5_connect_with_auth() {
+    let target = echo_server().await;
+    let factory = Socks5TransportFactory::arc(
+        socks5_server().await,
+        Some(ProxyCredentials::new("user", "secret")),
+    );
+
+    let mut stream = factory.connect_stream(target).await.unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+}
+
+#[tokio::test]
+async fn test_http_connect_with_auth() {
+    let target = echo_server().await;
+    let factory = HttpConnectTransportFactory::arc(
+        http_proxy_server().await,
+        Some(ProxyCredentials::new("user", "secret")),
+    );
+
+    let mut stream = factory.connect_stream(target).await.unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+
+    assert!(factory.bind_datagram("0.0.0.0:0").await.is_err());
+}
+
+#[tokio::test]
+async fn test_socks5_datagram_to_other_address_family() {
+    let factory = Socks5TransportFactory::arc(socks5_udp_server().await, None);
+    // An IPv6 target picks an IPv6 bind address, but the relay is reached over IPv4.
+    let socket = factory.bind_datagram("[::]:0").await.unwrap();
+    assert!(socket.local_addr().unwrap().is_ipv4());
+
+    let target: SocketAddr = "[2001:db8::1]:53".parse().unwrap();
+    socket.send_to(b"ping", target).await.unwrap();
+    let mut buf = [0u8; 16];
+    let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+    assert_eq!(&buf[..len], b"ping");
+    assert_eq!(from, target);
+}
+
+#[test]
+fn test_transport_router_rules() {
+    let direct = DefaultSocketFactory::transport();
+    let corporate = HttpConnectTransportFactory::arc("10.0.0.1:3128".parse().unwrap(), None);
+    let internal = Socks5TransportFactory::arc("10.0.0.2:1080".parse().unwrap(), None);
+    let router = TransportRouter::new(direct.clone())
+        .with_rule("*.corp.internal:443".parse().unwrap(), internal.clone())
+        .with_rule("0.0.0.0/0:443".parse().unwrap(), corporate.clone());
+    let target = |s: &str| s.parse::<TargetAddress>().unwrap();
+
+    assert!(Arc::ptr_eq(
+        router.factory_for(&target("93.184.216.34:443")),
+        &corporate
+    ));
+    assert!(Arc::ptr_eq(
+        router.factory_for(&target("93.184.216.34:80")),
+        &direct
+    ));
+    assert!(Arc::ptr_eq(
+        router.factory_for(&target("api.corp.internal:443")),
+        &internal
+    ));
+    assert!(router.resolves_names(&target("api.corp.internal:443")));
+    assert!(!router.resolves_names(&target("example.com:443")));
+}
+
+#[tokio::test]
+async fn test_transport_router_routes_each_datagram() {
+    let direct_target: SocketAddr = "198.51.100.1:53".parse().unwrap();
+    let routed_target: SocketAddr = "192.0.2.1:53".parse().unwrap();
+    let direct = MockSocketFactory::new().with_target(direct_target, MockTarget::Echo);
+    let routed = MockSocketFactory::new().with_target(routed_target, MockTarget::Echo);
+    let router = TransportRouter::new(Arc::new(direct.clone()))
+        .with_rule("192.0.2.0/24:53".parse().unwrap(), Arc::new(routed.clone()));
+
+    let socket = router.bind_datagram("0.0.0.0:0").await.unwrap();
+    socket.send_to(b"one", direct_target).await.unwrap();
+    socket.send_to(b"two", routed_target).await.unwrap();
+    socket.send_to(b"three", direct_target).await.unwrap();
+
+    let mut replies = HashSet::new();
+    let mut buf = [0u8; 16];
+    for _ in 0..3 {
+        let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+        replies.insert((buf[..len].to_vec(), from));
+    }
+    assert!(replies.contains(&(b"two".to_vec(), routed_target)));
+    assert!(replies.contains(&(b"three".to_vec(), direct_target)));
+
+    assert_eq!(direct.datagrams().len(), 2);
+    assert_eq!(routed.datagrams(), vec![(routed_target, b"two".to_vec())]);
+    assert_eq!(direct.binds().len(), 1);
+    assert_eq!(routed.binds().len(), 1);
+}
+
+#[tokio::test]
+async fn test_upstream_proxies_receive_domain_names() {
+    let node = SecretKey::from_bytes(&[5u8; 32]).public();
+    let target: TargetAddress = "echo.test:7".parse().unwrap();
+    // What the handler may have resolved the target to; the proxies do not connect there.
+    let resolved: SocketAddr = "192.0.2.1:7".parse().unwrap();
+
+    let (proxy, requested) = socks5_domain_server(echo_server().await).await;
+    let factory = Socks5TransportFactory::arc(proxy, None);
+    let mut stream = factory
+        .connect_target_for(node, &target, None)
+        .await
+        .unwrap();
+    assert_eq!(requested.await.unwrap(), "echo.test:7");
+    stream.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+
+    let (proxy, requested) = http_domain_server(echo_server().await).await;
+    let factory = HttpConnectTransportFactory::arc(proxy, None);
+    let mut stream = factory
+        .connect_target_for(node, &target, Some(resolved))
+        .await
+        .unwrap();
+    assert_eq!(requested.await.unwrap(), "CONNECT echo.test:7 HTTP/1.1");
+    stream.write_all(b"ping").await.unwrap();
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+}
+
+#[tokio::test]
+async fn test_exit_reaches_domain_targets_through_proxy_without_dns() {
+    let (proxy, requested) = socks5_domain_server(echo_server().await).await;
+    let router = TransportRouter::new(Arc::new(MockSocketFactory::new())).with_rule(
+        "*.test:*".parse().unwrap(),
+        Socks5TransportFactory::arc(proxy, None),
+    );
+    // The exit node cannot resolve the name itself.
+    let resolver = MockDnsResolver::new().with_failure("echo.test", io::ErrorKind::Other);
+    let socket_factory: Arc<dyn TransportFactory> = Arc::new(router);
+    let dns_resolver: Arc<dyn DnsResolver> = Arc::new(resolver);
+    let mut builder = S2pProtocol::builder();
+    builder
+        .socket_factory(socket_factory)
+        .dns_resolver(dns_resolver);
+    let nodes = TestNodes::start(builder.build().unwrap()).await.unwrap();
+
+    let mut stream = nodes
+        .tcp_client()
+        .connect("echo.test:7".parse().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(requested.await.unwrap(), "echo.test:7");
+    stream.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+
+    nodes.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_proxies_refuse_domains_that_inject_requests() {
+    // Matches `*.corp.internal` but would smuggle a second CONNECT to the HTTP proxy.
+    let target = TargetAddress {
+        host: Host::Domain("evil.test:22 HTTP/1.1\r\n\r\nCONNECT x.corp.internal".to_string()),
+        port: 443,
+    };
+    let policy = TargetPolicy {
+        allow: Some(vec!["*.corp.internal:*".parse().unwrap()]),
+        deny: Vec::new(),
+    };
+
+    for socks in [false, true] {
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+        let socket_factory = if socks {
+            Socks5TransportFactory::arc(proxy_addr, None)
+        } else {
+            HttpConnectTransportFactory::arc(proxy_addr, None)
+        };
+        let mut builder = S2pProtocol::builder();
+        builder
+            .socket_factory(socket_factory)
+            .target_policy(policy.clone());
+        let nodes = TestNodes::start(builder.build().unwrap()).await.unwrap();
+
+        assert_connect_status(
+            &nodes.connection,
+            target.clone(),
+            ConnectStatusCode::AddressTypeNotSupported,
+        )
+        .await;
+        // Nothing reached the proxy.
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), proxy.accept())
+                .await
+                .is_err()
+        );
+
+        nodes.shutdown().await;
+    }
+}